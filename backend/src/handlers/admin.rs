@@ -0,0 +1,606 @@
+use std::collections::BTreeMap;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+use validator::Validate;
+use crate::middleware::{AppError, AuthUser};
+use crate::models::{
+    AuditAction, MemberSummary, Money, OutboxStatus, OverpaymentDestination, PaymentStatus, Permission, ReconciliationStatus, Role,
+};
+use crate::services::accounts::{AccountService, MemberQuery};
+use crate::services::audit::{AuditLog, AuditQuery};
+use crate::services::ledger::LedgerService;
+use crate::services::loan_review::LoanReview;
+use crate::services::outbox::Outbox;
+use crate::services::repayments::{AllocationOrder, NewRepayment, RepaymentService};
+use crate::services::reversals::ReversalService;
+use crate::services::scoring::{ScoringService, ScoringWeights};
+
+#[derive(Deserialize, Validate)]
+pub struct ReasonRequest {
+    #[validate(length(min = 3, max = 500, message = "Please give a reason"))]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct RoleRequest {
+    pub role: Role,
+    #[validate(length(min = 3, max = 500, message = "Please give a reason"))]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ScoreAdjustmentRequest {
+    /// Added to the computed score; negative to take points off.
+    #[validate(range(min = -1000, max = 1000, message = "Adjustments are between -1000 and 1000 points"))]
+    pub points: i32,
+    #[validate(length(min = 3, max = 500, message = "Please give a reason"))]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ApproveLoanRequest {
+    #[validate(length(max = 500))]
+    pub note: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct RecordRepaymentRequest {
+    pub amount: Money,
+    /// Receipt of the paybill payment, checked against the M-Pesa statement.
+    #[validate(length(min = 8, max = 30, message = "Please give the M-Pesa receipt number"))]
+    pub mpesa_receipt_number: String,
+    /// Where money beyond the outstanding balance goes; savings when omitted.
+    pub overpayment: Option<OverpaymentDestination>,
+    #[validate(length(min = 3, max = 500, message = "Please give a reason"))]
+    pub reason: String,
+}
+
+fn audit_failed(e: sqlx::Error) -> AppError {
+    tracing::error!("Failed to write the audit log: {:?}", e);
+    AppError::InternalServerError
+}
+
+async fn member(conn: &mut sqlx::PgConnection, user_id: Uuid) -> Result<MemberSummary, AppError> {
+    AccountService::find(conn, user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load member {}: {:?}", user_id, e);
+            AppError::InternalServerError
+        })?
+        .ok_or(AppError::NotFound)
+}
+
+/// Members matching a username, email or phone number fragment, a role or a frozen state.
+pub async fn search_members(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    query: web::Query<MemberQuery>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::ViewMembers)?;
+
+    let members = AccountService::search(pool.get_ref(), &query).await.map_err(|e| {
+        tracing::error!("Failed to search members: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let details = json!({ "q": query.q, "role": query.role, "frozen": query.frozen, "results": members.len() });
+    AuditLog::record(&mut conn, actor_id, AuditAction::SearchMembers, "user", None, None, details)
+        .await
+        .map_err(audit_failed)?;
+
+    Ok(HttpResponse::Ok().json(members))
+}
+
+pub async fn get_member(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    member_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::ViewMembers)?;
+
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let summary = member(&mut conn, *member_id).await?;
+    AuditLog::record(&mut conn, actor_id, AuditAction::ViewMember, "user", Some(*member_id), None, json!({}))
+        .await
+        .map_err(audit_failed)?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+/// Freezes a member's account: they are signed out everywhere and cannot log in or move money.
+pub async fn freeze_member(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    member_id: web::Path<Uuid>,
+    form: web::Json<ReasonRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::FreezeAccounts)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+    if *member_id == actor_id {
+        return Err(AppError::BadRequest("You cannot freeze your own account".to_string()));
+    }
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let sessions_ended = AccountService::freeze(&mut tx, *member_id, &form.reason, Utc::now()).await?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::FreezeAccount,
+        "user",
+        Some(*member_id),
+        Some(&form.reason),
+        json!({ "sessions_ended": sessions_ended }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    let summary = member(&mut tx, *member_id).await?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+pub async fn unfreeze_member(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    member_id: web::Path<Uuid>,
+    form: web::Json<ReasonRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::FreezeAccounts)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    AccountService::unfreeze(&mut tx, *member_id).await?;
+    AuditLog::record(&mut tx, actor_id, AuditAction::UnfreezeAccount, "user", Some(*member_id), Some(&form.reason), json!({}))
+        .await
+        .map_err(audit_failed)?;
+    let summary = member(&mut tx, *member_id).await?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+/// Grants a staff role. The member gets it in their next access token.
+pub async fn grant_role(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    member_id: web::Path<Uuid>,
+    form: web::Json<RoleRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::ManageRoles)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    AccountService::grant_role(&mut tx, *member_id, form.role, actor_id).await?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::GrantRole,
+        "user",
+        Some(*member_id),
+        Some(&form.reason),
+        json!({ "role": form.role }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    let summary = member(&mut tx, *member_id).await?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+pub async fn revoke_role(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    member_id: web::Path<Uuid>,
+    form: web::Json<RoleRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::ManageRoles)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let sessions_ended = AccountService::revoke_role(&mut tx, *member_id, form.role, actor_id, Utc::now()).await?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::RevokeRole,
+        "user",
+        Some(*member_id),
+        Some(&form.reason),
+        json!({ "role": form.role, "sessions_ended": sessions_ended }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    let summary = member(&mut tx, *member_id).await?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+/// Adds or takes off points on a member's credit score for good, and rescores them.
+pub async fn adjust_score(
+    pool: web::Data<PgPool>,
+    weights: web::Data<ScoringWeights>,
+    user: AuthUser,
+    member_id: web::Path<Uuid>,
+    form: web::Json<ScoreAdjustmentRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::AdjustScores)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+    if form.points == 0 {
+        return Err(AppError::BadRequest("An adjustment needs a non-zero number of points".to_string()));
+    }
+    if *member_id == actor_id {
+        return Err(AppError::BadRequest("You cannot adjust your own score".to_string()));
+    }
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let before = member(&mut tx, *member_id).await?.reputation_score;
+    let breakdown = ScoringService::adjust(&mut tx, *member_id, form.points, &form.reason, actor_id, weights.get_ref(), Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to adjust score of user {}: {}", member_id, e);
+            AppError::InternalServerError
+        })?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::AdjustScore,
+        "user",
+        Some(*member_id),
+        Some(&form.reason),
+        json!({ "points": form.points, "score_before": before, "score_after": breakdown.score }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(breakdown))
+}
+
+/// Loan requests waiting for approval before lenders can fund them.
+pub async fn get_review_queue(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::ReviewLoans)?;
+
+    let loans = LoanReview::queue(pool.get_ref()).await.map_err(|e| {
+        tracing::error!("Failed to fetch loans for review: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok(HttpResponse::Ok().json(loans))
+}
+
+pub async fn approve_loan(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+    form: web::Json<ApproveLoanRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::ReviewLoans)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let loan = LoanReview::approve(&mut tx, *loan_id, actor_id, Utc::now()).await?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::ApproveLoan,
+        "loan",
+        Some(*loan_id),
+        form.note.as_deref(),
+        json!({ "borrower_id": loan.user_id, "amount": loan.amount }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(loan))
+}
+
+/// Turns down a loan request; it is cancelled with the reason in its history.
+pub async fn reject_loan(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+    form: web::Json<ReasonRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::ReviewLoans)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let loan = LoanReview::reject(&mut tx, *loan_id, actor_id, &form.reason).await?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::RejectLoan,
+        "loan",
+        Some(*loan_id),
+        Some(&form.reason),
+        json!({ "borrower_id": loan.user_id, "amount": loan.amount }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(loan))
+}
+
+/// Takes a savings deposit that should not have been credited back out of its goal.
+pub async fn reverse_savings_transaction(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    transaction_id: web::Path<Uuid>,
+    form: web::Json<ReasonRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::ReverseTransactions)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let reversal = ReversalService::reverse_savings_deposit(&mut tx, *transaction_id, actor_id).await?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::ReverseTransaction,
+        "savings_transaction",
+        Some(*transaction_id),
+        Some(&form.reason),
+        json!({
+            "savings_id": reversal.savings_id,
+            "amount": reversal.amount,
+            "reversal_id": reversal.id,
+            "journal_entry_id": reversal.journal_entry_id,
+        }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(reversal))
+}
+
+/// Books a repayment a borrower made to the paybill outside the app. Members' own
+/// repayments are collected with an STK push and applied once M-Pesa confirms them.
+pub async fn record_repayment(
+    pool: web::Data<PgPool>,
+    order: web::Data<AllocationOrder>,
+    weights: web::Data<ScoringWeights>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+    form: web::Json<RecordRepaymentRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::RecordRepayments)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let payment = NewRepayment {
+        loan_id: *loan_id,
+        payer_id: None,
+        amount: form.amount,
+        overpayment_destination: form.overpayment.unwrap_or_default(),
+        savings_id: None,
+        mpesa_receipt_number: Some(form.mpesa_receipt_number.trim().to_uppercase()),
+        payment_id: None,
+    };
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let receipt = RepaymentService::apply(&mut tx, &payment, order.get_ref(), weights.get_ref()).await?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::RecordRepayment,
+        "loan",
+        Some(*loan_id),
+        Some(&form.reason),
+        json!({
+            "repayment_id": receipt.repayment.id,
+            "amount": receipt.repayment.amount,
+            "mpesa_receipt_number": receipt.repayment.mpesa_receipt_number,
+        }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(receipt))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct JobHealth {
+    name: String,
+    next_run_at: DateTime<Utc>,
+    last_started_at: Option<DateTime<Utc>>,
+    last_finished_at: Option<DateTime<Utc>>,
+    last_status: Option<String>,
+    last_error: Option<String>,
+    run_count: i64,
+}
+
+#[derive(Serialize)]
+struct PlatformHealth {
+    database_latency_ms: i64,
+    members: i64,
+    frozen_members: i64,
+    locked_members: i64,
+    loans_by_status: BTreeMap<String, i64>,
+    loans_awaiting_approval: i64,
+    pending_payments: i64,
+    oldest_pending_payment_at: Option<DateTime<Utc>>,
+    open_reconciliation_exceptions: i64,
+    /// Refunds still to be paid out, and those M-Pesa kept turning down that staff must pay another way.
+    pending_refunds: i64,
+    failed_refunds: i64,
+    /// Side effects still waiting to be delivered, and those given up on.
+    pending_outbox_events: i64,
+    dead_outbox_events: i64,
+    ledger_balanced: bool,
+    jobs: Vec<JobHealth>,
+}
+
+/// How the platform is doing: the database, members, loans, payments, the ledger and
+/// the background jobs.
+pub async fn get_platform_health(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::ViewPlatformHealth)?;
+    let failed = |e: sqlx::Error| {
+        tracing::error!("Failed to gather platform health: {:?}", e);
+        AppError::InternalServerError
+    };
+
+    let started = std::time::Instant::now();
+    sqlx::query("SELECT 1").execute(pool.get_ref()).await.map_err(failed)?;
+    let database_latency_ms = started.elapsed().as_millis() as i64;
+
+    let now = Utc::now();
+    let (members, frozen_members, locked_members): (i64, i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*), COUNT(*) FILTER (WHERE frozen_at IS NOT NULL), COUNT(*) FILTER (WHERE locked_until > $1) FROM users"
+    )
+    .bind(now)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(failed)?;
+
+    let loans_by_status: Vec<(String, i64)> = sqlx::query_as("SELECT status, COUNT(*) FROM loans GROUP BY status")
+        .fetch_all(pool.get_ref())
+        .await
+        .map_err(failed)?;
+    let loans_awaiting_approval = LoanReview::queue(pool.get_ref()).await.map_err(failed)?.len() as i64;
+
+    let (pending_payments, oldest_pending_payment_at): (i64, Option<DateTime<Utc>>) =
+        sqlx::query_as("SELECT COUNT(*), MIN(created_at) FROM payments WHERE status = $1")
+            .bind(PaymentStatus::Pending)
+            .fetch_one(pool.get_ref())
+            .await
+            .map_err(failed)?;
+
+    let (open_reconciliation_exceptions,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM reconciliation_items WHERE status = $1")
+        .bind(ReconciliationStatus::Open)
+        .fetch_one(pool.get_ref())
+        .await
+        .map_err(failed)?;
+
+    let (pending_refunds, failed_refunds): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*) FILTER (WHERE status = $1), COUNT(*) FILTER (WHERE status = $2) FROM refunds"
+    )
+    .bind(PaymentStatus::Pending)
+    .bind(PaymentStatus::Failed)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(failed)?;
+
+    let (pending_outbox_events, dead_outbox_events): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*) FILTER (WHERE status = $1), COUNT(*) FILTER (WHERE status = $2) FROM outbox_events"
+    )
+    .bind(OutboxStatus::Pending)
+    .bind(OutboxStatus::Dead)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(failed)?;
+
+    let ledger_balanced = LedgerService::trial_balance(pool.get_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to compute trial balance: {}", e);
+            AppError::InternalServerError
+        })?
+        .balanced;
+
+    let jobs: Vec<JobHealth> = sqlx::query_as(
+        "SELECT name, next_run_at, last_started_at, last_finished_at, last_status, last_error, run_count
+         FROM scheduled_jobs ORDER BY name"
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(failed)?;
+
+    Ok(HttpResponse::Ok().json(PlatformHealth {
+        database_latency_ms,
+        members,
+        frozen_members,
+        locked_members,
+        loans_by_status: loans_by_status.into_iter().collect(),
+        loans_awaiting_approval,
+        pending_payments,
+        oldest_pending_payment_at,
+        open_reconciliation_exceptions,
+        pending_refunds,
+        failed_refunds,
+        pending_outbox_events,
+        dead_outbox_events,
+        ledger_balanced,
+        jobs,
+    }))
+}
+
+/// What staff did, newest first, optionally for one actor, record or kind of action.
+pub async fn get_audit_log(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::ViewAuditLog)?;
+
+    let entries = AuditLog::entries(pool.get_ref(), &query).await.map_err(|e| {
+        tracing::error!("Failed to fetch the audit log: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// Side effects that are not going through: dead-lettered events, and pending ones that have
+/// failed or waited too long, oldest first.
+pub async fn get_stuck_events(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::ViewPlatformHealth)?;
+
+    let events = Outbox::stuck(pool.get_ref(), Utc::now()).await.map_err(|e| {
+        tracing::error!("Failed to fetch stuck outbox events: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Sends a dead-lettered event again, once whatever made it fail has been fixed.
+pub async fn redeliver_event(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    event_id: web::Path<Uuid>,
+    form: web::Json<ReasonRequest>,
+) -> Result<HttpResponse, AppError> {
+    let actor_id = user.require(Permission::RedeliverEvents)?;
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let event = Outbox::redeliver(&mut *tx, *event_id, Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to requeue outbox event {}: {:?}", event_id, e);
+            AppError::InternalServerError
+        })?
+        .ok_or_else(|| AppError::Conflict("Only dead events can be sent again".to_string()))?;
+    AuditLog::record(
+        &mut tx,
+        actor_id,
+        AuditAction::RedeliverEvent,
+        "outbox_event",
+        Some(*event_id),
+        Some(&form.reason),
+        json!({ "event_type": event.event_type }),
+    )
+    .await
+    .map_err(audit_failed)?;
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(event))
+}
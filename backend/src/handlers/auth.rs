@@ -1,15 +1,21 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
-use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2, PasswordHash,
-};
-use jsonwebtoken::{encode, Header, EncodingKey};
-use chrono::{Utc, Duration};
+use sqlx::PgPool;
+use chrono::Utc;
 use uuid::Uuid;
-use crate::models::User;
-use crate::middleware::AppError;
+use crate::models::{OtpPurpose, Role, SessionRevocation, SessionSummary, User};
+use crate::middleware::{AppError, AuthUser};
+use crate::middleware::auth::JwtConfig;
+use crate::services::accounts::AccountService;
+use crate::services::lockout::LoginLockout;
+use crate::services::mpesa::MpesaClient;
+use crate::services::notifier::Notifier;
+use crate::services::otp::{self, OtpService};
+use crate::services::passwords::{self, PasswordResetConfig, PasswordService};
+use crate::services::sessions::{ClientInfo, SessionService};
+use crate::services::sms::SmsProvider;
+use crate::services::two_factor::{self, TwoFactorService};
+use crate::services::scoring::{ScoreBreakdown, ScoreSnapshot, ScoringService, ScoringWeights};
 
 use validator::Validate;
 
@@ -17,62 +23,216 @@ use validator::Validate;
 pub struct RegisterRequest {
     #[validate(length(min = 3, message = "Username must be at least 3 characters"))]
     pub username: String,
+    /// Phone sign-up: the number and the code sent to it by `/auth/otp`.
+    pub phone_number: Option<String>,
+    pub code: Option<String>,
     #[validate(email(message = "Invalid email format"))]
-    pub email: String,
+    pub email: Option<String>,
+    /// Optional with phone sign-up, whose members can log in with SMS codes.
     #[validate(length(min = 6, message = "Password must be at least 6 characters"))]
-    pub password: String,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OtpRequest {
+    pub phone_number: String,
+    pub purpose: OtpPurpose,
+}
+
+#[derive(Deserialize)]
+pub struct OtpLoginRequest {
+    pub phone_number: String,
+    pub code: String,
 }
 
 #[derive(Serialize)]
 pub struct AuthResponse {
+    /// Short-lived access token, sent as `Authorization: Bearer`.
     pub token: String,
+    /// Trades for a new token pair at `/auth/refresh`. Single use.
+    pub refresh_token: String,
+    /// Seconds until `token` expires.
+    pub expires_in: i64,
     pub user_id: Uuid,
+    pub session_id: Uuid,
+}
+
+/// Returned by the login endpoints instead of tokens when the member has two-factor
+/// authentication on. The client sends a code with `challenge_token` to `/auth/login/2fa`.
+#[derive(Serialize)]
+pub struct TwoFactorChallenge {
+    pub two_factor_required: bool,
+    pub challenge_token: String,
+    /// Seconds left to answer the challenge.
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub challenge_token: String,
+    /// From the authenticator app, or one of the recovery codes.
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCodeRequest {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    /// Username, email address or phone number.
+    pub account: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    #[validate(length(min = 6, message = "Password must be at least 6 characters"))]
+    pub new_password: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    #[validate(length(min = 6, message = "Password must be at least 6 characters"))]
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Sends a one-time code by SMS, to sign up with a number or to log in with it.
+pub async fn request_otp(
+    pool: web::Data<PgPool>,
+    sms: web::Data<dyn SmsProvider>,
+    form: web::Json<OtpRequest>,
+) -> Result<HttpResponse, AppError> {
+    let phone = normalize_phone(&form.phone_number)?;
+    let registered = find_by_phone(pool.get_ref(), &phone).await?;
+
+    match (form.purpose, registered) {
+        (OtpPurpose::Signup, Some(_)) => {
+            return Err(AppError::Conflict("This number is already registered; log in instead".to_string()));
+        }
+        // Answered like a sent code, so the endpoint does not reveal who is a member
+        (OtpPurpose::Login, None) => {
+            tracing::info!("Login code asked for unregistered number {}", phone);
+        }
+        _ => OtpService::send(pool.get_ref(), sms.get_ref(), &phone, form.purpose, Utc::now()).await?,
+    }
+
+    Ok(HttpResponse::Accepted().json(serde_json::json!({ "expires_in": otp::CODE_TTL_MINUTES * 60 })))
 }
 
+/// Signs up with a phone number and the code sent to it, or with an email and password.
 pub async fn register(
-    pool: web::Data<SqlitePool>,
+    pool: web::Data<PgPool>,
+    jwt: web::Data<JwtConfig>,
+    req: HttpRequest,
     form: web::Json<RegisterRequest>,
 ) -> Result<HttpResponse, AppError> {
     form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
-    
-    tracing::info!("Registering user: {}", form.username);
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(form.password.as_bytes(), &salt)
-        .map_err(|_| AppError::InternalServerError)?
-        .to_string();
 
-    let user_id = Uuid::new_v4();
+    let phone = form.phone_number.as_deref().map(normalize_phone).transpose()?;
+    if phone.is_none() && (form.email.is_none() || form.password.is_none()) {
+        return Err(AppError::BadRequest(
+            "Sign up with a phone number and the code sent to it, or with an email and password".to_string(),
+        ));
+    }
 
-    let result = sqlx::query!(
-        "INSERT INTO users (id, username, email, password_hash) VALUES ($1, $2, $3, $4) RETURNING id",
-        user_id,
-        form.username,
-        form.email,
-        password_hash
+    if let Some(phone) = &phone {
+        let code = form.code.as_deref().ok_or_else(|| {
+            AppError::BadRequest("Enter the code sent to your phone".to_string())
+        })?;
+
+        // Checked before the code is used up, so a taken username does not cost the member their code
+        let (taken,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS (SELECT 1 FROM users WHERE username = $1 OR email = $2 OR phone_number = $3)"
+        )
+        .bind(&form.username)
+        .bind(form.email.as_deref())
+        .bind(phone)
+        .fetch_one(pool.get_ref())
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        if taken {
+            return Err(AppError::Conflict("Username, email or phone number already exists".to_string()));
+        }
+
+        OtpService::verify(pool.get_ref(), phone, OtpPurpose::Signup, code, Utc::now()).await?;
+    }
+
+    tracing::info!("Registering user: {}", form.username);
+    let password_hash = form
+        .password
+        .as_deref()
+        .map(PasswordService::hash)
+        .transpose()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    let result: Result<(Uuid,), sqlx::Error> = sqlx::query_as(
+        "INSERT INTO users (username, email, password_hash, phone_number, phone_verified_at)
+         VALUES ($1, $2, $3, $4, CASE WHEN $4::varchar IS NULL THEN NULL ELSE NOW() END) RETURNING id"
     )
+    .bind(&form.username)
+    .bind(form.email.as_deref())
+    .bind(password_hash.as_deref())
+    .bind(phone.as_deref())
     .fetch_one(pool.get_ref())
     .await;
 
     match result {
-        Ok(record) => {
-            let token = generate_token(record.id);
-            Ok(HttpResponse::Ok().json(AuthResponse {
-                token,
-                user_id: record.id,
-            }))
+        Ok((user_id,)) => {
+            let auth = open_session(pool.get_ref(), &jwt, &req, user_id).await?;
+            Ok(HttpResponse::Ok().json(auth))
         }
         Err(e) => {
             log::error!("Failed to register user: {:?}", e);
-            if e.to_string().contains("unique constraint") {
-                return Err(AppError::Conflict("Username or email already exists".to_string()));
+            if e.as_database_error().is_some_and(|d| d.is_unique_violation()) {
+                return Err(AppError::Conflict("Username, email or phone number already exists".to_string()));
             }
             Err(AppError::InternalServerError)
         }
     }
 }
 
+/// Logs in with the code sent to a registered phone number.
+pub async fn login_with_otp(
+    pool: web::Data<PgPool>,
+    jwt: web::Data<JwtConfig>,
+    req: HttpRequest,
+    form: web::Json<OtpLoginRequest>,
+) -> Result<HttpResponse, AppError> {
+    let phone = normalize_phone(&form.phone_number)?;
+    let user = find_by_phone(pool.get_ref(), &phone).await?.ok_or(AppError::Unauthorized)?;
+
+    let now = Utc::now();
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    LoginLockout::ensure_unlocked(&mut conn, user.id, now).await?;
+    if OtpService::verify(pool.get_ref(), &phone, OtpPurpose::Login, &form.code, now).await.is_err() {
+        LoginLockout::record_failure(&mut conn, user.id, now).await?;
+        return Err(AppError::Unauthorized);
+    }
+
+    finish_login(pool.get_ref(), &jwt, &req, &user).await
+}
+
+/// Second step of logging in for members with two-factor authentication on.
+pub async fn login_with_two_factor(
+    pool: web::Data<PgPool>,
+    jwt: web::Data<JwtConfig>,
+    req: HttpRequest,
+    form: web::Json<TwoFactorLoginRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = TwoFactorService::complete_challenge(pool.get_ref(), &form.challenge_token, &form.code, Utc::now()).await?;
+
+    let auth = open_session(pool.get_ref(), &jwt, &req, user_id).await?;
+    Ok(HttpResponse::Ok().json(auth))
+}
+
 #[derive(Deserialize)]
 pub struct LoginRequest {
     pub username: String,
@@ -81,26 +241,29 @@ pub struct LoginRequest {
 
 pub async fn login(
     pool: web::Data<PgPool>,
+    jwt: web::Data<JwtConfig>,
+    req: HttpRequest,
     form: web::Json<LoginRequest>,
 ) -> Result<HttpResponse, AppError> {
-    let result = sqlx::query_as!(
-        User,
-        "SELECT * FROM users WHERE username = $1",
-        form.username
-    )
-    .fetch_optional(pool.get_ref())
-    .await;
+    let result: Result<Option<User>, sqlx::Error> = sqlx::query_as("SELECT * FROM users WHERE username = $1")
+        .bind(&form.username)
+        .fetch_optional(pool.get_ref())
+        .await;
 
     match result {
         Ok(Some(user)) => {
-            let parsed_hash = PasswordHash::new(&user.password_hash).map_err(|_| AppError::InternalServerError)?;
-            if Argon2::default().verify_password(form.password.as_bytes(), &parsed_hash).is_ok() {
-                let token = generate_token(user.id);
-                Ok(HttpResponse::Ok().json(AuthResponse {
-                    token,
-                    user_id: user.id,
-                }))
+            // Members who signed up by phone without a password log in with SMS codes
+            let Some(password_hash) = user.password_hash.as_deref() else {
+                return Err(AppError::Unauthorized);
+            };
+
+            let now = Utc::now();
+            let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+            LoginLockout::ensure_unlocked(&mut conn, user.id, now).await?;
+            if PasswordService::matches(&form.password, password_hash) {
+                finish_login(pool.get_ref(), &jwt, &req, &user).await
             } else {
+                LoginLockout::record_failure(&mut conn, user.id, now).await?;
                 Err(AppError::Unauthorized)
             }
         }
@@ -114,52 +277,336 @@ pub async fn login(
 
 pub async fn get_profile(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    user: AuthUser,
 ) -> Result<HttpResponse, AppError> {
-    use crate::handlers::loans::get_user_id_from_req;
-    let user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
 
-    let user = sqlx::query_as!(
-        User,
-        "SELECT * FROM users WHERE id = $1",
-        user_id
-    )
-    .fetch_one(pool.get_ref())
-    .await
-    .map_err(|_| AppError::NotFound)?;
+    let user: User = sqlx::query_as("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(pool.get_ref())
+        .await
+        .map_err(|_| AppError::NotFound)?;
 
     #[derive(Serialize)]
     struct ProfileResponse {
         username: String,
-        email: String,
+        email: Option<String>,
+        phone_number: Option<String>,
         reputation_score: i32,
+        two_factor_enabled: bool,
+        roles: Vec<Role>,
     }
 
+    let roles = {
+        let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+        AccountService::roles_of(&mut conn, user_id).await.map_err(|_| AppError::InternalServerError)?
+    };
+
     Ok(HttpResponse::Ok().json(ProfileResponse {
+        phone_number: user.verified_phone().map(str::to_string),
+        two_factor_enabled: user.two_factor_enabled(),
+        roles,
         username: user.username,
         email: user.email,
         reputation_score: user.reputation_score,
     }))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: Uuid,
-    exp: usize,
+/// The user's credit score, the factors behind it and recent snapshots.
+pub async fn get_profile_score(
+    pool: web::Data<PgPool>,
+    weights: web::Data<ScoringWeights>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user.id;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+
+    let breakdown = ScoringService::rescore(&mut tx, user_id, weights.get_ref(), Utc::now(), "profile_request")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to score user {}: {}", user_id, e);
+            AppError::InternalServerError
+        })?;
+
+    let history = ScoringService::snapshots(&mut tx, user_id, 10)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch score snapshots: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    #[derive(Serialize)]
+    struct ScoreResponse {
+        #[serde(flatten)]
+        breakdown: ScoreBreakdown,
+        weights: ScoringWeights,
+        history: Vec<ScoreSnapshot>,
+    }
+
+    Ok(HttpResponse::Ok().json(ScoreResponse {
+        breakdown,
+        weights: weights.get_ref().clone(),
+        history,
+    }))
+}
+
+/// Trades a refresh token for a new access token and refresh token.
+pub async fn refresh(
+    pool: web::Data<PgPool>,
+    jwt: web::Data<JwtConfig>,
+    form: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, AppError> {
+    let now = Utc::now();
+    let (session, refresh_token) = SessionService::rotate(pool.get_ref(), &form.refresh_token, now).await?;
+
+    // Roles are looked up again, so a change takes effect at the next refresh
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let roles = AccountService::session_roles(&mut conn, session.user_id).await?;
+
+    let token = issue_token(&jwt, session.user_id, session.id, roles)?;
+    Ok(HttpResponse::Ok().json(AuthResponse {
+        token,
+        refresh_token,
+        expires_in: jwt.access_ttl.num_seconds(),
+        user_id: session.user_id,
+        session_id: session.id,
+    }))
+}
+
+/// Ends the session the request was made from. Its access token lapses on its own shortly after.
+pub async fn logout(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    SessionService::revoke(pool.get_ref(), user.id, user.session_id, SessionRevocation::Logout, Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to end session {}: {:?}", user.session_id, e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The member's active sessions, marking the one the request was made from.
+pub async fn get_sessions(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    let sessions = SessionService::active_for_user(pool.get_ref(), user.id, Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch sessions: {:?}", e);
+            AppError::InternalServerError
+        })?
+        .into_iter()
+        .map(|session| SessionSummary {
+            current: session.id == user.session_id,
+            session,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// Signs out another device, e.g. a lost phone.
+pub async fn revoke_session(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    session_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let revoked = SessionService::revoke(pool.get_ref(), user.id, *session_id, SessionRevocation::Revoked, Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to revoke session {}: {:?}", session_id, e);
+            AppError::InternalServerError
+        })?;
+    if !revoked {
+        return Err(AppError::NotFound);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Sends a password reset link to the member's phone or email. Answered the same whether or
+/// not the account exists, so the endpoint does not reveal who is a member.
+pub async fn forgot_password(
+    pool: web::Data<PgPool>,
+    notifier: web::Data<Notifier>,
+    config: web::Data<PasswordResetConfig>,
+    form: web::Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse, AppError> {
+    let account = form.account.trim();
+    let phone = MpesaClient::normalize_phone(account).ok();
+    let user: Option<User> = sqlx::query_as(
+        "SELECT * FROM users WHERE username = $1 OR email = $1
+         OR (phone_number = $2 AND phone_verified_at IS NOT NULL) LIMIT 1"
+    )
+    .bind(account)
+    .bind(phone)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to look up account for password reset: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    match user {
+        Some(user) => PasswordService::request_reset(pool.get_ref(), &notifier, &config, &user, Utc::now()).await?,
+        None => tracing::info!("Password reset asked for unknown account"),
+    }
+
+    Ok(HttpResponse::Accepted().json(serde_json::json!({ "expires_in": passwords::RESET_TTL_MINUTES * 60 })))
+}
+
+/// Sets a new password with the token from a reset link. Signs the member out everywhere.
+pub async fn reset_password(
+    pool: web::Data<PgPool>,
+    form: web::Json<ResetPasswordRequest>,
+) -> Result<HttpResponse, AppError> {
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+    PasswordService::reset(pool.get_ref(), &form.token, &form.new_password, Utc::now()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Changes the password of a logged-in member. Other sessions are signed out.
+pub async fn change_password(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    form: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse, AppError> {
+    form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
+    PasswordService::change(
+        pool.get_ref(),
+        user.id,
+        user.session_id,
+        &form.current_password,
+        &form.new_password,
+        Utc::now(),
+    )
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Starts enrolling an authenticator app. Returns the secret and the `otpauth://` URI to scan.
+pub async fn setup_two_factor(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    let enrollment = TwoFactorService::begin_enrollment(pool.get_ref(), user.id).await?;
+    Ok(HttpResponse::Ok().json(enrollment))
+}
+
+/// Turns two-factor authentication on with a first code from the app. The recovery codes in
+/// the response are not shown again.
+pub async fn confirm_two_factor(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    form: web::Json<TwoFactorCodeRequest>,
+) -> Result<HttpResponse, AppError> {
+    let recovery_codes = TwoFactorService::confirm_enrollment(pool.get_ref(), user.id, &form.code, Utc::now()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "recovery_codes": recovery_codes })))
+}
+
+pub async fn disable_two_factor(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    form: web::Json<TwoFactorCodeRequest>,
+) -> Result<HttpResponse, AppError> {
+    TwoFactorService::disable(pool.get_ref(), user.id, &form.code, Utc::now()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Replaces the member's recovery codes with a new set.
+pub async fn regenerate_recovery_codes(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    form: web::Json<TwoFactorCodeRequest>,
+) -> Result<HttpResponse, AppError> {
+    let recovery_codes = TwoFactorService::regenerate_recovery_codes(pool.get_ref(), user.id, &form.code, Utc::now()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "recovery_codes": recovery_codes })))
 }
 
-fn generate_token(user_id: Uuid) -> String {
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::days(7))
-        .expect("valid timestamp")
-        .timestamp();
+/// Logs a member in whose first factor checked out: straight away, or with a challenge for
+/// their second factor when they have one.
+async fn finish_login(
+    pool: &PgPool,
+    jwt: &JwtConfig,
+    req: &HttpRequest,
+    user: &User,
+) -> Result<HttpResponse, AppError> {
+    if user.two_factor_enabled() {
+        let challenge_token = TwoFactorService::create_challenge(pool, user.id, Utc::now()).await?;
+        return Ok(HttpResponse::Ok().json(TwoFactorChallenge {
+            two_factor_required: true,
+            challenge_token,
+            expires_in: two_factor::CHALLENGE_TTL_MINUTES * 60,
+        }));
+    }
+
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    LoginLockout::clear(&mut conn, user.id).await?;
+    let auth = open_session(pool, jwt, req, user.id).await?;
+    Ok(HttpResponse::Ok().json(auth))
+}
 
-    let claims = Claims {
-        sub: user_id,
-        exp: expiration as usize,
+/// Opens a session for a member who just proved who they are and issues its first tokens.
+async fn open_session(
+    pool: &PgPool,
+    jwt: &JwtConfig,
+    req: &HttpRequest,
+    user_id: Uuid,
+) -> Result<AuthResponse, AppError> {
+    let client = ClientInfo {
+        user_agent: req
+            .headers()
+            .get(actix_web::http::header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(|ua| ua.chars().take(255).collect()),
+        ip_address: req.connection_info().realip_remote_addr().map(str::to_string),
     };
 
-    let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref()))
-        .expect("Failed to generate token")
+    let now = Utc::now();
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    // Frozen members get no new sessions
+    let roles = AccountService::session_roles(&mut conn, user_id).await?;
+    let (session, refresh_token) = SessionService::start(&mut conn, user_id, &client, jwt.session_ttl, now)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to open session for user {}: {:?}", user_id, e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(AuthResponse {
+        token: issue_token(jwt, user_id, session.id, roles)?,
+        refresh_token,
+        expires_in: jwt.access_ttl.num_seconds(),
+        user_id,
+        session_id: session.id,
+    })
+}
+
+fn issue_token(jwt: &JwtConfig, user_id: Uuid, session_id: Uuid, roles: Vec<Role>) -> Result<String, AppError> {
+    jwt.issue(user_id, session_id, roles, Utc::now()).map_err(|e| {
+        tracing::error!("{}", e);
+        AppError::InternalServerError
+    })
+}
+
+fn normalize_phone(phone: &str) -> Result<String, AppError> {
+    MpesaClient::normalize_phone(phone).map_err(|e| AppError::BadRequest(e.to_string()))
+}
+
+async fn find_by_phone(pool: &PgPool, phone: &str) -> Result<Option<User>, AppError> {
+    sqlx::query_as("SELECT * FROM users WHERE phone_number = $1 AND phone_verified_at IS NOT NULL")
+        .bind(phone)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to look up phone number: {:?}", e);
+            AppError::InternalServerError
+        })
 }
@@ -0,0 +1,108 @@
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::middleware::{AppError, AuthUser};
+use crate::models::Permission;
+use crate::services::ledger::LedgerService;
+use crate::services::checkpoints::CheckpointService;
+use crate::services::ledger_chain::{LedgerChain, LedgerSigner};
+
+/// Every ledger account with its debits, credits and normal-side balance,
+/// plus whether total debits equal total credits across the platform. Staff only, since it
+/// shows every member's balances.
+pub async fn get_trial_balance(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::ViewLedger)?;
+
+    let trial_balance = LedgerService::trial_balance(pool.get_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to compute trial balance: {}", e);
+            AppError::InternalServerError
+        })?;
+
+    if !trial_balance.balanced {
+        tracing::error!(
+            "Trial balance is off: debits {} vs credits {}",
+            trial_balance.total_debits,
+            trial_balance.total_credits
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(trial_balance))
+}
+
+/// Ledger accounts owned by the current user (savings goals, loans receivable, lender payables).
+pub async fn get_my_accounts(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user.id;
+
+    let accounts = LedgerService::account_balances(pool.get_ref(), Some(user_id))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch ledger accounts: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(accounts))
+}
+
+pub async fn get_account_balance(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    account_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user.id;
+
+    let accounts = LedgerService::account_balances(pool.get_ref(), Some(user_id))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch ledger account: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    match accounts.into_iter().find(|a| a.id == *account_id) {
+        Some(account) => Ok(HttpResponse::Ok().json(account)),
+        None => Err(AppError::NotFound),
+    }
+}
+
+/// Walks the hash-chained platform ledger and reports the first entry that does not check
+/// out. Public, like the ledger itself: anyone holding the public key can check it.
+pub async fn verify_chain(
+    pool: web::Data<PgPool>,
+    signer: web::Data<LedgerSigner>,
+) -> Result<HttpResponse, AppError> {
+    let report = LedgerChain::verify(pool.get_ref(), signer.verifying_key())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to verify the platform ledger: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    if let Some(chain_break) = &report.first_break {
+        tracing::error!(
+            "Platform ledger breaks at entry #{} ({}): {:?}",
+            chain_break.sequence_number,
+            chain_break.entry_id,
+            chain_break.problem
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Merkle inclusion proof of one platform ledger entry against its signed checkpoint root, so
+/// the entry can be checked without the rest of the ledger. Public, like the ledger itself.
+pub async fn get_inclusion_proof(
+    pool: web::Data<PgPool>,
+    signer: web::Data<LedgerSigner>,
+    entry_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let proof = CheckpointService::proof(pool.get_ref(), *entry_id, &signer.verifying_key()).await?;
+    Ok(HttpResponse::Ok().json(proof))
+}
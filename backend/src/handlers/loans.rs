@@ -1,25 +1,72 @@
-use actix_web::{web, HttpResponse, HttpRequest};
+use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use uuid::Uuid;
-use jsonwebtoken::{decode, DecodingKey, Validation, Algorithm};
 use chrono::{DateTime, Utc};
-use crate::models::Loan;
-use crate::middleware::AppError;
+use crate::models::{
+    InterestMethod, Loan, LoanStatus, LoanStatusChange, LoanTerms, Money, OverpaymentDestination, PaymentPurpose,
+    PaymentStatus, RepaymentFrequency, Savings, PLATFORM_CURRENCY,
+};
+use crate::middleware::{AppError, AuthUser};
+use crate::middleware::authz::{self, Access};
 use crate::services::blockchain::BlockchainService;
-use validator::Validate;
+use crate::services::accounts::AccountService;
+use crate::services::disbursements::DisbursementService;
+use crate::services::loan_lifecycle::{LoanLifecycle, LOAN_COLUMNS};
+use crate::services::loan_review::{LoanReview, LoanReviewConfig};
+use crate::services::loan_schedule::LoanScheduleService;
+use crate::services::mpesa::MpesaClient;
+use crate::services::payments::{NewPayment, PaymentService};
+use crate::services::repayments::RepaymentService;
+use crate::services::scoring::{ScoringService, ScoringWeights, MAX_LOAN_MAJOR};
+use validator::{Validate, ValidationError};
 
 #[derive(Deserialize, Validate)]
 pub struct CreateLoanRequest {
-    #[validate(range(min = 1.0, max = 5000.0, message = "Loan amount must be between $1 and $5000"))]
-    pub amount: f64,
+    #[validate(custom = "validate_loan_amount")]
+    pub amount: Money,
     #[validate(length(min = 3, message = "Please provide a valid reason"))]
     pub description: Option<String>,
+    /// Loan product to price the loan with; the default product when omitted.
+    pub product_id: Option<Uuid>,
+    /// M-Pesa number the loan is paid out to once funded.
+    pub phone_number: Option<String>,
+}
+
+/// Loans are booked in the platform currency, between 1 and `MAX_LOAN_MAJOR` major units.
+fn validate_loan_amount(amount: &Money) -> Result<(), ValidationError> {
+    let min = Money::from_major(1, PLATFORM_CURRENCY).expect("valid loan minimum");
+    let max = Money::from_major(MAX_LOAN_MAJOR, PLATFORM_CURRENCY).expect("valid loan maximum");
+    if amount.currency() != PLATFORM_CURRENCY || *amount < min || *amount > max {
+        let mut error = ValidationError::new("range");
+        error.message = Some(format!("Loan amount must be between {} and {}", min, max).into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Default)]
+pub struct FundLoanRequest {
+    /// M-Pesa number to pay the capital from; the member's verified number when omitted.
+    pub phone_number: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct RepayLoanRequest {
     pub loan_id: Uuid,
+    /// M-Pesa number to pay from; the member's verified number when omitted.
+    pub phone_number: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateRepaymentRequest {
+    pub amount: Money,
+    /// Where money beyond the outstanding balance goes; savings when omitted.
+    pub overpayment: Option<OverpaymentDestination>,
+    /// Savings goal to credit an overpayment to; the most recent goal when omitted.
+    pub savings_id: Option<Uuid>,
+    /// M-Pesa number to pay from; the member's verified number when omitted.
+    pub phone_number: Option<String>,
 }
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -27,24 +74,34 @@ pub struct MarketplaceLoan {
     pub id: Uuid,
     pub user_id: Uuid,
     pub borrower_username: String,
-    pub amount: f64,
+    pub amount: Money,
     pub description: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
+    pub interest_method: InterestMethod,
+    pub interest_rate_bps: i32,
+    pub term_count: i32,
+    pub repayment_frequency: RepaymentFrequency,
 }
 
 pub async fn get_marketplace(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    user: AuthUser,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
 
     let loans: Vec<MarketplaceLoan> = sqlx::query_as(
-        "SELECT l.id, l.user_id, u.username as borrower_username, l.amount::float8 as amount, l.description, l.created_at 
+        "SELECT l.id, l.user_id, u.username as borrower_username, l.amount, l.description, l.created_at,
+                l.interest_method, l.interest_rate_bps, l.term_count, l.repayment_frequency
          FROM loans l 
          JOIN users u ON l.user_id = u.id 
-         WHERE l.status = 'pending' AND l.user_id != $1"
+         WHERE l.status = $2 AND l.user_id != $1 AND (NOT l.requires_approval OR l.approved_at IS NOT NULL)
+           -- A lender is already paying for it
+           AND NOT EXISTS (SELECT 1 FROM payments p WHERE p.loan_id = l.id AND p.purpose = $3 AND p.status = $4)"
     )
     .bind(user_id)
+    .bind(LoanStatus::Pending)
+    .bind(PaymentPurpose::LoanFunding)
+    .bind(PaymentStatus::Pending)
     .fetch_all(pool.get_ref())
     .await
     .map_err(|e| {
@@ -55,75 +112,135 @@ pub async fn get_marketplace(
     Ok(HttpResponse::Ok().json(loans))
 }
 
+/// Asks the lender for the loan's capital with an STK push. Once M-Pesa confirms the payment
+/// the loan is funded and paid out to the borrower; it becomes `disbursed` once M-Pesa
+/// confirms the payout.
 pub async fn fund_loan(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    mpesa: web::Data<MpesaClient>,
+    user: AuthUser,
     loan_id: web::Path<Uuid>,
+    form: web::Json<FundLoanRequest>,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
 
-    let result = sqlx::query(
-        "UPDATE loans SET lender_id = $1, status = 'approved' WHERE id = $2 AND status = 'pending' AND user_id != $1 RETURNING id"
-    )
-    .bind(user_id)
-    .bind(*loan_id)
-    .fetch_optional(pool.get_ref())
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to fund loan: {:?}", e);
-        AppError::InternalServerError
-    })?;
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    AccountService::ensure_active(&mut conn, user_id).await?;
+
+    let loan: Loan = sqlx::query_as(&format!("SELECT {} FROM loans WHERE id = $1", LOAN_COLUMNS))
+        .bind(*loan_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+        .ok_or(AppError::NotFound)?;
+    if loan.user_id == user_id {
+        return Err(AppError::BadRequest("You cannot fund your own loan".to_string()));
+    }
+    if loan.status != LoanStatus::Pending {
+        return Err(AppError::Conflict(format!("A {} loan cannot be funded", loan.status)));
+    }
+    let cleared = LoanReview::is_cleared(&mut conn, *loan_id).await.map_err(|_| AppError::InternalServerError)?;
+    if !cleared {
+        return Err(AppError::Conflict("This loan is waiting for approval".to_string()));
+    }
 
-    match result {
-        Some(_) => {
-            BlockchainService::log_loan_repayment(*loan_id, "FUNDING_SIG").await.ok();
-            Ok(HttpResponse::Ok().body("Loan funded successfully"))
-        },
-        None => Err(AppError::BadRequest("Loan not available for funding".to_string())),
+    // Loans requested before payouts went over M-Pesa have no number to pay out to
+    let (phone,): (Option<String>,) = sqlx::query_as("SELECT disbursement_phone FROM loans WHERE id = $1")
+        .bind(*loan_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+    if phone.is_none() || MpesaClient::whole_shillings(loan.amount).is_err() {
+        return Err(AppError::Conflict("This loan cannot be paid out over M-Pesa".to_string()));
     }
+
+    let phone = AccountService::payment_phone(&mut conn, user_id, form.phone_number.as_deref()).await?;
+    drop(conn);
+
+    let payment = PaymentService::start(
+        pool.get_ref(),
+        mpesa.get_ref(),
+        &NewPayment::loan_funding(user_id, loan.id, loan.amount, &phone),
+    )
+    .await?;
+
+    tracing::info!("Funding of loan {} requested as payment {}", loan.id, payment.id);
+    Ok(HttpResponse::Accepted().json(payment))
 }
 
 pub async fn create_loan(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    weights: web::Data<ScoringWeights>,
+    review: web::Data<LoanReviewConfig>,
+    user: AuthUser,
     form: web::Json<CreateLoanRequest>,
 ) -> Result<HttpResponse, AppError> {
     form.validate().map_err(|e| AppError::BadRequest(e.to_string()))?;
-    let user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    AccountService::ensure_active(&mut tx, user_id).await?;
 
-    // INNOVATION: Reputation-based Dynamic Limits
-    let reputation: (Option<i32>,) = sqlx::query_as("SELECT reputation_score FROM users WHERE id = $1")
-        .bind(user_id)
-        .fetch_one(pool.get_ref())
+    // INNOVATION: Score-based Dynamic Limits
+    let score = ScoringService::rescore(&mut tx, user_id, weights.get_ref(), Utc::now(), "loan_request")
         .await
-        .map_err(|_| AppError::InternalServerError)?;
+        .map_err(|e| {
+            tracing::error!("Failed to score user {}: {}", user_id, e);
+            AppError::InternalServerError
+        })?;
 
-    let max_limit = (reputation.0.unwrap_or(100) as f64) * 2.0;
-    
-    if form.amount > max_limit {
+    if form.amount > score.credit_limit {
         return Err(AppError::BadRequest(format!(
-            "Your Trust Score restricts loans to ${:.2}. Repay more loans to increase your limit!", 
-            max_limit
+            "{} is above your loan limit. {}",
+            form.amount, score.summary
         )));
     }
 
-    tracing::info!("User {} creating loan of ${}", user_id, form.amount);
+    let product = LoanScheduleService::find_product(&mut tx, form.product_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load loan product: {:?}", e);
+            AppError::InternalServerError
+        })?
+        .ok_or_else(|| AppError::BadRequest("Unknown loan product".to_string()))?;
 
-    let _ = BlockchainService::log_to_ledger(
-        pool.get_ref(),
-        "LOAN_REQUEST",
-        &format!("Loan for: {}", form.description.clone().unwrap_or_default()),
-        form.amount
-    ).await;
+    let terms = LoanTerms {
+        interest_method: product.interest_method,
+        interest_rate_bps: product.interest_rate_bps,
+        origination_fee_bps: product.origination_fee_bps,
+        term_count: product.term_count,
+        repayment_frequency: product.repayment_frequency,
+    };
+
+    let installments = LoanScheduleService::build(form.amount, &terms, Utc::now())
+        .map_err(AppError::BadRequest)?;
+
+    // Funded loans are paid out over M-Pesa, which only moves whole shillings
+    let phone = form.phone_number.as_deref().filter(|p| !p.trim().is_empty()).ok_or_else(|| {
+        AppError::BadRequest("A phone number is needed to receive the loan over M-Pesa".to_string())
+    })?;
+    let phone = MpesaClient::normalize_phone(phone).map_err(|e| AppError::BadRequest(e.to_string()))?;
+    MpesaClient::whole_shillings(form.amount).map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    tracing::info!("User {} creating {} loan of {}", user_id, product.code, form.amount);
 
     let result = sqlx::query(
-        "INSERT INTO loans (user_id, amount, description, status) VALUES ($1, $2, $3, $4) RETURNING id"
+        "INSERT INTO loans (user_id, amount, description, status, product_id, interest_method, interest_rate_bps, origination_fee_bps, term_count, repayment_frequency, disbursement_phone, requires_approval)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING id"
     )
     .bind(user_id)
-    .bind(form.amount as f32)
+    .bind(form.amount)
     .bind(&form.description)
-    .bind("pending")
-    .fetch_one(pool.get_ref())
+    .bind(LoanStatus::Pending)
+    .bind(product.id)
+    .bind(terms.interest_method)
+    .bind(terms.interest_rate_bps)
+    .bind(terms.origination_fee_bps)
+    .bind(terms.term_count)
+    .bind(terms.repayment_frequency)
+    .bind(&phone)
+    .bind(review.requires_approval(form.amount))
+    .fetch_one(&mut *tx)
     .await
     .map_err(|e| {
         tracing::error!("Failed to create loan: {:?}", e);
@@ -131,58 +248,245 @@ pub async fn create_loan(
     })?;
 
     let id: Uuid = sqlx::Row::get(&result, "id");
+    LoanLifecycle::record(&mut tx, id, None, LoanStatus::Pending, Some(user_id), None).await?;
+
+    LoanScheduleService::insert_schedule(&mut tx, id, &installments)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store schedule of loan {}: {}", id, e);
+            AppError::InternalServerError
+        })?;
+
+    BlockchainService::log_to_ledger(
+        &mut *tx,
+        "LOAN_REQUEST",
+        &format!("Loan for: {}", form.description.clone().unwrap_or_default()),
+        form.amount
+    ).await.map_err(|_| AppError::InternalServerError)?;
+
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
     Ok(HttpResponse::Ok().json(id))
 }
 
+/// Asks the borrower to pay off everything still outstanding on a loan with an STK push.
+/// The loan is credited once M-Pesa confirms the payment.
 pub async fn repay_loan(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    mpesa: web::Data<MpesaClient>,
+    user: AuthUser,
     form: web::Json<RepayLoanRequest>,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
 
-    let result = sqlx::query(
-        "UPDATE loans SET status = 'repaid', repaid_at = NOW() WHERE id = $1 AND user_id = $2 RETURNING id"
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let loan: Loan = authz::load(&mut conn, form.loan_id, &user, Access::Owner).await?;
+    RepaymentService::ensure_repayable(&loan)?;
+
+    let schedule = LoanScheduleService::schedule(&mut conn, loan.id, loan.amount)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load schedule of loan {}: {}", loan.id, e);
+            AppError::InternalServerError
+        })?;
+
+    if !schedule.outstanding_balance.is_positive() {
+        return Err(AppError::Conflict("Nothing is outstanding on this loan".to_string()));
+    }
+
+    let phone = AccountService::payment_phone(&mut conn, user_id, form.phone_number.as_deref()).await?;
+    drop(conn);
+
+    let payment = PaymentService::start(
+        pool.get_ref(),
+        mpesa.get_ref(),
+        &NewPayment::loan_repayment(
+            user_id,
+            loan.id,
+            schedule.outstanding_balance,
+            &phone,
+            OverpaymentDestination::default(),
+            None,
+        ),
     )
-    .bind(form.loan_id)
-    .bind(user_id)
-    .fetch_optional(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Accepted().json(payment))
+}
+
+/// Asks the borrower for a payment of any amount against a loan with an STK push. It is
+/// allocated against the schedule once M-Pesa confirms it.
+pub async fn create_repayment(
+    pool: web::Data<PgPool>,
+    mpesa: web::Data<MpesaClient>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+    form: web::Json<CreateRepaymentRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user.id;
+
+    if form.amount.currency() != PLATFORM_CURRENCY || !form.amount.is_positive() {
+        return Err(AppError::BadRequest(format!(
+            "Repayments must be a positive {} amount",
+            PLATFORM_CURRENCY.code()
+        )));
+    }
+
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let loan: Loan = authz::load(&mut conn, *loan_id, &user, Access::Owner).await?;
+    RepaymentService::ensure_repayable(&loan)?;
+    if let Some(savings_id) = form.savings_id {
+        let _: Savings = authz::load(&mut conn, savings_id, &user, Access::Owner).await?;
+    }
+    let phone = AccountService::payment_phone(&mut conn, user_id, form.phone_number.as_deref()).await?;
+    drop(conn);
+
+    let payment = PaymentService::start(
+        pool.get_ref(),
+        mpesa.get_ref(),
+        &NewPayment::loan_repayment(
+            user_id,
+            loan.id,
+            form.amount,
+            &phone,
+            form.overpayment.unwrap_or_default(),
+            form.savings_id,
+        ),
+    )
+    .await?;
+
+    tracing::info!("Repayment of {} on loan {} requested as payment {}", form.amount, loan.id, payment.id);
+    Ok(HttpResponse::Accepted().json(payment))
+}
+
+pub async fn get_repayments(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+
+    let loan: Loan = authz::load(&mut conn, *loan_id, &user, Access::Party).await?;
+
+    let repayments = RepaymentService::repayments(&mut conn, loan.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch repayments: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(repayments))
+}
+
+/// Lets a borrower withdraw a loan request that no lender has funded yet.
+pub async fn cancel_loan(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user.id;
+
+    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+
+    let loan: Loan = authz::load_for_update(&mut tx, *loan_id, &user, Access::Owner).await?;
+    if loan.status == LoanStatus::Funded {
+        return Err(AppError::Conflict("The loan is being paid out to you".to_string()));
+    }
+
+    LoanLifecycle::transition(&mut tx, *loan_id, LoanStatus::Cancelled, Some(user_id), Some("Cancelled by borrower")).await?;
+
+    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().body("Loan cancelled"))
+}
+
+/// Payout attempts of a loan, visible to its borrower and lender.
+pub async fn get_loan_disbursements(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let loan: Loan = authz::load(&mut conn, *loan_id, &user, Access::Party).await?;
+
+    let disbursements = DisbursementService::for_loan(pool.get_ref(), loan.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch disbursements: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(disbursements))
+}
+
+pub async fn get_loan_history(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let loan: Loan = authz::load(&mut conn, *loan_id, &user, Access::Party).await?;
+
+    let history: Vec<LoanStatusChange> = sqlx::query_as(
+        "SELECT id, loan_id, from_status, to_status, actor_id, note, created_at
+         FROM loan_status_history WHERE loan_id = $1 ORDER BY created_at"
+    )
+    .bind(loan.id)
+    .fetch_all(&mut *conn)
     .await
     .map_err(|e| {
-        tracing::error!("Failed to repay loan: {:?}", e);
+        tracing::error!("Failed to fetch loan history: {:?}", e);
         AppError::InternalServerError
     })?;
 
-    match result {
-        Some(_) => {
-            BlockchainService::log_to_ledger(
-                pool.get_ref(),
-                "REPAYMENT",
-                &format!("Loan {} repaid", form.loan_id),
-                0.0 // Amount could be fetched if needed
-            ).await.ok();
-
-            // Increase user reputation score
-            let _ = sqlx::query("UPDATE users SET reputation_score = reputation_score + 10 WHERE id = $1")
-                .bind(user_id)
-                .execute(pool.get_ref())
-                .await;
-                
-            Ok(HttpResponse::Ok().body("Loan repaid successfully"))
-        },
-        None => Err(AppError::NotFound),
-    }
+    Ok(HttpResponse::Ok().json(history))
+}
+
+/// Active loan products a borrower can choose from.
+pub async fn get_products(
+    pool: web::Data<PgPool>,
+    _user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    let products = LoanScheduleService::active_products(pool.get_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch loan products: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(products))
+}
+
+/// Installment schedule of a loan, visible to its borrower and lender.
+pub async fn get_loan_schedule(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    loan_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+
+    let loan: Loan = authz::load(&mut conn, *loan_id, &user, Access::Party).await?;
+
+    let schedule = LoanScheduleService::schedule(&mut conn, loan.id, loan.amount)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch schedule of loan {}: {}", loan.id, e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(schedule))
 }
 
 pub async fn get_loans(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    user: AuthUser,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
 
-    let loans: Vec<Loan> = sqlx::query_as(
-        "SELECT id, user_id, lender_id, amount::float8 as amount, status, description, created_at, repaid_at FROM loans WHERE user_id = $1 OR lender_id = $1 ORDER BY created_at DESC"
-    )
+    let loans: Vec<Loan> = sqlx::query_as(&format!(
+        "SELECT {} FROM loans WHERE user_id = $1 OR lender_id = $1 ORDER BY created_at DESC",
+        LOAN_COLUMNS
+    ))
     .bind(user_id)
     .fetch_all(pool.get_ref())
     .await
@@ -193,31 +497,3 @@ pub async fn get_loans(
 
     Ok(HttpResponse::Ok().json(loans))
 }
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: Uuid,
-    exp: usize,
-}
-
-pub fn get_user_id_from_req(req: &HttpRequest) -> Result<Uuid, AppError> {
-    let auth_header = req.headers().get("Authorization");
-    if let Some(auth_str) = auth_header.and_then(|h| h.to_str().ok()) {
-        if auth_str.starts_with("Bearer ") {
-            let token = &auth_str[7..];
-            let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
-            
-            let token_data = decode::<Claims>(
-                token,
-                &DecodingKey::from_secret(secret.as_ref()),
-                &Validation::new(Algorithm::HS256),
-            );
-
-            match token_data {
-                Ok(data) => return Ok(data.claims.sub),
-                Err(_) => return Err(AppError::Unauthorized),
-            }
-        }
-    }
-    Err(AppError::Unauthorized)
-}
\ No newline at end of file
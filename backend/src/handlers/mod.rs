@@ -1,41 +1,149 @@
 use actix_web::{web, HttpResponse};
 use serde::Serialize;
 use sqlx::PgPool;
+use crate::middleware::idempotency::Idempotency;
+use crate::middleware::rate_limit::{Quota, RateLimit, RateLimitPolicy};
 use crate::middleware::AppError;
+use crate::models::Money;
 
+pub mod admin;
 pub mod auth;
+pub mod ledger;
 pub mod loans;
+pub mod payments;
+pub mod reconciliation;
 pub mod savings;
 
+/// Logins, sign-ups and code checks from one client, and from one logged-in member.
+const AUTH_LIMIT: RateLimitPolicy = RateLimitPolicy::new("auth", Quota::per_minute(20)).per_account(Quota::per_minute(10));
+/// Requests that send an SMS or email; each one costs money and reaches a real person.
+const MESSAGE_LIMIT: RateLimitPolicy = RateLimitPolicy::new("messages", Quota::per_hour(10));
+/// Requests that move money, per client and per member.
+const MONEY_LIMIT: RateLimitPolicy = RateLimitPolicy::new("money", Quota::per_minute(30)).per_account(Quota::per_minute(10));
+
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/auth")
+            .wrap(RateLimit::new(AUTH_LIMIT))
+            .service(web::resource("/otp").wrap(RateLimit::new(MESSAGE_LIMIT)).route(web::post().to(auth::request_otp)))
+            .service(
+                web::resource("/password/forgot")
+                    .wrap(RateLimit::new(MESSAGE_LIMIT))
+                    .route(web::post().to(auth::forgot_password))
+            )
             .route("/register", web::post().to(auth::register))
             .route("/login", web::post().to(auth::login))
+            .route("/login/otp", web::post().to(auth::login_with_otp))
+            .route("/login/2fa", web::post().to(auth::login_with_two_factor))
+            .route("/2fa/setup", web::post().to(auth::setup_two_factor))
+            .route("/2fa/confirm", web::post().to(auth::confirm_two_factor))
+            .route("/2fa/disable", web::post().to(auth::disable_two_factor))
+            .route("/2fa/recovery-codes", web::post().to(auth::regenerate_recovery_codes))
+            .route("/password/reset", web::post().to(auth::reset_password))
+            .route("/password/change", web::post().to(auth::change_password))
+            .route("/refresh", web::post().to(auth::refresh))
+            .route("/logout", web::post().to(auth::logout))
+            .route("/sessions", web::get().to(auth::get_sessions))
+            .route("/sessions/{id}/revoke", web::post().to(auth::revoke_session))
             .route("/profile", web::get().to(auth::get_profile))
+            .route("/profile/score", web::get().to(auth::get_profile_score))
     )
     .service(
         web::scope("/loans")
+            .wrap(RateLimit::new(MONEY_LIMIT))
+            .wrap(Idempotency)
             .route("", web::post().to(loans::create_loan))
             .route("", web::get().to(loans::get_loans))
             .route("/marketplace", web::get().to(loans::get_marketplace))
+            .route("/products", web::get().to(loans::get_products))
             .route("/{id}/fund", web::post().to(loans::fund_loan))
             .route("/repay", web::post().to(loans::repay_loan))
+            .route("/{id}/cancel", web::post().to(loans::cancel_loan))
+            .route("/{id}/history", web::get().to(loans::get_loan_history))
+            .route("/{id}/disbursements", web::get().to(loans::get_loan_disbursements))
+            .route("/{id}/schedule", web::get().to(loans::get_loan_schedule))
+            .route("/{id}/repayments", web::post().to(loans::create_repayment))
+            .route("/{id}/repayments", web::get().to(loans::get_repayments))
     )
     .service(
         web::scope("/savings")
+            .wrap(RateLimit::new(MONEY_LIMIT))
+            .wrap(Idempotency)
             .route("", web::get().to(savings::get_savings))
             .route("", web::post().to(savings::create_savings))
             .route("/{id}/deposit", web::post().to(savings::deposit))
+            .route("/{id}/withdraw", web::post().to(savings::withdraw))
+            .route("/{id}/withdrawals", web::get().to(savings::get_withdrawals))
+    )
+    .service(
+        web::scope("/payments")
+            .route("", web::get().to(payments::get_payments))
+            .route("/{id}", web::get().to(payments::get_payment))
+    )
+    .service(web::scope("/refunds").route("", web::get().to(payments::get_refunds)))
+    // Called by Safaricom, not by members
+    .service(
+        web::scope("/mpesa")
+            .route("/callback", web::post().to(payments::mpesa_callback))
+            .route("/b2c/result/{token}", web::post().to(payments::b2c_result))
+            .route("/b2c/timeout/{token}", web::post().to(payments::b2c_timeout))
+    )
+    // Staff only; each handler checks its permission
+    .service(
+        web::scope("/admin")
+            .service(
+                web::scope("/reconciliation")
+                    .app_data(web::PayloadConfig::new(reconciliation::MAX_STATEMENT_BYTES))
+                    .route("/statements", web::post().to(reconciliation::import_statement))
+                    .route("/statements", web::get().to(reconciliation::get_statements))
+                    .route("/statements/{id}", web::get().to(reconciliation::get_statement_report))
+                    .route("/statements/{id}/reconcile", web::post().to(reconciliation::reconcile_statement))
+                    .route("/exceptions", web::get().to(reconciliation::get_open_exceptions))
+                    .route("/exceptions/{id}/resolve", web::post().to(reconciliation::resolve_exception))
+            )
+            .route("/users", web::get().to(admin::search_members))
+            .route("/users/{id}", web::get().to(admin::get_member))
+            .route("/users/{id}/freeze", web::post().to(admin::freeze_member))
+            .route("/users/{id}/unfreeze", web::post().to(admin::unfreeze_member))
+            .route("/users/{id}/roles/grant", web::post().to(admin::grant_role))
+            .route("/users/{id}/roles/revoke", web::post().to(admin::revoke_role))
+            .route("/users/{id}/score-adjustments", web::post().to(admin::adjust_score))
+            .route("/loans/review", web::get().to(admin::get_review_queue))
+            .route("/loans/{id}/approve", web::post().to(admin::approve_loan))
+            .route("/loans/{id}/reject", web::post().to(admin::reject_loan))
+            // These move money, so a resent request must not record it twice
+            .service(
+                web::resource("/loans/{id}/repayments")
+                    .wrap(Idempotency)
+                    .route(web::post().to(admin::record_repayment))
+            )
+            .service(
+                web::resource("/savings-transactions/{id}/reverse")
+                    .wrap(Idempotency)
+                    .route(web::post().to(admin::reverse_savings_transaction))
+            )
+            .route("/health", web::get().to(admin::get_platform_health))
+            .route("/audit-log", web::get().to(admin::get_audit_log))
+            .route("/outbox", web::get().to(admin::get_stuck_events))
+            .route("/outbox/{id}/redeliver", web::post().to(admin::redeliver_event))
+    )
+    .service(
+        web::scope("/ledger")
+            .route("", web::get().to(get_live_ledger))
+            .route("/verify", web::get().to(ledger::verify_chain))
+            .route("/{id}/proof", web::get().to(ledger::get_inclusion_proof))
+            .route("/trial-balance", web::get().to(ledger::get_trial_balance))
+            .route("/accounts", web::get().to(ledger::get_my_accounts))
+            .route("/accounts/{id}", web::get().to(ledger::get_account_balance))
     )
     .route("/stats", web::get().to(get_platform_stats))
-    .route("/ledger", web::get().to(get_live_ledger))
     .route("/health", web::get().to(|| async { HttpResponse::Ok().body("OK") }));
 }
 
 async fn get_live_ledger(pool: web::Data<PgPool>) -> Result<HttpResponse, AppError> {
     let ledger: Vec<crate::models::PlatformTransaction> = sqlx::query_as(
-        "SELECT id, activity_type, description, amount::float8 as amount, signature, created_at FROM platform_transactions ORDER BY created_at DESC LIMIT 50"
+        "SELECT id, sequence_number, activity_type, description, amount, prev_hash, entry_hash, signature, created_at
+         FROM platform_transactions ORDER BY sequence_number DESC NULLS FIRST, created_at DESC LIMIT 50"
     )
     .fetch_all(pool.get_ref())
     .await
@@ -47,8 +155,8 @@ async fn get_live_ledger(pool: web::Data<PgPool>) -> Result<HttpResponse, AppErr
 #[derive(Serialize)]
 struct PlatformStats {
     total_users: i64,
-    total_loans_value: f64,
-    total_savings_value: f64,
+    total_loans_value: Money,
+    total_savings_value: Money,
     active_p2p_deals: i64,
 }
 
@@ -58,12 +166,12 @@ async fn get_platform_stats(pool: web::Data<PgPool>) -> Result<HttpResponse, App
         .await
         .map_err(|_| AppError::InternalServerError)?;
 
-    let loans_value: (Option<f64>,) = sqlx::query_as("SELECT sum(amount)::float8 FROM loans")
+    let loans_value: (Money,) = sqlx::query_as("SELECT COALESCE(sum(amount), 0)::bigint FROM loans")
         .fetch_one(pool.get_ref())
         .await
         .map_err(|_| AppError::InternalServerError)?;
 
-    let savings_value: (Option<f64>,) = sqlx::query_as("SELECT sum(amount)::float8 FROM savings")
+    let savings_value: (Money,) = sqlx::query_as("SELECT COALESCE(sum(amount), 0)::bigint FROM savings")
         .fetch_one(pool.get_ref())
         .await
         .map_err(|_| AppError::InternalServerError)?;
@@ -75,8 +183,8 @@ async fn get_platform_stats(pool: web::Data<PgPool>) -> Result<HttpResponse, App
 
     Ok(HttpResponse::Ok().json(PlatformStats {
         total_users: users_count.0,
-        total_loans_value: loans_value.0.unwrap_or(0.0),
-        total_savings_value: savings_value.0.unwrap_or(0.0),
+        total_loans_value: loans_value.0,
+        total_savings_value: savings_value.0,
         active_p2p_deals: marketplace_count.0,
     }))
 }
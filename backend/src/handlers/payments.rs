@@ -0,0 +1,131 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::middleware::{AppError, AuthUser};
+use crate::middleware::authz::{self, Access};
+use crate::services::disbursements::DisbursementService;
+use crate::services::mpesa::{B2cResultBody, MpesaClient, StkCallbackBody};
+use crate::services::payments::PaymentService;
+use crate::services::refunds::RefundService;
+use crate::services::repayments::AllocationOrder;
+use crate::services::scoring::ScoringWeights;
+use crate::services::withdrawals::WithdrawalService;
+
+/// Daraja posts STK push results here. Always acknowledged; payments that could not be
+/// settled now are picked up by the stale payment job.
+pub async fn mpesa_callback(
+    pool: web::Data<PgPool>,
+    mpesa: web::Data<MpesaClient>,
+    order: web::Data<AllocationOrder>,
+    weights: web::Data<ScoringWeights>,
+    body: web::Json<StkCallbackBody>,
+) -> HttpResponse {
+    let callback = &body.body.stk_callback;
+    tracing::info!(
+        "[M-PESA] Callback for {}: {} {}",
+        callback.checkout_request_id, callback.result_code, callback.result_desc
+    );
+
+    if let Err(e) = PaymentService::handle_callback(pool.get_ref(), mpesa.get_ref(), callback, order.get_ref(), weights.get_ref()).await {
+        tracing::error!("Failed to settle checkout {}: {}", callback.checkout_request_id, e);
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({ "ResultCode": 0, "ResultDesc": "Accepted" }))
+}
+
+/// Daraja posts B2C payout results here, to the URL carrying the token of the savings
+/// withdrawal, refund or loan payout attempt.
+/// Always acknowledged; results that could not be applied are logged for reconciliation.
+pub async fn b2c_result(
+    pool: web::Data<PgPool>,
+    token: web::Path<String>,
+    body: web::Json<B2cResultBody>,
+) -> HttpResponse {
+    let result = &body.result;
+    tracing::info!(
+        "[M-PESA] B2C result for {}: {} {}",
+        result.originator_conversation_id, result.result_code, result.result_desc
+    );
+
+    let applied = match WithdrawalService::handle_result(pool.get_ref(), &token, result).await {
+        Ok(None) => match RefundService::handle_result(pool.get_ref(), &token, result, Utc::now()).await {
+            Ok(None) => DisbursementService::handle_result(pool.get_ref(), &token, result, Utc::now()).await.map(|_| ()),
+            other => other.map(|_| ()),
+        },
+        other => other.map(|_| ()),
+    };
+    if let Err(e) = applied {
+        tracing::error!("Failed to apply B2C result for {}: {}", result.originator_conversation_id, e);
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({ "ResultCode": 0, "ResultDesc": "Accepted" }))
+}
+
+/// Daraja posts here when a B2C payout timed out in its queue before being processed.
+pub async fn b2c_timeout(
+    pool: web::Data<PgPool>,
+    token: web::Path<String>,
+) -> HttpResponse {
+    let applied = match WithdrawalService::handle_timeout(pool.get_ref(), &token).await {
+        Ok(None) => match RefundService::handle_timeout(pool.get_ref(), &token, Utc::now()).await {
+            Ok(None) => DisbursementService::handle_timeout(pool.get_ref(), &token, Utc::now()).await.map(|_| ()),
+            other => other.map(|_| ()),
+        },
+        other => other.map(|_| ()),
+    };
+    if let Err(e) = applied {
+        tracing::error!("Failed to apply B2C timeout: {}", e);
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({ "ResultCode": 0, "ResultDesc": "Accepted" }))
+}
+
+pub async fn get_payments(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user.id;
+
+    let payments = PaymentService::for_user(pool.get_ref(), user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch payments: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(payments))
+}
+
+pub async fn get_payment(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    payment_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let payment = PaymentService::find(pool.get_ref(), *payment_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch payment: {:?}", e);
+            AppError::InternalServerError
+        })?
+        .ok_or(AppError::NotFound)?;
+
+    authz::check(&payment, &user, Access::Party)?;
+
+    Ok(HttpResponse::Ok().json(payment))
+}
+
+/// Money owed back to the member and what became of its payout.
+pub async fn get_refunds(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    let refunds = RefundService::for_user(pool.get_ref(), user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch refunds: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(refunds))
+}
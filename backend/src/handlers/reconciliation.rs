@@ -0,0 +1,146 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::middleware::{AppError, AuthUser};
+use crate::models::{Permission, ReconciliationResolution};
+use crate::services::reconciliation::ReconciliationService;
+
+/// Largest statement export accepted, in bytes.
+pub const MAX_STATEMENT_BYTES: usize = 10 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct ImportStatementQuery {
+    pub file_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveItemRequest {
+    pub resolution: ReconciliationResolution,
+    pub note: Option<String>,
+}
+
+/// Imports a statement CSV exported from the M-Pesa portal (sent as the raw request body)
+/// and reconciles it straight away.
+pub async fn import_statement(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    query: web::Query<ImportStatementQuery>,
+    body: String,
+) -> Result<HttpResponse, AppError> {
+    let admin_id = user.require(Permission::Reconcile)?;
+
+    let lines = ReconciliationService::parse_statement(&body).map_err(AppError::BadRequest)?;
+    if lines.is_empty() {
+        return Err(AppError::BadRequest("The statement has no transactions".to_string()));
+    }
+
+    let statement = ReconciliationService::import(pool.get_ref(), query.file_name.as_deref(), &lines, admin_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to import M-Pesa statement: {}", e);
+            AppError::InternalServerError
+        })?;
+
+    let report = ReconciliationService::reconcile(pool.get_ref(), statement.id, Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to reconcile statement {}: {}", statement.id, e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Created().json(report))
+}
+
+pub async fn get_statements(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::Reconcile)?;
+
+    let statements = ReconciliationService::statements(pool.get_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch M-Pesa statements: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(statements))
+}
+
+/// The reconciliation report of one statement: counts per outcome and every exception.
+pub async fn get_statement_report(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    statement_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::Reconcile)?;
+
+    let report = ReconciliationService::report(pool.get_ref(), *statement_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch reconciliation report: {}", e);
+            AppError::InternalServerError
+        })?
+        .ok_or(AppError::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Runs the reconciliation of a statement again, e.g. after records were corrected.
+pub async fn reconcile_statement(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    statement_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::Reconcile)?;
+
+    if ReconciliationService::report(pool.get_ref(), *statement_id)
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+        .is_none()
+    {
+        return Err(AppError::NotFound);
+    }
+
+    let report = ReconciliationService::reconcile(pool.get_ref(), *statement_id, Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to reconcile statement {}: {}", statement_id, e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Open exceptions across all statements.
+pub async fn get_open_exceptions(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::Reconcile)?;
+
+    let items = ReconciliationService::open_exceptions(pool.get_ref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch reconciliation exceptions: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(items))
+}
+
+pub async fn resolve_exception(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    item_id: web::Path<Uuid>,
+    form: web::Json<ResolveItemRequest>,
+) -> Result<HttpResponse, AppError> {
+    let admin_id = user.require(Permission::Reconcile)?;
+
+    let note = form.note.as_deref().map(str::trim).filter(|note| !note.is_empty());
+    let item = ReconciliationService::resolve(pool.get_ref(), *item_id, form.resolution, note, admin_id).await?;
+
+    Ok(HttpResponse::Ok().json(item))
+}
@@ -1,55 +1,86 @@
-use actix_web::{web, HttpResponse, HttpRequest};
+use actix_web::{web, HttpResponse};
+use chrono::{NaiveDate, Utc};
 use serde::{Deserialize};
 use sqlx::PgPool;
 use uuid::Uuid;
-use crate::middleware::AppError;
-use crate::handlers::loans::get_user_id_from_req;
-use crate::models::Savings;
-use crate::services::mpesa::MpesaService;
+use crate::middleware::{AppError, AuthUser};
+use crate::middleware::authz::{self, Access};
+use crate::models::{Money, Savings, PLATFORM_CURRENCY};
+use crate::services::accounts::AccountService;
+use crate::services::mpesa::MpesaClient;
+use crate::services::payments::{NewPayment, PaymentService};
+use crate::services::withdrawals::WithdrawalService;
 
 #[derive(Deserialize)]
 pub struct CreateSavingsRequest {
     pub goal_name: String,
+    pub target_amount: Option<Money>,
+    pub target_date: Option<NaiveDate>,
+    /// Withdrawals are refused before this date.
+    pub locked_until: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct WithdrawRequest {
+    pub amount: Money,
+    /// Defaults to the number of the latest deposit into the goal.
+    pub phone_number: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct DepositRequest {
-    pub amount: f64,
+    pub amount: Money,
+    /// Defaults to the member's verified phone number.
     pub phone_number: Option<String>,
 }
 
 pub async fn get_savings(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    user: AuthUser,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
 
-    let savings: Vec<Savings> = sqlx::query_as(
-        "SELECT id, user_id, amount::float8 as amount, goal_name, created_at, updated_at FROM savings WHERE user_id = $1"
-    )
-    .bind(user_id)
-    .fetch_all(pool.get_ref())
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to fetch savings: {:?}", e);
-        AppError::InternalServerError
-    })?;
+    let savings = WithdrawalService::goals_for_user(pool.get_ref(), user_id, Utc::now().date_naive())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch savings: {:?}", e);
+            AppError::InternalServerError
+        })?;
 
     Ok(HttpResponse::Ok().json(savings))
 }
 
 pub async fn create_savings(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    user: AuthUser,
     form: web::Json<CreateSavingsRequest>,
 ) -> Result<HttpResponse, AppError> {
-    let user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
+
+    if let Some(target) = form.target_amount {
+        if target.currency() != PLATFORM_CURRENCY || !target.is_positive() {
+            return Err(AppError::BadRequest(format!(
+                "A target must be a positive {} amount",
+                PLATFORM_CURRENCY.code()
+            )));
+        }
+    }
+    let today = Utc::now().date_naive();
+    if form.target_date.is_some_and(|date| date <= today) {
+        return Err(AppError::BadRequest("The target date must be in the future".to_string()));
+    }
+    if form.locked_until.is_some_and(|date| date <= today) {
+        return Err(AppError::BadRequest("A goal can only be locked until a future date".to_string()));
+    }
 
     let result = sqlx::query(
-        "INSERT INTO savings (user_id, goal_name) VALUES ($1, $2) RETURNING id"
+        "INSERT INTO savings (user_id, goal_name, target_amount, target_date, locked_until) VALUES ($1, $2, $3, $4, $5) RETURNING id"
     )
     .bind(user_id)
     .bind(&form.goal_name)
+    .bind(form.target_amount)
+    .bind(form.target_date)
+    .bind(form.locked_until)
     .fetch_one(pool.get_ref())
     .await
     .map_err(|e| {
@@ -63,48 +94,88 @@ pub async fn create_savings(
 
 pub async fn deposit(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    mpesa: web::Data<MpesaClient>,
+    user: AuthUser,
     savings_id: web::Path<Uuid>,
     form: web::Json<DepositRequest>,
 ) -> Result<HttpResponse, AppError> {
-    let _user_id = get_user_id_from_req(&req)?;
+    let user_id = user.id;
 
-    // Simulate M-Pesa Payment if phone number is provided
-    if let Some(phone) = &form.phone_number {
-        MpesaService::initiate_stk_push(phone, form.amount)
-            .await
-            .map_err(|_| AppError::InternalServerError)?;
+    if form.amount.currency() != PLATFORM_CURRENCY || !form.amount.is_positive() {
+        return Err(AppError::BadRequest(format!(
+            "Deposits must be a positive {} amount",
+            PLATFORM_CURRENCY.code()
+        )));
     }
 
-    let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let savings: Savings = authz::load(&mut conn, *savings_id, &user, Access::Owner).await?;
 
-    sqlx::query(
-        "UPDATE savings SET amount = amount + $1, updated_at = NOW() WHERE id = $2"
-    )
-    .bind(form.amount as f32)
-    .bind(*savings_id)
-    .execute(&mut *tx)
-    .await
-    .map_err(|_| AppError::InternalServerError)?;
+    let phone = AccountService::payment_phone(&mut conn, user_id, form.phone_number.as_deref()).await?;
+    drop(conn);
 
-    sqlx::query(
-        "INSERT INTO savings_transactions (savings_id, amount, transaction_type) VALUES ($1, $2, $3)"
+    // The goal is credited when M-Pesa confirms the payment, not now
+    let payment = PaymentService::start(
+        pool.get_ref(),
+        mpesa.get_ref(),
+        &NewPayment::savings_deposit(user_id, savings.id, form.amount, &phone),
     )
-    .bind(*savings_id)
-    .bind(form.amount as f32)
-    .bind("deposit")
-    .execute(&mut *tx)
-    .await
-    .map_err(|_| AppError::InternalServerError)?;
+    .await?;
+
+    Ok(HttpResponse::Accepted().json(payment))
+}
+
+/// Pays savings back out over M-Pesa. The amount is held on the goal right away and
+/// leaves it once M-Pesa confirms the payout.
+pub async fn withdraw(
+    pool: web::Data<PgPool>,
+    mpesa: web::Data<MpesaClient>,
+    user: AuthUser,
+    savings_id: web::Path<Uuid>,
+    form: web::Json<WithdrawRequest>,
+) -> Result<HttpResponse, AppError> {
+    if form.amount.currency() != PLATFORM_CURRENCY || !form.amount.is_positive() {
+        return Err(AppError::BadRequest(format!(
+            "Withdrawals must be a positive {} amount",
+            PLATFORM_CURRENCY.code()
+        )));
+    }
 
-    tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+    {
+        let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+        AccountService::ensure_active(&mut conn, user.id).await?;
+    }
 
-    BlockchainService::log_to_ledger(
+    let phone = form.phone_number.as_deref().filter(|p| !p.trim().is_empty());
+    let withdrawal = WithdrawalService::start(
         pool.get_ref(),
-        "SAVINGS_DEPOSIT",
-        "Deposit to savings goal",
-        form.amount
-    ).await.ok();
+        mpesa.get_ref(),
+        &user,
+        *savings_id,
+        form.amount,
+        phone,
+        Utc::now().date_naive(),
+    )
+    .await?;
+
+    Ok(HttpResponse::Accepted().json(withdrawal))
+}
 
-    Ok(HttpResponse::Ok().body("Deposit successful"))
-}
\ No newline at end of file
+pub async fn get_withdrawals(
+    pool: web::Data<PgPool>,
+    user: AuthUser,
+    savings_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = pool.acquire().await.map_err(|_| AppError::InternalServerError)?;
+    let savings: Savings = authz::load(&mut conn, *savings_id, &user, Access::Party).await?;
+    drop(conn);
+
+    let withdrawals = WithdrawalService::for_savings(pool.get_ref(), savings.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to fetch withdrawals: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+    Ok(HttpResponse::Ok().json(withdrawals))
+}
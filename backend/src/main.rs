@@ -12,6 +12,22 @@ mod middleware;
 mod services;
 mod tests;
 
+use middleware::auth::JwtConfig;
+use middleware::rate_limit::RateLimiter;
+use services::clock::SystemClock;
+use services::delinquency::DelinquencyConfig;
+use services::ledger_chain::{LedgerChain, LedgerSigner};
+use services::loan_review::LoanReviewConfig;
+use services::mpesa::{MpesaClient, MpesaConfig, MpesaEnvironment};
+use services::mpesa_mock::MockDaraja;
+use services::solana::{SolanaClient, SolanaConfig, SolanaMode};
+use services::solana_mock::MockValidator;
+use services::notifier::Notifier;
+use services::passwords::PasswordResetConfig;
+use services::repayments::AllocationOrder;
+use services::scheduler::Scheduler;
+use services::scoring::ScoringWeights;
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Load environment variables from .env file
@@ -31,6 +47,87 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to create database connection pool");
 
+    // `microfund-backend verify-ledger` checks the platform ledger against the database and exits
+    if env::args().nth(1).as_deref() == Some("verify-ledger") {
+        return verify_ledger(&pool).await;
+    }
+
+    // Access tokens are signed and checked with one key for the life of the process
+    let jwt_config = JwtConfig::from_env().expect("Invalid JWT settings");
+
+    // Order in which repayments settle penalties, fees, interest and principal
+    let allocation_order = AllocationOrder::from_env().expect("Invalid REPAYMENT_ALLOCATION_ORDER");
+
+    // M-Pesa Daraja client. Mock mode runs a local stand-in for Daraja on a free port.
+    let mut mpesa_config = MpesaConfig::from_env().expect("Invalid M-Pesa settings");
+    let mock_daraja = if mpesa_config.environment == MpesaEnvironment::Mock {
+        let mock = MockDaraja::start().await?;
+        log::warn!("M-Pesa is mocked at {}; no real payments will be taken", mock.base_url);
+        mpesa_config.base_url = mock.base_url.clone();
+        Some(mock)
+    } else {
+        None
+    };
+    let mpesa = MpesaClient::new(mpesa_config).expect("Failed to build the M-Pesa client");
+
+    // Text messages for one-time codes, and SMS or email for account messages such as password resets
+    let sms = services::sms::provider_from_env().expect("Invalid SMS settings");
+    let notifier = Notifier {
+        sms: sms.clone(),
+        email: services::email::provider_from_env().expect("Invalid email settings"),
+    };
+    let password_reset = PasswordResetConfig::from_env().expect("Invalid PASSWORD_RESET_URL");
+
+    // Limits on login attempts, messages sent and money movements
+    let rate_limiter = RateLimiter::from_env(&pool).expect("Invalid rate limit settings");
+
+    // Loan requests that wait for a loan officer's approval
+    let loan_review = LoanReviewConfig::from_env().expect("Invalid LOAN_APPROVAL_ABOVE");
+
+    // Key that signs the hash-chained platform ledger
+    let ledger_signer = LedgerSigner::from_env().expect("Invalid LEDGER_SIGNING_KEY");
+    // Solana client of the microfund program. Mock mode runs a local stand-in for a validator.
+    let mut solana_config = SolanaConfig::from_env().expect("Invalid Solana settings");
+    let mock_validator = match &mut solana_config {
+        Some(config) if config.mode == SolanaMode::Mock => {
+            let mock = MockValidator::start().await?;
+            log::warn!("Solana is mocked at {}; nothing is recorded on a real chain", mock.rpc_url);
+            config.rpc_url = mock.rpc_url.clone();
+            Some(mock)
+        }
+        _ => None,
+    };
+    let solana = solana_config.map(|config| SolanaClient::new(config).expect("Failed to build the Solana client"));
+    // Where checkpoint roots of the ledger are published
+    let anchor = services::anchor::anchor_from_env(solana.as_ref()).expect("Invalid anchor settings");
+
+    // Weights of the credit score factors
+    let scoring_weights = ScoringWeights::from_env().expect("Invalid SCORING_WEIGHTS");
+
+    // Delivers payouts and member notifications queued with the changes that cause them
+    let outbox = services::outbox::OutboxDispatcher { mpesa: mpesa.clone(), notifier: notifier.clone() };
+
+    // Background jobs: overdue detection, late penalties, defaults, stale M-Pesa payments, loan payouts,
+    // sealing and checkpointing platform ledger entries, recording loans on Solana and delivering the outbox
+    let delinquency_config = DelinquencyConfig::from_env().expect("Invalid delinquency settings");
+    let scheduler_tick = env::var("SCHEDULER_TICK_SECONDS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(60);
+    Scheduler::new(
+        pool.clone(),
+        std::sync::Arc::new(SystemClock),
+        delinquency_config,
+        scoring_weights.clone(),
+        mpesa.clone(),
+        ledger_signer.clone(),
+        anchor,
+    )
+    .with_solana(solana)
+    .with_outbox(outbox)
+    .with_allocation_order(allocation_order.clone())
+    .start(std::time::Duration::from_secs(scheduler_tick));
+
     log::info!("MicroFund Africa Backend starting at http://127.0.0.1:8080");
 
     // Initialize and run the Actix-web server
@@ -45,15 +142,51 @@ async fn main() -> std::io::Result<()> {
             .wrap(cors)
             // Inject the DB pool into the application state
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(allocation_order.clone()))
+            .app_data(web::Data::new(scoring_weights.clone()))
+            .app_data(web::Data::new(mpesa.clone()))
+            .app_data(web::Data::new(jwt_config.clone()))
+            .app_data(web::Data::from(sms.clone()))
+            .app_data(web::Data::new(notifier.clone()))
+            .app_data(web::Data::new(password_reset.clone()))
+            .app_data(web::Data::new(rate_limiter.clone()))
+            .app_data(web::Data::new(loan_review.clone()))
+            .app_data(web::Data::new(ledger_signer.clone()))
             // Enable default request logging
             .wrap(Logger::default())
             // Register all API routes under the /api scope
             .service(
                 web::scope("/api")
+                    // Resolves bearer tokens into the `AuthUser` handlers take
+                    .wrap(actix_web::middleware::from_fn(middleware::auth::authenticate))
                     .configure(handlers::config)
             )
     })
     .bind(("127.0.0.1", 8080))?
     .run()
-    .await
-}
\ No newline at end of file
+    .await?;
+
+    if let Some(mock) = mock_daraja {
+        mock.stop().await;
+    }
+    if let Some(mock) = mock_validator {
+        mock.stop().await;
+    }
+    Ok(())
+}
+
+/// Walks the platform ledger straight from the database, without a running server, and prints
+/// the report. Checks with `LEDGER_PUBLIC_KEY` if set, so auditors need not hold the signing key.
+/// Exits with status 1 if the chain is broken.
+async fn verify_ledger(pool: &sqlx::PgPool) -> std::io::Result<()> {
+    let key = match env::var("LEDGER_PUBLIC_KEY").ok().filter(|v| !v.trim().is_empty()) {
+        Some(public_key) => services::ledger_chain::parse_verifying_key(&public_key).expect("Invalid LEDGER_PUBLIC_KEY"),
+        None => LedgerSigner::from_env().expect("Set LEDGER_PUBLIC_KEY or LEDGER_SIGNING_KEY").verifying_key(),
+    };
+    let report = LedgerChain::verify(pool, key).await.map_err(std::io::Error::other)?;
+    println!("{}", serde_json::to_string_pretty(&report).map_err(std::io::Error::other)?);
+    if !report.valid {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::future::{ready, Ready};
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::{Permission, Role};
+use crate::services::sessions::SessionService;
+
+/// HS256 secrets shorter than the hash output make brute-forcing tokens cheaper.
+const MIN_SECRET_BYTES: usize = 32;
+
+const DEFAULT_ACCESS_TTL_MINUTES: i64 = 15;
+const DEFAULT_SESSION_TTL_DAYS: i64 = 30;
+
+/// How access tokens are signed and checked. Loaded once at startup.
+#[derive(Clone)]
+pub struct JwtConfig {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    pub issuer: String,
+    pub audience: String,
+    /// Sent as the `kid` header; tokens signed under another key id are refused.
+    pub key_id: String,
+    /// How long an access token is good for while its session lasts.
+    pub access_ttl: Duration,
+    /// How long a session can be kept alive with refresh tokens after login.
+    pub session_ttl: Duration,
+}
+
+/// What an access token says about its bearer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    /// The session the token was issued for.
+    pub sid: Uuid,
+    pub iss: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+}
+
+impl JwtConfig {
+    pub fn new(secret: &str, issuer: &str, audience: &str, key_id: &str, access_ttl: Duration) -> Result<Self, String> {
+        if secret.len() < MIN_SECRET_BYTES {
+            return Err(format!("JWT_SECRET must be at least {} bytes long", MIN_SECRET_BYTES));
+        }
+        Ok(Self {
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            key_id: key_id.to_string(),
+            access_ttl,
+            session_ttl: Duration::days(DEFAULT_SESSION_TTL_DAYS),
+        })
+    }
+
+    /// `JWT_SECRET` is required. `JWT_ISSUER`, `JWT_AUDIENCE`, `JWT_KEY_ID`,
+    /// `JWT_ACCESS_TTL_MINUTES` (15 by default) and `SESSION_TTL_DAYS` (30) are optional.
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let positive = |name: &str, default: i64| -> Result<i64, String> {
+            match var(name) {
+                Some(value) => value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|n: &i64| *n > 0)
+                    .ok_or_else(|| format!("{} must be a positive whole number", name)),
+                None => Ok(default),
+            }
+        };
+
+        let secret = var("JWT_SECRET").ok_or("JWT_SECRET must be set")?;
+        let mut config = Self::new(
+            &secret,
+            &var("JWT_ISSUER").unwrap_or_else(|| "microfund-africa".to_string()),
+            &var("JWT_AUDIENCE").unwrap_or_else(|| "microfund-api".to_string()),
+            &var("JWT_KEY_ID").unwrap_or_else(|| "microfund-1".to_string()),
+            Duration::minutes(positive("JWT_ACCESS_TTL_MINUTES", DEFAULT_ACCESS_TTL_MINUTES)?),
+        )?;
+        config.session_ttl = Duration::days(positive("SESSION_TTL_DAYS", DEFAULT_SESSION_TTL_DAYS)?);
+        Ok(config)
+    }
+
+    /// Signs an access token for a member's session.
+    pub fn issue(&self, user_id: Uuid, session_id: Uuid, roles: Vec<Role>, now: DateTime<Utc>) -> Result<String, String> {
+        let claims = Claims {
+            sub: user_id,
+            sid: session_id,
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now.timestamp(),
+            exp: (now + self.access_ttl).timestamp(),
+            roles,
+        };
+        let header = Header {
+            kid: Some(self.key_id.clone()),
+            ..Header::new(Algorithm::HS256)
+        };
+        encode(&header, &claims, &self.encoding_key).map_err(|e| format!("Failed to sign token: {}", e))
+    }
+
+    /// Checks the signature, key id, issuer, audience and expiry of a token.
+    pub fn verify(&self, token: &str) -> Result<AuthUser, String> {
+        let header = decode_header(token).map_err(|e| e.to_string())?;
+        if header.kid.as_deref() != Some(self.key_id.as_str()) {
+            return Err(format!("Unknown signing key {:?}", header.kid));
+        }
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = 0;
+        let data = decode::<Claims>(token, &self.decoding_key, &validation).map_err(|e| e.to_string())?;
+
+        Ok(AuthUser {
+            id: data.claims.sub,
+            session_id: data.claims.sid,
+            roles: data.claims.roles,
+        })
+    }
+}
+
+/// The member a request was made by, put there by `authenticate`.
+/// Taking it as a handler argument makes the route answer 401 to anonymous callers.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub roles: Vec<Role>,
+}
+
+impl AuthUser {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+
+    /// Whether any of the caller's roles grants `permission`.
+    pub fn can(&self, permission: Permission) -> bool {
+        self.roles.iter().any(|role| role.permissions().contains(&permission))
+    }
+
+    /// The caller's id if they may use `permission`. Staff without it get a 403; members get
+    /// a 404, so the admin endpoints do not reveal that they exist.
+    pub fn require(&self, permission: Permission) -> Result<Uuid, AppError> {
+        if self.can(permission) {
+            Ok(self.id)
+        } else if Role::STAFF.iter().any(|role| self.has_role(*role)) {
+            tracing::warn!("User {} denied {:?}", self.id, permission);
+            Err(AppError::Forbidden)
+        } else {
+            Err(AppError::NotFound)
+        }
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<AuthUser>().cloned().ok_or(AppError::Unauthorized))
+    }
+}
+
+/// Verifies the bearer token of every request carrying one, and that its session has not
+/// been ended by a logout, revocation, password change or freeze since it was issued.
+/// Requests without a valid token go through anonymously and are turned away by the routes
+/// that take an `AuthUser`.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    if let Some(token) = token {
+        let config = req.app_data::<web::Data<JwtConfig>>().ok_or(AppError::InternalServerError)?;
+        match config.verify(&token) {
+            Ok(user) => {
+                let pool = req.app_data::<web::Data<PgPool>>().ok_or(AppError::InternalServerError)?;
+                let valid = SessionService::is_valid(pool, user.id, user.session_id, Utc::now()).await.map_err(|e| {
+                    tracing::error!("Failed to check session {}: {:?}", user.session_id, e);
+                    AppError::InternalServerError
+                })?;
+                if valid {
+                    req.extensions_mut().insert(user);
+                } else {
+                    tracing::debug!("Rejected bearer token of ended session {}", user.session_id);
+                }
+            }
+            Err(e) => tracing::debug!("Rejected bearer token: {}", e),
+        }
+    }
+
+    next.call(req).await
+}
@@ -0,0 +1,122 @@
+use sqlx::postgres::PgRow;
+use sqlx::{FromRow, PgConnection};
+use uuid::Uuid;
+use crate::middleware::{AppError, AuthUser};
+use crate::models::{Loan, Payment, Permission, Savings, SavingsWithdrawal};
+use crate::services::loan_lifecycle::LOAN_COLUMNS;
+use crate::services::withdrawals::SAVINGS_COLUMNS;
+
+/// What a handler is about to do with a resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Act on it: deposit into a goal, repay or cancel a loan. Only its owner may.
+    Owner,
+    /// Look at it. Its owner, the other party to it (a loan's lender) and staff may.
+    Party,
+}
+
+/// Something a single member owns.
+pub trait Owned {
+    fn owner_id(&self) -> Uuid;
+
+    /// The member on the other side, who may see the resource but not act on it.
+    fn counterparty_id(&self) -> Option<Uuid> {
+        None
+    }
+}
+
+/// An owned resource stored in its own table under a UUID primary key.
+pub trait Resource: Owned + for<'r> FromRow<'r, PgRow> + Send + Unpin {
+    const TABLE: &'static str;
+    const COLUMNS: &'static str;
+}
+
+impl Owned for Loan {
+    fn owner_id(&self) -> Uuid {
+        self.user_id
+    }
+
+    fn counterparty_id(&self) -> Option<Uuid> {
+        self.lender_id
+    }
+}
+
+impl Resource for Loan {
+    const TABLE: &'static str = "loans";
+    const COLUMNS: &'static str = LOAN_COLUMNS;
+}
+
+impl Owned for Savings {
+    fn owner_id(&self) -> Uuid {
+        self.user_id
+    }
+}
+
+impl Resource for Savings {
+    const TABLE: &'static str = "savings";
+    const COLUMNS: &'static str = SAVINGS_COLUMNS;
+}
+
+impl Owned for Payment {
+    fn owner_id(&self) -> Uuid {
+        self.user_id
+    }
+}
+
+impl Owned for SavingsWithdrawal {
+    fn owner_id(&self) -> Uuid {
+        self.user_id
+    }
+}
+
+/// Whether `user` may have `access` to `resource`. Staff who may view members may look at anything.
+pub fn check<R: Owned>(resource: &R, user: &AuthUser, access: Access) -> Result<(), AppError> {
+    let allowed = resource.owner_id() == user.id
+        || (access == Access::Party
+            && (resource.counterparty_id() == Some(user.id) || user.can(Permission::ViewMembers)));
+    if allowed {
+        Ok(())
+    } else {
+        tracing::warn!("User {} denied {:?} access to a resource of {}", user.id, access, resource.owner_id());
+        Err(AppError::Forbidden)
+    }
+}
+
+/// Loads a resource for a handler, if the user may have `access` to it.
+/// `NotFound` when it does not exist, `Forbidden` when it belongs to someone else.
+pub async fn load<R: Resource>(conn: &mut PgConnection, id: Uuid, user: &AuthUser, access: Access) -> Result<R, AppError> {
+    let query = format!("SELECT {} FROM {} WHERE id = $1", R::COLUMNS, R::TABLE);
+    load_with(conn, &query, id, user, access).await
+}
+
+/// `load`, also locking the row until the surrounding transaction ends.
+pub async fn load_for_update<R: Resource>(
+    conn: &mut PgConnection,
+    id: Uuid,
+    user: &AuthUser,
+    access: Access,
+) -> Result<R, AppError> {
+    let query = format!("SELECT {} FROM {} WHERE id = $1 FOR UPDATE", R::COLUMNS, R::TABLE);
+    load_with(conn, &query, id, user, access).await
+}
+
+async fn load_with<R: Resource>(
+    conn: &mut PgConnection,
+    query: &str,
+    id: Uuid,
+    user: &AuthUser,
+    access: Access,
+) -> Result<R, AppError> {
+    let resource: R = sqlx::query_as(query)
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load {} {}: {:?}", R::TABLE, id, e);
+            AppError::InternalServerError
+        })?
+        .ok_or(AppError::NotFound)?;
+
+    check(&resource, user, access)?;
+    Ok(resource)
+}
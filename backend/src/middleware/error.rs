@@ -1,7 +1,9 @@
+use actix_web::http::header;
 use actix_web::{HttpResponse, ResponseError};
 use derive_more::Display;
 use serde::Serialize;
 use thiserror::Error;
+use crate::models::LoanStatus;
 
 #[derive(Debug, Display, Error)]
 pub enum AppError {
@@ -14,11 +16,30 @@ pub enum AppError {
     #[display(fmt = "Unauthorized")]
     Unauthorized,
 
+    /// The resource exists but belongs to someone else.
+    #[display(fmt = "Forbidden")]
+    Forbidden,
+
     #[display(fmt = "Not Found")]
     NotFound,
 
     #[display(fmt = "Conflict: {}", _0)]
     Conflict(String),
+
+    /// Well-formed, but cannot be carried out as sent.
+    #[display(fmt = "Unprocessable Entity: {}", _0)]
+    UnprocessableEntity(String),
+
+    #[display(fmt = "Invalid loan transition from {} to {}", from, to)]
+    InvalidLoanTransition { from: LoanStatus, to: LoanStatus },
+
+    /// Staff froze the member's account.
+    #[display(fmt = "Account Frozen")]
+    AccountFrozen,
+
+    /// Rate limited or locked out; the client may try again after `retry_after` seconds.
+    #[display(fmt = "Too Many Requests (retry after {}s)", retry_after)]
+    TooManyRequests { retry_after: u64 },
 }
 
 #[derive(Serialize)]
@@ -44,6 +65,11 @@ impl ResponseError for AppError {
                     error: "Unauthorized".to_string(),
                 })
             }
+            AppError::Forbidden => {
+                HttpResponse::Forbidden().json(ErrorResponse {
+                    error: "You do not have access to this resource".to_string(),
+                })
+            }
             AppError::NotFound => {
                 HttpResponse::NotFound().json(ErrorResponse {
                     error: "Resource Not Found".to_string(),
@@ -54,6 +80,28 @@ impl ResponseError for AppError {
                     error: message.clone(),
                 })
             }
+            AppError::UnprocessableEntity(ref message) => {
+                HttpResponse::UnprocessableEntity().json(ErrorResponse {
+                    error: message.clone(),
+                })
+            }
+            AppError::InvalidLoanTransition { from, to } => {
+                HttpResponse::Conflict().json(ErrorResponse {
+                    error: format!("Loan cannot move from '{}' to '{}'", from, to),
+                })
+            }
+            AppError::AccountFrozen => {
+                HttpResponse::Forbidden().json(ErrorResponse {
+                    error: "This account is frozen; please contact support".to_string(),
+                })
+            }
+            AppError::TooManyRequests { retry_after } => {
+                HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(ErrorResponse {
+                        error: format!("Too many attempts; try again in {} seconds", retry_after),
+                    })
+            }
         }
     }
 }
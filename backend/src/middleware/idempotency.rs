@@ -0,0 +1,312 @@
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpMessage, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use uuid::Uuid;
+use crate::middleware::{AppError, AuthUser};
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+/// Set on answers replayed from the first request with the same key.
+pub const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+const MAX_KEY_LEN: usize = 255;
+/// Keys are remembered this long; a retry after that runs the request again.
+const KEY_TTL_HOURS: i64 = 24;
+/// How long a request may hold its key before a retry may take it over.
+const LEASE_SECONDS: i64 = 120;
+/// Times the answer to a request that ran is offered to the database before giving up on it.
+const SAVE_ATTEMPTS: u32 = 3;
+
+/// Identifies a request, so a key cannot be reused for a different one.
+pub fn fingerprint(method: &str, path: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(b" ");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Whether an answer is kept for retries to replay. Server errors, rate limiting and conflicts
+/// with a request still running say nothing about what the request would do when tried again,
+/// so their key is freed instead.
+pub fn is_replayable(status: StatusCode) -> bool {
+    !status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::CONFLICT
+}
+
+/// The first answer given to a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Claim {
+    /// The key is new, or taken over from a request whose lease ran out; the request should
+    /// run. Holds the lease, which `complete` and `release` need so only its holder can settle the key.
+    New(DateTime<Utc>),
+    /// The first request with the key has finished; answer with its response.
+    Completed(StoredResponse),
+    /// The first request with the key is still running and its lease has not run out.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// Keys and first responses in `idempotency_keys`, scoped to the member who sent them.
+pub struct IdempotencyStore;
+
+impl IdempotencyStore {
+    /// Reserves `key` for a request, or reports what became of the earlier one that used it.
+    /// A key still unanswered after its lease is taken over by a retry of the same request.
+    pub async fn claim(pool: &PgPool, user_id: Uuid, key: &str, fingerprint: &str, now: DateTime<Utc>) -> Result<Claim, sqlx::Error> {
+        sqlx::query("DELETE FROM idempotency_keys WHERE user_id = $1 AND key = $2 AND created_at < $3")
+            .bind(user_id)
+            .bind(key)
+            .bind(now - Duration::hours(KEY_TTL_HOURS))
+            .execute(pool)
+            .await?;
+        let claimed: Option<(DateTime<Utc>,)> = sqlx::query_as(
+            "INSERT INTO idempotency_keys (user_id, key, fingerprint, created_at, locked_until) VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (user_id, key) DO UPDATE SET locked_until = EXCLUDED.locked_until
+             WHERE idempotency_keys.response_status IS NULL AND idempotency_keys.fingerprint = EXCLUDED.fingerprint
+               AND (idempotency_keys.locked_until IS NULL OR idempotency_keys.locked_until < $4)
+             RETURNING locked_until"
+        )
+        .bind(user_id)
+        .bind(key)
+        .bind(fingerprint)
+        .bind(now)
+        .bind(now + Duration::seconds(LEASE_SECONDS))
+        .fetch_optional(pool)
+        .await?;
+        if let Some((lease,)) = claimed {
+            return Ok(Claim::New(lease));
+        }
+
+        let (stored_fingerprint, status, content_type, body): (String, Option<i16>, Option<String>, Option<Vec<u8>>) = sqlx::query_as(
+            "SELECT fingerprint, response_status, response_content_type, response_body FROM idempotency_keys
+             WHERE user_id = $1 AND key = $2"
+        )
+        .bind(user_id)
+        .bind(key)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(if stored_fingerprint != fingerprint {
+            Claim::Mismatch
+        } else {
+            match status {
+                Some(status) => Claim::Completed(StoredResponse {
+                    status: status as u16,
+                    content_type,
+                    body: body.unwrap_or_default(),
+                }),
+                None => Claim::InProgress,
+            }
+        })
+    }
+
+    /// Saves the answer to a key claimed with `lease` for retries to replay. `false` if the
+    /// lease was lost to a retry that took the key over, which then answers it instead.
+    pub async fn complete(
+        pool: &PgPool,
+        user_id: Uuid,
+        key: &str,
+        lease: DateTime<Utc>,
+        response: &StoredResponse,
+        now: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE idempotency_keys SET response_status = $4, response_content_type = $5, response_body = $6, completed_at = $7,
+                 locked_until = NULL
+             WHERE user_id = $1 AND key = $2 AND locked_until = $3 AND response_status IS NULL"
+        )
+        .bind(user_id)
+        .bind(key)
+        .bind(lease)
+        .bind(response.status as i16)
+        .bind(&response.content_type)
+        .bind(&response.body)
+        .bind(now)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Frees a key claimed with `lease` whose request failed without an answer worth keeping,
+    /// so a retry runs it again. A key taken over by a retry since is left to that retry.
+    pub async fn release(pool: &PgPool, user_id: Uuid, key: &str, lease: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "DELETE FROM idempotency_keys WHERE user_id = $1 AND key = $2 AND locked_until = $3 AND response_status IS NULL"
+        )
+        .bind(user_id)
+        .bind(key)
+        .bind(lease)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Forgets keys past their lifetime.
+    pub async fn prune(pool: &PgPool, now: DateTime<Utc>) -> Result<u64, String> {
+        let result = sqlx::query("DELETE FROM idempotency_keys WHERE created_at < $1")
+            .bind(now - Duration::hours(KEY_TTL_HOURS))
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// Makes the POSTs it wraps safe to retry. A logged-in member's request carrying an
+/// `Idempotency-Key` header runs once; retries with the same key and body get the first
+/// answer back, a retry while it is still running gets `409 Conflict` (until its lease runs
+/// out, when the retry takes the key over), and reusing the key for a different request gets `422`. Server errors, `429`
+/// and `409` answers are not kept, so those can be retried.
+/// A request whose answer cannot be saved, or that outlives its lease, may run again for a
+/// retry sent after the lease ran out. Requests without a key run as usual.
+pub struct Idempotency;
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotency
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = IdempotencyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let key = match req.headers().get(IDEMPOTENCY_KEY) {
+                Some(value) if *req.method() == Method::POST => Some(
+                    value
+                        .to_str()
+                        .ok()
+                        .map(str::trim)
+                        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+                        .ok_or_else(|| AppError::BadRequest(format!("{} must be 1 to {} visible characters", IDEMPOTENCY_KEY, MAX_KEY_LEN)))?
+                        .to_string(),
+                ),
+                _ => None,
+            };
+            let user_id = req.extensions().get::<AuthUser>().map(|user| user.id);
+            // Anonymous requests are turned away by the handlers anyway
+            let (Some(key), Some(user_id)) = (key, user_id) else {
+                return service.call(req).await.map(ServiceResponse::map_into_boxed_body);
+            };
+
+            let pool = req.app_data::<web::Data<PgPool>>().ok_or(AppError::InternalServerError)?.clone();
+            let body = req.extract::<web::Bytes>().await?;
+            let path = req.uri().path_and_query().map_or_else(|| req.path().to_string(), |p| p.to_string());
+            let fingerprint = fingerprint(req.method().as_str(), &path, &body);
+            req.set_payload(Payload::from(body));
+
+            let claim = IdempotencyStore::claim(&pool, user_id, &key, &fingerprint, Utc::now()).await.map_err(|e| {
+                tracing::error!("Failed to claim idempotency key: {}", e);
+                AppError::InternalServerError
+            })?;
+            let lease = match claim {
+                Claim::New(lease) => lease,
+                Claim::Completed(stored) => {
+                    tracing::info!("Replaying the answer to idempotency key {} of user {}", key, user_id);
+                    let mut response = HttpResponse::build(StatusCode::from_u16(stored.status).map_err(|_| AppError::InternalServerError)?);
+                    response.insert_header((IDEMPOTENT_REPLAYED, "true"));
+                    if let Some(content_type) = stored.content_type {
+                        response.insert_header((CONTENT_TYPE, content_type));
+                    }
+                    return Ok(req.into_response(response.body(stored.body)));
+                }
+                Claim::InProgress => {
+                    return Err(AppError::Conflict("A request with this Idempotency-Key is still being processed".to_string()).into());
+                }
+                Claim::Mismatch => {
+                    return Err(AppError::UnprocessableEntity("This Idempotency-Key was already used for a different request".to_string()).into());
+                }
+            };
+
+            let release = |pool: web::Data<PgPool>, key: String| async move {
+                if let Err(e) = IdempotencyStore::release(&pool, user_id, &key, lease).await {
+                    tracing::error!("Failed to release idempotency key {}: {}", key, e);
+                }
+            };
+            let res = match service.call(req).await {
+                Ok(res) if is_replayable(res.status()) => res,
+                other => {
+                    release(pool, key).await;
+                    return other.map(ServiceResponse::map_into_boxed_body);
+                }
+            };
+
+            let (request, response) = res.into_parts();
+            let content_type = response.headers().get(CONTENT_TYPE).and_then(|v: &HeaderValue| v.to_str().ok()).map(str::to_string);
+            let (response, body) = response.into_parts();
+            let body = match to_bytes(body).await {
+                Ok(body) => body,
+                Err(e) => {
+                    let e: Box<dyn std::error::Error> = e.into();
+                    tracing::error!("Failed to read the response to idempotency key {}: {}", key, e);
+                    release(pool, key).await;
+                    return Err(AppError::InternalServerError.into());
+                }
+            };
+
+            let stored = StoredResponse { status: response.status().as_u16(), content_type, body: body.to_vec() };
+            let mut attempt = 1;
+            loop {
+                match IdempotencyStore::complete(&pool, user_id, &key, lease, &stored, Utc::now()).await {
+                    Ok(true) => break,
+                    Ok(false) => {
+                        tracing::warn!("Idempotency key {} of user {} was taken over before its answer was saved", key, user_id);
+                        break;
+                    }
+                    Err(e) if attempt < SAVE_ATTEMPTS => {
+                        tracing::warn!("Failed to save the answer to idempotency key {} (attempt {}): {}", key, attempt, e);
+                        actix_web::rt::time::sleep(std::time::Duration::from_millis(100 * u64::from(attempt))).await;
+                        attempt += 1;
+                    }
+                    Err(e) => {
+                        // The request has been carried out, but a retry after its lease runs out will run it again
+                        tracing::error!("Failed to save the answer to idempotency key {}: {}", key, e);
+                        break;
+                    }
+                }
+            }
+            Ok(ServiceResponse::new(request, response.set_body(body).map_into_boxed_body()))
+        })
+    }
+}
@@ -1,3 +1,8 @@
+pub mod auth;
+pub mod authz;
 pub mod error;
+pub mod idempotency;
+pub mod rate_limit;
 
+pub use auth::AuthUser;
 pub use error::AppError;
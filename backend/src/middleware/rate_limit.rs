@@ -0,0 +1,322 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use actix_web::{web, HttpMessage};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use crate::middleware::{AppError, AuthUser};
+
+/// The in-memory store forgets idle buckets once it holds this many.
+const MAX_MEMORY_BUCKETS: usize = 100_000;
+/// A bucket untouched this long has refilled under any quota, so it can be forgotten.
+const IDLE_BUCKET_HOURS: i64 = 24;
+
+/// How many requests a client may make: bursts of up to `burst`, refilled evenly over
+/// `period_seconds`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub burst: u32,
+    pub period_seconds: u32,
+}
+
+impl Quota {
+    pub const fn per_minute(burst: u32) -> Self {
+        Quota { burst, period_seconds: 60 }
+    }
+
+    pub const fn per_hour(burst: u32) -> Self {
+        Quota { burst, period_seconds: 3600 }
+    }
+
+    /// Tokens added per second.
+    fn rate(&self) -> f64 {
+        self.burst as f64 / self.period_seconds as f64
+    }
+}
+
+/// The limits on a group of routes, counted per client IP and, for logged-in members, per account.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitPolicy {
+    /// Keeps the buckets of different groups apart.
+    pub name: &'static str,
+    pub per_ip: Quota,
+    pub per_account: Option<Quota>,
+}
+
+impl RateLimitPolicy {
+    pub const fn new(name: &'static str, per_ip: Quota) -> Self {
+        RateLimitPolicy { name, per_ip, per_account: None }
+    }
+
+    pub const fn per_account(self, quota: Quota) -> Self {
+        RateLimitPolicy { per_account: Some(quota), ..self }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allowed,
+    /// Seconds until a token is available.
+    Limited { retry_after: u64 },
+}
+
+/// A token bucket. Refilled lazily when a request comes in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Bucket {
+    pub fn full(quota: Quota, now: DateTime<Utc>) -> Self {
+        Bucket { tokens: quota.burst as f64, updated_at: now }
+    }
+
+    /// Refills the bucket up to `now` and takes a token if there is one.
+    pub fn take(&mut self, quota: Quota, now: DateTime<Utc>) -> Decision {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * quota.rate()).min(quota.burst as f64);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Decision::Allowed
+        } else {
+            let wait = (1.0 - self.tokens) / quota.rate();
+            Decision::Limited { retry_after: wait.ceil().max(1.0) as u64 }
+        }
+    }
+}
+
+/// Where buckets are kept.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket under `key`, creating it full if it is new.
+    async fn take(&self, key: &str, quota: Quota, now: DateTime<Utc>) -> Result<Decision, String>;
+}
+
+/// Buckets in process memory. Each instance counts on its own.
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, key: &str, quota: Quota, now: DateTime<Utc>) -> Result<Decision, String> {
+        let mut buckets = self.buckets.lock().map_err(|_| "Rate limit store poisoned".to_string())?;
+        if buckets.len() >= MAX_MEMORY_BUCKETS {
+            let cutoff = now - Duration::hours(IDLE_BUCKET_HOURS);
+            buckets.retain(|_, bucket| bucket.updated_at > cutoff);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert_with(|| Bucket::full(quota, now));
+        Ok(bucket.take(quota, now))
+    }
+}
+
+/// Buckets in `rate_limit_buckets`, shared by every instance.
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresStore { pool }
+    }
+
+    /// Forgets buckets that have been idle long enough to be full again.
+    pub async fn prune(pool: &PgPool, now: DateTime<Utc>) -> Result<u64, String> {
+        let result = sqlx::query("DELETE FROM rate_limit_buckets WHERE updated_at < $1")
+            .bind(now - Duration::hours(IDLE_BUCKET_HOURS))
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PostgresStore {
+    async fn take(&self, key: &str, quota: Quota, now: DateTime<Utc>) -> Result<Decision, String> {
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+
+        let full = Bucket::full(quota, now);
+        sqlx::query("INSERT INTO rate_limit_buckets (key, tokens, updated_at) VALUES ($1, $2, $3) ON CONFLICT (key) DO NOTHING")
+            .bind(key)
+            .bind(full.tokens)
+            .bind(full.updated_at)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        let (tokens, updated_at): (f64, DateTime<Utc>) =
+            sqlx::query_as("SELECT tokens, updated_at FROM rate_limit_buckets WHERE key = $1 FOR UPDATE")
+                .bind(key)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+
+        let mut bucket = Bucket { tokens, updated_at };
+        let decision = bucket.take(quota, now);
+        sqlx::query("UPDATE rate_limit_buckets SET tokens = $1, updated_at = $2 WHERE key = $3")
+            .bind(bucket.tokens)
+            .bind(bucket.updated_at)
+            .bind(key)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(decision)
+    }
+}
+
+/// Checks requests against route group policies. Loaded once at startup.
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    pub enabled: bool,
+    /// Take the client IP from `X-Forwarded-For`/`Forwarded`. Only safe behind a proxy that sets them.
+    pub trust_proxy: bool,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>) -> Self {
+        RateLimiter { store, enabled: true, trust_proxy: false }
+    }
+
+    /// `RATE_LIMIT_STORE` is `memory` (the default) or `postgres` for deployments with several
+    /// instances. `RATE_LIMIT_ENABLED=false` turns limiting off; `RATE_LIMIT_TRUST_PROXY=true`
+    /// takes client IPs from forwarding headers.
+    pub fn from_env(pool: &PgPool) -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let flag = |name: &str, default: bool| -> Result<bool, String> {
+            match var(name).as_deref().map(str::trim) {
+                None => Ok(default),
+                Some("true") | Some("1") => Ok(true),
+                Some("false") | Some("0") => Ok(false),
+                Some(other) => Err(format!("{} must be true or false, not '{}'", name, other)),
+            }
+        };
+
+        let store: Arc<dyn RateLimitStore> = match var("RATE_LIMIT_STORE").as_deref().map(str::trim) {
+            None | Some("memory") => Arc::new(MemoryStore::default()),
+            Some("postgres") => Arc::new(PostgresStore::new(pool.clone())),
+            Some(other) => return Err(format!("Unknown RATE_LIMIT_STORE '{}'", other)),
+        };
+        Ok(RateLimiter {
+            enabled: flag("RATE_LIMIT_ENABLED", true)?,
+            trust_proxy: flag("RATE_LIMIT_TRUST_PROXY", false)?,
+            ..RateLimiter::new(store)
+        })
+    }
+
+    /// Takes a token for the client and, if given, the account. `TooManyRequests` when either
+    /// bucket is empty. A store that cannot be reached lets requests through.
+    pub async fn check(
+        &self,
+        policy: &RateLimitPolicy,
+        ip: Option<&str>,
+        account: Option<Uuid>,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let mut buckets = Vec::with_capacity(2);
+        if let Some(ip) = ip {
+            buckets.push((format!("{}:ip:{}", policy.name, ip), policy.per_ip));
+        }
+        if let (Some(quota), Some(account)) = (policy.per_account, account) {
+            buckets.push((format!("{}:account:{}", policy.name, account), quota));
+        }
+
+        for (key, quota) in buckets {
+            match self.store.take(&key, quota, now).await {
+                Ok(Decision::Allowed) => {}
+                Ok(Decision::Limited { retry_after }) => {
+                    tracing::warn!("Rate limited {} for {}s", key, retry_after);
+                    return Err(AppError::TooManyRequests { retry_after });
+                }
+                Err(e) => tracing::error!("Rate limit store failed for {}: {}", key, e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies a `RateLimitPolicy` to the routes it wraps. Only requests that change something
+/// are counted; reads pass freely.
+pub struct RateLimit {
+    policy: RateLimitPolicy,
+}
+
+impl RateLimit {
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        RateLimit { policy }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            policy: self.policy,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    policy: RateLimitPolicy,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let policy = self.policy;
+
+        Box::pin(async move {
+            if !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+                let limiter = req
+                    .app_data::<web::Data<RateLimiter>>()
+                    .ok_or(AppError::InternalServerError)?
+                    .clone();
+                let ip = if limiter.trust_proxy {
+                    req.connection_info().realip_remote_addr().map(str::to_string)
+                } else {
+                    req.peer_addr().map(|addr| addr.ip().to_string())
+                };
+                let account = req.extensions().get::<AuthUser>().map(|user| user.id);
+                limiter.check(&policy, ip.as_deref(), account, Utc::now()).await?;
+            }
+            service.call(req).await
+        })
+    }
+}
@@ -1,45 +1,1124 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+
+pub use microfund_shared::{Money, PLATFORM_CURRENCY};
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
-    pub email: String,
-    pub password_hash: String,
+    pub email: Option<String>,
+    /// None for members who signed up by phone and log in with SMS codes.
+    pub password_hash: Option<String>,
     pub reputation_score: i32,
     pub created_at: Option<DateTime<Utc>>,
+    /// In the `2547XXXXXXXX` form; the default number for M-Pesa payments once verified.
+    pub phone_number: Option<String>,
+    pub phone_verified_at: Option<DateTime<Utc>>,
+    /// Set once the member confirmed an authenticator app; logins then ask for its codes.
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+}
+
+impl User {
+    /// The member's phone number, if they proved they own it with an SMS code.
+    pub fn verified_phone(&self) -> Option<&str> {
+        self.phone_verified_at.and(self.phone_number.as_deref())
+    }
+
+    pub fn two_factor_enabled(&self) -> bool {
+        self.totp_enabled_at.is_some()
+    }
+}
+
+/// Who a member is on the platform. Every member has `Member`; staff also have one or more
+/// of the others, granted in `user_roles`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Member,
+    /// Runs the platform: everything staff can do.
+    Admin,
+    /// Reviews loans and members' credit.
+    LoanOfficer,
+    /// Looks at everything and changes nothing.
+    Auditor,
+}
+
+impl Role {
+    /// The roles that can be granted.
+    pub const STAFF: [Role; 3] = [Role::Admin, Role::LoanOfficer, Role::Auditor];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Admin => "admin",
+            Role::LoanOfficer => "loan_officer",
+            Role::Auditor => "auditor",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "member" => Some(Role::Member),
+            "admin" => Some(Role::Admin),
+            "loan_officer" => Some(Role::LoanOfficer),
+            "auditor" => Some(Role::Auditor),
+            _ => None,
+        }
+    }
+
+    /// What the role allows beyond acting on the member's own account.
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Member => &[],
+            Role::Admin => &Permission::ALL,
+            Role::LoanOfficer => &[ViewMembers, ReviewLoans, AdjustScores, ViewPlatformHealth],
+            Role::Auditor => &[ViewMembers, ViewPlatformHealth, ViewAuditLog, ViewLedger],
+        }
+    }
+}
+
+/// A staff capability. Handlers check these rather than roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Look up members and see anything they own.
+    ViewMembers,
+    FreezeAccounts,
+    ManageRoles,
+    /// Approve or reject loan requests.
+    ReviewLoans,
+    ReverseTransactions,
+    /// Book paybill repayments made outside the app, by their M-Pesa receipt.
+    RecordRepayments,
+    AdjustScores,
+    ViewPlatformHealth,
+    ViewAuditLog,
+    /// See the ledger balances of every member, as in the trial balance.
+    ViewLedger,
+    /// Import and reconcile M-Pesa statements.
+    Reconcile,
+    /// Send dead-lettered side effects again.
+    RedeliverEvents,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 12] = [
+        Permission::ViewMembers,
+        Permission::FreezeAccounts,
+        Permission::ManageRoles,
+        Permission::ReviewLoans,
+        Permission::ReverseTransactions,
+        Permission::RecordRepayments,
+        Permission::AdjustScores,
+        Permission::ViewPlatformHealth,
+        Permission::ViewAuditLog,
+        Permission::ViewLedger,
+        Permission::Reconcile,
+        Permission::RedeliverEvents,
+    ];
+}
+
+/// Where a loan is in its life. Moves between states only through
+/// `LoanLifecycle::transition`, which enforces `can_transition_to`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoanStatus {
+    Pending,
+    Funded,
+    Disbursed,
+    InRepayment,
+    Repaid,
+    Overdue,
+    Defaulted,
+    Cancelled,
+    WrittenOff,
+}
+
+impl LoanStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoanStatus::Pending => "pending",
+            LoanStatus::Funded => "funded",
+            LoanStatus::Disbursed => "disbursed",
+            LoanStatus::InRepayment => "in_repayment",
+            LoanStatus::Repaid => "repaid",
+            LoanStatus::Overdue => "overdue",
+            LoanStatus::Defaulted => "defaulted",
+            LoanStatus::Cancelled => "cancelled",
+            LoanStatus::WrittenOff => "written_off",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(LoanStatus::Pending),
+            "funded" => Some(LoanStatus::Funded),
+            "disbursed" => Some(LoanStatus::Disbursed),
+            "in_repayment" => Some(LoanStatus::InRepayment),
+            "repaid" => Some(LoanStatus::Repaid),
+            "overdue" => Some(LoanStatus::Overdue),
+            "defaulted" => Some(LoanStatus::Defaulted),
+            "cancelled" => Some(LoanStatus::Cancelled),
+            "written_off" => Some(LoanStatus::WrittenOff),
+            _ => None,
+        }
+    }
+
+    /// The allowed moves of the loan state machine.
+    pub fn can_transition_to(&self, next: LoanStatus) -> bool {
+        use LoanStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Funded)
+                | (Pending, Cancelled)
+                // Payout failed or was reversed before reaching the borrower
+                | (Funded, Pending)
+                | (Funded, Disbursed)
+                | (Funded, Cancelled)
+                | (Disbursed, InRepayment)
+                | (Disbursed, Repaid)
+                | (Disbursed, Overdue)
+                | (InRepayment, Repaid)
+                | (InRepayment, Overdue)
+                | (Overdue, InRepayment)
+                | (Overdue, Repaid)
+                | (Overdue, Defaulted)
+                // Late recovery of a defaulted loan
+                | (Defaulted, Repaid)
+                | (Defaulted, WrittenOff)
+        )
+    }
+}
+
+impl std::fmt::Display for LoanStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Stores an enum with `as_str`/`parse` as its VARCHAR text in Postgres.
+macro_rules! varchar_enum {
+    ($name:ident) => {
+        impl sqlx::Type<sqlx::Postgres> for $name {
+            fn type_info() -> sqlx::postgres::PgTypeInfo {
+                <String as sqlx::Type<sqlx::Postgres>>::type_info()
+            }
+
+            fn compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+                <String as sqlx::Type<sqlx::Postgres>>::compatible(ty)
+            }
+        }
+
+        impl sqlx::Encode<'_, sqlx::Postgres> for $name {
+            fn encode_by_ref(&self, buf: &mut sqlx::postgres::PgArgumentBuffer) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+                <&str as sqlx::Encode<sqlx::Postgres>>::encode(self.as_str(), buf)
+            }
+        }
+
+        impl<'r> sqlx::Decode<'r, sqlx::Postgres> for $name {
+            fn decode(value: sqlx::postgres::PgValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+                let raw = <&str as sqlx::Decode<sqlx::Postgres>>::decode(value)?;
+                $name::parse(raw).ok_or_else(|| format!("Unknown {}: {}", stringify!($name), raw).into())
+            }
+        }
+
+        impl sqlx::postgres::PgHasArrayType for $name {
+            fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+                <String as sqlx::postgres::PgHasArrayType>::array_type_info()
+            }
+
+            fn array_compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+                <String as sqlx::postgres::PgHasArrayType>::array_compatible(ty)
+            }
+        }
+    };
+}
+
+// Stored as VARCHAR in `loans.status` and `loan_status_history`.
+varchar_enum!(LoanStatus);
+
+// Stored as VARCHAR in `user_roles.role`.
+varchar_enum!(Role);
+
+/// How interest is charged over the term of a loan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterestMethod {
+    /// Interest on the original principal for every period.
+    Flat,
+    /// Interest on the outstanding balance, repaid in equal installments.
+    DecliningBalance,
+}
+
+impl InterestMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InterestMethod::Flat => "flat",
+            InterestMethod::DecliningBalance => "declining_balance",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "flat" => Some(InterestMethod::Flat),
+            "declining_balance" => Some(InterestMethod::DecliningBalance),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(InterestMethod);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepaymentFrequency {
+    Weekly,
+    Monthly,
 }
 
+impl RepaymentFrequency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RepaymentFrequency::Weekly => "weekly",
+            RepaymentFrequency::Monthly => "monthly",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "weekly" => Some(RepaymentFrequency::Weekly),
+            "monthly" => Some(RepaymentFrequency::Monthly),
+            _ => None,
+        }
+    }
+
+    /// Number of installments in a year, used to turn an annual rate into a per-period rate.
+    pub fn periods_per_year(&self) -> i64 {
+        match self {
+            RepaymentFrequency::Weekly => 52,
+            RepaymentFrequency::Monthly => 12,
+        }
+    }
+}
+
+varchar_enum!(RepaymentFrequency);
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Loan {
     pub id: Uuid,
     pub user_id: Uuid,
     pub lender_id: Option<Uuid>,
-    pub amount: f64,
-    pub status: String,
+    pub amount: Money,
+    pub status: LoanStatus,
     pub description: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
     pub repaid_at: Option<DateTime<Utc>>,
+    pub product_id: Option<Uuid>,
+    /// Maturity: the due date of the last installment.
+    pub due_date: Option<DateTime<Utc>>,
+    /// Address of the loan's account in the microfund Solana program, once recorded there.
+    pub chain_account: Option<String>,
+    pub chain_loan_signature: Option<String>,
+    pub chain_repay_signature: Option<String>,
+}
+
+/// Pricing and term offered to borrowers. Rates are annual, in basis points.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct LoanProduct {
+    pub id: Uuid,
+    pub code: String,
+    pub name: String,
+    pub interest_method: InterestMethod,
+    pub interest_rate_bps: i32,
+    pub origination_fee_bps: i32,
+    pub term_count: i32,
+    pub repayment_frequency: RepaymentFrequency,
+    pub is_default: bool,
+}
+
+/// The terms a loan was priced with, copied from its product when the loan was created
+/// so later product changes never alter an existing schedule.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone, Copy, PartialEq)]
+pub struct LoanTerms {
+    pub interest_method: InterestMethod,
+    pub interest_rate_bps: i32,
+    pub origination_fee_bps: i32,
+    pub term_count: i32,
+    pub repayment_frequency: RepaymentFrequency,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct LoanInstallment {
+    pub id: Uuid,
+    pub loan_id: Uuid,
+    pub installment_number: i32,
+    pub due_date: DateTime<Utc>,
+    pub principal_due: Money,
+    pub interest_due: Money,
+    pub fee_due: Money,
+    pub penalty_due: Money,
+    pub total_due: Money,
+    pub principal_paid: Money,
+    pub interest_paid: Money,
+    pub fee_paid: Money,
+    pub penalty_paid: Money,
+    pub paid_at: Option<DateTime<Utc>>,
+    /// When the scheduler first found the installment unpaid past its due date.
+    pub overdue_at: Option<DateTime<Utc>>,
+}
+
+impl LoanInstallment {
+    pub fn due(&self, bucket: RepaymentBucket) -> Money {
+        match bucket {
+            RepaymentBucket::Penalty => self.penalty_due,
+            RepaymentBucket::Fee => self.fee_due,
+            RepaymentBucket::Interest => self.interest_due,
+            RepaymentBucket::Principal => self.principal_due,
+        }
+    }
+
+    pub fn paid(&self, bucket: RepaymentBucket) -> Money {
+        match bucket {
+            RepaymentBucket::Penalty => self.penalty_paid,
+            RepaymentBucket::Fee => self.fee_paid,
+            RepaymentBucket::Interest => self.interest_paid,
+            RepaymentBucket::Principal => self.principal_paid,
+        }
+    }
+}
+
+/// The components of what a borrower owes, in the vocabulary of allocation rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepaymentBucket {
+    Penalty,
+    Fee,
+    Interest,
+    Principal,
+}
+
+impl RepaymentBucket {
+    pub const ALL: [RepaymentBucket; 4] = [
+        RepaymentBucket::Penalty,
+        RepaymentBucket::Fee,
+        RepaymentBucket::Interest,
+        RepaymentBucket::Principal,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RepaymentBucket::Penalty => "penalty",
+            RepaymentBucket::Fee => "fee",
+            RepaymentBucket::Interest => "interest",
+            RepaymentBucket::Principal => "principal",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "penalty" => Some(RepaymentBucket::Penalty),
+            "fee" => Some(RepaymentBucket::Fee),
+            "interest" => Some(RepaymentBucket::Interest),
+            "principal" => Some(RepaymentBucket::Principal),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to money paid beyond the outstanding balance of a loan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverpaymentDestination {
+    /// Credited to one of the borrower's savings goals.
+    #[default]
+    Savings,
+    /// Paid back to the borrower over M-Pesa.
+    Refund,
+}
+
+impl OverpaymentDestination {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverpaymentDestination::Savings => "savings",
+            OverpaymentDestination::Refund => "refund",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "savings" => Some(OverpaymentDestination::Savings),
+            "refund" => Some(OverpaymentDestination::Refund),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(OverpaymentDestination);
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct LoanRepayment {
+    pub id: Uuid,
+    pub loan_id: Uuid,
+    pub payer_id: Option<Uuid>,
+    pub amount: Money,
+    pub principal_paid: Money,
+    pub interest_paid: Money,
+    pub fee_paid: Money,
+    pub penalty_paid: Money,
+    pub overpayment: Money,
+    pub overpayment_destination: Option<OverpaymentDestination>,
+    pub savings_id: Option<Uuid>,
+    pub outstanding_after: Money,
+    pub mpesa_receipt_number: Option<String>,
+    /// The STK push payment the repayment was collected with.
+    pub payment_id: Option<Uuid>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoanSchedule {
+    pub loan_id: Uuid,
+    pub principal: Money,
+    pub terms: LoanTerms,
+    pub total_interest: Money,
+    pub total_fees: Money,
+    pub total_penalties: Money,
+    pub total_repayable: Money,
+    pub total_paid: Money,
+    pub outstanding_balance: Money,
+    pub installments: Vec<LoanInstallment>,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct LoanStatusChange {
+    pub id: Uuid,
+    pub loan_id: Uuid,
+    pub from_status: Option<LoanStatus>,
+    pub to_status: LoanStatus,
+    pub actor_id: Option<Uuid>,
+    pub note: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    /// Waiting for M-Pesa to confirm: the customer has not approved the STK push yet,
+    /// or a payout has not been reported on.
+    Pending,
+    Completed,
+    Failed,
+}
+
+impl PaymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Completed => "completed",
+            PaymentStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(PaymentStatus::Pending),
+            "completed" => Some(PaymentStatus::Completed),
+            "failed" => Some(PaymentStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(PaymentStatus);
+
+/// What an incoming M-Pesa payment is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentPurpose {
+    SavingsDeposit,
+    LoanRepayment,
+    /// A lender's capital for a loan.
+    LoanFunding,
+}
+
+impl PaymentPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentPurpose::SavingsDeposit => "savings_deposit",
+            PaymentPurpose::LoanRepayment => "loan_repayment",
+            PaymentPurpose::LoanFunding => "loan_funding",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "savings_deposit" => Some(PaymentPurpose::SavingsDeposit),
+            "loan_repayment" => Some(PaymentPurpose::LoanRepayment),
+            "loan_funding" => Some(PaymentPurpose::LoanFunding),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(PaymentPurpose);
+
+/// An STK push sent to a customer and what became of it.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct Payment {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub purpose: PaymentPurpose,
+    /// The goal a deposit goes to, or the goal for any overpayment of a repayment.
+    pub savings_id: Option<Uuid>,
+    pub loan_id: Option<Uuid>,
+    pub overpayment_destination: Option<OverpaymentDestination>,
+    pub amount: Money,
+    pub phone_number: String,
+    pub status: PaymentStatus,
+    pub checkout_request_id: Option<String>,
+    pub merchant_request_id: Option<String>,
+    pub result_code: Option<String>,
+    pub result_desc: Option<String>,
+    pub mpesa_receipt_number: Option<String>,
+    /// When M-Pesa confirmed a payment whose receipt we do not have yet.
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub settled_at: Option<DateTime<Utc>>,
+}
+
+/// One B2C payout attempt of a funded loan to its borrower.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct LoanDisbursement {
+    pub id: Uuid,
+    pub loan_id: Uuid,
+    pub attempt: i32,
+    pub amount: Money,
+    pub phone_number: String,
+    pub status: PaymentStatus,
+    #[serde(skip)]
+    pub callback_token: String,
+    pub conversation_id: Option<String>,
+    pub transaction_id: Option<String>,
+    pub result_code: Option<String>,
+    pub result_desc: Option<String>,
+    pub retry_at: Option<DateTime<Utc>>,
+    /// When the attempt was handed to reconciliation for want of a result.
+    pub unacknowledged_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub settled_at: Option<DateTime<Utc>>,
+}
+
+/// Money owed back to a member, paid out to them over M-Pesa B2C. `attempt` counts the
+/// payouts sent so far; `sent_at` is set while one is waiting for its result.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct Refund {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub amount: Money,
+    pub phone_number: String,
+    pub reason: String,
+    pub loan_id: Option<Uuid>,
+    /// The repayment whose overpayment is refunded.
+    pub repayment_id: Option<Uuid>,
+    /// The payment the money came in with.
+    pub payment_id: Option<Uuid>,
+    pub status: PaymentStatus,
+    pub attempt: i32,
+    #[serde(skip)]
+    pub callback_token: String,
+    pub conversation_id: Option<String>,
+    pub transaction_id: Option<String>,
+    pub result_code: Option<String>,
+    pub result_desc: Option<String>,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub retry_at: Option<DateTime<Utc>>,
+    /// When the payout in flight was handed to reconciliation for want of a result.
+    pub unacknowledged_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub settled_at: Option<DateTime<Utc>>,
+}
+
+/// How a statement line or one of our records came out of reconciliation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconciliationKind {
+    Matched,
+    /// Money moved at Safaricom with nothing recorded here.
+    MissingInternally,
+    /// We recorded money moving that no statement shows.
+    MissingInStatement,
+    /// Both sides know the transaction but disagree on its amount, phone or outcome.
+    Mismatched,
+}
+
+impl ReconciliationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReconciliationKind::Matched => "matched",
+            ReconciliationKind::MissingInternally => "missing_internally",
+            ReconciliationKind::MissingInStatement => "missing_in_statement",
+            ReconciliationKind::Mismatched => "mismatched",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "matched" => Some(ReconciliationKind::Matched),
+            "missing_internally" => Some(ReconciliationKind::MissingInternally),
+            "missing_in_statement" => Some(ReconciliationKind::MissingInStatement),
+            "mismatched" => Some(ReconciliationKind::Mismatched),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(ReconciliationKind);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconciliationStatus {
+    Matched,
+    /// An exception waiting for an admin.
+    Open,
+    Resolved,
+}
+
+impl ReconciliationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReconciliationStatus::Matched => "matched",
+            ReconciliationStatus::Open => "open",
+            ReconciliationStatus::Resolved => "resolved",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "matched" => Some(ReconciliationStatus::Matched),
+            "open" => Some(ReconciliationStatus::Open),
+            "resolved" => Some(ReconciliationStatus::Resolved),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(ReconciliationStatus);
+
+/// What an admin did about a reconciliation exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconciliationResolution {
+    /// Our records were corrected to match Safaricom.
+    Adjusted,
+    /// The difference was accepted as a loss or gain.
+    WrittenOff,
+    /// Not a problem, such as a transaction charge or a transfer outside the platform.
+    NotAnIssue,
+}
+
+impl ReconciliationResolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReconciliationResolution::Adjusted => "adjusted",
+            ReconciliationResolution::WrittenOff => "written_off",
+            ReconciliationResolution::NotAnIssue => "not_an_issue",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "adjusted" => Some(ReconciliationResolution::Adjusted),
+            "written_off" => Some(ReconciliationResolution::WrittenOff),
+            "not_an_issue" => Some(ReconciliationResolution::NotAnIssue),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(ReconciliationResolution);
+
+/// An imported M-Pesa organisation statement.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct MpesaStatement {
+    pub id: Uuid,
+    pub file_name: Option<String>,
+    pub imported_by: Option<Uuid>,
+    pub period_start: Option<DateTime<Utc>>,
+    pub period_end: Option<DateTime<Utc>>,
+    pub line_count: i32,
+    pub duplicate_count: i32,
+    pub created_at: Option<DateTime<Utc>>,
+    pub reconciled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct MpesaStatementLine {
+    pub id: Uuid,
+    pub statement_id: Uuid,
+    pub receipt_number: String,
+    pub completed_at: DateTime<Utc>,
+    pub details: Option<String>,
+    pub transaction_status: String,
+    pub paid_in: Money,
+    pub withdrawn: Money,
+    pub other_party: Option<String>,
+    pub phone_number: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct ReconciliationItem {
+    pub id: Uuid,
+    pub statement_id: Uuid,
+    pub kind: ReconciliationKind,
+    pub status: ReconciliationStatus,
+    pub receipt_number: Option<String>,
+    pub statement_line_id: Option<Uuid>,
+    pub payment_id: Option<Uuid>,
+    pub disbursement_id: Option<Uuid>,
+    pub repayment_id: Option<Uuid>,
+    pub withdrawal_id: Option<Uuid>,
+    pub refund_id: Option<Uuid>,
+    pub statement_amount: Option<Money>,
+    pub internal_amount: Option<Money>,
+    pub detail: String,
+    pub resolution: Option<ReconciliationResolution>,
+    pub resolution_note: Option<String>,
+    pub resolved_by: Option<Uuid>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// Outcome of reconciling one statement: counts per kind and every exception.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReconciliationReport {
+    pub statement: MpesaStatement,
+    pub matched: i64,
+    pub missing_internally: i64,
+    pub missing_in_statement: i64,
+    pub mismatched: i64,
+    pub exceptions: Vec<ReconciliationItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
 pub struct Savings {
     pub id: Uuid,
     pub user_id: Uuid,
-    pub amount: f64,
+    pub amount: Money,
     pub goal_name: Option<String>,
+    pub target_amount: Option<Money>,
+    pub target_date: Option<NaiveDate>,
+    pub locked_until: Option<NaiveDate>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl Savings {
+    /// How far the goal is towards its target, in whole percent capped at 100.
+    /// `None` for goals without a target.
+    pub fn progress_percent(&self) -> Option<u8> {
+        let target = self.target_amount.filter(|target| target.is_positive())?;
+        let percent = (self.amount.minor_units().max(0) as i128 * 100) / target.minor_units() as i128;
+        Some(percent.min(100) as u8)
+    }
+
+    /// Locked goals refuse withdrawals until their lock date.
+    pub fn is_locked(&self, today: NaiveDate) -> bool {
+        self.locked_until.is_some_and(|until| today < until)
+    }
+}
+
+/// A savings goal as members see it: its balance, what can be withdrawn now and
+/// how close it is to its target.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SavingsGoal {
+    #[serde(flatten)]
+    pub savings: Savings,
+    /// The balance minus withdrawals still on their way.
+    pub available: Money,
+    pub progress_percent: Option<u8>,
+    pub locked: bool,
+}
+
+/// One B2C payout of savings back to their owner.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct SavingsWithdrawal {
+    pub id: Uuid,
+    pub savings_id: Uuid,
+    pub user_id: Uuid,
+    pub amount: Money,
+    pub phone_number: String,
+    pub status: PaymentStatus,
+    #[serde(skip)]
+    pub callback_token: String,
+    pub conversation_id: Option<String>,
+    pub transaction_id: Option<String>,
+    pub result_code: Option<String>,
+    pub result_desc: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub settled_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
 pub struct PlatformTransaction {
     pub id: Uuid,
+    /// Position in the hash chain; None until the entry is sealed.
+    pub sequence_number: Option<i64>,
     pub activity_type: String,
     pub description: String,
-    pub amount: f64,
-    pub signature: String,
+    pub amount: Money,
+    pub prev_hash: Option<String>,
+    pub entry_hash: Option<String>,
+    /// Ed25519 signature of `entry_hash` by the server's ledger key.
+    pub signature: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct AccountBalance {
+    pub id: Uuid,
+    pub code: String,
+    pub name: String,
+    pub account_type: String,
+    pub owner_id: Option<Uuid>,
+    pub debits: Money,
+    pub credits: Money,
+    pub balance: Money,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrialBalance {
+    pub accounts: Vec<AccountBalance>,
+    pub total_debits: Money,
+    pub total_credits: Money,
+    pub balanced: bool,
+}
+
+/// Why a session was ended before it expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionRevocation {
+    /// The member logged out of it.
+    Logout,
+    /// The member ended it from another session.
+    Revoked,
+    /// A refresh token of the session was used twice.
+    RefreshTokenReuse,
+    /// The member's password was changed or reset.
+    PasswordChange,
+    /// Staff froze the account.
+    AccountFrozen,
+    /// Staff took away one of the member's roles, which its access tokens still carried.
+    RoleRevoked,
+}
+
+impl SessionRevocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionRevocation::Logout => "logout",
+            SessionRevocation::Revoked => "revoked",
+            SessionRevocation::RefreshTokenReuse => "refresh_token_reuse",
+            SessionRevocation::PasswordChange => "password_change",
+            SessionRevocation::AccountFrozen => "account_frozen",
+            SessionRevocation::RoleRevoked => "role_revoked",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "logout" => Some(SessionRevocation::Logout),
+            "revoked" => Some(SessionRevocation::Revoked),
+            "refresh_token_reuse" => Some(SessionRevocation::RefreshTokenReuse),
+            "password_change" => Some(SessionRevocation::PasswordChange),
+            "account_frozen" => Some(SessionRevocation::AccountFrozen),
+            "role_revoked" => Some(SessionRevocation::RoleRevoked),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(SessionRevocation);
+
+/// A signed-in device, kept alive by its refresh token.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub revoked_reason: Option<SessionRevocation>,
+}
+
+impl Session {
+    /// Whether the session may still be refreshed.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && now < self.expires_at
+    }
+}
+
+/// A session as listed to its member.
+#[derive(Debug, Serialize)]
+pub struct SessionSummary {
+    #[serde(flatten)]
+    pub session: Session,
+    /// Whether this is the session the request was made from.
+    pub current: bool,
+}
+
+/// What a one-time SMS code is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtpPurpose {
+    /// Proving a new member owns the number they sign up with.
+    Signup,
+    /// Logging in without a password.
+    Login,
+}
+
+impl OtpPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OtpPurpose::Signup => "signup",
+            OtpPurpose::Login => "login",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "signup" => Some(OtpPurpose::Signup),
+            "login" => Some(OtpPurpose::Login),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(OtpPurpose);
+
+/// What a staff member did, as recorded in `admin_audit_log`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    SearchMembers,
+    ViewMember,
+    FreezeAccount,
+    UnfreezeAccount,
+    GrantRole,
+    RevokeRole,
+    ApproveLoan,
+    RejectLoan,
+    ReverseTransaction,
+    RecordRepayment,
+    AdjustScore,
+    RedeliverEvent,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::SearchMembers => "search_members",
+            AuditAction::ViewMember => "view_member",
+            AuditAction::FreezeAccount => "freeze_account",
+            AuditAction::UnfreezeAccount => "unfreeze_account",
+            AuditAction::GrantRole => "grant_role",
+            AuditAction::RevokeRole => "revoke_role",
+            AuditAction::ApproveLoan => "approve_loan",
+            AuditAction::RejectLoan => "reject_loan",
+            AuditAction::ReverseTransaction => "reverse_transaction",
+            AuditAction::RecordRepayment => "record_repayment",
+            AuditAction::AdjustScore => "adjust_score",
+            AuditAction::RedeliverEvent => "redeliver_event",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "search_members" => Some(AuditAction::SearchMembers),
+            "view_member" => Some(AuditAction::ViewMember),
+            "freeze_account" => Some(AuditAction::FreezeAccount),
+            "unfreeze_account" => Some(AuditAction::UnfreezeAccount),
+            "grant_role" => Some(AuditAction::GrantRole),
+            "revoke_role" => Some(AuditAction::RevokeRole),
+            "approve_loan" => Some(AuditAction::ApproveLoan),
+            "reject_loan" => Some(AuditAction::RejectLoan),
+            "reverse_transaction" => Some(AuditAction::ReverseTransaction),
+            "record_repayment" => Some(AuditAction::RecordRepayment),
+            "adjust_score" => Some(AuditAction::AdjustScore),
+            "redeliver_event" => Some(AuditAction::RedeliverEvent),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(AuditAction);
+
+/// Where a side effect in `outbox_events` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboxStatus {
+    /// Waiting for its first or next delivery attempt.
+    Pending,
+    Delivered,
+    /// Every attempt failed; stays put until staff send it again.
+    Dead,
+}
+
+impl OutboxStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutboxStatus::Pending => "pending",
+            OutboxStatus::Delivered => "delivered",
+            OutboxStatus::Dead => "dead",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(OutboxStatus::Pending),
+            "delivered" => Some(OutboxStatus::Delivered),
+            "dead" => Some(OutboxStatus::Dead),
+            _ => None,
+        }
+    }
+}
+
+varchar_enum!(OutboxStatus);
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub actor_id: Uuid,
+    pub action: AuditAction,
+    /// The kind of record acted on: `user`, `loan`, `savings_transaction`.
+    pub target_type: String,
+    pub target_id: Option<Uuid>,
+    pub reason: Option<String>,
+    pub details: sqlx::types::Json<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A member as staff see them: contact details and account state, no credentials.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct MemberSummary {
+    pub id: Uuid,
+    pub username: String,
+    pub email: Option<String>,
+    pub phone_number: Option<String>,
+    pub phone_verified_at: Option<DateTime<Utc>>,
+    pub reputation_score: i32,
+    pub roles: Vec<Role>,
+    pub two_factor_enabled: bool,
+    pub frozen_at: Option<DateTime<Utc>>,
+    pub frozen_reason: Option<String>,
+    pub locked_until: Option<DateTime<Utc>>,
     pub created_at: Option<DateTime<Utc>>,
 }
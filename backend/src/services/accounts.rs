@@ -0,0 +1,215 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::{MemberSummary, Role, SessionRevocation};
+use crate::services::sessions::SessionService;
+
+/// Most members returned by one search.
+pub const MAX_SEARCH_RESULTS: i64 = 100;
+
+const MEMBER_COLUMNS: &str = "u.id, u.username, u.email, u.phone_number, u.phone_verified_at, u.reputation_score,
+    ARRAY['member'] || ARRAY(SELECT r.role::text FROM user_roles r WHERE r.user_id = u.id ORDER BY r.role) AS roles,
+    u.totp_enabled_at IS NOT NULL AS two_factor_enabled,
+    u.frozen_at, u.frozen_reason, u.locked_until, u.created_at";
+
+/// Filters of a member search; all optional.
+#[derive(Debug, Default, Deserialize)]
+pub struct MemberQuery {
+    /// Part of a username, email or phone number.
+    pub q: Option<String>,
+    pub role: Option<Role>,
+    pub frozen: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Staff roles and frozen accounts.
+pub struct AccountService;
+
+impl AccountService {
+    /// The roles to put in a new access token: `Member` and any granted staff roles.
+    /// `AccountFrozen` if the account is frozen.
+    pub async fn session_roles(conn: &mut PgConnection, user_id: Uuid) -> Result<Vec<Role>, AppError> {
+        Self::ensure_active(conn, user_id).await?;
+        Self::roles_of(conn, user_id).await.map_err(|e| {
+            tracing::error!("Failed to load roles of user {}: {:?}", user_id, e);
+            AppError::InternalServerError
+        })
+    }
+
+    pub async fn roles_of(conn: &mut PgConnection, user_id: Uuid) -> Result<Vec<Role>, sqlx::Error> {
+        let granted: Vec<(Role,)> = sqlx::query_as("SELECT role FROM user_roles WHERE user_id = $1 ORDER BY role")
+            .bind(user_id)
+            .fetch_all(conn)
+            .await?;
+        Ok(std::iter::once(Role::Member).chain(granted.into_iter().map(|(role,)| role)).collect())
+    }
+
+    /// The number to collect an M-Pesa payment from: the one the member gave, or else their
+    /// verified number.
+    pub async fn payment_phone(conn: &mut PgConnection, user_id: Uuid, requested: Option<&str>) -> Result<String, AppError> {
+        if let Some(phone) = requested.map(str::trim).filter(|p| !p.is_empty()) {
+            return Ok(phone.to_string());
+        }
+        let verified: Option<(String,)> = sqlx::query_as(
+            "SELECT phone_number FROM users WHERE id = $1 AND phone_verified_at IS NOT NULL"
+        )
+        .bind(user_id)
+        .fetch_optional(conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        verified
+            .map(|(phone,)| phone)
+            .ok_or_else(|| AppError::BadRequest("A phone number is needed to pay with M-Pesa".to_string()))
+    }
+
+    /// `AccountFrozen` if staff froze the account. Checked again by requests that move money,
+    /// in case the account was frozen after the request was authenticated.
+    pub async fn ensure_active(conn: &mut PgConnection, user_id: Uuid) -> Result<(), AppError> {
+        let frozen: Option<(Option<DateTime<Utc>>,)> = sqlx::query_as("SELECT frozen_at FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        match frozen {
+            Some((Some(_),)) => Err(AppError::AccountFrozen),
+            Some((None,)) => Ok(()),
+            None => Err(AppError::NotFound),
+        }
+    }
+
+    pub async fn search(pool: &PgPool, query: &MemberQuery) -> Result<Vec<MemberSummary>, sqlx::Error> {
+        let pattern = query
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(|q| format!("%{}%", q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")));
+
+        sqlx::query_as(&format!(
+            "SELECT {} FROM users u
+             WHERE ($1::text IS NULL OR u.username ILIKE $1 OR u.email ILIKE $1 OR u.phone_number LIKE $1)
+               AND ($2::text IS NULL OR EXISTS (SELECT 1 FROM user_roles r WHERE r.user_id = u.id AND r.role = $2))
+               AND ($3::boolean IS NULL OR (u.frozen_at IS NOT NULL) = $3)
+             ORDER BY u.created_at DESC, u.id
+             LIMIT $4 OFFSET $5",
+            MEMBER_COLUMNS
+        ))
+        .bind(pattern)
+        .bind(query.role.filter(|role| *role != Role::Member))
+        .bind(query.frozen)
+        .bind(query.limit.unwrap_or(50).clamp(1, MAX_SEARCH_RESULTS))
+        .bind(query.offset.unwrap_or(0).max(0))
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn find(conn: &mut PgConnection, user_id: Uuid) -> Result<Option<MemberSummary>, sqlx::Error> {
+        sqlx::query_as(&format!("SELECT {} FROM users u WHERE u.id = $1", MEMBER_COLUMNS))
+            .bind(user_id)
+            .fetch_optional(conn)
+            .await
+    }
+
+    /// Freezes the account and ends all of its sessions. Returns how many sessions ended.
+    pub async fn freeze(conn: &mut PgConnection, user_id: Uuid, reason: &str, now: DateTime<Utc>) -> Result<u64, AppError> {
+        let frozen = sqlx::query("UPDATE users SET frozen_at = $2, frozen_reason = $3 WHERE id = $1 AND frozen_at IS NULL")
+            .bind(user_id)
+            .bind(now)
+            .bind(reason)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?
+            .rows_affected();
+        if frozen == 0 {
+            Self::ensure_exists(conn, user_id).await?;
+            return Err(AppError::Conflict("The account is already frozen".to_string()));
+        }
+
+        SessionService::revoke_all(conn, user_id, None, SessionRevocation::AccountFrozen, now)
+            .await
+            .map_err(|_| AppError::InternalServerError)
+    }
+
+    pub async fn unfreeze(conn: &mut PgConnection, user_id: Uuid) -> Result<(), AppError> {
+        let unfrozen = sqlx::query("UPDATE users SET frozen_at = NULL, frozen_reason = NULL WHERE id = $1 AND frozen_at IS NOT NULL")
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?
+            .rows_affected();
+        if unfrozen == 0 {
+            Self::ensure_exists(conn, user_id).await?;
+            return Err(AppError::Conflict("The account is not frozen".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Grants a staff role. Takes effect when the member's access token is next refreshed.
+    pub async fn grant_role(conn: &mut PgConnection, user_id: Uuid, role: Role, granted_by: Uuid) -> Result<(), AppError> {
+        if !Role::STAFF.contains(&role) {
+            return Err(AppError::BadRequest("Only staff roles can be granted".to_string()));
+        }
+        let granted = sqlx::query(
+            "INSERT INTO user_roles (user_id, role, granted_by) SELECT id, $2, $3 FROM users WHERE id = $1
+             ON CONFLICT (user_id, role) DO NOTHING"
+        )
+        .bind(user_id)
+        .bind(role)
+        .bind(granted_by)
+        .execute(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+        .rows_affected();
+        if granted == 0 {
+            Self::ensure_exists(conn, user_id).await?;
+            return Err(AppError::Conflict(format!("The member already has the {} role", role.as_str())));
+        }
+        Ok(())
+    }
+
+    /// Takes a staff role away and ends the member's sessions, whose access tokens still carry
+    /// the role. Admins cannot take their own admin role, so the platform always keeps one.
+    /// Returns how many sessions ended.
+    pub async fn revoke_role(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        role: Role,
+        revoked_by: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<u64, AppError> {
+        if role == Role::Admin && user_id == revoked_by {
+            return Err(AppError::BadRequest("You cannot remove your own admin role".to_string()));
+        }
+        let revoked = sqlx::query("DELETE FROM user_roles WHERE user_id = $1 AND role = $2")
+            .bind(user_id)
+            .bind(role)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?
+            .rows_affected();
+        if revoked == 0 {
+            Self::ensure_exists(conn, user_id).await?;
+            return Err(AppError::Conflict(format!("The member does not have the {} role", role.as_str())));
+        }
+
+        SessionService::revoke_all(conn, user_id, None, SessionRevocation::RoleRevoked, now)
+            .await
+            .map_err(|_| AppError::InternalServerError)
+    }
+
+    async fn ensure_exists(conn: &mut PgConnection, user_id: Uuid) -> Result<(), AppError> {
+        let (exists,): (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1)")
+            .bind(user_id)
+            .fetch_one(conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        if exists {
+            Ok(())
+        } else {
+            Err(AppError::NotFound)
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use crate::services::checkpoints::LedgerCheckpoint;
+use crate::services::solana::SolanaClient;
+
+/// Publishes ledger checkpoint roots somewhere the platform cannot quietly rewrite, so a
+/// changed history no longer matches what was published.
+#[async_trait]
+pub trait Anchor: Send + Sync {
+    /// Stored with each checkpoint it publishes, e.g. `file`.
+    fn name(&self) -> &'static str;
+
+    /// Publishes the checkpoint. Returns where it can be looked up, such as a transaction
+    /// signature.
+    async fn publish(&self, checkpoint: &LedgerCheckpoint) -> Result<String, String>;
+}
+
+/// Development stand-in that appends checkpoints to a file as JSON, one per line.
+pub struct FileAnchor {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl Anchor for FileAnchor {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn publish(&self, checkpoint: &LedgerCheckpoint) -> Result<String, String> {
+        let line = serde_json::json!({
+            "checkpoint_id": checkpoint.id,
+            "first_sequence_number": checkpoint.first_sequence_number,
+            "last_sequence_number": checkpoint.last_sequence_number,
+            "merkle_root": checkpoint.merkle_root,
+            "signature": checkpoint.signature,
+        });
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", self.path.display(), e))?;
+        let write_error = |e: std::io::Error| format!("Failed to write {}: {}", self.path.display(), e);
+        file.write_all(format!("{}\n", line).as_bytes()).await.map_err(write_error)?;
+        file.flush().await.map_err(write_error)?;
+        Ok(format!("{}#{}", self.path.display(), checkpoint.last_sequence_number))
+    }
+}
+
+/// Publishes roots with the microfund program's `anchor_checkpoint`, one account per checkpoint.
+pub struct SolanaAnchor {
+    pub client: SolanaClient,
+}
+
+#[async_trait]
+impl Anchor for SolanaAnchor {
+    fn name(&self) -> &'static str {
+        "solana"
+    }
+
+    async fn publish(&self, checkpoint: &LedgerCheckpoint) -> Result<String, String> {
+        let merkle_root: [u8; 32] = hex::decode(&checkpoint.merkle_root)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| format!("Malformed root of checkpoint {}", checkpoint.id))?;
+        self.client
+            .anchor_checkpoint(checkpoint.first_sequence_number as u64, checkpoint.last_sequence_number as u64, merkle_root)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// `ANCHOR_BACKEND` is `none` (the default: checkpoints are signed but not published),
+/// `file`, which appends to `ANCHOR_FILE` (`ledger_anchors.log` by default), or `solana`,
+/// which needs `SOLANA_MODE` set.
+pub fn anchor_from_env(solana: Option<&SolanaClient>) -> Result<Option<Arc<dyn Anchor>>, String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    match var("ANCHOR_BACKEND").as_deref().map(str::trim) {
+        None | Some("none") => Ok(None),
+        Some("file") => Ok(Some(Arc::new(FileAnchor {
+            path: var("ANCHOR_FILE").unwrap_or_else(|| "ledger_anchors.log".to_string()).into(),
+        }))),
+        Some("solana") => match solana {
+            Some(client) => Ok(Some(Arc::new(SolanaAnchor { client: client.clone() }))),
+            None => Err("ANCHOR_BACKEND=solana needs SOLANA_MODE to be set".to_string()),
+        },
+        Some(other) => Err(format!("Unknown ANCHOR_BACKEND '{}'", other)),
+    }
+}
@@ -0,0 +1,68 @@
+use serde::Deserialize;
+use sqlx::types::Json;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::models::{AuditAction, AuditEntry};
+
+/// Most entries returned by one query of the log.
+pub const MAX_AUDIT_ENTRIES: i64 = 500;
+
+/// Filters of an audit log query; all optional.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub action: Option<AuditAction>,
+    pub limit: Option<i64>,
+}
+
+/// The append-only record of what staff did. Entries of changes are written on the
+/// transaction of the change, so there is never one without the other.
+pub struct AuditLog;
+
+impl AuditLog {
+    pub async fn record(
+        conn: &mut PgConnection,
+        actor_id: Uuid,
+        action: AuditAction,
+        target_type: &str,
+        target_id: Option<Uuid>,
+        reason: Option<&str>,
+        details: serde_json::Value,
+    ) -> Result<Uuid, sqlx::Error> {
+        let (id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO admin_audit_log (actor_id, action, target_type, target_id, reason, details)
+             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id"
+        )
+        .bind(actor_id)
+        .bind(action)
+        .bind(target_type)
+        .bind(target_id)
+        .bind(reason)
+        .bind(Json(details))
+        .fetch_one(conn)
+        .await?;
+
+        tracing::info!("[AUDIT] User {} did {} on {} {:?}", actor_id, action.as_str(), target_type, target_id);
+        Ok(id)
+    }
+
+    /// Newest first.
+    pub async fn entries(pool: &PgPool, query: &AuditQuery) -> Result<Vec<AuditEntry>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT id, actor_id, action, target_type, target_id, reason, details, created_at
+             FROM admin_audit_log
+             WHERE ($1::uuid IS NULL OR actor_id = $1)
+               AND ($2::uuid IS NULL OR target_id = $2)
+               AND ($3::text IS NULL OR action = $3)
+             ORDER BY created_at DESC, id
+             LIMIT $4"
+        )
+        .bind(query.actor_id)
+        .bind(query.target_id)
+        .bind(query.action)
+        .bind(query.limit.unwrap_or(100).clamp(1, MAX_AUDIT_ENTRIES))
+        .fetch_all(pool)
+        .await
+    }
+}
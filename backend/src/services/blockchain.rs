@@ -1,29 +1,133 @@
+use chrono::{DateTime, Duration, Utc};
 use uuid::Uuid;
-use sqlx::PgPool;
+use sqlx::{PgExecutor, PgPool};
+use crate::models::{LoanStatus, Money};
+use crate::services::solana::{SolanaClient, SolanaError};
+
+/// Loans recorded on-chain per run of the sync job.
+const SYNC_BATCH: i64 = 50;
+const RETRY_BASE_SECONDS: i64 = 30;
+const MAX_RETRY_DELAY_MINUTES: i64 = 60;
+
+/// A loan the sync job still has to record on-chain or mark repaid there.
+#[derive(sqlx::FromRow)]
+struct UnsyncedLoan {
+    id: Uuid,
+    amount: Money,
+    description: Option<String>,
+    status: LoanStatus,
+    chain_loan_signature: Option<String>,
+    chain_attempts: i32,
+}
 
 pub struct BlockchainService;
 
 impl BlockchainService {
     /// Records a transaction to the persistent platform ledger (Live Data).
-    pub async fn log_to_ledger(
-        pool: &PgPool,
+    /// Pass the handler's transaction so the log entry commits together with the change it describes.
+    /// The entry is chained and signed shortly after by the seal job; see `LedgerChain`.
+    pub async fn log_to_ledger<'e, E: PgExecutor<'e>>(
+        executor: E,
         activity_type: &str,
         description: &str,
-        amount: f64,
-    ) -> Result<String, String> {
-        let signature = format!("5tZ...{}", Uuid::new_v4().to_string().chars().take(8).collect::<String>());
-        
-        let _ = sqlx::query(
-            "INSERT INTO platform_transactions (activity_type, description, amount, signature) VALUES ($1, $2, $3, $4)"
+        amount: Money,
+    ) -> Result<Uuid, String> {
+        let (id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO platform_transactions (activity_type, description, amount) VALUES ($1, $2, $3) RETURNING id"
         )
         .bind(activity_type)
         .bind(description)
-        .bind(amount as f32)
-        .bind(&signature)
-        .execute(pool)
-        .await;
+        .bind(amount)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        tracing::info!("[LIVE DATA] Action logged: {} as entry {}", activity_type, id);
+        Ok(id)
+    }
+
+    /// Wait before the next try after `attempt` failed ones: 30 seconds, doubling up to an hour.
+    pub fn retry_delay(attempt: i32) -> Duration {
+        let delay = Duration::seconds(RETRY_BASE_SECONDS << (attempt - 1).clamp(0, 16));
+        delay.min(Duration::minutes(MAX_RETRY_DELAY_MINUTES))
+    }
+
+    /// Records paid-out loans with the microfund program's `initialize_loan` and marks repaid
+    /// ones with `repay_loan`, saving the account address and signatures on the loan. A loan
+    /// that fails is retried with backoff; the program's accounts are keyed by loan id, so a
+    /// retry after a lost confirmation finds the loan already recorded instead of recording it
+    /// twice. Returns the loans brought up to date.
+    pub async fn sync_loans(pool: &PgPool, solana: &SolanaClient, now: DateTime<Utc>) -> Result<u64, String> {
+        let due: Vec<UnsyncedLoan> = sqlx::query_as(
+            "SELECT id, amount, description, status, chain_loan_signature, chain_attempts FROM loans
+             WHERE status IN ('disbursed', 'in_repayment', 'overdue', 'repaid', 'defaulted', 'written_off')
+               AND (chain_loan_signature IS NULL OR (status = 'repaid' AND chain_repay_signature IS NULL))
+               AND (chain_next_attempt_at IS NULL OR chain_next_attempt_at <= $1)
+             ORDER BY created_at LIMIT $2"
+        )
+        .bind(now)
+        .bind(SYNC_BATCH)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut synced = 0;
+        for loan in due {
+            match Self::sync_loan(pool, solana, &loan).await {
+                Ok(()) => synced += 1,
+                Err(e) => {
+                    let retry_at = now + Self::retry_delay(loan.chain_attempts + 1);
+                    tracing::warn!("[BLOCKCHAIN] Could not record loan {} on-chain ({}); retrying at {}", loan.id, e, retry_at);
+                    sqlx::query(
+                        "UPDATE loans SET chain_attempts = chain_attempts + 1, chain_next_attempt_at = $2, chain_last_error = $3
+                         WHERE id = $1"
+                    )
+                    .bind(loan.id)
+                    .bind(retry_at)
+                    .bind(e.to_string())
+                    .execute(pool)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(synced)
+    }
+
+    async fn sync_loan(pool: &PgPool, solana: &SolanaClient, loan: &UnsyncedLoan) -> Result<(), SolanaError> {
+        let db = |e: sqlx::Error| SolanaError::InvalidRequest(format!("Failed to save the on-chain record: {}", e));
+        let loan_id = loan.id;
+
+        if loan.chain_loan_signature.is_none() {
+            let description = loan.description.clone().unwrap_or_else(|| format!("Loan {}", loan_id));
+            let record = solana.initialize_loan(loan_id, loan.amount, &description).await?;
+            sqlx::query(
+                "UPDATE loans SET chain_account = $2, chain_loan_signature = $3, chain_attempts = 0,
+                     chain_next_attempt_at = NULL, chain_last_error = NULL
+                 WHERE id = $1"
+            )
+            .bind(loan_id)
+            .bind(record.address.to_string())
+            .bind(&record.signature)
+            .execute(pool)
+            .await
+            .map_err(db)?;
+            tracing::info!("[BLOCKCHAIN] Loan {} recorded at {}: {}", loan_id, record.address, record.signature);
+        }
 
-        tracing::info!("[LIVE DATA] Action logged: {} with signature {}", activity_type, signature);
-        Ok(signature)
+        if loan.status == LoanStatus::Repaid {
+            let signature = solana.repay_loan(loan_id).await?;
+            sqlx::query(
+                "UPDATE loans SET chain_repay_signature = $2, chain_attempts = 0, chain_next_attempt_at = NULL, chain_last_error = NULL
+                 WHERE id = $1"
+            )
+            .bind(loan_id)
+            .bind(&signature)
+            .execute(pool)
+            .await
+            .map_err(db)?;
+            tracing::info!("[BLOCKCHAIN] Repayment of loan {} recorded: {}", loan_id, signature);
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,253 @@
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::services::anchor::Anchor;
+use crate::services::ledger_chain::{verify_signature, ChainEntry, LedgerSigner};
+use crate::services::merkle::{self, Hash};
+
+/// Most entries under one checkpoint root.
+pub const MAX_CHECKPOINT_ENTRIES: i64 = 1024;
+
+/// Signed Merkle root over a run of consecutive sealed ledger entries.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LedgerCheckpoint {
+    pub id: Uuid,
+    pub first_sequence_number: i64,
+    pub last_sequence_number: i64,
+    pub merkle_root: String,
+    /// Ed25519 signature of `signed_message()` by the server's ledger key.
+    pub signature: String,
+    pub created_at: DateTime<Utc>,
+    pub anchor_backend: Option<String>,
+    pub anchor_reference: Option<String>,
+    pub anchored_at: Option<DateTime<Utc>>,
+}
+
+impl LedgerCheckpoint {
+    /// What the server signs: the range and its root, so a root cannot be moved to other entries.
+    pub fn signed_message(first: i64, last: i64, merkle_root: &str) -> String {
+        format!("microfund-checkpoint-v1:{}:{}:{}", first, last, merkle_root)
+    }
+
+    pub fn size(&self) -> u64 {
+        (self.last_sequence_number - self.first_sequence_number + 1) as u64
+    }
+}
+
+/// Everything needed to check one ledger entry without the rest of the ledger: the entry, the
+/// Merkle path from its leaf to a signed, possibly anchored, checkpoint root.
+#[derive(Debug, Serialize)]
+pub struct InclusionProof {
+    pub entry: ChainEntry,
+    /// Position of the entry's leaf under the checkpoint root, from 0.
+    pub leaf_index: u64,
+    /// RFC 6962 leaf hash of the entry's `entry_hash` bytes.
+    pub leaf_hash: String,
+    /// Sibling hashes from the leaf up to the root, lowest first.
+    pub path: Vec<String>,
+    pub checkpoint: LedgerCheckpoint,
+    pub public_key: String,
+}
+
+impl InclusionProof {
+    /// Checks the entry's own hash and signature, its path to the root and the signature of
+    /// the checkpoint. The anchor, if any, is for the reader to compare against `merkle_root`.
+    pub fn verify(&self, key: &VerifyingKey) -> bool {
+        let (Some(entry_hash), Some(root)) = (parse_hash(&self.entry.entry_hash), parse_hash(&self.checkpoint.merkle_root)) else {
+            return false;
+        };
+        let Some(path) = self.path.iter().map(|h| parse_hash(h)).collect::<Option<Vec<Hash>>>() else {
+            return false;
+        };
+        let leaf = merkle::leaf_hash(&entry_hash);
+        let checkpoint = &self.checkpoint;
+
+        self.entry.compute_hash() == self.entry.entry_hash
+            && self.entry.has_valid_signature(key)
+            && hex::encode(leaf) == self.leaf_hash
+            && self.entry.sequence_number - checkpoint.first_sequence_number == self.leaf_index as i64
+            && merkle::verify_inclusion(&leaf, self.leaf_index, checkpoint.size(), &path, &root)
+            && verify_signature(
+                key,
+                LedgerCheckpoint::signed_message(checkpoint.first_sequence_number, checkpoint.last_sequence_number, &checkpoint.merkle_root).as_bytes(),
+                &checkpoint.signature,
+            )
+    }
+}
+
+fn parse_hash(hex_hash: &str) -> Option<Hash> {
+    hex::decode(hex_hash).ok()?.try_into().ok()
+}
+
+const CHECKPOINT_COLUMNS: &str = "id, first_sequence_number, last_sequence_number, merkle_root, signature, created_at,
+    anchor_backend, anchor_reference, anchored_at";
+
+/// Periodic Merkle checkpoints of the sealed platform ledger, published through an `Anchor`.
+/// Checkpoints cover consecutive entries with no gaps, so every sealed entry ends up under
+/// exactly one root.
+pub struct CheckpointService;
+
+impl CheckpointService {
+    /// Checkpoints every sealed entry not under a root yet, then publishes the checkpoints not
+    /// anchored yet. A failed publish is retried on the next run. Returns checkpoints created.
+    pub async fn run(pool: &PgPool, signer: &LedgerSigner, anchor: Option<&dyn Anchor>, now: DateTime<Utc>) -> Result<u64, String> {
+        let mut created = 0;
+        while Self::create_next(pool, signer, now).await? {
+            created += 1;
+        }
+        if let Some(anchor) = anchor {
+            Self::anchor_pending(pool, anchor, now).await.map_err(|e| e.to_string())?;
+        }
+        Ok(created)
+    }
+
+    /// Signs a root over the next run of sealed entries. False when none are left.
+    async fn create_next(pool: &PgPool, signer: &LedgerSigner, now: DateTime<Utc>) -> Result<bool, String> {
+        let db = |e: sqlx::Error| e.to_string();
+        let mut tx = pool.begin().await.map_err(db)?;
+        // Two instances must not checkpoint the same entries; the unique range columns would
+        // reject the second, but waiting is cheaper than failing the job
+        sqlx::query("LOCK TABLE ledger_checkpoints IN SHARE ROW EXCLUSIVE MODE").execute(&mut *tx).await.map_err(db)?;
+
+        let (covered,): (i64,) = sqlx::query_as("SELECT COALESCE(MAX(last_sequence_number), 0) FROM ledger_checkpoints")
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db)?;
+        let hashes: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT sequence_number, entry_hash FROM platform_transactions
+             WHERE sequence_number > $1 ORDER BY sequence_number LIMIT $2"
+        )
+        .bind(covered)
+        .bind(MAX_CHECKPOINT_ENTRIES)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(db)?;
+        let (Some((first, _)), Some((last, _))) = (hashes.first(), hashes.last()) else {
+            return Ok(false);
+        };
+        let (first, last) = (*first, *last);
+
+        let leaves: Vec<Hash> = hashes
+            .iter()
+            .map(|(_, entry_hash)| parse_hash(entry_hash).map(|hash| merkle::leaf_hash(&hash)))
+            .collect::<Option<_>>()
+            .ok_or_else(|| format!("Malformed entry hash between #{} and #{}", first, last))?;
+        let merkle_root = hex::encode(merkle::root(&leaves));
+        let signature = signer.sign(LedgerCheckpoint::signed_message(first, last, &merkle_root).as_bytes());
+
+        sqlx::query(
+            "INSERT INTO ledger_checkpoints (first_sequence_number, last_sequence_number, merkle_root, signature, created_at)
+             VALUES ($1, $2, $3, $4, $5)"
+        )
+        .bind(first)
+        .bind(last)
+        .bind(&merkle_root)
+        .bind(&signature)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .map_err(db)?;
+        tx.commit().await.map_err(db)?;
+
+        tracing::info!("[LEDGER] Checkpoint of entries #{}-#{} with root {}", first, last, merkle_root);
+        Ok(true)
+    }
+
+    async fn anchor_pending(pool: &PgPool, anchor: &dyn Anchor, now: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        let pending: Vec<LedgerCheckpoint> = sqlx::query_as(&format!(
+            "SELECT {} FROM ledger_checkpoints WHERE anchored_at IS NULL ORDER BY last_sequence_number",
+            CHECKPOINT_COLUMNS
+        ))
+        .fetch_all(pool)
+        .await?;
+
+        for checkpoint in pending {
+            match anchor.publish(&checkpoint).await {
+                Ok(reference) => {
+                    sqlx::query(
+                        "UPDATE ledger_checkpoints
+                         SET anchor_backend = $2, anchor_reference = $3, anchored_at = $4, anchor_attempts = anchor_attempts + 1,
+                             last_anchor_error = NULL
+                         WHERE id = $1"
+                    )
+                    .bind(checkpoint.id)
+                    .bind(anchor.name())
+                    .bind(&reference)
+                    .bind(now)
+                    .execute(pool)
+                    .await?;
+                    tracing::info!("[LEDGER] Checkpoint #{} anchored via {}: {}", checkpoint.last_sequence_number, anchor.name(), reference);
+                }
+                Err(e) => {
+                    tracing::warn!("[LEDGER] Could not anchor checkpoint #{} via {}: {}", checkpoint.last_sequence_number, anchor.name(), e);
+                    sqlx::query("UPDATE ledger_checkpoints SET anchor_attempts = anchor_attempts + 1, last_anchor_error = $2 WHERE id = $1")
+                        .bind(checkpoint.id)
+                        .bind(&e)
+                        .execute(pool)
+                        .await?;
+                    // Later checkpoints wait, so roots are published in order
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Inclusion proof of one entry. `Conflict` while the entry is not under a checkpoint yet.
+    pub async fn proof(pool: &PgPool, entry_id: Uuid, key: &VerifyingKey) -> Result<InclusionProof, AppError> {
+        let entry: Option<(Option<i64>,)> = sqlx::query_as("SELECT sequence_number FROM platform_transactions WHERE id = $1")
+            .bind(entry_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        let sequence_number = entry
+            .ok_or(AppError::NotFound)?
+            .0
+            .ok_or_else(|| AppError::Conflict("The entry is not sealed yet".to_string()))?;
+
+        let checkpoint: LedgerCheckpoint = sqlx::query_as(&format!(
+            "SELECT {} FROM ledger_checkpoints WHERE $1 BETWEEN first_sequence_number AND last_sequence_number",
+            CHECKPOINT_COLUMNS
+        ))
+        .bind(sequence_number)
+        .fetch_optional(pool)
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+        .ok_or_else(|| AppError::Conflict("The entry is not under a checkpoint yet".to_string()))?;
+
+        let entries: Vec<ChainEntry> = sqlx::query_as(
+            "SELECT id, sequence_number, activity_type, description, amount, created_at, prev_hash, entry_hash, signature
+             FROM platform_transactions
+             WHERE sequence_number BETWEEN $1 AND $2 ORDER BY sequence_number"
+        )
+        .bind(checkpoint.first_sequence_number)
+        .bind(checkpoint.last_sequence_number)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+
+        let leaves: Vec<Hash> = entries
+            .iter()
+            .filter_map(|entry| parse_hash(&entry.entry_hash).map(|hash| merkle::leaf_hash(&hash)))
+            .collect();
+        let leaf_index = (sequence_number - checkpoint.first_sequence_number) as usize;
+        let proof = InclusionProof {
+            entry: entries.get(leaf_index).cloned().ok_or(AppError::InternalServerError)?,
+            leaf_index: leaf_index as u64,
+            leaf_hash: leaves.get(leaf_index).map(hex::encode).ok_or(AppError::InternalServerError)?,
+            path: merkle::inclusion_proof(&leaves, leaf_index).iter().map(hex::encode).collect(),
+            checkpoint,
+            public_key: hex::encode(key.as_bytes()),
+        };
+
+        // Entries changed since the checkpoint no longer lead to its root
+        if !proof.verify(key) {
+            tracing::error!("Inclusion proof of ledger entry {} does not check out", entry_id);
+            return Err(AppError::Conflict("The entry no longer matches its checkpoint; the ledger may have been altered".to_string()));
+        }
+        Ok(proof)
+    }
+}
@@ -0,0 +1,38 @@
+use chrono::{DateTime, Utc};
+
+/// Source of the current time. Background jobs read the time through a clock
+/// so tests can run them at any moment they like.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(test)]
+pub struct FixedClock(std::sync::Mutex<DateTime<Utc>>);
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        FixedClock(std::sync::Mutex::new(now))
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        let mut now = self.0.lock().unwrap();
+        *now += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
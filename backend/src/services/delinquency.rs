@@ -0,0 +1,228 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+use microfund_shared::Rounding;
+use crate::models::{LoanStatus, Money};
+use crate::services::ledger::LedgerService;
+use crate::services::loan_lifecycle::LoanLifecycle;
+use crate::services::scoring::{ScoringService, ScoringWeights};
+
+/// Rules for loans that fall behind. Read once at startup from the environment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelinquencyConfig {
+    /// Days the oldest unpaid installment may be overdue before the loan defaults.
+    pub grace_period_days: i64,
+    /// One-off late penalty on an overdue installment, in basis points of its unpaid principal and interest.
+    pub late_penalty_bps: i64,
+}
+
+impl Default for DelinquencyConfig {
+    fn default() -> Self {
+        DelinquencyConfig {
+            grace_period_days: 30,
+            late_penalty_bps: 500,
+        }
+    }
+}
+
+impl DelinquencyConfig {
+    pub fn from_env() -> Result<Self, String> {
+        fn read<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+            match std::env::var(name) {
+                Ok(value) => value.trim().parse().map_err(|_| format!("{} must be a whole number", name)),
+                Err(_) => Ok(default),
+            }
+        }
+
+        let defaults = Self::default();
+        let config = DelinquencyConfig {
+            grace_period_days: read("LOAN_GRACE_PERIOD_DAYS", defaults.grace_period_days)?,
+            late_penalty_bps: read("LATE_PENALTY_BPS", defaults.late_penalty_bps)?,
+        };
+
+        if config.grace_period_days < 0 || config.late_penalty_bps < 0 {
+            return Err("Delinquency settings cannot be negative".to_string());
+        }
+        Ok(config)
+    }
+
+    /// Installments due before this moment are past the grace period.
+    pub fn default_cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - Duration::days(self.grace_period_days)
+    }
+
+    /// Late penalty on what is still unpaid of an installment's principal and interest.
+    pub fn late_penalty(&self, unpaid: Money) -> Result<Money, String> {
+        unpaid.percent_bps(self.late_penalty_bps, Rounding::HalfEven).map_err(|e| e.to_string())
+    }
+}
+
+/// Jobs that keep loans honest about being late. Each run only acts on what has not been
+/// handled yet, so running a job twice, or after a crash halfway through, is harmless.
+pub struct DelinquencyService;
+
+impl DelinquencyService {
+    /// Flags installments that are past due, moves their loans to `overdue` and rescores the borrower.
+    pub async fn mark_overdue(pool: &PgPool, now: DateTime<Utc>, weights: &ScoringWeights) -> Result<u64, String> {
+        let flagged = sqlx::query(
+            "UPDATE loan_installments SET overdue_at = $1
+             WHERE paid_at IS NULL AND overdue_at IS NULL AND due_date < $1"
+        )
+        .bind(now)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?
+        .rows_affected();
+
+        let loans: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT DISTINCT l.id FROM loans l
+             JOIN loan_installments i ON i.loan_id = l.id
+             WHERE l.status IN ($1, $2) AND i.paid_at IS NULL AND i.due_date < $3"
+        )
+        .bind(LoanStatus::Disbursed)
+        .bind(LoanStatus::InRepayment)
+        .bind(now)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut moved = 0;
+        for (loan_id,) in loans {
+            let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+            // A repayment may have landed since the scan; decide again under the row lock.
+            let loan = LoanLifecycle::load_for_update(&mut tx, loan_id).await.map_err(|e| e.to_string())?;
+            let (past_due,): (bool,) = sqlx::query_as(
+                "SELECT EXISTS (SELECT 1 FROM loan_installments WHERE loan_id = $1 AND paid_at IS NULL AND due_date < $2)"
+            )
+            .bind(loan_id)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+            if !past_due || !loan.status.can_transition_to(LoanStatus::Overdue) {
+                continue;
+            }
+
+            LoanLifecycle::transition(&mut tx, loan_id, LoanStatus::Overdue, None, Some("Installment past due"))
+                .await
+                .map_err(|e| e.to_string())?;
+            ScoringService::rescore(&mut tx, loan.user_id, weights, now, "loan_overdue").await?;
+
+            tx.commit().await.map_err(|e| e.to_string())?;
+            moved += 1;
+        }
+
+        tracing::info!("[SCHEDULER] {} installments flagged overdue, {} loans moved to overdue", flagged, moved);
+        Ok(flagged + moved)
+    }
+
+    /// Charges the one-off late penalty on every overdue installment that has not had it yet.
+    pub async fn apply_penalties(pool: &PgPool, now: DateTime<Utc>, config: &DelinquencyConfig) -> Result<u64, String> {
+        let installments: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT id FROM loan_installments
+             WHERE overdue_at IS NOT NULL AND penalty_assessed_at IS NULL AND paid_at IS NULL"
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut charged = 0;
+        for (installment_id,) in installments {
+            let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+            let row: Option<(Uuid, Uuid, Money)> = sqlx::query_as(
+                "SELECT i.loan_id, l.user_id,
+                        (i.principal_due - i.principal_paid + i.interest_due - i.interest_paid)::bigint
+                 FROM loan_installments i
+                 JOIN loans l ON l.id = i.loan_id
+                 WHERE i.id = $1 AND i.penalty_assessed_at IS NULL AND i.paid_at IS NULL
+                 FOR UPDATE OF i"
+            )
+            .bind(installment_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+            let Some((loan_id, borrower_id, unpaid)) = row else {
+                continue;
+            };
+            let penalty = config.late_penalty(unpaid)?;
+
+            sqlx::query(
+                "UPDATE loan_installments
+                 SET penalty_due = penalty_due + $2, total_due = total_due + $2, penalty_assessed_at = $3
+                 WHERE id = $1"
+            )
+            .bind(installment_id)
+            .bind(penalty)
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+            if penalty.is_positive() {
+                LedgerService::record_penalty(&mut tx, loan_id, borrower_id, penalty).await?;
+                charged += 1;
+            }
+
+            tx.commit().await.map_err(|e| e.to_string())?;
+        }
+
+        tracing::info!("[SCHEDULER] Late penalties charged on {} installments", charged);
+        Ok(charged)
+    }
+
+    /// Defaults overdue loans whose oldest unpaid installment is past the grace period
+    /// and rescores the borrower.
+    pub async fn default_loans(
+        pool: &PgPool,
+        now: DateTime<Utc>,
+        config: &DelinquencyConfig,
+        weights: &ScoringWeights,
+    ) -> Result<u64, String> {
+        let cutoff = config.default_cutoff(now);
+        let loans: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT DISTINCT l.id FROM loans l
+             JOIN loan_installments i ON i.loan_id = l.id
+             WHERE l.status = $1 AND i.paid_at IS NULL AND i.due_date < $2"
+        )
+        .bind(LoanStatus::Overdue)
+        .bind(cutoff)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut defaulted = 0;
+        for (loan_id,) in loans {
+            let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+            let loan = LoanLifecycle::load_for_update(&mut tx, loan_id).await.map_err(|e| e.to_string())?;
+            let (past_grace,): (bool,) = sqlx::query_as(
+                "SELECT EXISTS (SELECT 1 FROM loan_installments WHERE loan_id = $1 AND paid_at IS NULL AND due_date < $2)"
+            )
+            .bind(loan_id)
+            .bind(cutoff)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+            if !past_grace || loan.status != LoanStatus::Overdue {
+                continue;
+            }
+
+            let note = format!("Unpaid {} days past due", config.grace_period_days);
+            LoanLifecycle::transition(&mut tx, loan_id, LoanStatus::Defaulted, None, Some(&note))
+                .await
+                .map_err(|e| e.to_string())?;
+            ScoringService::rescore(&mut tx, loan.user_id, weights, now, "loan_defaulted").await?;
+
+            tx.commit().await.map_err(|e| e.to_string())?;
+            defaulted += 1;
+        }
+
+        tracing::info!("[SCHEDULER] {} loans defaulted", defaulted);
+        Ok(defaulted)
+    }
+}
@@ -0,0 +1,524 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::models::{LoanDisbursement, LoanStatus, PaymentStatus};
+use crate::services::blockchain::BlockchainService;
+use crate::services::ledger::LedgerService;
+use crate::services::loan_lifecycle::LoanLifecycle;
+use crate::services::loan_schedule::LoanScheduleService;
+use crate::services::mpesa::{B2cPaymentRequest, B2cResult, MpesaClient, MpesaError};
+use crate::services::outbox::{Outbox, OutboxEvent};
+use crate::services::payments::PaymentService;
+
+const DISBURSEMENT_COLUMNS: &str = "id, loan_id, attempt, amount, phone_number, status, callback_token, conversation_id, \
+    transaction_id, result_code, result_desc, retry_at, unacknowledged_at, created_at, updated_at, settled_at";
+
+/// Payouts tried before the funding is reversed and the loan goes back to the marketplace.
+pub const MAX_ATTEMPTS: i32 = 3;
+/// Wait after the first failed attempt; doubled after each further failure.
+const RETRY_BASE_MINUTES: i64 = 5;
+/// Attempts without a result after this long are left to the M-Pesa statement.
+const STALE_AFTER_MINUTES: i64 = 30;
+
+/// B2C payouts of funded loans. A loan only moves to `disbursed` once Daraja or an M-Pesa
+/// statement confirms that its payout went through. Failed payouts are retried with backoff;
+/// after `MAX_ATTEMPTS` the funding is reversed and the loan goes back to `pending`.
+pub struct DisbursementService;
+
+impl DisbursementService {
+    /// How long to wait after attempt number `attempt` failed. `None` once every attempt is used.
+    pub fn retry_delay(attempt: i32) -> Option<Duration> {
+        if attempt >= MAX_ATTEMPTS {
+            return None;
+        }
+        Some(Duration::minutes(RETRY_BASE_MINUTES << (attempt - 1).clamp(0, 16)))
+    }
+
+    /// Sends the next payout attempt of a funded loan. Returns the attempt already in flight
+    /// if there is one.
+    pub async fn start(pool: &PgPool, mpesa: &MpesaClient, loan_id: Uuid) -> Result<LoanDisbursement, String> {
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+        let loan = LoanLifecycle::load_for_update(&mut tx, loan_id).await.map_err(|e| e.to_string())?;
+        if loan.status != LoanStatus::Funded {
+            return Err(format!("Loan {} is {}, not waiting for a payout", loan_id, loan.status));
+        }
+
+        let in_flight: Option<LoanDisbursement> = sqlx::query_as(&format!(
+            "SELECT {} FROM loan_disbursements WHERE loan_id = $1 AND status = $2",
+            DISBURSEMENT_COLUMNS
+        ))
+        .bind(loan_id)
+        .bind(PaymentStatus::Pending)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        if let Some(disbursement) = in_flight {
+            return Ok(disbursement);
+        }
+
+        // Attempts are counted afresh each time a lender funds the loan
+        let (phone, attempts): (Option<String>, i32) = sqlx::query_as(
+            "SELECT l.disbursement_phone,
+                    (SELECT COUNT(*)::int FROM loan_disbursements d
+                     WHERE d.loan_id = l.id AND d.created_at >= (
+                         SELECT MAX(h.created_at) FROM loan_status_history h WHERE h.loan_id = l.id AND h.to_status = $2
+                     ))
+             FROM loans l WHERE l.id = $1"
+        )
+        .bind(loan_id)
+        .bind(LoanStatus::Funded)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let Some(phone) = phone.filter(|_| attempts < MAX_ATTEMPTS) else {
+            let reason = if attempts >= MAX_ATTEMPTS {
+                "Every payout attempt failed"
+            } else {
+                "No M-Pesa number to pay the loan out to"
+            };
+            Self::revert(&mut tx, loan_id, reason).await?;
+            tx.commit().await.map_err(|e| e.to_string())?;
+            return Err(format!("Loan {} cannot be paid out: {}", loan_id, reason));
+        };
+
+        sqlx::query("UPDATE loan_disbursements SET retry_at = NULL WHERE loan_id = $1 AND retry_at IS NOT NULL")
+            .bind(loan_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // The row exists before the request so a fast result always finds it
+        let disbursement: LoanDisbursement = sqlx::query_as(&format!(
+            "INSERT INTO loan_disbursements (loan_id, attempt, amount, phone_number, callback_token)
+             VALUES ($1, $2, $3, $4, $5) RETURNING {}",
+            DISBURSEMENT_COLUMNS
+        ))
+        .bind(loan_id)
+        .bind(attempts + 1)
+        .bind(loan.amount)
+        .bind(&phone)
+        .bind(Uuid::new_v4().simple().to_string())
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+
+        let payment = mpesa
+            .b2c_payment(&B2cPaymentRequest {
+                originator_conversation_id: disbursement.id.to_string(),
+                phone_number: phone,
+                amount: disbursement.amount,
+                remarks: format!("MicroFund loan {}", loan_id),
+                occasion: "Loan".to_string(),
+                callback_token: disbursement.callback_token.clone(),
+            })
+            .await;
+
+        match payment {
+            Ok(response) => sqlx::query_as(&format!(
+                "UPDATE loan_disbursements SET conversation_id = $2, updated_at = NOW() WHERE id = $1 RETURNING {}",
+                DISBURSEMENT_COLUMNS
+            ))
+            .bind(disbursement.id)
+            .bind(&response.conversation_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| e.to_string()),
+            // The request may still have reached Daraja; its result decides, or the stale check does
+            Err(MpesaError::Transport(e)) => {
+                tracing::warn!("[M-PESA] B2C request for disbursement {} failed: {}", disbursement.id, e);
+                Ok(disbursement)
+            }
+            Err(e) => {
+                tracing::error!("[M-PESA] B2C payment for disbursement {} rejected: {}", disbursement.id, e);
+                let code = match &e {
+                    MpesaError::Rejected { code, .. } => Some(code.clone()),
+                    _ => None,
+                };
+                // A request M-Pesa finds invalid will not get better by sending it again
+                let retry = !matches!(e, MpesaError::InvalidRequest(_));
+                Self::fail(pool, disbursement.id, code, &e.to_string(), retry, Utc::now()).await
+            }
+        }
+    }
+
+    /// Applies a B2C result posted to the result URL of an attempt. Attempts that are no longer
+    /// pending are returned untouched, so repeated results are harmless. `None` if the token
+    /// or conversation does not match any attempt.
+    pub async fn handle_result(
+        pool: &PgPool,
+        callback_token: &str,
+        result: &B2cResult,
+        now: DateTime<Utc>,
+    ) -> Result<Option<LoanDisbursement>, String> {
+        let Some(disbursement) = Self::find_by_token(pool, callback_token).await? else {
+            tracing::warn!("[M-PESA] B2C result for unknown token");
+            return Ok(None);
+        };
+        if disbursement.id.to_string() != result.originator_conversation_id {
+            tracing::warn!(
+                "[M-PESA] B2C result for disbursement {} names conversation {}",
+                disbursement.id, result.originator_conversation_id
+            );
+            return Ok(None);
+        }
+        if disbursement.status != PaymentStatus::Pending {
+            if result.is_success() && disbursement.status == PaymentStatus::Failed {
+                tracing::error!(
+                    "[M-PESA] Disbursement {} of loan {} was given up on but M-Pesa paid it out ({:?})",
+                    disbursement.id, disbursement.loan_id, result.receipt_number()
+                );
+            }
+            return Ok(Some(disbursement));
+        }
+
+        if !result.is_success() {
+            let code = Some(result.result_code.to_string());
+            return Self::fail(pool, disbursement.id, code, &result.result_desc, true, now).await.map(Some);
+        }
+
+        let expected = MpesaClient::whole_shillings(disbursement.amount).map_err(|e| e.to_string())?;
+        if let Some(paid) = result.amount().filter(|paid| *paid != expected) {
+            // Left pending for reconciliation rather than booking the wrong amount
+            return Err(format!(
+                "Result for disbursement {} reports KES {} paid out, expected KES {}",
+                disbursement.id, paid, expected
+            ));
+        }
+
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        let disbursement = Self::lock(&mut tx, disbursement.id).await?;
+        if disbursement.status != PaymentStatus::Pending {
+            return Ok(Some(disbursement));
+        }
+
+        let receipt = result.receipt_number();
+        let completed: LoanDisbursement = sqlx::query_as(&format!(
+            "UPDATE loan_disbursements
+             SET status = $2, conversation_id = $3, transaction_id = $4, result_code = $5, result_desc = $6,
+                 updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 RETURNING {}",
+            DISBURSEMENT_COLUMNS
+        ))
+        .bind(disbursement.id)
+        .bind(PaymentStatus::Completed)
+        .bind(&result.conversation_id)
+        .bind(&receipt)
+        .bind(result.result_code.to_string())
+        .bind(&result.result_desc)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        Self::disburse(&mut tx, &completed, receipt.as_deref(), now).await?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+        tracing::info!("[M-PESA] Loan {} paid out to {}", completed.loan_id, completed.phone_number);
+        Ok(Some(completed))
+    }
+
+    /// Daraja reports that the payment timed out in its queue, so it was never made.
+    pub async fn handle_timeout(pool: &PgPool, callback_token: &str, now: DateTime<Utc>) -> Result<Option<LoanDisbursement>, String> {
+        let Some(disbursement) = Self::find_by_token(pool, callback_token).await? else {
+            tracing::warn!("[M-PESA] B2C timeout for unknown token");
+            return Ok(None);
+        };
+        if disbursement.status != PaymentStatus::Pending {
+            return Ok(Some(disbursement));
+        }
+        Self::fail(pool, disbursement.id, None, "The payment timed out in the M-Pesa queue", true, now)
+            .await
+            .map(Some)
+    }
+
+    /// Flags attempts that have had no result for too long, whether or not Daraja accepted
+    /// them, for reconciliation: they may have been paid, so their loan gets no next attempt
+    /// until an M-Pesa statement shows they were not. Then sends the next attempt of every
+    /// funded loan whose retry is due or that has no attempt yet. Returns how many attempts
+    /// were flagged or sent.
+    pub async fn run_due(pool: &PgPool, mpesa: &MpesaClient, now: DateTime<Utc>) -> Result<u64, String> {
+        let flagged: Vec<(Uuid, Uuid)> = sqlx::query_as(
+            "UPDATE loan_disbursements SET unacknowledged_at = $3, updated_at = NOW()
+             WHERE status = $1 AND unacknowledged_at IS NULL AND created_at < $2
+             RETURNING id, loan_id"
+        )
+        .bind(PaymentStatus::Pending)
+        .bind(now - Duration::minutes(STALE_AFTER_MINUTES))
+        .bind(now)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for (disbursement_id, loan_id) in &flagged {
+            tracing::warn!(
+                "[M-PESA] Disbursement {} of loan {} has had no result; left until the M-Pesa statement shows it",
+                disbursement_id, loan_id
+            );
+        }
+        let mut handled = flagged.len() as u64;
+
+        let due: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT l.id FROM loans l
+             WHERE l.status = $1 AND NOT EXISTS (
+                 SELECT 1 FROM loan_disbursements d
+                 WHERE d.loan_id = l.id AND (d.status = $2 OR d.retry_at > $3)
+             )
+             ORDER BY l.created_at LIMIT 100"
+        )
+        .bind(LoanStatus::Funded)
+        .bind(PaymentStatus::Pending)
+        .bind(now)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for (loan_id,) in due {
+            match Self::start(pool, mpesa, loan_id).await {
+                Ok(_) => handled += 1,
+                Err(e) => tracing::warn!("[SCHEDULER] Could not pay out loan {}: {}", loan_id, e),
+            }
+        }
+
+        tracing::info!("[SCHEDULER] {} loan payouts flagged or sent", handled);
+        Ok(handled)
+    }
+
+    /// Completes a pending attempt that an M-Pesa statement line shows was paid out at
+    /// `paid_at`, paying out its loan. Attempts no longer pending are returned untouched.
+    pub async fn complete_from_statement(
+        conn: &mut PgConnection,
+        disbursement_id: Uuid,
+        receipt_number: &str,
+        paid_at: DateTime<Utc>,
+    ) -> Result<LoanDisbursement, String> {
+        let disbursement = Self::lock(conn, disbursement_id).await?;
+        if disbursement.status != PaymentStatus::Pending {
+            return Ok(disbursement);
+        }
+
+        let completed: LoanDisbursement = sqlx::query_as(&format!(
+            "UPDATE loan_disbursements
+             SET status = $2, transaction_id = $3, result_desc = $4, updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 RETURNING {}",
+            DISBURSEMENT_COLUMNS
+        ))
+        .bind(disbursement.id)
+        .bind(PaymentStatus::Completed)
+        .bind(receipt_number)
+        .bind("Paid out according to the M-Pesa statement")
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        Self::disburse(conn, &completed, Some(receipt_number), paid_at).await?;
+        tracing::info!("[M-PESA] Disbursement {} confirmed by statement receipt {}", completed.id, receipt_number);
+        Ok(completed)
+    }
+
+    /// Fails unacknowledged attempts made during a statement's period, from `start` to `end`,
+    /// that no line of it paid out within `window`, so their loan gets its next attempt or
+    /// goes back to the marketplace. Any line that could be the payout, such as one with a
+    /// masked phone, keeps the attempt waiting. Returns the attempts failed.
+    pub async fn release_unpaid(
+        conn: &mut PgConnection,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Uuid>, String> {
+        let unpaid: Vec<LoanDisbursement> = sqlx::query_as(&format!(
+            "SELECT {} FROM loan_disbursements r
+             WHERE status = $1 AND unacknowledged_at IS NOT NULL AND created_at BETWEEN $2 AND $3
+               AND NOT EXISTS (
+                   SELECT 1 FROM mpesa_statement_lines l
+                   WHERE l.withdrawn = r.amount AND l.completed_at BETWEEN r.created_at AND r.created_at + $4
+                     AND (l.phone_number IS NULL OR l.phone_number = r.phone_number))
+             FOR UPDATE",
+            DISBURSEMENT_COLUMNS
+        ))
+        .bind(PaymentStatus::Pending)
+        .bind(start)
+        .bind(end - window)
+        .bind(window)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut released = Vec::new();
+        for disbursement in unpaid {
+            tracing::warn!("[M-PESA] Disbursement {} of loan {} is in no M-Pesa statement", disbursement.id, disbursement.loan_id);
+            Self::fail_locked(conn, &disbursement, None, "Not paid out according to the M-Pesa statement", true, now).await?;
+            released.push(disbursement.id);
+        }
+        Ok(released)
+    }
+
+    pub async fn for_loan(pool: &PgPool, loan_id: Uuid) -> Result<Vec<LoanDisbursement>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM loan_disbursements WHERE loan_id = $1 ORDER BY created_at",
+            DISBURSEMENT_COLUMNS
+        ))
+        .bind(loan_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn find_by_token(pool: &PgPool, callback_token: &str) -> Result<Option<LoanDisbursement>, String> {
+        sqlx::query_as(&format!("SELECT {} FROM loan_disbursements WHERE callback_token = $1", DISBURSEMENT_COLUMNS))
+            .bind(callback_token)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn lock(conn: &mut PgConnection, disbursement_id: Uuid) -> Result<LoanDisbursement, String> {
+        sqlx::query_as(&format!("SELECT {} FROM loan_disbursements WHERE id = $1 FOR UPDATE", DISBURSEMENT_COLUMNS))
+            .bind(disbursement_id)
+            .fetch_one(conn)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Marks a pending attempt failed and schedules the next one, or reverses the funding
+    /// when there is no next one.
+    async fn fail(
+        pool: &PgPool,
+        disbursement_id: Uuid,
+        result_code: Option<String>,
+        reason: &str,
+        retry: bool,
+        now: DateTime<Utc>,
+    ) -> Result<LoanDisbursement, String> {
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        let disbursement = Self::lock(&mut tx, disbursement_id).await?;
+        if disbursement.status != PaymentStatus::Pending {
+            return Ok(disbursement);
+        }
+        let failed = Self::fail_locked(&mut tx, &disbursement, result_code, reason, retry, now).await?;
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(failed)
+    }
+
+    /// `fail` for a pending attempt the caller has locked.
+    async fn fail_locked(
+        conn: &mut PgConnection,
+        disbursement: &LoanDisbursement,
+        result_code: Option<String>,
+        reason: &str,
+        retry: bool,
+        now: DateTime<Utc>,
+    ) -> Result<LoanDisbursement, String> {
+        let retry_at = Self::retry_delay(disbursement.attempt).filter(|_| retry).map(|delay| now + delay);
+        let failed: LoanDisbursement = sqlx::query_as(&format!(
+            "UPDATE loan_disbursements
+             SET status = $2, result_code = $3, result_desc = $4, retry_at = $5, updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 RETURNING {}",
+            DISBURSEMENT_COLUMNS
+        ))
+        .bind(disbursement.id)
+        .bind(PaymentStatus::Failed)
+        .bind(&result_code)
+        .bind(reason)
+        .bind(retry_at)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        match retry_at {
+            Some(at) => tracing::warn!(
+                "[M-PESA] Payout attempt {} of loan {} failed ({}); retrying at {}",
+                failed.attempt, failed.loan_id, reason, at
+            ),
+            None => Self::revert(conn, failed.loan_id, reason).await?,
+        }
+        Ok(failed)
+    }
+
+    /// Books a confirmed payout: the borrower owes the loan and its fee from now on.
+    async fn disburse(
+        conn: &mut PgConnection,
+        disbursement: &LoanDisbursement,
+        receipt: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        let loan = LoanLifecycle::load_for_update(conn, disbursement.loan_id).await.map_err(|e| e.to_string())?;
+
+        LedgerService::record_disbursement(conn, loan.id, loan.user_id, disbursement.amount).await?;
+
+        let schedule = LoanScheduleService::schedule(conn, loan.id, loan.amount).await?;
+        if schedule.total_fees.is_positive() {
+            LedgerService::record_origination_fee(conn, loan.id, loan.user_id, schedule.total_fees).await?;
+        }
+
+        let note = format!("Paid out to {} (M-Pesa {})", disbursement.phone_number, receipt.unwrap_or("receipt pending"));
+        LoanLifecycle::transition(conn, loan.id, LoanStatus::Disbursed, None, Some(&note))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // Installments fall due from the day the money arrived
+        LoanScheduleService::rebase(conn, loan.id, now).await?;
+
+        BlockchainService::log_to_ledger(
+            &mut *conn,
+            "LOAN_DISBURSED",
+            &format!("Loan {} paid out", loan.id),
+            disbursement.amount,
+        ).await?;
+
+        Outbox::enqueue(&mut *conn, &OutboxEvent::NotifyMember {
+            user_id: loan.user_id,
+            subject: "Your loan has been paid out".to_string(),
+            body: format!("{} from your MicroFund loan has been sent to M-Pesa {}.", disbursement.amount, disbursement.phone_number),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Refunds the lender's capital and puts the loan back on the marketplace.
+    async fn revert(conn: &mut PgConnection, loan_id: Uuid, reason: &str) -> Result<(), String> {
+        let loan = LoanLifecycle::load_for_update(conn, loan_id).await.map_err(|e| e.to_string())?;
+        if loan.status != LoanStatus::Funded {
+            return Ok(());
+        }
+        let lender_id = loan.lender_id.ok_or_else(|| format!("Funded loan {} has no lender", loan_id))?;
+
+        LoanLifecycle::transition(conn, loan_id, LoanStatus::Pending, None, Some(&format!("Payout failed: {}", reason)))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        sqlx::query("UPDATE loans SET lender_id = NULL WHERE id = $1")
+            .bind(loan_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let why = "A loan you funded could not be paid out to the borrower";
+        match PaymentService::funding_of(conn, loan_id, lender_id).await? {
+            Some(funding) => PaymentService::return_loan_funding(conn, &funding, why).await?,
+            None => {
+                // Funded before lenders paid their capital in, so there is nothing to send back
+                LedgerService::record_funding_reversal(conn, loan_id, lender_id, loan.amount).await?;
+
+                BlockchainService::log_to_ledger(
+                    &mut *conn,
+                    "LOAN_FUNDING_REVERSED",
+                    &format!("Funding of loan {} returned", loan_id),
+                    loan.amount,
+                ).await?;
+
+                Outbox::enqueue(&mut *conn, &OutboxEvent::NotifyMember {
+                    user_id: lender_id,
+                    subject: "Your loan funding was cancelled".to_string(),
+                    body: format!("{}, so your commitment of {} has been cancelled. Nothing was taken from you.", why, loan.amount),
+                })
+                .await?;
+            }
+        }
+
+        tracing::warn!("[M-PESA] Loan {} back on the marketplace: {}", loan_id, reason);
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Sends email to members.
+#[async_trait]
+pub trait EmailProvider: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+/// Development stand-in that writes messages to the log instead of sending them.
+pub struct ConsoleEmail;
+
+#[async_trait]
+impl EmailProvider for ConsoleEmail {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        tracing::info!("[Email] To {}: {} - {}", to, subject, body);
+        Ok(())
+    }
+}
+
+/// Development stand-in that appends messages to a file, one per line, for tests and tools to read.
+pub struct FileEmail {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl EmailProvider for FileEmail {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let line = format!("{}\t{}\t{}\t{}\n", Utc::now().to_rfc3339(), to, subject, body.replace('\n', " "));
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", self.path.display(), e))?;
+        let write_error = |e: std::io::Error| format!("Failed to write {}: {}", self.path.display(), e);
+        file.write_all(line.as_bytes()).await.map_err(write_error)?;
+        file.flush().await.map_err(write_error)
+    }
+}
+
+/// `EMAIL_PROVIDER` is `console` (the default) or `file`, which writes to `EMAIL_OUTBOX_FILE`
+/// (`email_outbox.log` by default).
+pub fn provider_from_env() -> Result<Arc<dyn EmailProvider>, String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    match var("EMAIL_PROVIDER").as_deref().map(str::trim) {
+        None | Some("console") => Ok(Arc::new(ConsoleEmail)),
+        Some("file") => Ok(Arc::new(FileEmail {
+            path: var("EMAIL_OUTBOX_FILE").unwrap_or_else(|| "email_outbox.log".to_string()).into(),
+        })),
+        Some(other) => Err(format!("Unknown EMAIL_PROVIDER '{}'", other)),
+    }
+}
@@ -0,0 +1,462 @@
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::models::{AccountBalance, Money, TrialBalance, PLATFORM_CURRENCY};
+
+/// Code of the system account holding the platform's M-Pesa float.
+pub const PLATFORM_CASH: &str = "PLATFORM_CASH";
+/// Code of the income account for origination fees charged on loans.
+pub const FEE_INCOME: &str = "FEE_INCOME";
+/// Code of the income account for late penalties on overdue installments.
+pub const PENALTY_INCOME: &str = "PENALTY_INCOME";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccountType {
+    Asset,
+    Liability,
+    Income,
+}
+
+impl AccountType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountType::Asset => "asset",
+            AccountType::Liability => "liability",
+            AccountType::Income => "income",
+        }
+    }
+}
+
+/// A single leg of a journal entry. Positive amounts are debits, negative amounts are credits.
+#[derive(Debug, Clone)]
+pub struct NewPosting {
+    pub account_id: Uuid,
+    pub amount: Money,
+}
+
+impl NewPosting {
+    pub fn debit(account_id: Uuid, amount: Money) -> Self {
+        Self { account_id, amount }
+    }
+
+    pub fn credit(account_id: Uuid, amount: Money) -> Self {
+        Self { account_id, amount: Money::new(-amount.minor_units(), amount.currency()) }
+    }
+}
+
+pub struct LedgerService;
+
+impl LedgerService {
+    /// Checks that a set of postings forms a valid journal entry: at least two legs,
+    /// no zero legs, all in the platform currency, and debits exactly equal to credits.
+    pub fn validate_postings(postings: &[NewPosting]) -> Result<(), String> {
+        if postings.len() < 2 {
+            return Err("A journal entry needs at least two postings".to_string());
+        }
+        if postings.iter().any(|p| p.amount.is_zero()) {
+            return Err("Postings must have a non-zero amount".to_string());
+        }
+        let total = Money::sum(postings.iter().map(|p| p.amount), PLATFORM_CURRENCY)
+            .map_err(|e| e.to_string())?;
+        if !total.is_zero() {
+            return Err(format!("Journal entry is unbalanced by {}", total));
+        }
+        Ok(())
+    }
+
+    /// Returns the id of the account with the given code, creating it on first use.
+    pub async fn ensure_account(
+        conn: &mut PgConnection,
+        code: &str,
+        name: &str,
+        account_type: AccountType,
+        owner_id: Option<Uuid>,
+    ) -> Result<Uuid, String> {
+        let row: (Uuid,) = sqlx::query_as(
+            "INSERT INTO ledger_accounts (code, name, account_type, owner_id) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (code) DO UPDATE SET code = EXCLUDED.code
+             RETURNING id"
+        )
+        .bind(code)
+        .bind(name)
+        .bind(account_type.as_str())
+        .bind(owner_id)
+        .fetch_one(conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        Ok(row.0)
+    }
+
+    /// Writes a balanced journal entry. Must be called on the same transaction as the
+    /// business change it records so both commit or roll back together.
+    pub async fn post_entry(
+        conn: &mut PgConnection,
+        entry_type: &str,
+        description: &str,
+        reference_id: Option<Uuid>,
+        created_by: Option<Uuid>,
+        postings: &[NewPosting],
+    ) -> Result<Uuid, String> {
+        Self::validate_postings(postings)?;
+
+        let entry: (Uuid,) = sqlx::query_as(
+            "INSERT INTO journal_entries (entry_type, description, reference_id, created_by) VALUES ($1, $2, $3, $4) RETURNING id"
+        )
+        .bind(entry_type)
+        .bind(description)
+        .bind(reference_id)
+        .bind(created_by)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for posting in postings {
+            sqlx::query(
+                "INSERT INTO postings (journal_entry_id, account_id, amount) VALUES ($1, $2, $3)"
+            )
+            .bind(entry.0)
+            .bind(posting.account_id)
+            .bind(posting.amount)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+
+        tracing::info!("[LEDGER] Posted {} entry {} with {} postings", entry_type, entry.0, postings.len());
+        Ok(entry.0)
+    }
+
+    pub async fn platform_cash_account(conn: &mut PgConnection) -> Result<Uuid, String> {
+        Self::ensure_account(conn, PLATFORM_CASH, "Platform Cash (M-Pesa)", AccountType::Asset, None).await
+    }
+
+    pub async fn fee_income_account(conn: &mut PgConnection) -> Result<Uuid, String> {
+        Self::ensure_account(conn, FEE_INCOME, "Origination fee income", AccountType::Income, None).await
+    }
+
+    pub async fn penalty_income_account(conn: &mut PgConnection) -> Result<Uuid, String> {
+        Self::ensure_account(conn, PENALTY_INCOME, "Late penalty income", AccountType::Income, None).await
+    }
+
+    /// Money owed back to a member, such as a loan overpayment, until its refund is paid out.
+    pub async fn refunds_payable_account(conn: &mut PgConnection, owner_id: Uuid) -> Result<Uuid, String> {
+        Self::ensure_account(
+            conn,
+            &format!("REFUNDS_PAYABLE:{}", owner_id),
+            "Refunds payable",
+            AccountType::Liability,
+            Some(owner_id),
+        ).await
+    }
+
+    pub async fn savings_account(conn: &mut PgConnection, savings_id: Uuid, owner_id: Uuid) -> Result<Uuid, String> {
+        Self::ensure_account(
+            conn,
+            &format!("SAVINGS:{}", savings_id),
+            "Member savings",
+            AccountType::Liability,
+            Some(owner_id),
+        ).await
+    }
+
+    pub async fn loans_receivable_account(conn: &mut PgConnection, borrower_id: Uuid) -> Result<Uuid, String> {
+        Self::ensure_account(
+            conn,
+            &format!("LOANS_RECEIVABLE:{}", borrower_id),
+            "Loans receivable",
+            AccountType::Asset,
+            Some(borrower_id),
+        ).await
+    }
+
+    pub async fn lender_payable_account(conn: &mut PgConnection, lender_id: Uuid) -> Result<Uuid, String> {
+        Self::ensure_account(
+            conn,
+            &format!("LENDER_PAYABLE:{}", lender_id),
+            "Lender capital payable",
+            AccountType::Liability,
+            Some(lender_id),
+        ).await
+    }
+
+    /// The lender's capital for a loan has arrived over M-Pesa. It sits in platform cash, owed
+    /// to the lender, until the payout to the borrower goes through.
+    pub async fn record_loan_funding(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        lender_id: Uuid,
+        amount: Money,
+    ) -> Result<Uuid, String> {
+        let cash = Self::platform_cash_account(conn).await?;
+        let payable = Self::lender_payable_account(conn, lender_id).await?;
+
+        Self::post_entry(
+            conn,
+            "LOAN_FUNDING",
+            &format!("Loan {} funded", loan_id),
+            Some(loan_id),
+            Some(lender_id),
+            &[NewPosting::debit(cash, amount), NewPosting::credit(payable, amount)],
+        ).await
+    }
+
+    /// M-Pesa has paid the loan out: the cash has left the platform and the borrower owes it.
+    pub async fn record_disbursement(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        borrower_id: Uuid,
+        amount: Money,
+    ) -> Result<Uuid, String> {
+        let receivable = Self::loans_receivable_account(conn, borrower_id).await?;
+        let cash = Self::platform_cash_account(conn).await?;
+
+        Self::post_entry(
+            conn,
+            "LOAN_DISBURSEMENT",
+            &format!("Loan {} paid out", loan_id),
+            Some(loan_id),
+            None,
+            &[NewPosting::debit(receivable, amount), NewPosting::credit(cash, amount)],
+        ).await
+    }
+
+    /// Capital the lender paid in for a loan cannot be used, so it is owed back to them
+    /// until their refund is paid out.
+    pub async fn record_funding_refund(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        lender_id: Uuid,
+        amount: Money,
+    ) -> Result<Uuid, String> {
+        let payable = Self::lender_payable_account(conn, lender_id).await?;
+        let refunds = Self::refunds_payable_account(conn, lender_id).await?;
+
+        Self::post_entry(
+            conn,
+            "LOAN_FUNDING_REFUND",
+            &format!("Funding of loan {} to be refunded", loan_id),
+            Some(loan_id),
+            Some(lender_id),
+            &[NewPosting::debit(payable, amount), NewPosting::credit(refunds, amount)],
+        ).await
+    }
+
+    /// Takes back the funding of a loan funded before lenders paid their capital in, which
+    /// was booked as cash the platform never received.
+    pub async fn record_funding_reversal(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        lender_id: Uuid,
+        amount: Money,
+    ) -> Result<Uuid, String> {
+        let payable = Self::lender_payable_account(conn, lender_id).await?;
+        let cash = Self::platform_cash_account(conn).await?;
+
+        Self::post_entry(
+            conn,
+            "LOAN_FUNDING_REVERSAL",
+            &format!("Funding of loan {} returned", loan_id),
+            Some(loan_id),
+            None,
+            &[NewPosting::debit(payable, amount), NewPosting::credit(cash, amount)],
+        ).await
+    }
+
+    /// The origination fee is earned by the platform when the loan is paid out
+    /// and added to what the borrower owes.
+    pub async fn record_origination_fee(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        borrower_id: Uuid,
+        fee: Money,
+    ) -> Result<Uuid, String> {
+        let receivable = Self::loans_receivable_account(conn, borrower_id).await?;
+        let income = Self::fee_income_account(conn).await?;
+
+        Self::post_entry(
+            conn,
+            "ORIGINATION_FEE",
+            &format!("Origination fee on loan {}", loan_id),
+            Some(loan_id),
+            Some(borrower_id),
+            &[NewPosting::debit(receivable, fee), NewPosting::credit(income, fee)],
+        ).await
+    }
+
+    /// A late penalty is earned by the platform when assessed and added to what the borrower owes.
+    pub async fn record_penalty(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        borrower_id: Uuid,
+        penalty: Money,
+    ) -> Result<Uuid, String> {
+        let receivable = Self::loans_receivable_account(conn, borrower_id).await?;
+        let income = Self::penalty_income_account(conn).await?;
+
+        Self::post_entry(
+            conn,
+            "LATE_PENALTY",
+            &format!("Late penalty on loan {}", loan_id),
+            Some(loan_id),
+            None,
+            &[NewPosting::debit(receivable, penalty), NewPosting::credit(income, penalty)],
+        ).await
+    }
+
+    /// Borrower cash comes in: `settled` (principal, fees and penalties) clears the receivable,
+    /// `interest` is owed on to the lender who funded the loan, and any `overpayment`
+    /// credit goes to the account the borrower chose for it.
+    pub async fn record_repayment(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        borrower_id: Uuid,
+        lender_id: Option<Uuid>,
+        settled: Money,
+        interest: Money,
+        overpayment: Option<NewPosting>,
+    ) -> Result<Uuid, String> {
+        let cash = Self::platform_cash_account(conn).await?;
+        let receivable = Self::loans_receivable_account(conn, borrower_id).await?;
+        let mut total = settled.checked_add(interest).map_err(|e| e.to_string())?;
+        if let Some(credit) = &overpayment {
+            // Credits are stored negative, so this adds the overpayment to the cash received
+            total = total.checked_sub(credit.amount).map_err(|e| e.to_string())?;
+        }
+
+        let mut postings = vec![NewPosting::debit(cash, total)];
+        if settled.is_positive() {
+            postings.push(NewPosting::credit(receivable, settled));
+        }
+        if interest.is_positive() {
+            let lender_id = lender_id.ok_or_else(|| format!("Interest paid on loan {} without a lender", loan_id))?;
+            let payable = Self::lender_payable_account(conn, lender_id).await?;
+            postings.push(NewPosting::credit(payable, interest));
+        }
+        postings.extend(overpayment);
+
+        Self::post_entry(
+            conn,
+            "REPAYMENT",
+            &format!("Loan {} repaid", loan_id),
+            Some(loan_id),
+            Some(borrower_id),
+            &postings,
+        ).await
+    }
+
+    /// Member cash comes in and is held on their behalf in the savings goal.
+    pub async fn record_savings_deposit(
+        conn: &mut PgConnection,
+        savings_id: Uuid,
+        owner_id: Uuid,
+        amount: Money,
+    ) -> Result<Uuid, String> {
+        let cash = Self::platform_cash_account(conn).await?;
+        let savings = Self::savings_account(conn, savings_id, owner_id).await?;
+
+        Self::post_entry(
+            conn,
+            "SAVINGS_DEPOSIT",
+            &format!("Deposit to savings goal {}", savings_id),
+            Some(savings_id),
+            Some(owner_id),
+            &[NewPosting::debit(cash, amount), NewPosting::credit(savings, amount)],
+        ).await
+    }
+
+    /// A deposit recorded in error is taken back out of the savings goal.
+    pub async fn record_savings_reversal(
+        conn: &mut PgConnection,
+        savings_id: Uuid,
+        owner_id: Uuid,
+        amount: Money,
+        reversed_by: Uuid,
+    ) -> Result<Uuid, String> {
+        let savings = Self::savings_account(conn, savings_id, owner_id).await?;
+        let cash = Self::platform_cash_account(conn).await?;
+
+        Self::post_entry(
+            conn,
+            "SAVINGS_REVERSAL",
+            &format!("Reversed deposit to savings goal {}", savings_id),
+            Some(savings_id),
+            Some(reversed_by),
+            &[NewPosting::debit(savings, amount), NewPosting::credit(cash, amount)],
+        ).await
+    }
+
+    /// Savings paid back out to their owner: the platform no longer holds them.
+    pub async fn record_savings_withdrawal(
+        conn: &mut PgConnection,
+        savings_id: Uuid,
+        owner_id: Uuid,
+        amount: Money,
+    ) -> Result<Uuid, String> {
+        let savings = Self::savings_account(conn, savings_id, owner_id).await?;
+        let cash = Self::platform_cash_account(conn).await?;
+
+        Self::post_entry(
+            conn,
+            "SAVINGS_WITHDRAWAL",
+            &format!("Withdrawal from savings goal {}", savings_id),
+            Some(savings_id),
+            Some(owner_id),
+            &[NewPosting::debit(savings, amount), NewPosting::credit(cash, amount)],
+        ).await
+    }
+
+    /// A refund paid out to its member: the platform no longer owes it.
+    pub async fn record_refund(
+        conn: &mut PgConnection,
+        refund_id: Uuid,
+        owner_id: Uuid,
+        amount: Money,
+    ) -> Result<Uuid, String> {
+        let payable = Self::refunds_payable_account(conn, owner_id).await?;
+        let cash = Self::platform_cash_account(conn).await?;
+
+        Self::post_entry(
+            conn,
+            "REFUND",
+            &format!("Refund {} paid out", refund_id),
+            Some(refund_id),
+            Some(owner_id),
+            &[NewPosting::debit(payable, amount), NewPosting::credit(cash, amount)],
+        ).await
+    }
+
+    /// Balances of every account, reported on each account's normal side
+    /// (debit for assets and expenses, credit for everything else).
+    pub async fn account_balances(pool: &PgPool, owner_id: Option<Uuid>) -> Result<Vec<AccountBalance>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT a.id, a.code, a.name, a.account_type, a.owner_id,
+                    COALESCE(SUM(p.amount) FILTER (WHERE p.amount > 0), 0)::bigint AS debits,
+                    COALESCE(-SUM(p.amount) FILTER (WHERE p.amount < 0), 0)::bigint AS credits,
+                    (CASE WHEN a.account_type IN ('asset', 'expense') THEN 1 ELSE -1 END
+                        * COALESCE(SUM(p.amount), 0))::bigint AS balance
+             FROM ledger_accounts a
+             LEFT JOIN postings p ON p.account_id = a.id
+             WHERE $1::uuid IS NULL OR a.owner_id = $1
+             GROUP BY a.id
+             ORDER BY a.code"
+        )
+        .bind(owner_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn trial_balance(pool: &PgPool) -> Result<TrialBalance, String> {
+        let accounts = Self::account_balances(pool, None).await.map_err(|e| e.to_string())?;
+        let total_debits = Money::sum(accounts.iter().map(|a| a.debits), PLATFORM_CURRENCY)
+            .map_err(|e| e.to_string())?;
+        let total_credits = Money::sum(accounts.iter().map(|a| a.credits), PLATFORM_CURRENCY)
+            .map_err(|e| e.to_string())?;
+
+        Ok(TrialBalance {
+            balanced: total_debits == total_credits,
+            total_debits,
+            total_credits,
+            accounts,
+        })
+    }
+}
@@ -0,0 +1,323 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::models::Money;
+
+/// `prev_hash` of the first entry of the chain.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Hashed ahead of every entry, so a hash of the ledger is never mistaken for any other hash.
+const HASH_DOMAIN: &[u8] = b"microfund-platform-ledger-v1";
+
+/// Entries sealed, and entries verified, per database round trip.
+const BATCH_SIZE: i64 = 500;
+
+/// Advisory lock held while sealing, so two instances never number entries at once.
+const SEAL_LOCK_KEY: i64 = 0x4d46_4c45_4447_4552;
+
+/// The server key that signs ledger entries. Read once at startup from the environment.
+#[derive(Clone)]
+pub struct LedgerSigner {
+    key: SigningKey,
+}
+
+impl LedgerSigner {
+    /// From the hex of a 32-byte Ed25519 seed.
+    pub fn from_seed_hex(seed: &str) -> Result<Self, String> {
+        let bytes: [u8; 32] = hex::decode(seed.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or("The ledger signing key must be 64 hex characters")?;
+        Ok(LedgerSigner { key: SigningKey::from_bytes(&bytes) })
+    }
+
+    /// `LEDGER_SIGNING_KEY`, required.
+    pub fn from_env() -> Result<Self, String> {
+        let seed = std::env::var("LEDGER_SIGNING_KEY")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .ok_or("LEDGER_SIGNING_KEY must be set")?;
+        Self::from_seed_hex(&seed)
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    /// Fills in the entry's hash and signs it. Its number and `prev_hash` must be set.
+    pub fn seal(&self, entry: &mut ChainEntry) {
+        entry.entry_hash = entry.compute_hash();
+        entry.signature = self.sign(entry.entry_hash.as_bytes());
+    }
+
+    /// Hex of the Ed25519 signature of `message`.
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(self.key.sign(message).to_bytes())
+    }
+}
+
+/// Whether `signature`, in hex, is the key's signature of `message`.
+pub fn verify_signature(key: &VerifyingKey, message: &[u8], signature: &str) -> bool {
+    hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .is_some_and(|signature| key.verify(message, &signature).is_ok())
+}
+
+/// The key anyone can check the ledger with, from its hex.
+pub fn parse_verifying_key(public_key: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = hex::decode(public_key.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("The ledger public key must be 64 hex characters")?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| "The ledger public key is not a valid Ed25519 key".to_string())
+}
+
+/// A sealed entry of the platform ledger.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ChainEntry {
+    pub id: Uuid,
+    pub sequence_number: i64,
+    pub activity_type: String,
+    pub description: String,
+    pub amount: Money,
+    pub created_at: DateTime<Utc>,
+    pub prev_hash: String,
+    pub entry_hash: String,
+    pub signature: String,
+}
+
+impl ChainEntry {
+    /// SHA-256 over the entry's number, content and the hash before it. Fields are
+    /// length-prefixed, so no two different entries hash the same bytes.
+    pub fn compute_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_be_bytes());
+            hasher.update(bytes);
+        };
+        field(HASH_DOMAIN);
+        field(&self.sequence_number.to_be_bytes());
+        field(self.prev_hash.as_bytes());
+        field(self.id.as_bytes());
+        field(self.activity_type.as_bytes());
+        field(self.description.as_bytes());
+        field(&self.amount.minor_units().to_be_bytes());
+        field(self.amount.currency().code().as_bytes());
+        field(self.created_at.to_rfc3339_opts(SecondsFormat::Micros, true).as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn has_valid_signature(&self, key: &VerifyingKey) -> bool {
+        verify_signature(key, self.entry_hash.as_bytes(), &self.signature)
+    }
+}
+
+/// What is wrong with the first entry that does not check out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainProblem {
+    /// An entry is missing or numbered out of order.
+    Gap,
+    /// `prev_hash` is not the hash of the entry before.
+    BrokenLink,
+    /// The content no longer hashes to `entry_hash`.
+    Altered,
+    /// The signature is not the server key's over `entry_hash`.
+    BadSignature,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChainBreak {
+    pub sequence_number: i64,
+    pub entry_id: Uuid,
+    pub problem: ChainProblem,
+}
+
+/// Outcome of walking the chain from its first entry.
+#[derive(Debug, Serialize)]
+pub struct ChainReport {
+    pub valid: bool,
+    pub public_key: String,
+    pub entries_checked: i64,
+    /// Number and hash of the last entry that checked out.
+    pub head_sequence_number: Option<i64>,
+    pub head_hash: Option<String>,
+    /// Entries written but not sealed yet; the seal job picks them up.
+    pub unsealed_entries: i64,
+    pub first_break: Option<ChainBreak>,
+}
+
+/// Checks entries one at a time, in order, stopping at the first that does not check out.
+pub struct ChainWalk {
+    key: VerifyingKey,
+    checked: i64,
+    head: Option<(i64, String)>,
+    first_break: Option<ChainBreak>,
+}
+
+impl ChainWalk {
+    pub fn new(key: VerifyingKey) -> Self {
+        ChainWalk { key, checked: 0, head: None, first_break: None }
+    }
+
+    /// Checks the next entry. False once the chain is broken.
+    pub fn check(&mut self, entry: &ChainEntry) -> bool {
+        if self.first_break.is_some() {
+            return false;
+        }
+        let (expected_number, expected_prev) = match &self.head {
+            Some((number, hash)) => (number + 1, hash.as_str()),
+            None => (1, GENESIS_HASH),
+        };
+        let problem = if entry.sequence_number != expected_number {
+            Some(ChainProblem::Gap)
+        } else if entry.prev_hash != expected_prev {
+            Some(ChainProblem::BrokenLink)
+        } else if entry.compute_hash() != entry.entry_hash {
+            Some(ChainProblem::Altered)
+        } else if !entry.has_valid_signature(&self.key) {
+            Some(ChainProblem::BadSignature)
+        } else {
+            None
+        };
+
+        match problem {
+            Some(problem) => {
+                self.first_break = Some(ChainBreak { sequence_number: entry.sequence_number, entry_id: entry.id, problem });
+                false
+            }
+            None => {
+                self.checked += 1;
+                self.head = Some((entry.sequence_number, entry.entry_hash.clone()));
+                true
+            }
+        }
+    }
+
+    pub fn report(self, unsealed_entries: i64) -> ChainReport {
+        let (head_sequence_number, head_hash) = self.head.unzip();
+        ChainReport {
+            valid: self.first_break.is_none(),
+            public_key: hex::encode(self.key.as_bytes()),
+            entries_checked: self.checked,
+            head_sequence_number,
+            head_hash,
+            unsealed_entries,
+            first_break: self.first_break,
+        }
+    }
+}
+
+/// The tamper-evident platform ledger. `BlockchainService::log_to_ledger` writes entries with
+/// the change they describe; they are then sealed in the order they are picked up, each
+/// hashed over its content and the previous entry's hash and signed with the server key.
+/// Changing, removing or reordering any sealed entry breaks every hash after it.
+pub struct LedgerChain;
+
+impl LedgerChain {
+    /// Seals every entry written so far. Returns how many were sealed.
+    pub async fn seal_pending(pool: &PgPool, signer: &LedgerSigner, now: DateTime<Utc>) -> Result<u64, String> {
+        let mut sealed = 0;
+        loop {
+            let batch = Self::seal_batch(pool, signer, now).await.map_err(|e| e.to_string())?;
+            sealed += batch;
+            if batch < BATCH_SIZE as u64 {
+                return Ok(sealed);
+            }
+        }
+    }
+
+    async fn seal_batch(pool: &PgPool, signer: &LedgerSigner, now: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)").bind(SEAL_LOCK_KEY).execute(&mut *tx).await?;
+
+        let head: Option<(i64, String)> = sqlx::query_as(
+            "SELECT sequence_number, entry_hash FROM platform_transactions
+             WHERE sequence_number IS NOT NULL ORDER BY sequence_number DESC LIMIT 1"
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let (mut number, mut prev_hash) = head.unwrap_or((0, GENESIS_HASH.to_string()));
+
+        let pending: Vec<(Uuid, String, String, Money, DateTime<Utc>)> = sqlx::query_as(
+            "SELECT id, activity_type, description, amount, created_at FROM platform_transactions
+             WHERE sequence_number IS NULL ORDER BY created_at, id LIMIT $1 FOR UPDATE"
+        )
+        .bind(BATCH_SIZE)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for (id, activity_type, description, amount, created_at) in &pending {
+            number += 1;
+            let mut entry = ChainEntry {
+                id: *id,
+                sequence_number: number,
+                activity_type: activity_type.clone(),
+                description: description.clone(),
+                amount: *amount,
+                created_at: *created_at,
+                prev_hash,
+                entry_hash: String::new(),
+                signature: String::new(),
+            };
+            signer.seal(&mut entry);
+
+            sqlx::query(
+                "UPDATE platform_transactions
+                 SET sequence_number = $2, prev_hash = $3, entry_hash = $4, signature = $5, sealed_at = $6
+                 WHERE id = $1"
+            )
+            .bind(entry.id)
+            .bind(entry.sequence_number)
+            .bind(&entry.prev_hash)
+            .bind(&entry.entry_hash)
+            .bind(&entry.signature)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+            prev_hash = entry.entry_hash;
+        }
+
+        tx.commit().await?;
+        if !pending.is_empty() {
+            tracing::info!("[LEDGER] Sealed {} entries up to #{}", pending.len(), number);
+        }
+        Ok(pending.len() as u64)
+    }
+
+    /// Walks the whole chain from its first entry and reports the first break, if any.
+    pub async fn verify(pool: &PgPool, key: VerifyingKey) -> Result<ChainReport, sqlx::Error> {
+        let mut walk = ChainWalk::new(key);
+        let mut after = 0;
+        'pages: loop {
+            let page: Vec<ChainEntry> = sqlx::query_as(
+                "SELECT id, sequence_number, activity_type, description, amount, created_at, prev_hash, entry_hash, signature
+                 FROM platform_transactions
+                 WHERE sequence_number > $1 ORDER BY sequence_number LIMIT $2"
+            )
+            .bind(after)
+            .bind(BATCH_SIZE)
+            .fetch_all(pool)
+            .await?;
+
+            for entry in &page {
+                if !walk.check(entry) {
+                    break 'pages;
+                }
+                after = entry.sequence_number;
+            }
+            if page.len() < BATCH_SIZE as usize {
+                break;
+            }
+        }
+
+        let (unsealed,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM platform_transactions WHERE sequence_number IS NULL")
+            .fetch_one(pool)
+            .await?;
+        Ok(walk.report(unsealed))
+    }
+}
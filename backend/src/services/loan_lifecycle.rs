@@ -0,0 +1,91 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::{Loan, LoanStatus};
+
+pub const LOAN_COLUMNS: &str = "id, user_id, lender_id, amount, status, description, created_at, repaid_at, product_id, due_date,
+    chain_account, chain_loan_signature, chain_repay_signature";
+
+pub struct LoanLifecycle;
+
+impl LoanLifecycle {
+    /// Loads a loan and locks its row until the surrounding transaction ends,
+    /// so concurrent handlers cannot race each other through the state machine.
+    pub async fn load_for_update(conn: &mut PgConnection, loan_id: Uuid) -> Result<Loan, AppError> {
+        sqlx::query_as(&format!("SELECT {} FROM loans WHERE id = $1 FOR UPDATE", LOAN_COLUMNS))
+            .bind(loan_id)
+            .fetch_optional(conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to load loan {}: {:?}", loan_id, e);
+                AppError::InternalServerError
+            })?
+            .ok_or(AppError::NotFound)
+    }
+
+    /// The only way a loan changes status. Rejects moves the state machine does not allow
+    /// and records the change, its actor (`None` for system jobs) and a note in
+    /// `loan_status_history`. Must run inside the caller's transaction.
+    pub async fn transition(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        to: LoanStatus,
+        actor_id: Option<Uuid>,
+        note: Option<&str>,
+    ) -> Result<Loan, AppError> {
+        let current = Self::load_for_update(conn, loan_id).await?;
+
+        if !current.status.can_transition_to(to) {
+            tracing::warn!("Rejected loan {} transition {} -> {}", loan_id, current.status, to);
+            return Err(AppError::InvalidLoanTransition { from: current.status, to });
+        }
+
+        let loan: Loan = sqlx::query_as(&format!(
+            "UPDATE loans SET status = $2,
+                repaid_at = CASE WHEN $2 = 'repaid' THEN NOW() ELSE repaid_at END
+             WHERE id = $1
+             RETURNING {}",
+            LOAN_COLUMNS
+        ))
+        .bind(loan_id)
+        .bind(to)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update loan {} status: {:?}", loan_id, e);
+            AppError::InternalServerError
+        })?;
+
+        Self::record(conn, loan_id, Some(current.status), to, actor_id, note).await?;
+
+        tracing::info!("Loan {} moved {} -> {}", loan_id, current.status, to);
+        Ok(loan)
+    }
+
+    /// Writes a history row. Called directly only for the creation of a loan, which has no previous status.
+    pub async fn record(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        from: Option<LoanStatus>,
+        to: LoanStatus,
+        actor_id: Option<Uuid>,
+        note: Option<&str>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO loan_status_history (loan_id, from_status, to_status, actor_id, note) VALUES ($1, $2, $3, $4, $5)"
+        )
+        .bind(loan_id)
+        .bind(from)
+        .bind(to)
+        .bind(actor_id)
+        .bind(note)
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to record loan {} history: {:?}", loan_id, e);
+            AppError::InternalServerError
+        })?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::{Loan, LoanStatus, Money, PLATFORM_CURRENCY};
+use crate::services::loan_lifecycle::LoanLifecycle;
+
+/// Which loan requests wait for a loan officer. Read once at startup from the environment.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoanReviewConfig {
+    /// Requests above this amount stay off the marketplace until approved. None: no request waits.
+    pub approval_above: Option<Money>,
+}
+
+impl LoanReviewConfig {
+    /// `LOAN_APPROVAL_ABOVE`, in major units; unset or empty for no manual approval.
+    pub fn from_env() -> Result<Self, String> {
+        let approval_above = match std::env::var("LOAN_APPROVAL_ABOVE").ok().filter(|v| !v.trim().is_empty()) {
+            None => None,
+            Some(value) => {
+                let major: i64 = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| "LOAN_APPROVAL_ABOVE must be a whole number".to_string())?;
+                Some(Money::from_major(major, PLATFORM_CURRENCY).map_err(|e| e.to_string())?)
+            }
+        };
+        Ok(LoanReviewConfig { approval_above })
+    }
+
+    pub fn requires_approval(&self, amount: Money) -> bool {
+        self.approval_above.is_some_and(|limit| amount > limit)
+    }
+}
+
+/// A loan request waiting for a loan officer.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LoanForReview {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub borrower_username: String,
+    pub borrower_score: i32,
+    pub amount: Money,
+    pub description: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// Manual approval and rejection of loan requests by staff.
+pub struct LoanReview;
+
+impl LoanReview {
+    /// Pending requests that cannot be funded until approved, oldest first.
+    pub async fn queue(pool: &PgPool) -> Result<Vec<LoanForReview>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT l.id, l.user_id, u.username AS borrower_username, u.reputation_score AS borrower_score,
+                    l.amount, l.description, l.created_at
+             FROM loans l
+             JOIN users u ON u.id = l.user_id
+             WHERE l.status = $1 AND l.requires_approval AND l.approved_at IS NULL
+             ORDER BY l.created_at"
+        )
+        .bind(LoanStatus::Pending)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Whether lenders may fund the loan yet.
+    pub async fn is_cleared(conn: &mut PgConnection, loan_id: Uuid) -> Result<bool, sqlx::Error> {
+        let (cleared,): (bool,) = sqlx::query_as("SELECT NOT requires_approval OR approved_at IS NOT NULL FROM loans WHERE id = $1")
+            .bind(loan_id)
+            .fetch_one(conn)
+            .await?;
+        Ok(cleared)
+    }
+
+    /// Signs off a pending request, which puts it on the marketplace if it was waiting.
+    pub async fn approve(conn: &mut PgConnection, loan_id: Uuid, officer_id: Uuid, now: DateTime<Utc>) -> Result<Loan, AppError> {
+        let loan = Self::load_for_review(conn, loan_id, officer_id).await?;
+
+        let approved = sqlx::query("UPDATE loans SET approved_by = $2, approved_at = $3 WHERE id = $1 AND approved_at IS NULL")
+            .bind(loan_id)
+            .bind(officer_id)
+            .bind(now)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?
+            .rows_affected();
+        if approved == 0 {
+            return Err(AppError::Conflict("The loan is already approved".to_string()));
+        }
+
+        tracing::info!("Loan {} approved by {}", loan_id, officer_id);
+        Ok(loan)
+    }
+
+    /// Turns a pending request down. It is cancelled with the reason in its history.
+    pub async fn reject(conn: &mut PgConnection, loan_id: Uuid, officer_id: Uuid, reason: &str) -> Result<Loan, AppError> {
+        Self::load_for_review(conn, loan_id, officer_id).await?;
+        LoanLifecycle::transition(conn, loan_id, LoanStatus::Cancelled, Some(officer_id), Some(&format!("Rejected: {}", reason))).await
+    }
+
+    /// Only requests no lender has funded yet are reviewed, and never by their own borrower.
+    async fn load_for_review(conn: &mut PgConnection, loan_id: Uuid, officer_id: Uuid) -> Result<Loan, AppError> {
+        let loan = LoanLifecycle::load_for_update(conn, loan_id).await?;
+        if loan.user_id == officer_id {
+            return Err(AppError::BadRequest("You cannot review your own loan".to_string()));
+        }
+        if loan.status != LoanStatus::Pending {
+            return Err(AppError::Conflict(format!("Only pending loans can be reviewed; this one is {}", loan.status)));
+        }
+        Ok(loan)
+    }
+}
@@ -0,0 +1,276 @@
+use chrono::{DateTime, Duration, Months, Utc};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use microfund_shared::Rounding;
+use crate::models::{InterestMethod, LoanInstallment, LoanProduct, LoanSchedule, LoanTerms, Money, RepaymentBucket, RepaymentFrequency};
+
+const PRODUCT_COLUMNS: &str = "id, code, name, interest_method, interest_rate_bps, origination_fee_bps, term_count, repayment_frequency, is_default";
+const INSTALLMENT_COLUMNS: &str = "id, loan_id, installment_number, due_date, principal_due, interest_due, fee_due, penalty_due, total_due,
+    principal_paid, interest_paid, fee_paid, penalty_paid, paid_at, overdue_at";
+
+/// One row of a schedule before it is stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledInstallment {
+    pub installment_number: i32,
+    pub due_date: DateTime<Utc>,
+    pub principal_due: Money,
+    pub interest_due: Money,
+    pub fee_due: Money,
+}
+
+impl ScheduledInstallment {
+    pub fn total_due(&self) -> Result<Money, String> {
+        Money::sum([self.principal_due, self.interest_due, self.fee_due], self.principal_due.currency())
+            .map_err(|e| e.to_string())
+    }
+}
+
+pub struct LoanScheduleService;
+
+impl LoanScheduleService {
+    /// Builds the installment schedule for `principal` under `terms`, with the first
+    /// installment one period after `start`. Every amount is exact: the installments
+    /// add up to the principal, the interest and the origination fee to the minor unit.
+    pub fn build(principal: Money, terms: &LoanTerms, start: DateTime<Utc>) -> Result<Vec<ScheduledInstallment>, String> {
+        if !principal.is_positive() {
+            return Err("Loan principal must be positive".to_string());
+        }
+        if terms.term_count <= 0 {
+            return Err("Loan term must have at least one installment".to_string());
+        }
+        if terms.interest_rate_bps < 0 || terms.origination_fee_bps < 0 {
+            return Err("Interest rate and fees cannot be negative".to_string());
+        }
+
+        let count = terms.term_count as usize;
+        let fee = principal
+            .percent_bps(terms.origination_fee_bps as i64, Rounding::HalfEven)
+            .map_err(|e| e.to_string())?;
+
+        let parts = match terms.interest_method {
+            InterestMethod::Flat => Self::flat_parts(principal, terms)?,
+            InterestMethod::DecliningBalance => Self::declining_balance_parts(principal, terms)?,
+        };
+
+        parts
+            .into_iter()
+            .zip(fee.allocate(count))
+            .enumerate()
+            .map(|(i, ((principal_due, interest_due), fee_due))| {
+                let installment_number = i as i32 + 1;
+                Ok(ScheduledInstallment {
+                    installment_number,
+                    due_date: Self::due_date(start, terms.repayment_frequency, installment_number)?,
+                    principal_due,
+                    interest_due,
+                    fee_due,
+                })
+            })
+            .collect()
+    }
+
+    /// Interest for one period on `balance`: the annual rate divided evenly over the year's periods.
+    fn period_interest(balance: Money, terms: &LoanTerms) -> Result<Money, String> {
+        balance
+            .mul_ratio(
+                terms.interest_rate_bps as i64,
+                10_000 * terms.repayment_frequency.periods_per_year(),
+                Rounding::HalfEven,
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    /// Principal and interest both split evenly across the installments.
+    fn flat_parts(principal: Money, terms: &LoanTerms) -> Result<Vec<(Money, Money)>, String> {
+        let count = terms.term_count as usize;
+        let total_interest = principal
+            .mul_ratio(
+                terms.interest_rate_bps as i64 * terms.term_count as i64,
+                10_000 * terms.repayment_frequency.periods_per_year(),
+                Rounding::HalfEven,
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(principal.allocate(count).into_iter().zip(total_interest.allocate(count)).collect())
+    }
+
+    /// Equal installments (annuity): each period pays that period's interest on the
+    /// outstanding balance and the rest goes to principal. The installment is the smallest
+    /// whole amount that clears the loan in time; the last one takes whatever is left.
+    fn declining_balance_parts(principal: Money, terms: &LoanTerms) -> Result<Vec<(Money, Money)>, String> {
+        let count = terms.term_count as i64;
+        let mut low = (principal.minor_units() + count - 1) / count;
+        let mut high = principal
+            .checked_add(Self::period_interest(principal, terms)?)
+            .map_err(|e| e.to_string())?
+            .minor_units();
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let parts = Self::amortize(principal, terms, mid)?;
+            if parts.last().map(|(p, i)| p.minor_units() + i.minor_units() <= mid).unwrap_or(true) {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        Self::amortize(principal, terms, low)
+    }
+
+    /// Runs the schedule with a fixed installment, the last installment settling the balance.
+    fn amortize(principal: Money, terms: &LoanTerms, installment: i64) -> Result<Vec<(Money, Money)>, String> {
+        let currency = principal.currency();
+        let mut balance = principal;
+        let mut parts = Vec::with_capacity(terms.term_count as usize);
+
+        for number in 1..=terms.term_count {
+            let interest = Self::period_interest(balance, terms)?;
+            let principal_due = if number == terms.term_count {
+                balance
+            } else {
+                Money::new((installment - interest.minor_units()).clamp(0, balance.minor_units()), currency)
+            };
+            balance = balance.checked_sub(principal_due).map_err(|e| e.to_string())?;
+            parts.push((principal_due, interest));
+        }
+
+        Ok(parts)
+    }
+
+    fn due_date(start: DateTime<Utc>, frequency: RepaymentFrequency, number: i32) -> Result<DateTime<Utc>, String> {
+        let due = match frequency {
+            RepaymentFrequency::Weekly => start.checked_add_signed(Duration::weeks(number as i64)),
+            RepaymentFrequency::Monthly => start.checked_add_months(Months::new(number as u32)),
+        };
+        due.ok_or_else(|| "Installment due date out of range".to_string())
+    }
+
+    pub async fn active_products(pool: &PgPool) -> Result<Vec<LoanProduct>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM loan_products WHERE active ORDER BY is_default DESC, name",
+            PRODUCT_COLUMNS
+        ))
+        .fetch_all(pool)
+        .await
+    }
+
+    /// The requested product, or the default one when the borrower did not choose.
+    pub async fn find_product(conn: &mut PgConnection, product_id: Option<Uuid>) -> Result<Option<LoanProduct>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM loan_products WHERE active AND (id = $1 OR ($1 IS NULL AND is_default))",
+            PRODUCT_COLUMNS
+        ))
+        .bind(product_id)
+        .fetch_optional(conn)
+        .await
+    }
+
+    pub async fn insert_schedule(
+        conn: &mut PgConnection,
+        loan_id: Uuid,
+        installments: &[ScheduledInstallment],
+    ) -> Result<(), String> {
+        for installment in installments {
+            sqlx::query(
+                "INSERT INTO loan_installments (loan_id, installment_number, due_date, principal_due, interest_due, fee_due, total_due)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)"
+            )
+            .bind(loan_id)
+            .bind(installment.installment_number)
+            .bind(installment.due_date)
+            .bind(installment.principal_due)
+            .bind(installment.interest_due)
+            .bind(installment.fee_due)
+            .bind(installment.total_due()?)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+
+        let maturity = installments.last().map(|i| i.due_date);
+        sqlx::query("UPDATE loans SET due_date = $2 WHERE id = $1")
+            .bind(loan_id)
+            .bind(maturity)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    /// Moves the schedule so the first installment falls one period after `start`.
+    /// The schedule is priced when the loan is requested but the clock starts at disbursement.
+    pub async fn rebase(conn: &mut PgConnection, loan_id: Uuid, start: DateTime<Utc>) -> Result<(), String> {
+        sqlx::query(
+            "UPDATE loan_installments i
+             SET due_date = $2 + i.installment_number *
+                 CASE l.repayment_frequency WHEN 'weekly' THEN INTERVAL '1 week' ELSE INTERVAL '1 month' END
+             FROM loans l
+             WHERE l.id = i.loan_id AND i.loan_id = $1"
+        )
+        .bind(loan_id)
+        .bind(start)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        sqlx::query("UPDATE loans SET due_date = (SELECT MAX(due_date) FROM loan_installments WHERE loan_id = $1) WHERE id = $1")
+            .bind(loan_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    pub async fn loan_terms(conn: &mut PgConnection, loan_id: Uuid) -> Result<LoanTerms, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT interest_method, interest_rate_bps, origination_fee_bps, term_count, repayment_frequency FROM loans WHERE id = $1"
+        )
+        .bind(loan_id)
+        .fetch_one(conn)
+        .await
+    }
+
+    pub async fn installments(conn: &mut PgConnection, loan_id: Uuid) -> Result<Vec<LoanInstallment>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM loan_installments WHERE loan_id = $1 ORDER BY installment_number",
+            INSTALLMENT_COLUMNS
+        ))
+        .bind(loan_id)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// The stored schedule of a loan with its totals.
+    pub async fn schedule(conn: &mut PgConnection, loan_id: Uuid, principal: Money) -> Result<LoanSchedule, String> {
+        let terms = Self::loan_terms(conn, loan_id).await.map_err(|e| e.to_string())?;
+        let installments = Self::installments(conn, loan_id).await.map_err(|e| e.to_string())?;
+
+        let currency = principal.currency();
+        let total = |part: fn(&LoanInstallment) -> Money| {
+            Money::sum(installments.iter().map(part), currency).map_err(|e| e.to_string())
+        };
+
+        let total_repayable = total(|i| i.total_due)?;
+        let total_paid = Money::sum(
+            installments.iter().flat_map(|i| RepaymentBucket::ALL.map(|bucket| i.paid(bucket))),
+            currency,
+        )
+        .map_err(|e| e.to_string())?;
+
+        Ok(LoanSchedule {
+            loan_id,
+            principal,
+            terms,
+            total_interest: total(|i| i.interest_due)?,
+            total_fees: total(|i| i.fee_due)?,
+            total_penalties: total(|i| i.penalty_due)?,
+            outstanding_balance: total_repayable.checked_sub(total_paid).map_err(|e| e.to_string())?,
+            total_repayable,
+            total_paid,
+            installments,
+        })
+    }
+}
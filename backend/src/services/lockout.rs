@@ -0,0 +1,72 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgConnection;
+use uuid::Uuid;
+use crate::middleware::AppError;
+
+/// Failed logins in a row before the account is locked.
+pub const FREE_ATTEMPTS: i32 = 5;
+/// The first lock; each failure after it doubles the lock, up to `MAX_LOCK_SECONDS`.
+const FIRST_LOCK_SECONDS: i64 = 60;
+const MAX_LOCK_SECONDS: i64 = 3600;
+
+/// Progressive lockout of accounts whose logins keep failing, whether on the password, the
+/// SMS code or the two-factor code. A completed login or a password reset clears it.
+pub struct LoginLockout;
+
+impl LoginLockout {
+    /// How long the account is locked after its `failures`th failed login in a row.
+    pub fn lock_duration(failures: i32) -> Option<Duration> {
+        if failures < FREE_ATTEMPTS {
+            return None;
+        }
+        let doublings = (failures - FREE_ATTEMPTS).min(16) as u32;
+        let seconds = FIRST_LOCK_SECONDS.saturating_mul(1 << doublings).min(MAX_LOCK_SECONDS);
+        Some(Duration::seconds(seconds))
+    }
+
+    /// `TooManyRequests` while the account is locked.
+    pub async fn ensure_unlocked(conn: &mut PgConnection, user_id: Uuid, now: DateTime<Utc>) -> Result<(), AppError> {
+        let locked_until: Option<(Option<DateTime<Utc>>,)> = sqlx::query_as("SELECT locked_until FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        match locked_until {
+            Some((Some(until),)) if until > now => Err(AppError::TooManyRequests {
+                retry_after: (until - now).num_seconds().max(1) as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn record_failure(conn: &mut PgConnection, user_id: Uuid, now: DateTime<Utc>) -> Result<(), AppError> {
+        let (failures,): (i32,) = sqlx::query_as(
+            "UPDATE users SET failed_logins = failed_logins + 1 WHERE id = $1 RETURNING failed_logins"
+        )
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+
+        if let Some(lock) = Self::lock_duration(failures) {
+            sqlx::query("UPDATE users SET locked_until = $1 WHERE id = $2")
+                .bind(now + lock)
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+            tracing::warn!("Locked user {} for {}s after {} failed logins", user_id, lock.num_seconds(), failures);
+        }
+        Ok(())
+    }
+
+    pub async fn clear(conn: &mut PgConnection, user_id: Uuid) -> Result<(), AppError> {
+        sqlx::query("UPDATE users SET failed_logins = 0, locked_until = NULL WHERE id = $1 AND failed_logins > 0")
+            .bind(user_id)
+            .execute(conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+/// Merkle trees as in RFC 6962 (Certificate Transparency): leaves and inner nodes are hashed
+/// with different prefixes, and a tree of `n` leaves splits at the largest power of two below
+/// `n`. Any RFC 6962 implementation can check the proofs.
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root over leaf hashes, in order.
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => Sha256::digest([]).into(),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+/// Sibling hashes from the leaf at `index` up to the root, lowest first.
+pub fn inclusion_proof(leaves: &[Hash], index: usize) -> Vec<Hash> {
+    let n = leaves.len();
+    if n <= 1 || index >= n {
+        return Vec::new();
+    }
+    let k = split(n);
+    let (mut path, sibling) = if index < k {
+        (inclusion_proof(&leaves[..k], index), root(&leaves[k..]))
+    } else {
+        (inclusion_proof(&leaves[k..], index - k), root(&leaves[..k]))
+    };
+    path.push(sibling);
+    path
+}
+
+/// Whether `leaf` is at `index` of the tree of `size` leaves with this root (RFC 9162, 2.1.3.2).
+pub fn verify_inclusion(leaf: &Hash, index: u64, size: u64, path: &[Hash], root: &Hash) -> bool {
+    if index >= size {
+        return false;
+    }
+    let (mut fnode, mut snode) = (index, size - 1);
+    let mut hash = *leaf;
+    for sibling in path {
+        if snode == 0 {
+            return false;
+        }
+        if fnode & 1 == 1 || fnode == snode {
+            hash = node_hash(sibling, &hash);
+            while fnode & 1 == 0 && fnode != 0 {
+                fnode >>= 1;
+                snode >>= 1;
+            }
+        } else {
+            hash = node_hash(&hash, sibling);
+        }
+        fnode >>= 1;
+        snode >>= 1;
+    }
+    snode == 0 && hash == *root
+}
+
+/// The largest power of two below `n`, for `n` > 1.
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < n {
+        k *= 2;
+    }
+    k
+}
@@ -1,2 +1,35 @@
+pub mod accounts;
+pub mod anchor;
+pub mod audit;
 pub mod blockchain;
+pub mod checkpoints;
+pub mod clock;
+pub mod delinquency;
+pub mod disbursements;
+pub mod email;
+pub mod ledger;
+pub mod ledger_chain;
+pub mod loan_lifecycle;
+pub mod loan_review;
+pub mod loan_schedule;
+pub mod lockout;
+pub mod merkle;
 pub mod mpesa;
+pub mod mpesa_mock;
+pub mod notifier;
+pub mod otp;
+pub mod outbox;
+pub mod passwords;
+pub mod payments;
+pub mod reconciliation;
+pub mod refunds;
+pub mod repayments;
+pub mod reversals;
+pub mod scheduler;
+pub mod scoring;
+pub mod sessions;
+pub mod sms;
+pub mod solana;
+pub mod solana_mock;
+pub mod two_factor;
+pub mod withdrawals;
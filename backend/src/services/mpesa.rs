@@ -1,37 +1,641 @@
-use uuid::Uuid;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use crate::models::{Money, PLATFORM_CURRENCY};
 
-pub struct MpesaService;
+const SANDBOX_BASE_URL: &str = "https://sandbox.safaricom.co.ke";
+const PRODUCTION_BASE_URL: &str = "https://api.safaricom.co.ke";
+/// Paybill and passkey Safaricom publishes for everyone testing Lipa na M-Pesa Online.
+const SANDBOX_SHORTCODE: &str = "174379";
+const SANDBOX_PASSKEY: &str = "bfb279f9aa9bdbcf158e97dd71a467cd2e0c893059b10f78e6b72ada1ed2c919";
+/// B2C shortcode and initiator of the Daraja sandbox.
+const SANDBOX_B2C_SHORTCODE: &str = "600000";
+const SANDBOX_INITIATOR_NAME: &str = "testapi";
+/// Daraja answers STK queries with this error code while the customer has not responded yet.
+const STILL_PROCESSING: &str = "500.001.1001";
+/// Tokens are refreshed this long before Daraja says they expire.
+const TOKEN_EXPIRY_MARGIN_SECONDS: i64 = 60;
 
-#[derive(Debug, serde::Serialize)]
-pub struct MpesaResponse {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpesaEnvironment {
+    Sandbox,
+    Production,
+    /// The bundled mock Daraja server, started in-process. No network or credentials needed.
+    Mock,
+}
+
+impl MpesaEnvironment {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "sandbox" => Some(MpesaEnvironment::Sandbox),
+            "production" => Some(MpesaEnvironment::Production),
+            "mock" => Some(MpesaEnvironment::Mock),
+            _ => None,
+        }
+    }
+}
+
+/// Daraja credentials and endpoints. Read once at startup from the environment.
+#[derive(Debug, Clone)]
+pub struct MpesaConfig {
+    pub environment: MpesaEnvironment,
+    pub base_url: String,
+    pub consumer_key: String,
+    pub consumer_secret: String,
+    pub shortcode: String,
+    pub passkey: String,
+    /// Where Daraja posts the result of each STK push.
+    pub callback_url: String,
+    /// Shortcode B2C payouts are sent from.
+    pub b2c_shortcode: String,
+    pub initiator_name: String,
+    /// The initiator password, encrypted with Safaricom's public certificate.
+    pub security_credential: String,
+    /// Where Daraja posts the result of each B2C payment. A per-payout token is appended.
+    pub b2c_result_url: String,
+    /// Where Daraja reports B2C payments that timed out in its queue. A per-payout token is appended.
+    pub b2c_timeout_url: String,
+    /// Timeout of each HTTP request to Daraja.
+    pub request_timeout: StdDuration,
+    /// Payments with no callback after this long are looked up with STK query.
+    pub pending_timeout: StdDuration,
+}
+
+impl MpesaConfig {
+    /// Settings for talking to a mock Daraja server at `base_url`.
+    pub fn mock(base_url: &str) -> Self {
+        MpesaConfig {
+            environment: MpesaEnvironment::Mock,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            consumer_key: "mock-consumer-key".to_string(),
+            consumer_secret: "mock-consumer-secret".to_string(),
+            shortcode: SANDBOX_SHORTCODE.to_string(),
+            passkey: SANDBOX_PASSKEY.to_string(),
+            callback_url: "http://127.0.0.1:8080/api/mpesa/callback".to_string(),
+            b2c_shortcode: SANDBOX_B2C_SHORTCODE.to_string(),
+            initiator_name: SANDBOX_INITIATOR_NAME.to_string(),
+            security_credential: "mock-security-credential".to_string(),
+            b2c_result_url: "http://127.0.0.1:8080/api/mpesa/b2c/result".to_string(),
+            b2c_timeout_url: "http://127.0.0.1:8080/api/mpesa/b2c/timeout".to_string(),
+            request_timeout: StdDuration::from_secs(5),
+            pending_timeout: StdDuration::from_secs(30),
+        }
+    }
+
+    /// `MPESA_ENVIRONMENT` is `sandbox`, `production` or `mock` (the default). Sandbox falls back
+    /// to Safaricom's public test paybill and B2C initiator; production needs every credential set.
+    /// In mock mode the base URL is filled in once the mock server is up.
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let seconds = |name: &str, default: u64| -> Result<StdDuration, String> {
+            match var(name) {
+                Some(value) => value
+                    .trim()
+                    .parse()
+                    .map(StdDuration::from_secs)
+                    .map_err(|_| format!("{} must be a whole number of seconds", name)),
+                None => Ok(StdDuration::from_secs(default)),
+            }
+        };
+
+        let environment = match var("MPESA_ENVIRONMENT") {
+            Some(value) => MpesaEnvironment::parse(&value)
+                .ok_or_else(|| format!("Unknown MPESA_ENVIRONMENT '{}'", value))?,
+            None => MpesaEnvironment::Mock,
+        };
+
+        let mut config = MpesaConfig::mock("");
+        config.environment = environment;
+        config.request_timeout = seconds("MPESA_REQUEST_TIMEOUT_SECONDS", 10)?;
+        config.pending_timeout = seconds("MPESA_PENDING_TIMEOUT_SECONDS", 120)?;
+        if let Some(url) = var("MPESA_CALLBACK_URL") {
+            config.callback_url = url;
+        }
+        if let Some(url) = var("MPESA_B2C_RESULT_URL") {
+            config.b2c_result_url = url;
+        }
+        if let Some(url) = var("MPESA_B2C_TIMEOUT_URL") {
+            config.b2c_timeout_url = url;
+        }
+        if environment == MpesaEnvironment::Mock {
+            return Ok(config);
+        }
+
+        let required = |name: &str| var(name).ok_or_else(|| format!("{} must be set", name));
+        config.base_url = var("MPESA_BASE_URL")
+            .unwrap_or_else(|| match environment {
+                MpesaEnvironment::Production => PRODUCTION_BASE_URL.to_string(),
+                _ => SANDBOX_BASE_URL.to_string(),
+            })
+            .trim_end_matches('/')
+            .to_string();
+        config.consumer_key = required("MPESA_CONSUMER_KEY")?;
+        config.consumer_secret = required("MPESA_CONSUMER_SECRET")?;
+        config.callback_url = required("MPESA_CALLBACK_URL")?;
+        config.b2c_result_url = required("MPESA_B2C_RESULT_URL")?;
+        config.b2c_timeout_url = required("MPESA_B2C_TIMEOUT_URL")?;
+        config.security_credential = required("MPESA_SECURITY_CREDENTIAL")?;
+        if environment == MpesaEnvironment::Production {
+            config.shortcode = required("MPESA_SHORTCODE")?;
+            config.passkey = required("MPESA_PASSKEY")?;
+            config.b2c_shortcode = required("MPESA_B2C_SHORTCODE")?;
+            config.initiator_name = required("MPESA_INITIATOR_NAME")?;
+        } else {
+            config.shortcode = var("MPESA_SHORTCODE").unwrap_or(config.shortcode);
+            config.passkey = var("MPESA_PASSKEY").unwrap_or(config.passkey);
+            config.b2c_shortcode = var("MPESA_B2C_SHORTCODE").unwrap_or(config.b2c_shortcode);
+            config.initiator_name = var("MPESA_INITIATOR_NAME").unwrap_or(config.initiator_name);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MpesaError {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("M-Pesa could not be reached: {0}")]
+    Transport(String),
+    #[error("M-Pesa rejected the request ({code}): {message}")]
+    Rejected { code: String, message: String },
+}
+
+impl From<reqwest::Error> for MpesaError {
+    fn from(e: reqwest::Error) -> Self {
+        MpesaError::Transport(e.to_string())
+    }
+}
+
+/// Where an STK push stands once Daraja has been asked about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StkStatus {
+    /// The customer has not entered their PIN yet.
+    Pending,
+    Completed,
+    InsufficientFunds,
+    Cancelled,
+    /// The customer's phone could not be reached or they did not respond in time.
+    Timeout,
+    Failed,
+}
+
+impl StkStatus {
+    pub fn from_result_code(code: &str) -> Self {
+        match code.trim() {
+            "0" => StkStatus::Completed,
+            "1" => StkStatus::InsufficientFunds,
+            "1032" => StkStatus::Cancelled,
+            "1037" => StkStatus::Timeout,
+            _ => StkStatus::Failed,
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        *self != StkStatus::Pending
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StkPushRequest {
+    pub phone_number: String,
+    pub amount: Money,
+    /// Shown to the customer on the PIN prompt. Daraja allows up to 12 characters.
+    pub account_reference: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StkPushResponse {
+    #[serde(rename = "MerchantRequestID")]
+    pub merchant_request_id: String,
+    #[serde(rename = "CheckoutRequestID")]
     pub checkout_request_id: String,
     pub response_code: String,
+    pub response_description: String,
     pub customer_message: String,
 }
 
-impl MpesaService {
-    /// Simulates initiating an STK Push (Lipa na M-Pesa Online).
-    pub async fn initiate_stk_push(phone_number: &str, amount: f64) -> Result<MpesaResponse, String> {
-        log::info!(
-            "[M-PESA] Initiating STK Push for {} - Amount: KES {:.2}",
-            phone_number,
-            amount
-        );
-        
-        // Simulate a short delay for the network request
-        // tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-
-        Ok(MpesaResponse {
-            checkout_request_id: Uuid::new_v4().to_string(),
-            response_code: "0".to_string(),
-            customer_message: "Success. Request accepted for processing".to_string(),
+#[derive(Debug, Clone, Serialize)]
+pub struct StkQueryResult {
+    pub checkout_request_id: String,
+    pub status: StkStatus,
+    pub result_code: Option<String>,
+    pub result_desc: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StkQueryBody {
+    result_code: String,
+    result_desc: String,
+}
+
+/// Body Daraja posts to `CallBackURL` once the customer has answered an STK push.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StkCallbackBody {
+    #[serde(rename = "Body")]
+    pub body: StkCallbackEnvelope,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StkCallbackEnvelope {
+    #[serde(rename = "stkCallback")]
+    pub stk_callback: StkCallback,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StkCallback {
+    #[serde(rename = "MerchantRequestID")]
+    pub merchant_request_id: String,
+    #[serde(rename = "CheckoutRequestID")]
+    pub checkout_request_id: String,
+    pub result_code: i64,
+    pub result_desc: String,
+    /// Only present on successful payments.
+    pub callback_metadata: Option<CallbackMetadata>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CallbackMetadata {
+    pub item: Vec<CallbackItem>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CallbackItem {
+    pub name: String,
+    pub value: Option<serde_json::Value>,
+}
+
+impl StkCallback {
+    pub fn status(&self) -> StkStatus {
+        StkStatus::from_result_code(&self.result_code.to_string())
+    }
+
+    fn item(&self, name: &str) -> Option<&serde_json::Value> {
+        self.callback_metadata
+            .as_ref()?
+            .item
+            .iter()
+            .find(|i| i.name == name)?
+            .value
+            .as_ref()
+    }
+
+    pub fn receipt_number(&self) -> Option<String> {
+        self.item("MpesaReceiptNumber")?.as_str().map(str::to_string)
+    }
+
+    /// The amount paid, in whole shillings.
+    pub fn amount(&self) -> Option<i64> {
+        let value = self.item("Amount")?;
+        value.as_i64().or_else(|| value.as_f64().map(|v| v.round() as i64))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct B2cPaymentRequest {
+    /// Our id for the payment, echoed back in its result.
+    pub originator_conversation_id: String,
+    pub phone_number: String,
+    pub amount: Money,
+    pub remarks: String,
+    pub occasion: String,
+    /// Appended to the result and timeout URLs so only Daraja, which was given them,
+    /// can report on this payment.
+    pub callback_token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct B2cPaymentResponse {
+    #[serde(rename = "ConversationID")]
+    pub conversation_id: String,
+    #[serde(rename = "OriginatorConversationID")]
+    pub originator_conversation_id: String,
+    pub response_code: String,
+    pub response_description: String,
+}
+
+/// Body Daraja posts to `ResultURL` once a B2C payment has gone through or failed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct B2cResultBody {
+    #[serde(rename = "Result")]
+    pub result: B2cResult,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct B2cResult {
+    pub result_code: i64,
+    pub result_desc: String,
+    #[serde(rename = "OriginatorConversationID")]
+    pub originator_conversation_id: String,
+    #[serde(rename = "ConversationID")]
+    pub conversation_id: String,
+    #[serde(rename = "TransactionID")]
+    pub transaction_id: Option<String>,
+    /// Only present on successful payments.
+    pub result_parameters: Option<B2cResultParameters>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct B2cResultParameters {
+    pub result_parameter: Vec<B2cResultParameter>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct B2cResultParameter {
+    pub key: String,
+    pub value: Option<serde_json::Value>,
+}
+
+impl B2cResult {
+    pub fn is_success(&self) -> bool {
+        self.result_code == 0
+    }
+
+    fn parameter(&self, key: &str) -> Option<&serde_json::Value> {
+        self.result_parameters
+            .as_ref()?
+            .result_parameter
+            .iter()
+            .find(|p| p.key == key)?
+            .value
+            .as_ref()
+    }
+
+    /// The M-Pesa receipt of the payout.
+    pub fn receipt_number(&self) -> Option<String> {
+        self.parameter("TransactionReceipt")
+            .and_then(|v| v.as_str().map(str::to_string))
+            .or_else(|| self.transaction_id.clone().filter(|id| !id.is_empty()))
+    }
+
+    /// The amount paid out, in whole shillings.
+    pub fn amount(&self) -> Option<i64> {
+        let value = self.parameter("TransactionAmount")?;
+        value.as_i64().or_else(|| value.as_f64().map(|v| v.round() as i64))
+    }
+}
+
+/// Error body Daraja sends with non-2xx responses.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DarajaError {
+    error_code: String,
+    error_message: String,
+}
+
+#[derive(Deserialize)]
+struct TokenBody {
+    access_token: String,
+    /// Seconds, sent as a string.
+    expires_in: String,
+}
+
+struct AccessToken {
+    value: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Client for the Daraja API. Cheap to clone; clones share the cached OAuth token.
+#[derive(Clone)]
+pub struct MpesaClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: MpesaConfig,
+    http: reqwest::Client,
+    token: Mutex<Option<AccessToken>>,
+}
+
+impl MpesaClient {
+    pub fn new(config: MpesaConfig) -> Result<Self, MpesaError> {
+        let http = reqwest::Client::builder().timeout(config.request_timeout).build()?;
+        Ok(MpesaClient {
+            inner: Arc::new(Inner { config, http, token: Mutex::new(None) }),
         })
     }
 
-    /// Simulates the callback from M-Pesa after user enters PIN.
-    pub async fn verify_payment(checkout_request_id: &str) -> Result<bool, String> {
-        log::info!("[M-PESA] Verifying payment for request ID: {}", checkout_request_id);
-        // In a real app, this would check the status in the DB updated by a callback
-        Ok(true)
+    pub fn pending_timeout(&self) -> StdDuration {
+        self.inner.config.pending_timeout
+    }
+
+    /// Daraja timestamps are East Africa Time, formatted `YYYYMMDDHHmmss`.
+    pub fn timestamp(now: DateTime<Utc>) -> String {
+        let eat = FixedOffset::east_opt(3 * 3600).expect("valid offset");
+        now.with_timezone(&eat).format("%Y%m%d%H%M%S").to_string()
+    }
+
+    /// The STK password: base64 of shortcode, passkey and timestamp.
+    pub fn password(shortcode: &str, passkey: &str, timestamp: &str) -> String {
+        BASE64.encode(format!("{}{}{}", shortcode, passkey, timestamp))
+    }
+
+    /// Brings a Kenyan mobile number to the `2547XXXXXXXX` form Daraja expects.
+    pub fn normalize_phone(phone: &str) -> Result<String, MpesaError> {
+        let digits: String = phone.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+        let digits = digits.strip_prefix('+').unwrap_or(&digits);
+        let local = if let Some(rest) = digits.strip_prefix("254") {
+            rest
+        } else if let Some(rest) = digits.strip_prefix('0') {
+            rest
+        } else {
+            digits
+        };
+
+        let valid = local.len() == 9
+            && local.chars().all(|c| c.is_ascii_digit())
+            && (local.starts_with('7') || local.starts_with('1'));
+        if !valid {
+            return Err(MpesaError::InvalidRequest(format!("'{}' is not a Kenyan mobile number", phone)));
+        }
+        Ok(format!("254{}", local))
+    }
+
+    /// M-Pesa moves whole shillings only.
+    pub fn whole_shillings(amount: Money) -> Result<i64, MpesaError> {
+        let unit = Money::from_major(1, PLATFORM_CURRENCY)
+            .map_err(|e| MpesaError::InvalidRequest(e.to_string()))?
+            .minor_units();
+        if amount.currency() != PLATFORM_CURRENCY || amount.minor_units() < unit || amount.minor_units() % unit != 0 {
+            return Err(MpesaError::InvalidRequest(format!(
+                "M-Pesa payments must be whole {} amounts of at least 1, got {}",
+                PLATFORM_CURRENCY.code(),
+                amount
+            )));
+        }
+        Ok(amount.minor_units() / unit)
+    }
+
+    /// A valid OAuth token, fetched again only when the cached one is about to expire.
+    pub async fn access_token(&self) -> Result<String, MpesaError> {
+        let mut cached = self.inner.token.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if token.expires_at > Utc::now() {
+                return Ok(token.value.clone());
+            }
+        }
+
+        let config = &self.inner.config;
+        let response = self
+            .inner
+            .http
+            .get(format!("{}/oauth/v1/generate", config.base_url))
+            .query(&[("grant_type", "client_credentials")])
+            .basic_auth(&config.consumer_key, Some(&config.consumer_secret))
+            .send()
+            .await?;
+        let body: TokenBody = Self::parse(response).await?;
+
+        let lifetime = body.expires_in.trim().parse::<i64>().unwrap_or(3599);
+        let token = AccessToken {
+            value: body.access_token,
+            expires_at: Utc::now() + Duration::seconds((lifetime - TOKEN_EXPIRY_MARGIN_SECONDS).max(0)),
+        };
+        let value = token.value.clone();
+        *cached = Some(token);
+        Ok(value)
+    }
+
+    async fn forget_token(&self) {
+        *self.inner.token.lock().await = None;
+    }
+
+    /// Sends a Lipa na M-Pesa Online prompt to the customer's phone.
+    pub async fn stk_push(&self, request: &StkPushRequest) -> Result<StkPushResponse, MpesaError> {
+        let config = &self.inner.config;
+        let phone = Self::normalize_phone(&request.phone_number)?;
+        let amount = Self::whole_shillings(request.amount)?;
+        let timestamp = Self::timestamp(Utc::now());
+
+        let body = serde_json::json!({
+            "BusinessShortCode": config.shortcode,
+            "Password": Self::password(&config.shortcode, &config.passkey, &timestamp),
+            "Timestamp": timestamp,
+            "TransactionType": "CustomerPayBillOnline",
+            "Amount": amount,
+            "PartyA": phone,
+            "PartyB": config.shortcode,
+            "PhoneNumber": phone,
+            "CallBackURL": config.callback_url,
+            "AccountReference": request.account_reference.chars().take(12).collect::<String>(),
+            "TransactionDesc": request.description.chars().take(13).collect::<String>(),
+        });
+
+        tracing::info!("[M-PESA] STK push of KES {} to {}", amount, phone);
+        let response: StkPushResponse = self.post("/mpesa/stkpush/v1/processrequest", &body).await?;
+        if response.response_code != "0" {
+            return Err(MpesaError::Rejected {
+                code: response.response_code,
+                message: response.response_description,
+            });
+        }
+        Ok(response)
+    }
+
+    /// Asks Daraja how an STK push ended. Still-pending pushes come back as `StkStatus::Pending`.
+    pub async fn stk_query(&self, checkout_request_id: &str) -> Result<StkQueryResult, MpesaError> {
+        let config = &self.inner.config;
+        let timestamp = Self::timestamp(Utc::now());
+        let body = serde_json::json!({
+            "BusinessShortCode": config.shortcode,
+            "Password": Self::password(&config.shortcode, &config.passkey, &timestamp),
+            "Timestamp": timestamp,
+            "CheckoutRequestID": checkout_request_id,
+        });
+
+        match self.post::<StkQueryBody>("/mpesa/stkpushquery/v1/query", &body).await {
+            Ok(result) => Ok(StkQueryResult {
+                checkout_request_id: checkout_request_id.to_string(),
+                status: StkStatus::from_result_code(&result.result_code),
+                result_code: Some(result.result_code),
+                result_desc: result.result_desc,
+            }),
+            Err(MpesaError::Rejected { code, message }) if code == STILL_PROCESSING => Ok(StkQueryResult {
+                checkout_request_id: checkout_request_id.to_string(),
+                status: StkStatus::Pending,
+                result_code: None,
+                result_desc: message,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends money from the B2C shortcode to a customer. Daraja only queues the payment here;
+    /// the outcome is posted to the result URL, or to the timeout URL if it never left the queue.
+    pub async fn b2c_payment(&self, request: &B2cPaymentRequest) -> Result<B2cPaymentResponse, MpesaError> {
+        let config = &self.inner.config;
+        let phone = Self::normalize_phone(&request.phone_number)?;
+        let amount = Self::whole_shillings(request.amount)?;
+        let with_token = |url: &str| match url {
+            "" => String::new(),
+            url => format!("{}/{}", url.trim_end_matches('/'), request.callback_token),
+        };
+
+        let body = serde_json::json!({
+            "OriginatorConversationID": request.originator_conversation_id,
+            "InitiatorName": config.initiator_name,
+            "SecurityCredential": config.security_credential,
+            "CommandID": "BusinessPayment",
+            "Amount": amount,
+            "PartyA": config.b2c_shortcode,
+            "PartyB": phone,
+            "Remarks": request.remarks.chars().take(100).collect::<String>(),
+            "QueueTimeOutURL": with_token(&config.b2c_timeout_url),
+            "ResultURL": with_token(&config.b2c_result_url),
+            "Occasion": request.occasion.chars().take(100).collect::<String>(),
+        });
+
+        tracing::info!("[M-PESA] B2C payment of KES {} to {}", amount, phone);
+        let response: B2cPaymentResponse = self.post("/mpesa/b2c/v3/paymentrequest", &body).await?;
+        if response.response_code != "0" {
+            return Err(MpesaError::Rejected {
+                code: response.response_code,
+                message: response.response_description,
+            });
+        }
+        Ok(response)
+    }
+
+    /// Posts to Daraja with a bearer token, fetching a fresh token once if Daraja turned it down.
+    async fn post<T: serde::de::DeserializeOwned>(&self, path: &str, body: &serde_json::Value) -> Result<T, MpesaError> {
+        let url = format!("{}{}", self.inner.config.base_url, path);
+        let mut retried = false;
+        loop {
+            let token = self.access_token().await?;
+            let response = self.inner.http.post(&url).bearer_auth(token).json(body).send().await?;
+            if response.status() == reqwest::StatusCode::UNAUTHORIZED && !retried {
+                self.forget_token().await;
+                retried = true;
+                continue;
+            }
+            return Self::parse(response).await;
+        }
+    }
+
+    async fn parse<T: serde::de::DeserializeOwned>(response: reqwest::Response) -> Result<T, MpesaError> {
+        let status = response.status();
+        let text = response.text().await?;
+        if status.is_success() {
+            return serde_json::from_str(&text)
+                .map_err(|e| MpesaError::Transport(format!("Unexpected response from M-Pesa: {}", e)));
+        }
+        match serde_json::from_str::<DarajaError>(&text) {
+            Ok(error) => Err(MpesaError::Rejected { code: error.error_code, message: error.error_message }),
+            Err(_) => Err(MpesaError::Rejected { code: status.as_u16().to_string(), message: text }),
+        }
     }
 }
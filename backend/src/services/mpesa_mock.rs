@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde_json::json;
+use uuid::Uuid;
+use crate::services::mpesa::MpesaConfig;
+
+/// How a mocked customer answers an STK push, or how a B2C payout to them ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockScenario {
+    Success,
+    InsufficientFunds,
+    Cancelled,
+    Timeout,
+}
+
+impl MockScenario {
+    fn result(&self) -> (&'static str, &'static str) {
+        match self {
+            MockScenario::Success => ("0", "The service request is processed successfully."),
+            MockScenario::InsufficientFunds => ("1", "The balance is insufficient for the transaction."),
+            MockScenario::Cancelled => ("1032", "Request cancelled by user"),
+            MockScenario::Timeout => ("1037", "DS timeout user cannot be reached"),
+        }
+    }
+
+    fn b2c_result(&self) -> (i64, &'static str) {
+        match self {
+            MockScenario::Success => (0, "The service request is processed successfully."),
+            MockScenario::InsufficientFunds => (1, "The balance is insufficient for the transaction."),
+            MockScenario::Cancelled => (2040, "Credit Party customer type can't be supported by the service."),
+            // Reported on the queue timeout URL instead
+            MockScenario::Timeout => (1037, "The request timed out in the queue."),
+        }
+    }
+
+    /// Phone numbers that always answer the same way, handy when clicking through the app.
+    fn for_test_number(phone: &str) -> Option<Self> {
+        match phone {
+            "254700000001" => Some(MockScenario::InsufficientFunds),
+            "254700000002" => Some(MockScenario::Cancelled),
+            "254700000003" => Some(MockScenario::Timeout),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockPush {
+    pub checkout_request_id: String,
+    pub merchant_request_id: String,
+    pub phone_number: String,
+    pub amount: i64,
+    pub account_reference: String,
+    pub callback_url: String,
+    pub scenario: MockScenario,
+    /// Receipt of the payment if the customer pays.
+    pub receipt_number: String,
+    pub completed_at: chrono::DateTime<chrono::Utc>,
+    queries: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockPayout {
+    pub originator_conversation_id: String,
+    pub conversation_id: String,
+    pub transaction_id: String,
+    pub phone_number: String,
+    pub amount: i64,
+    pub result_url: String,
+    pub timeout_url: String,
+    pub scenario: MockScenario,
+    pub completed_at: chrono::DateTime<chrono::Utc>,
+}
+
+struct MockState {
+    consumer_key: String,
+    consumer_secret: String,
+    shortcode: String,
+    passkey: String,
+    b2c_shortcode: String,
+    initiator_name: String,
+    security_credential: String,
+    tokens: Vec<String>,
+    token_requests: u32,
+    scenarios: HashMap<String, MockScenario>,
+    /// Queries answered "still processing" before each push resolves.
+    pending_queries: u32,
+    pushes: Vec<MockPush>,
+    payouts: Vec<MockPayout>,
+}
+
+/// A stand-in for Daraja on a local port: OAuth, STK push, STK query and B2C payments, with
+/// checks on credentials, tokens and passwords like the real thing. Used by the tests and by
+/// `MPESA_ENVIRONMENT=mock`.
+pub struct MockDaraja {
+    pub base_url: String,
+    // Inspected and steered by the tests
+    #[cfg_attr(not(test), allow(dead_code))]
+    state: Arc<Mutex<MockState>>,
+    handle: ServerHandle,
+}
+
+impl MockDaraja {
+    /// Starts the server on a free port. Must be called inside an actix runtime.
+    pub async fn start() -> std::io::Result<Self> {
+        let template = MpesaConfig::mock("");
+        let state = Arc::new(Mutex::new(MockState {
+            consumer_key: template.consumer_key,
+            consumer_secret: template.consumer_secret,
+            shortcode: template.shortcode,
+            passkey: template.passkey,
+            b2c_shortcode: template.b2c_shortcode,
+            initiator_name: template.initiator_name,
+            security_credential: template.security_credential,
+            tokens: Vec::new(),
+            token_requests: 0,
+            scenarios: HashMap::new(),
+            pending_queries: 0,
+            pushes: Vec::new(),
+            payouts: Vec::new(),
+        }));
+
+        let data = web::Data::from(state.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/oauth/v1/generate", web::get().to(generate_token))
+                .route("/mpesa/stkpush/v1/processrequest", web::post().to(stk_push))
+                .route("/mpesa/stkpushquery/v1/query", web::post().to(stk_query))
+                .route("/mpesa/b2c/v3/paymentrequest", web::post().to(b2c_payment))
+                // Not part of Daraja: what the organisation portal would export for this shortcode
+                .route("/mock/statement", web::get().to(statement))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))?;
+
+        let base_url = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        Ok(MockDaraja { base_url, state, handle })
+    }
+
+    /// A client configured for this server. Its pushes and payouts carry no callback URLs.
+    #[cfg(test)]
+    pub fn client(&self) -> crate::services::mpesa::MpesaClient {
+        let mut config = MpesaConfig::mock(&self.base_url);
+        config.callback_url = String::new();
+        config.b2c_result_url = String::new();
+        config.b2c_timeout_url = String::new();
+        crate::services::mpesa::MpesaClient::new(config).expect("mock client")
+    }
+
+    /// Pushes and payouts to `phone_number` (in `2547XXXXXXXX` form) end with `scenario`.
+    #[cfg(test)]
+    pub fn set_scenario(&self, phone_number: &str, scenario: MockScenario) {
+        self.state.lock().unwrap().scenarios.insert(phone_number.to_string(), scenario);
+    }
+
+    #[cfg(test)]
+    pub fn set_pending_queries(&self, count: u32) {
+        self.state.lock().unwrap().pending_queries = count;
+    }
+
+    #[cfg(test)]
+    pub fn token_requests(&self) -> u32 {
+        self.state.lock().unwrap().token_requests
+    }
+
+    /// Invalidates every token handed out so far, as if they had expired.
+    #[cfg(test)]
+    pub fn expire_tokens(&self) {
+        self.state.lock().unwrap().tokens.clear();
+    }
+
+    #[cfg(test)]
+    pub fn pushes(&self) -> Vec<MockPush> {
+        self.state.lock().unwrap().pushes.clone()
+    }
+
+    #[cfg(test)]
+    pub fn payouts(&self) -> Vec<MockPayout> {
+        self.state.lock().unwrap().payouts.clone()
+    }
+
+    /// The organisation statement of everything paid in and out so far, as the M-Pesa portal exports it.
+    #[cfg(test)]
+    pub fn statement_csv(&self) -> String {
+        statement_csv(&self.state.lock().unwrap())
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+    }
+}
+
+/// The body Daraja would post to the push's callback URL once the customer has answered.
+pub fn callback_body(push: &MockPush) -> serde_json::Value {
+    let (code, desc) = push.scenario.result();
+    let mut callback = json!({
+        "MerchantRequestID": push.merchant_request_id,
+        "CheckoutRequestID": push.checkout_request_id,
+        "ResultCode": code.parse::<i64>().unwrap_or(1),
+        "ResultDesc": desc,
+    });
+    if push.scenario == MockScenario::Success {
+        callback["CallbackMetadata"] = json!({
+            "Item": [
+                { "Name": "Amount", "Value": push.amount },
+                { "Name": "MpesaReceiptNumber", "Value": push.receipt_number },
+                { "Name": "Balance" },
+                { "Name": "TransactionDate", "Value": push.completed_at.format("%Y%m%d%H%M%S").to_string().parse::<i64>().unwrap_or(0) },
+                { "Name": "PhoneNumber", "Value": push.phone_number.parse::<i64>().unwrap_or(0) },
+            ]
+        });
+    }
+    json!({ "Body": { "stkCallback": callback } })
+}
+
+/// The body Daraja would post to the payout's result URL.
+pub fn b2c_result_body(payout: &MockPayout) -> serde_json::Value {
+    let (code, desc) = payout.scenario.b2c_result();
+    let mut result = json!({
+        "ResultType": 0,
+        "ResultCode": code,
+        "ResultDesc": desc,
+        "OriginatorConversationID": payout.originator_conversation_id,
+        "ConversationID": payout.conversation_id,
+        "TransactionID": payout.transaction_id,
+    });
+    if payout.scenario == MockScenario::Success {
+        result["ResultParameters"] = json!({
+            "ResultParameter": [
+                { "Key": "TransactionAmount", "Value": payout.amount },
+                { "Key": "TransactionReceipt", "Value": payout.transaction_id },
+                { "Key": "ReceiverPartyPublicName", "Value": format!("{} - Mock Customer", payout.phone_number) },
+                { "Key": "TransactionCompletedDateTime", "Value": chrono::Utc::now().format("%d.%m.%Y %H:%M:%S").to_string() },
+                { "Key": "B2CRecipientIsRegisteredCustomer", "Value": "Y" },
+            ]
+        });
+    }
+    json!({ "Result": result })
+}
+
+fn statement_csv(state: &MockState) -> String {
+    let eat = chrono::FixedOffset::east_opt(3 * 3600).expect("valid offset");
+    let mut rows: Vec<(chrono::DateTime<chrono::Utc>, String)> = Vec::new();
+    for push in state.pushes.iter().filter(|p| p.scenario == MockScenario::Success) {
+        rows.push((push.completed_at, format!(
+            "{},{},{},\"Pay Bill from {} - Mock Customer Acc. {}\",Completed,\"{}.00\",,,{},\"{} - Mock Customer\"",
+            push.receipt_number,
+            push.completed_at.with_timezone(&eat).format("%Y-%m-%d %H:%M:%S"),
+            push.completed_at.with_timezone(&eat).format("%Y-%m-%d %H:%M:%S"),
+            push.phone_number, push.account_reference, push.amount, "Pay Bill Online", push.phone_number
+        )));
+    }
+    for payout in state.payouts.iter().filter(|p| p.scenario == MockScenario::Success) {
+        rows.push((payout.completed_at, format!(
+            "{},{},{},\"Business Payment to {} - Mock Customer\",Completed,,\"-{}.00\",,{},\"{} - Mock Customer\"",
+            payout.transaction_id,
+            payout.completed_at.with_timezone(&eat).format("%Y-%m-%d %H:%M:%S"),
+            payout.completed_at.with_timezone(&eat).format("%Y-%m-%d %H:%M:%S"),
+            payout.phone_number, payout.amount, "Business Payment", payout.phone_number
+        )));
+    }
+    rows.sort_by_key(|(at, _)| *at);
+
+    let mut csv = format!(
+        "Account Holder:,MicroFund Africa\nShort Code:,{}\n\n\
+         Receipt No.,Completion Time,Initiation Time,Details,Transaction Status,Paid In,Withdrawn,Balance,Reason Type,Other Party Info\n",
+        state.shortcode
+    );
+    for (_, row) in rows {
+        csv.push_str(&row);
+        csv.push('\n');
+    }
+    csv
+}
+
+async fn statement(state: web::Data<Mutex<MockState>>) -> HttpResponse {
+    let state = state.lock().unwrap();
+    HttpResponse::Ok().content_type("text/csv").body(statement_csv(&state))
+}
+
+/// Posts the callback shortly after the push, like Daraja does once the PIN is entered.
+fn send_callback(push: &MockPush) {
+    deliver(push.callback_url.clone(), callback_body(push));
+}
+
+/// Reports a payout on its result URL, or on its timeout URL if it "timed out in the queue".
+fn send_b2c_result(payout: &MockPayout) {
+    if payout.scenario == MockScenario::Timeout {
+        let body = json!({
+            "OriginatorConversationID": payout.originator_conversation_id,
+            "ConversationID": payout.conversation_id,
+        });
+        deliver(payout.timeout_url.clone(), body);
+    } else {
+        deliver(payout.result_url.clone(), b2c_result_body(payout));
+    }
+}
+
+fn deliver(url: String, body: serde_json::Value) {
+    if url.is_empty() {
+        return;
+    }
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(500)).await;
+        if let Err(e) = reqwest::Client::new().post(&url).json(&body).send().await {
+            tracing::warn!("[MOCK M-PESA] Could not deliver callback to {}: {}", url, e);
+        }
+    });
+}
+
+fn daraja_error(status: actix_web::http::StatusCode, code: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "requestId": Uuid::new_v4().to_string(),
+        "errorCode": code,
+        "errorMessage": message,
+    }))
+}
+
+fn bearer_valid(state: &MockState, req: &HttpRequest) -> bool {
+    req.headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|token| state.tokens.iter().any(|t| t == token))
+        .unwrap_or(false)
+}
+
+fn password_valid(state: &MockState, body: &serde_json::Value) -> bool {
+    let timestamp = body["Timestamp"].as_str().unwrap_or_default();
+    let expected = BASE64.encode(format!("{}{}{}", state.shortcode, state.passkey, timestamp));
+    body["BusinessShortCode"].as_str() == Some(state.shortcode.as_str())
+        && body["Password"].as_str() == Some(expected.as_str())
+}
+
+async fn generate_token(state: web::Data<Mutex<MockState>>, req: HttpRequest) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    let expected = format!(
+        "Basic {}",
+        BASE64.encode(format!("{}:{}", state.consumer_key, state.consumer_secret))
+    );
+    let authorized = req.headers().get("Authorization").and_then(|h| h.to_str().ok()) == Some(expected.as_str());
+    if !authorized {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.008.01", "Invalid Authentication passed");
+    }
+
+    state.token_requests += 1;
+    let token = Uuid::new_v4().simple().to_string();
+    state.tokens.push(token.clone());
+    HttpResponse::Ok().json(json!({ "access_token": token, "expires_in": "3599" }))
+}
+
+async fn stk_push(
+    state: web::Data<Mutex<MockState>>,
+    req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if !bearer_valid(&state, &req) {
+        return daraja_error(actix_web::http::StatusCode::UNAUTHORIZED, "404.001.03", "Invalid Access Token");
+    }
+    if !password_valid(&state, &body) {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.002.02", "Bad Request - Invalid Password");
+    }
+    let amount = body["Amount"].as_i64().unwrap_or(0);
+    if amount < 1 {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.002.02", "Bad Request - Invalid Amount");
+    }
+
+    let phone = body["PhoneNumber"].as_str().unwrap_or_default().to_string();
+    let scenario = state
+        .scenarios
+        .get(&phone)
+        .copied()
+        .or_else(|| MockScenario::for_test_number(&phone))
+        .unwrap_or(MockScenario::Success);
+    let checkout_request_id = format!("ws_CO_{}", Uuid::new_v4().simple());
+    let merchant_request_id = format!("{}-1", Uuid::new_v4().simple());
+
+    let push = MockPush {
+        checkout_request_id: checkout_request_id.clone(),
+        merchant_request_id: merchant_request_id.clone(),
+        phone_number: phone,
+        amount,
+        account_reference: body["AccountReference"].as_str().unwrap_or_default().to_string(),
+        callback_url: body["CallBackURL"].as_str().unwrap_or_default().to_string(),
+        scenario,
+        receipt_number: Uuid::new_v4().simple().to_string().to_uppercase().chars().take(10).collect(),
+        completed_at: chrono::Utc::now(),
+        queries: 0,
+    };
+    tracing::info!(
+        "[MOCK M-PESA] STK push {} of KES {} to {} for '{}' will end as {:?} (callback {})",
+        push.checkout_request_id, push.amount, push.phone_number, push.account_reference, push.scenario, push.callback_url
+    );
+    send_callback(&push);
+    state.pushes.push(push);
+
+    HttpResponse::Ok().json(json!({
+        "MerchantRequestID": merchant_request_id,
+        "CheckoutRequestID": checkout_request_id,
+        "ResponseCode": "0",
+        "ResponseDescription": "Success. Request accepted for processing",
+        "CustomerMessage": "Success. Request accepted for processing",
+    }))
+}
+
+async fn b2c_payment(
+    state: web::Data<Mutex<MockState>>,
+    req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if !bearer_valid(&state, &req) {
+        return daraja_error(actix_web::http::StatusCode::UNAUTHORIZED, "404.001.03", "Invalid Access Token");
+    }
+    let initiator_valid = body["InitiatorName"].as_str() == Some(state.initiator_name.as_str())
+        && body["SecurityCredential"].as_str() == Some(state.security_credential.as_str());
+    if !initiator_valid {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.002.02", "Bad Request - Invalid Initiator");
+    }
+    if body["PartyA"].as_str() != Some(state.b2c_shortcode.as_str()) {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.002.02", "Bad Request - Invalid PartyA");
+    }
+    let amount = body["Amount"].as_i64().unwrap_or(0);
+    if amount < 1 {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.002.02", "Bad Request - Invalid Amount");
+    }
+    let Some(originator_conversation_id) = body["OriginatorConversationID"].as_str().filter(|id| !id.is_empty()) else {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.002.02", "Bad Request - Invalid OriginatorConversationID");
+    };
+
+    let phone = body["PartyB"].as_str().unwrap_or_default().to_string();
+    let scenario = state
+        .scenarios
+        .get(&phone)
+        .copied()
+        .or_else(|| MockScenario::for_test_number(&phone))
+        .unwrap_or(MockScenario::Success);
+    let payout = MockPayout {
+        originator_conversation_id: originator_conversation_id.to_string(),
+        conversation_id: format!("AG_{}", Uuid::new_v4().simple()),
+        transaction_id: Uuid::new_v4().simple().to_string().to_uppercase().chars().take(10).collect(),
+        phone_number: phone,
+        amount,
+        result_url: body["ResultURL"].as_str().unwrap_or_default().to_string(),
+        timeout_url: body["QueueTimeOutURL"].as_str().unwrap_or_default().to_string(),
+        scenario,
+        completed_at: chrono::Utc::now(),
+    };
+    tracing::info!(
+        "[MOCK M-PESA] B2C payment {} of KES {} to {} will end as {:?} (result {})",
+        payout.conversation_id, payout.amount, payout.phone_number, payout.scenario, payout.result_url
+    );
+    send_b2c_result(&payout);
+
+    let response = json!({
+        "ConversationID": payout.conversation_id,
+        "OriginatorConversationID": payout.originator_conversation_id,
+        "ResponseCode": "0",
+        "ResponseDescription": "Accept the service request successfully.",
+    });
+    state.payouts.push(payout);
+    HttpResponse::Ok().json(response)
+}
+
+async fn stk_query(
+    state: web::Data<Mutex<MockState>>,
+    req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if !bearer_valid(&state, &req) {
+        return daraja_error(actix_web::http::StatusCode::UNAUTHORIZED, "404.001.03", "Invalid Access Token");
+    }
+    if !password_valid(&state, &body) {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.002.02", "Bad Request - Invalid Password");
+    }
+
+    let pending_queries = state.pending_queries;
+    let checkout_request_id = body["CheckoutRequestID"].as_str().unwrap_or_default();
+    let Some(push) = state.pushes.iter_mut().find(|p| p.checkout_request_id == checkout_request_id) else {
+        return daraja_error(actix_web::http::StatusCode::BAD_REQUEST, "400.002.02", "Bad Request - Invalid CheckoutRequestID");
+    };
+
+    push.queries += 1;
+    if push.queries <= pending_queries {
+        return daraja_error(
+            actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            "500.001.1001",
+            "The transaction is being processed",
+        );
+    }
+
+    let (code, desc) = push.scenario.result();
+    HttpResponse::Ok().json(json!({
+        "ResponseCode": "0",
+        "ResponseDescription": "The service request has been accepted successsfully",
+        "MerchantRequestID": Uuid::new_v4().simple().to_string(),
+        "CheckoutRequestID": push.checkout_request_id,
+        "ResultCode": code,
+        "ResultDesc": desc,
+    }))
+}
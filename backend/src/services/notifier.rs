@@ -0,0 +1,45 @@
+use std::sync::Arc;
+use crate::models::User;
+use crate::services::email::EmailProvider;
+use crate::services::sms::SmsProvider;
+
+/// Where an account message for a member is delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Contact {
+    /// In the `2547XXXXXXXX` form.
+    Sms(String),
+    Email(String),
+}
+
+impl Contact {
+    /// The member's verified phone number, or else their email address.
+    pub fn for_user(user: &User) -> Option<Contact> {
+        user.verified_phone()
+            .map(|phone| Contact::Sms(phone.to_string()))
+            .or_else(|| user.email.clone().map(Contact::Email))
+    }
+
+    pub fn channel(&self) -> &'static str {
+        match self {
+            Contact::Sms(_) => "sms",
+            Contact::Email(_) => "email",
+        }
+    }
+}
+
+/// Sends account messages, such as password reset links, by SMS or email.
+#[derive(Clone)]
+pub struct Notifier {
+    pub sms: Arc<dyn SmsProvider>,
+    pub email: Arc<dyn EmailProvider>,
+}
+
+impl Notifier {
+    /// `subject` is only used for email; a text message is just the body.
+    pub async fn send(&self, to: &Contact, subject: &str, body: &str) -> Result<(), String> {
+        match to {
+            Contact::Sms(phone) => self.sms.send(phone, body).await,
+            Contact::Email(address) => self.email.send(address, subject, body).await,
+        }
+    }
+}
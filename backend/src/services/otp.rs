@@ -0,0 +1,164 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, PasswordHash};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::OtpPurpose;
+use crate::services::sms::SmsProvider;
+
+/// Digits in a code.
+pub const CODE_LENGTH: usize = 6;
+/// A code can be used this long after it was sent.
+pub const CODE_TTL_MINUTES: i64 = 10;
+/// Wrong guesses before a code stops working.
+pub const MAX_ATTEMPTS: i32 = 5;
+/// A new code for the same number and purpose is not sent sooner than this.
+pub const RESEND_AFTER_SECONDS: i64 = 60;
+
+/// One-time codes sent by SMS to prove a member holds a phone number. Codes are stored
+/// hashed, expire after a few minutes and stop working after a few wrong guesses.
+pub struct OtpService;
+
+impl OtpService {
+    /// A random code of `CODE_LENGTH` digits.
+    pub fn generate_code() -> String {
+        let modulus = 10u64.pow(CODE_LENGTH as u32);
+        // Rejection sampling keeps every code equally likely
+        let limit = u64::MAX - u64::MAX % modulus;
+        loop {
+            let n = OsRng.next_u64();
+            if n < limit {
+                return format!("{:0width$}", n % modulus, width = CODE_LENGTH);
+            }
+        }
+    }
+
+    pub fn hash_code(code: &str) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(code.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn code_matches(code: &str, code_hash: &str) -> bool {
+        PasswordHash::new(code_hash)
+            .map(|hash| Argon2::default().verify_password(code.trim().as_bytes(), &hash).is_ok())
+            .unwrap_or(false)
+    }
+
+    /// Sends a new code to `phone_number`, replacing any earlier one for the same purpose.
+    pub async fn send(
+        pool: &PgPool,
+        sms: &dyn SmsProvider,
+        phone_number: &str,
+        purpose: OtpPurpose,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let (recent,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS (SELECT 1 FROM otp_codes WHERE phone_number = $1 AND purpose = $2 AND created_at > $3)"
+        )
+        .bind(phone_number)
+        .bind(purpose)
+        .bind(now - Duration::seconds(RESEND_AFTER_SECONDS))
+        .fetch_one(pool)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        if recent {
+            return Err(AppError::BadRequest("A code was just sent; wait a minute before asking for another".to_string()));
+        }
+
+        let code = Self::generate_code();
+        let code_hash = Self::hash_code(&code).map_err(|e| {
+            tracing::error!("Failed to hash one-time code: {}", e);
+            AppError::InternalServerError
+        })?;
+
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+        // Only the newest code works
+        sqlx::query("UPDATE otp_codes SET consumed_at = $1 WHERE phone_number = $2 AND purpose = $3 AND consumed_at IS NULL")
+            .bind(now)
+            .bind(phone_number)
+            .bind(purpose)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        sqlx::query(
+            "INSERT INTO otp_codes (phone_number, purpose, code_hash, created_at, expires_at) VALUES ($1, $2, $3, $4, $5)"
+        )
+        .bind(phone_number)
+        .bind(purpose)
+        .bind(&code_hash)
+        .bind(now)
+        .bind(now + Duration::minutes(CODE_TTL_MINUTES))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to record one-time code: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+        let message = format!(
+            "Your MicroFund code is {}. It expires in {} minutes. Never share it with anyone.",
+            code, CODE_TTL_MINUTES
+        );
+        sms.send(phone_number, &message).await.map_err(|e| {
+            tracing::error!("Failed to send one-time code to {}: {}", phone_number, e);
+            AppError::InternalServerError
+        })?;
+
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+        Ok(())
+    }
+
+    /// Uses up the code sent to `phone_number` if `code` is right. A wrong code counts
+    /// against the attempts left; `BadRequest` either way it fails.
+    pub async fn verify(
+        pool: &PgPool,
+        phone_number: &str,
+        purpose: OtpPurpose,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+
+        let pending: Option<(Uuid, String, i32, DateTime<Utc>)> = sqlx::query_as(
+            "SELECT id, code_hash, attempts, expires_at FROM otp_codes
+             WHERE phone_number = $1 AND purpose = $2 AND consumed_at IS NULL
+             ORDER BY created_at DESC LIMIT 1 FOR UPDATE"
+        )
+        .bind(phone_number)
+        .bind(purpose)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+
+        let expired = || AppError::BadRequest("The code has expired; ask for a new one".to_string());
+        let (id, code_hash, attempts, expires_at) = pending.ok_or_else(expired)?;
+        if now >= expires_at || attempts >= MAX_ATTEMPTS {
+            return Err(expired());
+        }
+
+        if !Self::code_matches(code, &code_hash) {
+            sqlx::query("UPDATE otp_codes SET attempts = attempts + 1 WHERE id = $1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+            tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+            tracing::warn!("Wrong one-time code for {} ({} of {})", phone_number, attempts + 1, MAX_ATTEMPTS);
+            return Err(AppError::BadRequest("The code is not correct".to_string()));
+        }
+
+        sqlx::query("UPDATE otp_codes SET consumed_at = $1 WHERE id = $2")
+            .bind(now)
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+        Ok(())
+    }
+}
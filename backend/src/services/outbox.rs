@@ -0,0 +1,221 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+use crate::models::{LoanStatus, OutboxStatus, User};
+use crate::services::disbursements::DisbursementService;
+use crate::services::mpesa::MpesaClient;
+use crate::services::notifier::{Contact, Notifier};
+use crate::services::refunds::RefundService;
+
+/// Delivery attempts before an event is dead-lettered.
+pub const MAX_ATTEMPTS: i32 = 10;
+const RETRY_BASE_SECONDS: i64 = 30;
+const MAX_RETRY_DELAY_MINUTES: i64 = 60;
+/// How long a dispatcher may hold an event before another may take it over.
+const LEASE_SECONDS: i64 = 300;
+/// Events delivered per run of the dispatch job.
+const DISPATCH_BATCH: i64 = 100;
+/// Pending events older than this show up as stuck.
+const STUCK_AFTER_MINUTES: i64 = 10;
+
+/// A side effect of a committed change, delivered after the commit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutboxEvent {
+    /// Start the M-Pesa payout of a funded loan.
+    LoanPayout { loan_id: Uuid },
+    /// Send the M-Pesa payout of a refund.
+    RefundPayout { refund_id: Uuid },
+    /// Tell a member about money moving on their account, by SMS or else email.
+    NotifyMember { user_id: Uuid, subject: String, body: String },
+}
+
+impl OutboxEvent {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            OutboxEvent::LoanPayout { .. } => "loan_payout",
+            OutboxEvent::RefundPayout { .. } => "refund_payout",
+            OutboxEvent::NotifyMember { .. } => "notify_member",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct OutboxEntry {
+    pub id: Uuid,
+    pub event_type: String,
+    pub payload: Json<OutboxEvent>,
+    pub status: OutboxStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+const OUTBOX_COLUMNS: &str = "id, event_type, payload, status, attempts, next_attempt_at, last_error, created_at, delivered_at";
+
+/// Side effects that must not be lost. `enqueue` writes them on the transaction of the change
+/// that causes them, so there is never one without the other; the dispatcher then delivers
+/// them with retries and dead-letters those that keep failing. Handlers must tolerate being
+/// run more than once for the same event.
+pub struct Outbox;
+
+impl Outbox {
+    pub async fn enqueue<'e, E: PgExecutor<'e>>(executor: E, event: &OutboxEvent) -> Result<Uuid, String> {
+        let (id,): (Uuid,) = sqlx::query_as("INSERT INTO outbox_events (event_type, payload) VALUES ($1, $2) RETURNING id")
+            .bind(event.event_type())
+            .bind(Json(event))
+            .fetch_one(executor)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(id)
+    }
+
+    /// Wait before the next try after `attempt` failed ones: 30 seconds, doubling up to an hour.
+    /// `None` once every attempt is used.
+    pub fn retry_delay(attempt: i32) -> Option<Duration> {
+        if attempt >= MAX_ATTEMPTS {
+            return None;
+        }
+        let delay = Duration::seconds(RETRY_BASE_SECONDS << (attempt - 1).clamp(0, 16));
+        Some(delay.min(Duration::minutes(MAX_RETRY_DELAY_MINUTES)))
+    }
+
+    /// Dead events, and pending ones that have failed or waited too long, oldest first.
+    pub async fn stuck(pool: &PgPool, now: DateTime<Utc>) -> Result<Vec<OutboxEntry>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM outbox_events
+             WHERE status = $1 OR (status = $2 AND (attempts > 0 OR created_at < $3))
+             ORDER BY created_at LIMIT 500",
+            OUTBOX_COLUMNS
+        ))
+        .bind(OutboxStatus::Dead)
+        .bind(OutboxStatus::Pending)
+        .bind(now - Duration::minutes(STUCK_AFTER_MINUTES))
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Puts a dead event back in line with a fresh set of attempts. None if there is no dead event with this id.
+    pub async fn redeliver<'e, E: PgExecutor<'e>>(executor: E, id: Uuid, now: DateTime<Utc>) -> Result<Option<OutboxEntry>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "UPDATE outbox_events SET status = $2, attempts = 0, next_attempt_at = $3, locked_until = NULL
+             WHERE id = $1 AND status = $4 RETURNING {}",
+            OUTBOX_COLUMNS
+        ))
+        .bind(id)
+        .bind(OutboxStatus::Pending)
+        .bind(now)
+        .bind(OutboxStatus::Dead)
+        .fetch_optional(executor)
+        .await
+    }
+}
+
+/// Delivers outbox events to the services that carry them out.
+#[derive(Clone)]
+pub struct OutboxDispatcher {
+    pub mpesa: MpesaClient,
+    pub notifier: Notifier,
+}
+
+impl OutboxDispatcher {
+    /// Delivers every event that is due. Returns how many were delivered.
+    pub async fn run_due(&self, pool: &PgPool, now: DateTime<Utc>) -> Result<u64, String> {
+        let due: Vec<OutboxEntry> = sqlx::query_as(&format!(
+            "UPDATE outbox_events SET locked_until = $2
+             WHERE id IN (
+                 SELECT id FROM outbox_events
+                 WHERE status = $3 AND next_attempt_at <= $1 AND (locked_until IS NULL OR locked_until < $1)
+                 ORDER BY created_at LIMIT $4 FOR UPDATE SKIP LOCKED
+             )
+             RETURNING {}",
+            OUTBOX_COLUMNS
+        ))
+        .bind(now)
+        .bind(now + Duration::seconds(LEASE_SECONDS))
+        .bind(OutboxStatus::Pending)
+        .bind(DISPATCH_BATCH)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut delivered = 0;
+        for entry in due {
+            if self.attempt(pool, &entry, now).await? == OutboxStatus::Delivered {
+                delivered += 1;
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Delivers a claimed event and records the outcome. Returns its new status.
+    async fn attempt(&self, pool: &PgPool, entry: &OutboxEntry, now: DateTime<Utc>) -> Result<OutboxStatus, String> {
+        let attempt = entry.attempts + 1;
+        let (status, next_attempt_at, error) = match self.deliver(pool, &entry.payload, now).await {
+            Ok(()) => (OutboxStatus::Delivered, entry.next_attempt_at, None),
+            Err(e) => match Outbox::retry_delay(attempt) {
+                Some(delay) => {
+                    tracing::warn!("[OUTBOX] Attempt {} at {} event {} failed: {}", attempt, entry.event_type, entry.id, e);
+                    (OutboxStatus::Pending, now + delay, Some(e))
+                }
+                None => {
+                    tracing::error!("[OUTBOX] Giving up on {} event {} after {} attempts: {}", entry.event_type, entry.id, attempt, e);
+                    (OutboxStatus::Dead, entry.next_attempt_at, Some(e))
+                }
+            },
+        };
+
+        sqlx::query(
+            "UPDATE outbox_events
+             SET status = $2, attempts = $3, next_attempt_at = $4, last_error = $5, locked_until = NULL,
+                 delivered_at = CASE WHEN $2 = 'delivered' THEN $6 END
+             WHERE id = $1"
+        )
+        .bind(entry.id)
+        .bind(status)
+        .bind(attempt)
+        .bind(next_attempt_at)
+        .bind(error)
+        .bind(now)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(status)
+    }
+
+    async fn deliver(&self, pool: &PgPool, event: &OutboxEvent, now: DateTime<Utc>) -> Result<(), String> {
+        match event {
+            OutboxEvent::LoanPayout { loan_id } => {
+                // Paid out, or handed back to the marketplace, since the event was written
+                let (status,): (LoanStatus,) = sqlx::query_as("SELECT status FROM loans WHERE id = $1")
+                    .bind(loan_id)
+                    .fetch_one(pool)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if status != LoanStatus::Funded {
+                    return Ok(());
+                }
+                // Failed attempts from here on are retried by the disbursement job
+                DisbursementService::start(pool, &self.mpesa, *loan_id).await.map(|_| ())
+            }
+            // A payout already sent is not sent again; turned-down ones are retried by the refund job
+            OutboxEvent::RefundPayout { refund_id } => RefundService::send(pool, &self.mpesa, *refund_id, now).await.map(|_| ()),
+            OutboxEvent::NotifyMember { user_id, subject, body } => {
+                let user: User = sqlx::query_as("SELECT * FROM users WHERE id = $1")
+                    .bind(user_id)
+                    .fetch_one(pool)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let Some(contact) = Contact::for_user(&user) else {
+                    tracing::info!("[OUTBOX] User {} has no phone or email; '{}' not sent", user_id, subject);
+                    return Ok(());
+                };
+                self.notifier.send(&contact, subject, body).await
+            }
+        }
+    }
+}
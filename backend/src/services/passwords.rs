@@ -0,0 +1,222 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, PasswordHash};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::{SessionRevocation, User};
+use crate::services::notifier::{Contact, Notifier};
+use crate::services::sessions::SessionService;
+
+/// A reset link can be used this long after it was sent.
+pub const RESET_TTL_MINUTES: i64 = 30;
+/// A new reset link for the same member is not sent sooner than this.
+pub const RESET_RESEND_AFTER_SECONDS: i64 = 60;
+
+/// Where the reset links sent to members point.
+#[derive(Debug, Clone)]
+pub struct PasswordResetConfig {
+    /// The frontend's reset page; the token is added as `?token=`.
+    pub reset_url: String,
+}
+
+impl PasswordResetConfig {
+    /// `PASSWORD_RESET_URL`, the frontend's `/reset-password` page by default.
+    pub fn from_env() -> Result<Self, String> {
+        let reset_url = std::env::var("PASSWORD_RESET_URL")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| "http://localhost:8081/reset-password".to_string());
+        if !reset_url.starts_with("http://") && !reset_url.starts_with("https://") {
+            return Err("PASSWORD_RESET_URL must be an http(s) URL".to_string());
+        }
+        Ok(Self { reset_url })
+    }
+
+    pub fn link(&self, token: &str) -> String {
+        format!("{}?token={}", self.reset_url, token)
+    }
+}
+
+/// Password hashing, resets of forgotten passwords and changes of known ones. A new password
+/// signs the member out everywhere else.
+pub struct PasswordService;
+
+impl PasswordService {
+    pub fn hash(password: &str) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn matches(password: &str, password_hash: &str) -> bool {
+        PasswordHash::new(password_hash)
+            .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+            .unwrap_or(false)
+    }
+
+    /// Sends `user` a single-use link to set a new password, by SMS to their verified phone
+    /// or else by email. Does nothing if a link was just sent or they cannot be reached.
+    pub async fn request_reset(
+        pool: &PgPool,
+        notifier: &Notifier,
+        config: &PasswordResetConfig,
+        user: &User,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let Some(contact) = Contact::for_user(user) else {
+            tracing::warn!("User {} asked for a password reset but has no phone or email", user.id);
+            return Ok(());
+        };
+
+        let (recent,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS (SELECT 1 FROM password_resets WHERE user_id = $1 AND created_at > $2)"
+        )
+        .bind(user.id)
+        .bind(now - Duration::seconds(RESET_RESEND_AFTER_SECONDS))
+        .fetch_one(pool)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        if recent {
+            tracing::info!("Password reset for user {} asked for again too soon; not sent", user.id);
+            return Ok(());
+        }
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+        // Only the newest link works
+        sqlx::query("UPDATE password_resets SET used_at = $1 WHERE user_id = $2 AND used_at IS NULL")
+            .bind(now)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        sqlx::query(
+            "INSERT INTO password_resets (user_id, token_hash, channel, created_at, expires_at) VALUES ($1, $2, $3, $4, $5)"
+        )
+        .bind(user.id)
+        .bind(SessionService::hash_token(&token))
+        .bind(contact.channel())
+        .bind(now)
+        .bind(now + Duration::minutes(RESET_TTL_MINUTES))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to record password reset: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+        let message = format!(
+            "Reset your MicroFund password here: {} The link expires in {} minutes. If you did not ask for this, ignore this message.",
+            config.link(&token),
+            RESET_TTL_MINUTES
+        );
+        notifier
+            .send(&contact, "Reset your MicroFund password", &message)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to send password reset to user {}: {}", user.id, e);
+                AppError::InternalServerError
+            })?;
+
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+        Ok(())
+    }
+
+    /// Sets a new password with a reset token and ends all of the member's sessions.
+    /// Returns the member's id.
+    pub async fn reset(pool: &PgPool, token: &str, new_password: &str, now: DateTime<Utc>) -> Result<Uuid, AppError> {
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+
+        let found: Option<(Uuid, Uuid, DateTime<Utc>)> = sqlx::query_as(
+            "SELECT id, user_id, expires_at FROM password_resets WHERE token_hash = $1 AND used_at IS NULL FOR UPDATE"
+        )
+        .bind(SessionService::hash_token(token))
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        let invalid = || AppError::BadRequest("This reset link is not valid or has expired; ask for a new one".to_string());
+        let (reset_id, user_id, expires_at) = found.ok_or_else(invalid)?;
+        if now >= expires_at {
+            return Err(invalid());
+        }
+
+        let password_hash = Self::hash(new_password).map_err(|e| {
+            tracing::error!("Failed to hash password: {}", e);
+            AppError::InternalServerError
+        })?;
+        // Proving access to the phone or inbox also lifts a lockout
+        sqlx::query("UPDATE users SET password_hash = $1, failed_logins = 0, locked_until = NULL WHERE id = $2")
+            .bind(&password_hash)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        sqlx::query("UPDATE password_resets SET used_at = $1 WHERE id = $2")
+            .bind(now)
+            .bind(reset_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        // Whoever knew the old password is signed out
+        let ended = SessionService::revoke_all(&mut tx, user_id, None, SessionRevocation::PasswordChange, now)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+        tracing::info!("Password of user {} reset; {} sessions ended", user_id, ended);
+        Ok(user_id)
+    }
+
+    /// Replaces a member's password after checking the current one. Ends every session but
+    /// `session_id`, the one the change was made from.
+    pub async fn change(
+        pool: &PgPool,
+        user_id: Uuid,
+        session_id: Uuid,
+        current_password: &str,
+        new_password: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+
+        let (current_hash,): (Option<String>,) = sqlx::query_as("SELECT password_hash FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        // Members who signed up by phone have no password to check; they set one with a reset link
+        let current_hash = current_hash.ok_or_else(|| {
+            AppError::BadRequest("Your account has no password yet; set one with a reset link".to_string())
+        })?;
+        if !Self::matches(current_password, &current_hash) {
+            return Err(AppError::BadRequest("The current password is not correct".to_string()));
+        }
+
+        let password_hash = Self::hash(new_password).map_err(|e| {
+            tracing::error!("Failed to hash password: {}", e);
+            AppError::InternalServerError
+        })?;
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+            .bind(&password_hash)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        let ended = SessionService::revoke_all(&mut tx, user_id, Some(session_id), SessionRevocation::PasswordChange, now)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+        tracing::info!("Password of user {} changed; {} other sessions ended", user_id, ended);
+        Ok(())
+    }
+}
@@ -0,0 +1,619 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::{LoanStatus, Money, OverpaymentDestination, Payment, PaymentPurpose, PaymentStatus};
+use crate::services::blockchain::BlockchainService;
+use crate::services::ledger::LedgerService;
+use crate::services::loan_lifecycle::LoanLifecycle;
+use crate::services::loan_review::LoanReview;
+use crate::services::mpesa::{MpesaClient, MpesaError, StkCallback, StkPushRequest, StkStatus};
+use crate::services::outbox::{Outbox, OutboxEvent};
+use crate::services::refunds::{NewRefund, RefundService};
+use crate::services::repayments::{AllocationOrder, NewRepayment, RepaymentService};
+use crate::services::scoring::ScoringWeights;
+
+const PAYMENT_COLUMNS: &str = "id, user_id, purpose, savings_id, loan_id, overpayment_destination, amount, phone_number, \
+    status, checkout_request_id, merchant_request_id, result_code, result_desc, mpesa_receipt_number, confirmed_at, created_at, \
+    updated_at, settled_at";
+
+/// Pending payments nobody could resolve within this long are given up on.
+const EXPIRE_AFTER_HOURS: i64 = 24;
+
+/// A completed statement line `l` that could be payment `p`: its amount from its phone, paid
+/// after it started, and not yet claimed by a payment or repayment.
+const MATCHING_STATEMENT_LINE: &str = "LOWER(l.transaction_status) = 'completed' AND l.paid_in = p.amount \
+    AND l.phone_number = p.phone_number AND l.completed_at >= p.created_at \
+    AND NOT EXISTS (SELECT 1 FROM payments c WHERE UPPER(c.mpesa_receipt_number) = UPPER(l.receipt_number)) \
+    AND NOT EXISTS (SELECT 1 FROM loan_repayments r WHERE UPPER(r.mpesa_receipt_number) = UPPER(l.receipt_number))";
+
+/// How an STK push ended, from a callback or an STK query.
+#[derive(Debug, Clone)]
+pub struct PaymentResult {
+    pub status: StkStatus,
+    pub result_code: Option<String>,
+    pub result_desc: String,
+    pub receipt_number: Option<String>,
+}
+
+/// A payment to collect from a member with an STK push.
+#[derive(Debug, Clone)]
+pub struct NewPayment {
+    pub user_id: Uuid,
+    pub purpose: PaymentPurpose,
+    pub savings_id: Option<Uuid>,
+    pub loan_id: Option<Uuid>,
+    pub overpayment_destination: Option<OverpaymentDestination>,
+    pub amount: Money,
+    pub phone_number: String,
+}
+
+impl NewPayment {
+    pub fn savings_deposit(user_id: Uuid, savings_id: Uuid, amount: Money, phone_number: &str) -> Self {
+        NewPayment {
+            user_id,
+            purpose: PaymentPurpose::SavingsDeposit,
+            savings_id: Some(savings_id),
+            loan_id: None,
+            overpayment_destination: None,
+            amount,
+            phone_number: phone_number.to_string(),
+        }
+    }
+
+    /// Anything paid beyond what is outstanding goes to `overpayment`, and to the goal
+    /// `savings_id` (the borrower's most recent one when `None`) if that is savings.
+    pub fn loan_repayment(
+        user_id: Uuid,
+        loan_id: Uuid,
+        amount: Money,
+        phone_number: &str,
+        overpayment: OverpaymentDestination,
+        savings_id: Option<Uuid>,
+    ) -> Self {
+        NewPayment {
+            user_id,
+            purpose: PaymentPurpose::LoanRepayment,
+            savings_id,
+            loan_id: Some(loan_id),
+            overpayment_destination: Some(overpayment),
+            amount,
+            phone_number: phone_number.to_string(),
+        }
+    }
+
+    /// The whole amount of the loan, paid in by the lender funding it.
+    pub fn loan_funding(user_id: Uuid, loan_id: Uuid, amount: Money, phone_number: &str) -> Self {
+        NewPayment {
+            user_id,
+            purpose: PaymentPurpose::LoanFunding,
+            savings_id: None,
+            loan_id: Some(loan_id),
+            overpayment_destination: None,
+            amount,
+            phone_number: phone_number.to_string(),
+        }
+    }
+}
+
+/// Incoming M-Pesa payments. Money only moves once Daraja confirms the payment, and
+/// settling is idempotent: a payment leaves `pending` exactly once.
+pub struct PaymentService;
+
+impl PaymentService {
+    /// Records a pending payment and sends the STK push for it. Nothing is credited until
+    /// M-Pesa confirms it. The caller has already checked that the goal or loan belongs to
+    /// the member.
+    pub async fn start(pool: &PgPool, mpesa: &MpesaClient, new: &NewPayment) -> Result<Payment, AppError> {
+        let invalid = |e: MpesaError| AppError::BadRequest(e.to_string());
+        let phone = MpesaClient::normalize_phone(&new.phone_number).map_err(invalid)?;
+        MpesaClient::whole_shillings(new.amount).map_err(invalid)?;
+
+        // The row exists before the push so a fast callback always finds it
+        let payment: Payment = sqlx::query_as(&format!(
+            "INSERT INTO payments (user_id, purpose, savings_id, loan_id, overpayment_destination, amount, phone_number)
+             VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING {}",
+            PAYMENT_COLUMNS
+        ))
+        .bind(new.user_id)
+        .bind(new.purpose)
+        .bind(new.savings_id)
+        .bind(new.loan_id)
+        .bind(new.overpayment_destination)
+        .bind(new.amount)
+        .bind(&phone)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            if e.as_database_error().is_some_and(|d| d.is_unique_violation()) {
+                return AppError::Conflict("Another payment for this loan is in progress".to_string());
+            }
+            tracing::error!("Failed to record payment: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+        let push = mpesa
+            .stk_push(&StkPushRequest {
+                phone_number: phone,
+                amount: new.amount,
+                account_reference: "MicroFund".to_string(),
+                description: match new.purpose {
+                    PaymentPurpose::SavingsDeposit => "Savings",
+                    PaymentPurpose::LoanRepayment => "Repayment",
+                    PaymentPurpose::LoanFunding => "Loan funding",
+                }
+                .to_string(),
+            })
+            .await;
+
+        let updated = match push {
+            Ok(response) => sqlx::query_as(&format!(
+                "UPDATE payments SET checkout_request_id = $2, merchant_request_id = $3, updated_at = NOW()
+                 WHERE id = $1 RETURNING {}",
+                PAYMENT_COLUMNS
+            ))
+            .bind(payment.id)
+            .bind(&response.checkout_request_id)
+            .bind(&response.merchant_request_id)
+            .fetch_one(pool)
+            .await,
+            Err(e) => {
+                tracing::error!("STK push for payment {} failed: {}", payment.id, e);
+                sqlx::query(
+                    "UPDATE payments SET status = $2, result_desc = $3, updated_at = NOW(), settled_at = NOW() WHERE id = $1"
+                )
+                .bind(payment.id)
+                .bind(PaymentStatus::Failed)
+                .bind(e.to_string())
+                .execute(pool)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+                return Err(match e {
+                    MpesaError::InvalidRequest(message) => AppError::BadRequest(message),
+                    _ => AppError::InternalServerError,
+                });
+            }
+        };
+
+        updated.map_err(|e| {
+            tracing::error!("Failed to store checkout of payment {}: {:?}", payment.id, e);
+            AppError::InternalServerError
+        })
+    }
+
+    /// Applies the outcome of an STK push. Payments that are no longer pending are returned
+    /// untouched, so repeated callbacks and polls are harmless. `None` if no payment has
+    /// this checkout request id. A repayment on a loan that no longer takes repayments goes
+    /// to its overpayment destination; one that cannot be applied at all is left pending,
+    /// with the error returned, for reconciliation to sort out. A confirmed payment
+    /// without a receipt is only marked confirmed; it stays pending until a statement line
+    /// gives the receipt.
+    pub async fn settle(
+        pool: &PgPool,
+        checkout_request_id: &str,
+        result: &PaymentResult,
+        order: &AllocationOrder,
+        weights: &ScoringWeights,
+    ) -> Result<Option<Payment>, String> {
+        if result.status == StkStatus::Pending {
+            return Self::find_by_checkout(pool, checkout_request_id).await;
+        }
+
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        let payment: Option<Payment> = sqlx::query_as(&format!(
+            "SELECT {} FROM payments WHERE checkout_request_id = $1 FOR UPDATE",
+            PAYMENT_COLUMNS
+        ))
+        .bind(checkout_request_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let Some(payment) = payment else {
+            return Ok(None);
+        };
+        if payment.status != PaymentStatus::Pending {
+            return Ok(Some(payment));
+        }
+        if result.status == StkStatus::Completed && result.receipt_number.is_none() {
+            let confirmed: Payment = sqlx::query_as(&format!(
+                "UPDATE payments
+                 SET confirmed_at = COALESCE(confirmed_at, NOW()), result_code = $2, result_desc = $3, updated_at = NOW()
+                 WHERE id = $1 RETURNING {}",
+                PAYMENT_COLUMNS
+            ))
+            .bind(payment.id)
+            .bind(&result.result_code)
+            .bind(&result.result_desc)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+            tx.commit().await.map_err(|e| e.to_string())?;
+            tracing::warn!("[M-PESA] Payment {} confirmed without a receipt; waiting for the statement", confirmed.id);
+            return Ok(Some(confirmed));
+        }
+
+        let status = if result.status == StkStatus::Completed {
+            match payment.purpose {
+                PaymentPurpose::SavingsDeposit => Self::credit_savings_deposit(&mut tx, &payment).await?,
+                PaymentPurpose::LoanRepayment => {
+                    Self::apply_repayment(&mut tx, &payment, result.receipt_number.clone(), order, weights).await?
+                }
+                PaymentPurpose::LoanFunding => Self::fund_loan(&mut tx, &payment).await?,
+            }
+            PaymentStatus::Completed
+        } else {
+            PaymentStatus::Failed
+        };
+
+        let settled: Payment = sqlx::query_as(&format!(
+            "UPDATE payments
+             SET status = $2, result_code = $3, result_desc = $4, mpesa_receipt_number = $5,
+                 updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 RETURNING {}",
+            PAYMENT_COLUMNS
+        ))
+        .bind(payment.id)
+        .bind(status)
+        .bind(&result.result_code)
+        .bind(&result.result_desc)
+        .bind(&result.receipt_number)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+        tracing::info!("[M-PESA] Payment {} {}", settled.id, settled.status.as_str());
+        Ok(Some(settled))
+    }
+
+    /// Handles a Daraja callback. The callback URL is public, so the outcome is confirmed
+    /// with an STK query before any money moves.
+    pub async fn handle_callback(
+        pool: &PgPool,
+        mpesa: &MpesaClient,
+        callback: &StkCallback,
+        order: &AllocationOrder,
+        weights: &ScoringWeights,
+    ) -> Result<Option<Payment>, String> {
+        let Some(payment) = Self::find_by_checkout(pool, &callback.checkout_request_id).await? else {
+            tracing::warn!("[M-PESA] Callback for unknown checkout {}", callback.checkout_request_id);
+            return Ok(None);
+        };
+        if payment.status != PaymentStatus::Pending {
+            return Ok(Some(payment));
+        }
+
+        let confirmed = mpesa
+            .stk_query(&callback.checkout_request_id)
+            .await
+            .map_err(|e| format!("Could not confirm checkout {}: {}", callback.checkout_request_id, e))?;
+        if confirmed.status != callback.status() {
+            tracing::warn!(
+                "[M-PESA] Callback for {} says {:?} but STK query says {:?}; going with the query",
+                callback.checkout_request_id, callback.status(), confirmed.status
+            );
+        }
+
+        if confirmed.status == StkStatus::Completed {
+            let paid = callback.amount();
+            let expected = MpesaClient::whole_shillings(payment.amount).map_err(|e| e.to_string())?;
+            if paid.is_some_and(|paid| paid != expected) {
+                // Left pending for reconciliation rather than crediting the wrong amount
+                return Err(format!(
+                    "Callback for payment {} reports KES {} paid, expected KES {}",
+                    payment.id, paid.unwrap_or_default(), expected
+                ));
+            }
+        }
+
+        let result = PaymentResult {
+            status: confirmed.status,
+            result_code: confirmed.result_code,
+            result_desc: confirmed.result_desc,
+            receipt_number: if confirmed.status == StkStatus::Completed { callback.receipt_number() } else { None },
+        };
+        Self::settle(pool, &callback.checkout_request_id, &result, order, weights).await
+    }
+
+    /// Looks up payments whose callback never came, then completes payments M-Pesa confirmed
+    /// without a receipt once an M-Pesa statement line supplies one. Returns how many were
+    /// settled. A confirmed payment is never expired while it waits for its receipt.
+    pub async fn resolve_stale(
+        pool: &PgPool,
+        mpesa: &MpesaClient,
+        order: &AllocationOrder,
+        weights: &ScoringWeights,
+        now: DateTime<Utc>,
+    ) -> Result<u64, String> {
+        let timeout = Duration::from_std(mpesa.pending_timeout()).map_err(|e| e.to_string())?;
+        let expire_before = now - Duration::hours(EXPIRE_AFTER_HOURS);
+
+        let stale: Vec<Payment> = sqlx::query_as(&format!(
+            "SELECT {} FROM payments WHERE status = $1 AND confirmed_at IS NULL AND created_at < $2 ORDER BY created_at LIMIT 100",
+            PAYMENT_COLUMNS
+        ))
+        .bind(PaymentStatus::Pending)
+        .bind(now - timeout)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut settled = 0;
+        for payment in stale {
+            let expired = payment.created_at.is_some_and(|at| at < expire_before);
+            let Some(checkout_request_id) = &payment.checkout_request_id else {
+                // Never acknowledged by Daraja, so there is nothing to look up
+                Self::expire(pool, payment.id, "The STK push was never accepted by M-Pesa").await?;
+                settled += 1;
+                continue;
+            };
+
+            let result = match mpesa.stk_query(checkout_request_id).await {
+                Ok(query) if query.status.is_final() => PaymentResult {
+                    receipt_number: match query.status {
+                        StkStatus::Completed => Self::statement_receipt(pool, &payment).await?,
+                        _ => None,
+                    },
+                    status: query.status,
+                    result_code: query.result_code,
+                    result_desc: query.result_desc,
+                },
+                Ok(_) if !expired => continue,
+                Err(e) if !expired => {
+                    tracing::warn!("[M-PESA] STK query for payment {} failed: {}", payment.id, e);
+                    continue;
+                }
+                _ => {
+                    Self::expire(pool, payment.id, "No answer from M-Pesa").await?;
+                    settled += 1;
+                    continue;
+                }
+            };
+
+            match Self::settle(pool, checkout_request_id, &result, order, weights).await {
+                Ok(Some(payment)) if payment.status != PaymentStatus::Pending => settled += 1,
+                Ok(_) => {}
+                Err(e) => tracing::error!("[M-PESA] Could not settle payment {}: {}", payment.id, e),
+            }
+        }
+
+        // Confirmed payments only wait for their receipt, so only those a statement line can complete are picked up
+        let receipted: Vec<Payment> = sqlx::query_as(&format!(
+            "SELECT {} FROM payments p
+             WHERE p.status = $1 AND p.confirmed_at IS NOT NULL AND p.checkout_request_id IS NOT NULL
+               AND EXISTS (SELECT 1 FROM mpesa_statement_lines l WHERE {})
+             ORDER BY p.created_at LIMIT 100",
+            PAYMENT_COLUMNS, MATCHING_STATEMENT_LINE
+        ))
+        .bind(PaymentStatus::Pending)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for payment in receipted {
+            let (Some(checkout_request_id), Some(receipt_number)) =
+                (&payment.checkout_request_id, Self::statement_receipt(pool, &payment).await?)
+            else {
+                continue;
+            };
+            let result = PaymentResult {
+                status: StkStatus::Completed,
+                result_code: payment.result_code.clone(),
+                result_desc: payment.result_desc.clone().unwrap_or_default(),
+                receipt_number: Some(receipt_number),
+            };
+            match Self::settle(pool, checkout_request_id, &result, order, weights).await {
+                Ok(Some(payment)) if payment.status != PaymentStatus::Pending => settled += 1,
+                Ok(_) => {}
+                Err(e) => tracing::error!("[M-PESA] Could not settle payment {}: {}", payment.id, e),
+            }
+        }
+
+        tracing::info!("[SCHEDULER] {} stale payments resolved", settled);
+        Ok(settled)
+    }
+
+    pub async fn find(pool: &PgPool, payment_id: Uuid) -> Result<Option<Payment>, sqlx::Error> {
+        sqlx::query_as(&format!("SELECT {} FROM payments WHERE id = $1", PAYMENT_COLUMNS))
+            .bind(payment_id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn for_user(pool: &PgPool, user_id: Uuid) -> Result<Vec<Payment>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM payments WHERE user_id = $1 ORDER BY created_at DESC LIMIT 50",
+            PAYMENT_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn find_by_checkout(pool: &PgPool, checkout_request_id: &str) -> Result<Option<Payment>, String> {
+        sqlx::query_as(&format!("SELECT {} FROM payments WHERE checkout_request_id = $1", PAYMENT_COLUMNS))
+            .bind(checkout_request_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// The receipt of a completed statement line for the payment's amount from its phone,
+    /// paid after it started, that no payment or repayment has claimed yet.
+    async fn statement_receipt(pool: &PgPool, payment: &Payment) -> Result<Option<String>, String> {
+        let receipt: Option<(String,)> = sqlx::query_as(&format!(
+            "SELECT l.receipt_number FROM payments p JOIN mpesa_statement_lines l ON {}
+             WHERE p.id = $1 ORDER BY l.completed_at LIMIT 1",
+            MATCHING_STATEMENT_LINE
+        ))
+        .bind(payment.id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(receipt.map(|(receipt,)| receipt))
+    }
+
+    async fn expire(pool: &PgPool, payment_id: Uuid, reason: &str) -> Result<(), String> {
+        sqlx::query(
+            "UPDATE payments SET status = $2, result_desc = $3, updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 AND status = $4"
+        )
+        .bind(payment_id)
+        .bind(PaymentStatus::Failed)
+        .bind(reason)
+        .bind(PaymentStatus::Pending)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Applies a confirmed repayment to its loan, or to the overpayment destination if the
+    /// loan has closed since the payment started.
+    async fn apply_repayment(
+        conn: &mut PgConnection,
+        payment: &Payment,
+        receipt_number: Option<String>,
+        order: &AllocationOrder,
+        weights: &ScoringWeights,
+    ) -> Result<(), String> {
+        let loan_id = payment.loan_id.ok_or("Loan repayment without a loan")?;
+        let repayment = NewRepayment {
+            loan_id,
+            payer_id: Some(payment.user_id),
+            amount: payment.amount,
+            overpayment_destination: payment.overpayment_destination.unwrap_or_default(),
+            savings_id: payment.savings_id,
+            mpesa_receipt_number: receipt_number,
+            payment_id: Some(payment.id),
+        };
+        RepaymentService::apply_confirmed(conn, &repayment, order, weights)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Could not apply payment {} to loan {}: {}", payment.id, loan_id, e))
+    }
+
+    /// Funds the loan a lender's confirmed capital is for and queues its payout. Capital for
+    /// a loan that is no longer looking for a lender is sent back.
+    async fn fund_loan(conn: &mut PgConnection, payment: &Payment) -> Result<(), String> {
+        let loan_id = payment.loan_id.ok_or("Loan funding without a loan")?;
+        let lender_id = payment.user_id;
+        LedgerService::record_loan_funding(conn, loan_id, lender_id, payment.amount).await?;
+
+        let loan = LoanLifecycle::load_for_update(conn, loan_id).await.map_err(|e| e.to_string())?;
+        let cleared = LoanReview::is_cleared(conn, loan_id).await.map_err(|e| e.to_string())?;
+        if loan.status != LoanStatus::Pending || !cleared {
+            tracing::warn!("[M-PESA] Funding payment {} arrived for {} loan {}", payment.id, loan.status, loan_id);
+            return Self::return_loan_funding(conn, payment, "The loan you paid for no longer needed funding").await;
+        }
+
+        LoanLifecycle::transition(conn, loan_id, LoanStatus::Funded, Some(lender_id), None)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        sqlx::query("UPDATE loans SET lender_id = $1 WHERE id = $2")
+            .bind(lender_id)
+            .bind(loan_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        BlockchainService::log_to_ledger(&mut *conn, "LOAN_FUNDING", &format!("Loan {} funded", loan_id), payment.amount).await?;
+
+        Outbox::enqueue(&mut *conn, &OutboxEvent::LoanPayout { loan_id }).await?;
+        Outbox::enqueue(&mut *conn, &OutboxEvent::NotifyMember {
+            user_id: loan.user_id,
+            subject: "Your loan has been funded".to_string(),
+            body: format!("Your MicroFund loan of {} has been funded and is on its way to your M-Pesa.", loan.amount),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Sends the capital a lender paid in for a loan back to the number it came from. `why`
+    /// starts the message telling the lender.
+    pub async fn return_loan_funding(conn: &mut PgConnection, payment: &Payment, why: &str) -> Result<(), String> {
+        let loan_id = payment.loan_id.ok_or("Loan funding without a loan")?;
+        LedgerService::record_funding_refund(conn, loan_id, payment.user_id, payment.amount).await?;
+
+        RefundService::request(conn, &NewRefund {
+            user_id: payment.user_id,
+            amount: payment.amount,
+            phone_number: payment.phone_number.clone(),
+            reason: format!("Funding of loan {} returned", loan_id),
+            loan_id: Some(loan_id),
+            repayment_id: None,
+            payment_id: Some(payment.id),
+        })
+        .await?;
+
+        BlockchainService::log_to_ledger(
+            &mut *conn,
+            "LOAN_FUNDING_REVERSED",
+            &format!("Funding of loan {} returned", loan_id),
+            payment.amount,
+        ).await?;
+
+        Outbox::enqueue(&mut *conn, &OutboxEvent::NotifyMember {
+            user_id: payment.user_id,
+            subject: "Your loan funding is being returned".to_string(),
+            body: format!("{}, so the {} you paid in is being sent back to M-Pesa {}.", why, payment.amount, payment.phone_number),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// The confirmed payment the current lender of a loan funded it with. `None` for loans
+    /// funded before lenders paid their capital in.
+    pub async fn funding_of(conn: &mut PgConnection, loan_id: Uuid, lender_id: Uuid) -> Result<Option<Payment>, String> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM payments WHERE loan_id = $1 AND user_id = $2 AND purpose = $3 AND status = $4
+             ORDER BY settled_at DESC LIMIT 1",
+            PAYMENT_COLUMNS
+        ))
+        .bind(loan_id)
+        .bind(lender_id)
+        .bind(PaymentPurpose::LoanFunding)
+        .bind(PaymentStatus::Completed)
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    async fn credit_savings_deposit(conn: &mut PgConnection, payment: &Payment) -> Result<(), String> {
+        let savings_id = payment.savings_id.ok_or("Savings deposit without a savings goal")?;
+
+        let (owner_id,): (Uuid,) = sqlx::query_as(
+            "UPDATE savings SET amount = amount + $1, updated_at = NOW() WHERE id = $2 RETURNING user_id"
+        )
+        .bind(payment.amount)
+        .bind(savings_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        sqlx::query(
+            "INSERT INTO savings_transactions (savings_id, amount, transaction_type, payment_id) VALUES ($1, $2, $3, $4)"
+        )
+        .bind(savings_id)
+        .bind(payment.amount)
+        .bind("deposit")
+        .bind(payment.id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        LedgerService::record_savings_deposit(conn, savings_id, owner_id, payment.amount).await?;
+
+        BlockchainService::log_to_ledger(&mut *conn, "SAVINGS_DEPOSIT", "Deposit to savings goal", payment.amount).await?;
+
+        Outbox::enqueue(&mut *conn, &OutboxEvent::NotifyMember {
+            user_id: owner_id,
+            subject: "Deposit received".to_string(),
+            body: format!("We received your MicroFund savings deposit of {}.", payment.amount),
+        })
+        .await?;
+        Ok(())
+    }
+}
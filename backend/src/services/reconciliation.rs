@@ -0,0 +1,768 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::{
+    Money, MpesaStatement, MpesaStatementLine, PaymentStatus, ReconciliationItem, ReconciliationKind,
+    ReconciliationReport, ReconciliationResolution, ReconciliationStatus, PLATFORM_CURRENCY,
+};
+use crate::services::disbursements::DisbursementService;
+use crate::services::mpesa::MpesaClient;
+use crate::services::refunds::RefundService;
+use crate::services::withdrawals::WithdrawalService;
+
+const STATEMENT_COLUMNS: &str = "id, file_name, imported_by, period_start, period_end, line_count, duplicate_count, \
+    created_at, reconciled_at";
+
+const LINE_COLUMNS: &str = "id, statement_id, receipt_number, completed_at, details, transaction_status, paid_in, \
+    withdrawn, other_party, phone_number";
+
+const ITEM_COLUMNS: &str = "id, statement_id, kind, status, receipt_number, statement_line_id, payment_id, \
+    disbursement_id, repayment_id, withdrawal_id, refund_id, statement_amount, internal_amount, detail, resolution, resolution_note, \
+    resolved_by, resolved_at, created_at";
+
+/// How far before a statement line a record without a receipt may have been created and still
+/// be taken for the same transaction.
+const MATCH_WINDOW_HOURS: i64 = 24;
+
+/// Timestamp layouts seen in statement exports, all in East Africa Time.
+const TIME_FORMATS: [&str; 6] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%d-%m-%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M:%S",
+    "%d.%m.%Y %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%d/%m/%Y %H:%M",
+];
+
+/// A transaction row of an M-Pesa organisation statement export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedStatementLine {
+    pub receipt_number: String,
+    pub completed_at: DateTime<Utc>,
+    pub details: Option<String>,
+    pub transaction_status: String,
+    pub paid_in: Money,
+    pub withdrawn: Money,
+    pub other_party: Option<String>,
+    pub phone_number: Option<String>,
+}
+
+/// Which of our tables a statement line can stand for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Payment,
+    Disbursement,
+    Repayment,
+    Withdrawal,
+    Refund,
+}
+
+/// One of our records as reconciliation sees it.
+#[derive(Debug, sqlx::FromRow)]
+struct InternalRecord {
+    id: Uuid,
+    amount: Money,
+    phone_number: Option<String>,
+    status: PaymentStatus,
+    receipt_number: Option<String>,
+    /// When it settled; only looked up for records missing from statements.
+    #[sqlx(default)]
+    at: Option<DateTime<Utc>>,
+}
+
+struct NewItem {
+    kind: ReconciliationKind,
+    receipt_number: Option<String>,
+    statement_line_id: Option<Uuid>,
+    record: Option<(Source, Uuid)>,
+    statement_amount: Option<Money>,
+    internal_amount: Option<Money>,
+    detail: String,
+}
+
+/// Matches M-Pesa organisation statements against the deposits, loan payouts, repayments,
+/// savings withdrawals and refunds we recorded. Every statement line and every unaccounted record becomes
+/// a reconciliation item; anything but a clean match stays open until an admin resolves it.
+pub struct ReconciliationService;
+
+impl ReconciliationService {
+    /// Reads a statement CSV as exported from the M-Pesa portal. The account summary above the
+    /// transactions is skipped and columns are found by their header, so column order and
+    /// extra columns do not matter.
+    pub fn parse_statement(csv: &str) -> Result<Vec<ParsedStatementLine>, String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(csv.as_bytes());
+
+        let mut columns: Option<HashMap<String, usize>> = None;
+        let mut lines = Vec::new();
+        for (index, record) in reader.records().enumerate() {
+            let row = index + 1;
+            let record = record.map_err(|e| format!("Row {}: {}", row, e))?;
+            if record.iter().all(|field| field.trim().is_empty()) {
+                continue;
+            }
+
+            let Some(columns) = &columns else {
+                let header: HashMap<String, usize> = record
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| (name.trim().trim_end_matches('.').to_lowercase(), i))
+                    .collect();
+                if header.contains_key("receipt no") {
+                    for required in ["completion time", "transaction status", "paid in", "withdrawn"] {
+                        if !header.contains_key(required) {
+                            return Err(format!("Row {}: the statement has no '{}' column", row, required));
+                        }
+                    }
+                    columns = Some(header);
+                }
+                continue;
+            };
+
+            let field = |name: &str| {
+                columns
+                    .get(name)
+                    .and_then(|i| record.get(*i))
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+            };
+
+            let receipt_number = field("receipt no")
+                .ok_or_else(|| format!("Row {}: missing receipt number", row))?
+                .to_uppercase();
+            let completed_at = field("completion time")
+                .ok_or_else(|| format!("Row {}: missing completion time", row))
+                .and_then(|value| Self::parse_time(value).ok_or_else(|| format!("Row {}: '{}' is not a time", row, value)))?;
+            let transaction_status = field("transaction status")
+                .ok_or_else(|| format!("Row {}: missing transaction status", row))?
+                .to_string();
+            let paid_in = Self::parse_amount(field("paid in")).map_err(|e| format!("Row {}: {}", row, e))?;
+            let withdrawn = Self::parse_amount(field("withdrawn")).map_err(|e| format!("Row {}: {}", row, e))?;
+            let other_party = field("other party info").map(str::to_string);
+            // "254712345678 - JANE DOE"; masked numbers are left unknown
+            let phone_number = other_party
+                .as_deref()
+                .and_then(|party| party.split(" - ").next())
+                .and_then(|number| MpesaClient::normalize_phone(number).ok());
+
+            lines.push(ParsedStatementLine {
+                receipt_number,
+                completed_at,
+                details: field("details").map(str::to_string),
+                transaction_status,
+                paid_in,
+                withdrawn,
+                other_party,
+                phone_number,
+            });
+        }
+
+        if columns.is_none() {
+            return Err("No 'Receipt No.' header found; is this an M-Pesa statement export?".to_string());
+        }
+        Ok(lines)
+    }
+
+    /// Statement amounts read "1,500.00"; withdrawals may carry a minus sign.
+    fn parse_amount(value: Option<&str>) -> Result<Money, String> {
+        let Some(value) = value else {
+            return Ok(Money::zero(PLATFORM_CURRENCY));
+        };
+        let digits: String = value.chars().filter(|c| *c != ',' && !c.is_whitespace()).collect();
+        Money::parse(digits.trim_start_matches('-'), PLATFORM_CURRENCY).map_err(|e| e.to_string())
+    }
+
+    fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+        let eat = FixedOffset::east_opt(3 * 3600).expect("valid offset");
+        TIME_FORMATS.iter().find_map(|format| {
+            let local = NaiveDateTime::parse_from_str(value, format).ok()?;
+            eat.from_local_datetime(&local).single().map(|at| at.with_timezone(&Utc))
+        })
+    }
+
+    /// Compares a statement line with the record it names. `None` when they agree, otherwise
+    /// every disagreement in words.
+    pub fn compare(
+        statement_amount: Money,
+        statement_phone: Option<&str>,
+        internal_amount: Money,
+        internal_phone: Option<&str>,
+        internal_status: PaymentStatus,
+    ) -> Option<String> {
+        let mut differences = Vec::new();
+        if statement_amount != internal_amount {
+            differences.push(format!("{} on the statement but {} here", statement_amount, internal_amount));
+        }
+        if let (Some(theirs), Some(ours)) = (statement_phone, internal_phone) {
+            if theirs != ours {
+                differences.push(format!("phone {} on the statement but {} here", theirs, ours));
+            }
+        }
+        if internal_status != PaymentStatus::Completed {
+            differences.push(format!("completed at M-Pesa but {} here", internal_status.as_str()));
+        }
+        if differences.is_empty() {
+            None
+        } else {
+            Some(differences.join("; "))
+        }
+    }
+
+    /// Stores the lines of a statement. Lines already imported with an earlier, overlapping
+    /// statement are skipped and counted as duplicates.
+    pub async fn import(
+        pool: &PgPool,
+        file_name: Option<&str>,
+        lines: &[ParsedStatementLine],
+        imported_by: Uuid,
+    ) -> Result<MpesaStatement, String> {
+        let period_start = lines.iter().map(|line| line.completed_at).min();
+        let period_end = lines.iter().map(|line| line.completed_at).max();
+
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+        let (statement_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO mpesa_statements (file_name, imported_by, period_start, period_end) VALUES ($1, $2, $3, $4) RETURNING id"
+        )
+        .bind(file_name)
+        .bind(imported_by)
+        .bind(period_start)
+        .bind(period_end)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut imported = 0;
+        for line in lines {
+            imported += sqlx::query(
+                "INSERT INTO mpesa_statement_lines
+                 (statement_id, receipt_number, completed_at, details, transaction_status, paid_in, withdrawn, other_party, phone_number)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                 ON CONFLICT (receipt_number) DO NOTHING"
+            )
+            .bind(statement_id)
+            .bind(&line.receipt_number)
+            .bind(line.completed_at)
+            .bind(&line.details)
+            .bind(&line.transaction_status)
+            .bind(line.paid_in)
+            .bind(line.withdrawn)
+            .bind(&line.other_party)
+            .bind(&line.phone_number)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?
+            .rows_affected() as i32;
+        }
+
+        let statement: MpesaStatement = sqlx::query_as(&format!(
+            "UPDATE mpesa_statements SET line_count = $2, duplicate_count = $3 WHERE id = $1 RETURNING {}",
+            STATEMENT_COLUMNS
+        ))
+        .bind(statement_id)
+        .bind(imported)
+        .bind(lines.len() as i32 - imported)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+        tracing::info!(
+            "[RECONCILIATION] Imported statement {} with {} lines ({} already known)",
+            statement.id, statement.line_count, statement.duplicate_count
+        );
+        Ok(statement)
+    }
+
+    /// Works out the reconciliation of a statement afresh. Resolved exceptions are kept and
+    /// the lines and records they cover are left alone; everything else is rebuilt, so
+    /// running it again after our records were corrected closes the exceptions that went away.
+    pub async fn reconcile(pool: &PgPool, statement_id: Uuid, now: DateTime<Utc>) -> Result<ReconciliationReport, String> {
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+        // Serializes runs over the same statement
+        let statement: Option<MpesaStatement> = sqlx::query_as(&format!(
+            "SELECT {} FROM mpesa_statements WHERE id = $1 FOR UPDATE",
+            STATEMENT_COLUMNS
+        ))
+        .bind(statement_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        let statement = statement.ok_or_else(|| format!("Statement {} not found", statement_id))?;
+
+        sqlx::query("DELETE FROM reconciliation_items WHERE statement_id = $1 AND status <> $2")
+            .bind(statement_id)
+            .bind(ReconciliationStatus::Resolved)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let lines: Vec<MpesaStatementLine> = sqlx::query_as(&format!(
+            "SELECT {} FROM mpesa_statement_lines l
+             WHERE l.statement_id = $1 AND LOWER(l.transaction_status) = 'completed'
+               AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.statement_line_id = l.id AND i.status = $2)
+             ORDER BY l.completed_at",
+            LINE_COLUMNS
+        ))
+        .bind(statement_id)
+        .bind(ReconciliationStatus::Resolved)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for line in &lines {
+            if let Some(item) = Self::match_line(&mut tx, line).await? {
+                Self::insert_item(&mut tx, statement_id, item).await?;
+            }
+        }
+
+        if let (Some(start), Some(end)) = (statement.period_start, statement.period_end) {
+            // Lines paying out unacknowledged payouts completed them above; the rest were not paid
+            let failed = DisbursementService::release_unpaid(&mut tx, start, end, Duration::hours(MATCH_WINDOW_HOURS), now).await?;
+            if !failed.is_empty() {
+                tracing::info!("[RECONCILIATION] Statement {}: {} unpaid loan payout attempts failed", statement_id, failed.len());
+            }
+            let released = WithdrawalService::release_unpaid(&mut tx, start, end, Duration::hours(MATCH_WINDOW_HOURS)).await?;
+            if !released.is_empty() {
+                tracing::info!("[RECONCILIATION] Statement {}: {} unpaid savings withdrawals released", statement_id, released.len());
+            }
+            let resent = RefundService::release_unpaid(&mut tx, start, end, Duration::hours(MATCH_WINDOW_HOURS), now).await?;
+            if !resent.is_empty() {
+                tracing::info!("[RECONCILIATION] Statement {}: {} unpaid refunds to be sent again", statement_id, resent.len());
+            }
+            for (source, record) in Self::unaccounted(&mut tx, start, end).await? {
+                let at = record.at.map_or_else(String::new, |at| format!(" at {}", at.format("%Y-%m-%d %H:%M")));
+                Self::insert_item(&mut tx, statement_id, NewItem {
+                    kind: ReconciliationKind::MissingInStatement,
+                    receipt_number: record.receipt_number.clone(),
+                    statement_line_id: None,
+                    record: Some((source, record.id)),
+                    statement_amount: None,
+                    internal_amount: Some(record.amount),
+                    detail: format!("{}{} is in no M-Pesa statement", source.describe(), at),
+                })
+                .await?;
+            }
+        }
+
+        sqlx::query("UPDATE mpesa_statements SET reconciled_at = $2 WHERE id = $1")
+            .bind(statement_id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+
+        let report = Self::report(pool, statement_id)
+            .await?
+            .ok_or_else(|| format!("Statement {} disappeared", statement_id))?;
+        tracing::info!(
+            "[RECONCILIATION] Statement {}: {} matched, {} missing here, {} missing at M-Pesa, {} mismatched",
+            statement_id, report.matched, report.missing_internally, report.missing_in_statement, report.mismatched
+        );
+        Ok(report)
+    }
+
+    /// Counts and exceptions of a statement, as of its last reconciliation.
+    pub async fn report(pool: &PgPool, statement_id: Uuid) -> Result<Option<ReconciliationReport>, String> {
+        let statement: Option<MpesaStatement> = sqlx::query_as(&format!(
+            "SELECT {} FROM mpesa_statements WHERE id = $1",
+            STATEMENT_COLUMNS
+        ))
+        .bind(statement_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let Some(statement) = statement else {
+            return Ok(None);
+        };
+
+        let counts: Vec<(ReconciliationKind, i64)> = sqlx::query_as(
+            "SELECT kind, COUNT(*) FROM reconciliation_items WHERE statement_id = $1 GROUP BY kind"
+        )
+        .bind(statement_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let count = |kind: ReconciliationKind| counts.iter().find(|(k, _)| *k == kind).map_or(0, |(_, n)| *n);
+
+        let exceptions: Vec<ReconciliationItem> = sqlx::query_as(&format!(
+            "SELECT {} FROM reconciliation_items WHERE statement_id = $1 AND kind <> $2 ORDER BY created_at, id",
+            ITEM_COLUMNS
+        ))
+        .bind(statement_id)
+        .bind(ReconciliationKind::Matched)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        Ok(Some(ReconciliationReport {
+            matched: count(ReconciliationKind::Matched),
+            missing_internally: count(ReconciliationKind::MissingInternally),
+            missing_in_statement: count(ReconciliationKind::MissingInStatement),
+            mismatched: count(ReconciliationKind::Mismatched),
+            statement,
+            exceptions,
+        }))
+    }
+
+    pub async fn statements(pool: &PgPool) -> Result<Vec<MpesaStatement>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM mpesa_statements ORDER BY created_at DESC LIMIT 100",
+            STATEMENT_COLUMNS
+        ))
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Exceptions of every statement still waiting for an admin, oldest first.
+    pub async fn open_exceptions(pool: &PgPool) -> Result<Vec<ReconciliationItem>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM reconciliation_items WHERE status = $1 ORDER BY created_at, id LIMIT 500",
+            ITEM_COLUMNS
+        ))
+        .bind(ReconciliationStatus::Open)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Closes an open exception with what the admin did about it.
+    pub async fn resolve(
+        pool: &PgPool,
+        item_id: Uuid,
+        resolution: ReconciliationResolution,
+        note: Option<&str>,
+        admin_id: Uuid,
+    ) -> Result<ReconciliationItem, AppError> {
+        let resolved: Option<ReconciliationItem> = sqlx::query_as(&format!(
+            "UPDATE reconciliation_items
+             SET status = $2, resolution = $3, resolution_note = $4, resolved_by = $5, resolved_at = NOW()
+             WHERE id = $1 AND status = $6 RETURNING {}",
+            ITEM_COLUMNS
+        ))
+        .bind(item_id)
+        .bind(ReconciliationStatus::Resolved)
+        .bind(resolution)
+        .bind(note)
+        .bind(admin_id)
+        .bind(ReconciliationStatus::Open)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to resolve reconciliation item {}: {}", item_id, e);
+            AppError::InternalServerError
+        })?;
+        if let Some(item) = resolved {
+            tracing::info!("[RECONCILIATION] Item {} resolved as {} by {}", item.id, resolution.as_str(), admin_id);
+            return Ok(item);
+        }
+
+        let status: Option<(ReconciliationStatus,)> = sqlx::query_as("SELECT status FROM reconciliation_items WHERE id = $1")
+            .bind(item_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        match status {
+            None => Err(AppError::NotFound),
+            Some((ReconciliationStatus::Resolved,)) => Err(AppError::Conflict("This exception is already resolved".to_string())),
+            Some(_) => Err(AppError::Conflict("Only open exceptions can be resolved".to_string())),
+        }
+    }
+
+    /// Re-reconciles statements that were never reconciled or still have open exceptions,
+    /// since payments settling late or records being corrected can close them. Returns how
+    /// many statements were reconciled.
+    pub async fn run_due(pool: &PgPool, now: DateTime<Utc>) -> Result<u64, String> {
+        let due: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT s.id FROM mpesa_statements s
+             WHERE s.reconciled_at IS NULL
+                OR EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.statement_id = s.id AND i.status = $1)
+             ORDER BY s.created_at LIMIT 50"
+        )
+        .bind(ReconciliationStatus::Open)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut reconciled = 0;
+        for (statement_id,) in due {
+            match Self::reconcile(pool, statement_id, now).await {
+                Ok(_) => reconciled += 1,
+                Err(e) => tracing::error!("[SCHEDULER] Could not reconcile statement {}: {}", statement_id, e),
+            }
+        }
+
+        tracing::info!("[SCHEDULER] {} M-Pesa statements reconciled", reconciled);
+        Ok(reconciled)
+    }
+
+    /// Finds what a completed statement line stands for: by receipt first, then among records
+    /// that never got a receipt by amount, phone and time.
+    async fn match_line(conn: &mut PgConnection, line: &MpesaStatementLine) -> Result<Option<NewItem>, String> {
+        let (amount, sources) = if line.paid_in.is_positive() {
+            (line.paid_in, [Source::Payment, Source::Repayment].as_slice())
+        } else if line.withdrawn.is_positive() {
+            (line.withdrawn, [Source::Disbursement, Source::Withdrawal, Source::Refund].as_slice())
+        } else {
+            return Ok(None);
+        };
+
+        let item = |kind, record: Option<(Source, &InternalRecord)>, detail: String| NewItem {
+            kind,
+            receipt_number: Some(line.receipt_number.clone()),
+            statement_line_id: Some(line.id),
+            record: record.map(|(source, record)| (source, record.id)),
+            statement_amount: Some(amount),
+            internal_amount: record.map(|(_, record)| record.amount),
+            detail,
+        };
+
+        for source in sources {
+            if let Some(record) = Self::find_by_receipt(conn, *source, &line.receipt_number).await? {
+                let difference = Self::compare(
+                    amount,
+                    line.phone_number.as_deref(),
+                    record.amount,
+                    record.phone_number.as_deref(),
+                    record.status,
+                );
+                return Ok(Some(match difference {
+                    None => item(ReconciliationKind::Matched, Some((*source, &record)), format!("{} matched by receipt", source.describe())),
+                    Some(difference) => item(ReconciliationKind::Mismatched, Some((*source, &record)), format!("{}: {}", source.describe(), difference)),
+                }));
+            }
+        }
+
+        if let Some(phone) = line.phone_number.as_deref() {
+            for source in sources {
+                let Some(record) = Self::find_unreceipted(conn, *source, amount, phone, line.completed_at).await? else {
+                    continue;
+                };
+                if record.status == PaymentStatus::Completed {
+                    // The callback never told us the receipt; the statement does
+                    Self::record_receipt(conn, *source, record.id, &line.receipt_number).await?;
+                    return Ok(Some(item(
+                        ReconciliationKind::Matched,
+                        Some((*source, &record)),
+                        format!("{} matched by amount and phone; receipt recorded", source.describe()),
+                    )));
+                }
+                if matches!(source, Source::Disbursement | Source::Withdrawal | Source::Refund) && record.status == PaymentStatus::Pending {
+                    // A payout Daraja never acknowledged, or whose result never came, was made after all
+                    if *source == Source::Disbursement {
+                        DisbursementService::complete_from_statement(conn, record.id, &line.receipt_number, line.completed_at).await?;
+                    } else if *source == Source::Withdrawal {
+                        WithdrawalService::complete_from_statement(conn, record.id, &line.receipt_number).await?;
+                    } else {
+                        RefundService::complete_from_statement(conn, record.id, &line.receipt_number).await?;
+                    }
+                    return Ok(Some(item(
+                        ReconciliationKind::Matched,
+                        Some((*source, &record)),
+                        format!("{} matched by amount and phone; completed from the statement", source.describe()),
+                    )));
+                }
+                return Ok(Some(item(
+                    ReconciliationKind::Mismatched,
+                    Some((*source, &record)),
+                    format!("{}: completed at M-Pesa but {} here", source.describe(), record.status.as_str()),
+                )));
+            }
+        }
+
+        let detail = if line.paid_in.is_positive() {
+            "Paid in at M-Pesa with nothing recorded here"
+        } else {
+            "Paid out at M-Pesa with nothing recorded here"
+        };
+        Ok(Some(item(ReconciliationKind::MissingInternally, None, detail.to_string())))
+    }
+
+    async fn find_by_receipt(conn: &mut PgConnection, source: Source, receipt: &str) -> Result<Option<InternalRecord>, String> {
+        let query = match source {
+            Source::Payment => "SELECT id, amount, phone_number, status, mpesa_receipt_number AS receipt_number
+                 FROM payments WHERE UPPER(mpesa_receipt_number) = $1",
+            Source::Disbursement => "SELECT id, amount, phone_number, status, transaction_id AS receipt_number
+                 FROM loan_disbursements WHERE UPPER(transaction_id) = $1",
+            Source::Withdrawal => "SELECT id, amount, phone_number, status, transaction_id AS receipt_number
+                 FROM savings_withdrawals WHERE UPPER(transaction_id) = $1",
+            Source::Refund => "SELECT id, amount, phone_number, status, transaction_id AS receipt_number
+                 FROM refunds WHERE UPPER(transaction_id) = $1",
+            // Paybill payments can come from any phone, so a repayment has none to compare
+            Source::Repayment => "SELECT id, amount, NULL::varchar AS phone_number, 'completed'::varchar AS status,
+                 mpesa_receipt_number AS receipt_number FROM loan_repayments WHERE UPPER(mpesa_receipt_number) = $1",
+        };
+        sqlx::query_as(query)
+            .bind(receipt)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// The closest record without a receipt that the line could be, and that no other line
+    /// already claimed.
+    async fn find_unreceipted(
+        conn: &mut PgConnection,
+        source: Source,
+        amount: Money,
+        phone: &str,
+        completed_at: DateTime<Utc>,
+    ) -> Result<Option<InternalRecord>, String> {
+        let query = match source {
+            Source::Payment => "SELECT id, amount, phone_number, status, mpesa_receipt_number AS receipt_number
+                 FROM payments r
+                 WHERE mpesa_receipt_number IS NULL AND amount = $1 AND phone_number = $2 AND created_at BETWEEN $3 AND $4
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.payment_id = r.id AND i.statement_line_id IS NOT NULL)
+                 ORDER BY ABS(EXTRACT(EPOCH FROM (created_at - $4))) LIMIT 1",
+            Source::Disbursement => "SELECT id, amount, phone_number, status, transaction_id AS receipt_number
+                 FROM loan_disbursements r
+                 WHERE transaction_id IS NULL AND amount = $1 AND phone_number = $2 AND created_at BETWEEN $3 AND $4
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.disbursement_id = r.id AND i.statement_line_id IS NOT NULL)
+                 ORDER BY ABS(EXTRACT(EPOCH FROM (created_at - $4))) LIMIT 1",
+            Source::Withdrawal => "SELECT id, amount, phone_number, status, transaction_id AS receipt_number
+                 FROM savings_withdrawals r
+                 WHERE transaction_id IS NULL AND amount = $1 AND phone_number = $2 AND created_at BETWEEN $3 AND $4
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.withdrawal_id = r.id AND i.statement_line_id IS NOT NULL)
+                 ORDER BY ABS(EXTRACT(EPOCH FROM (created_at - $4))) LIMIT 1",
+            // Sent again after earlier payouts failed, so the latest payout's time is what counts
+            Source::Refund => "SELECT id, amount, phone_number, status, transaction_id AS receipt_number
+                 FROM refunds r
+                 WHERE transaction_id IS NULL AND amount = $1 AND phone_number = $2 AND sent_at BETWEEN $3 AND $4
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.refund_id = r.id AND i.statement_line_id IS NOT NULL)
+                 ORDER BY ABS(EXTRACT(EPOCH FROM (sent_at - $4))) LIMIT 1",
+            Source::Repayment => return Ok(None),
+        };
+        sqlx::query_as(query)
+            .bind(amount)
+            .bind(phone)
+            .bind(completed_at - Duration::hours(MATCH_WINDOW_HOURS))
+            .bind(completed_at)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn record_receipt(conn: &mut PgConnection, source: Source, id: Uuid, receipt: &str) -> Result<(), String> {
+        let query = match source {
+            Source::Payment => "UPDATE payments SET mpesa_receipt_number = $2, updated_at = NOW() WHERE id = $1",
+            Source::Disbursement => "UPDATE loan_disbursements SET transaction_id = $2, updated_at = NOW() WHERE id = $1",
+            Source::Repayment => "UPDATE loan_repayments SET mpesa_receipt_number = $2 WHERE id = $1",
+            Source::Withdrawal => "UPDATE savings_withdrawals SET transaction_id = $2, updated_at = NOW() WHERE id = $1",
+            Source::Refund => "UPDATE refunds SET transaction_id = $2, updated_at = NOW() WHERE id = $1",
+        };
+        sqlx::query(query)
+            .bind(id)
+            .bind(receipt)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Money we recorded as moved through M-Pesa during the period that no statement line
+    /// and no other reconciliation item accounts for.
+    async fn unaccounted(
+        conn: &mut PgConnection,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<(Source, InternalRecord)>, String> {
+        let queries = [
+            (Source::Payment, "SELECT id, amount, phone_number, status, mpesa_receipt_number AS receipt_number, settled_at AS at
+                 FROM payments r
+                 WHERE status = $1 AND settled_at BETWEEN $2 AND $3
+                   AND NOT EXISTS (SELECT 1 FROM mpesa_statement_lines l WHERE l.receipt_number = UPPER(r.mpesa_receipt_number))
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.payment_id = r.id)
+                 ORDER BY settled_at"),
+            (Source::Disbursement, "SELECT id, amount, phone_number, status, transaction_id AS receipt_number, settled_at AS at
+                 FROM loan_disbursements r
+                 WHERE status = $1 AND settled_at BETWEEN $2 AND $3
+                   AND NOT EXISTS (SELECT 1 FROM mpesa_statement_lines l WHERE l.receipt_number = UPPER(r.transaction_id))
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.disbursement_id = r.id)
+                 ORDER BY settled_at"),
+            (Source::Repayment, "SELECT id, amount, NULL::varchar AS phone_number, $1::varchar AS status,
+                     mpesa_receipt_number AS receipt_number, created_at AS at
+                 FROM loan_repayments r
+                 -- Repayments collected with an STK push are accounted for by their payment
+                 WHERE mpesa_receipt_number IS NOT NULL AND payment_id IS NULL AND created_at BETWEEN $2 AND $3
+                   AND NOT EXISTS (SELECT 1 FROM mpesa_statement_lines l WHERE l.receipt_number = UPPER(r.mpesa_receipt_number))
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.repayment_id = r.id)
+                 ORDER BY created_at"),
+            (Source::Withdrawal, "SELECT id, amount, phone_number, status, transaction_id AS receipt_number, settled_at AS at
+                 FROM savings_withdrawals r
+                 WHERE status = $1 AND settled_at BETWEEN $2 AND $3
+                   AND NOT EXISTS (SELECT 1 FROM mpesa_statement_lines l WHERE l.receipt_number = UPPER(r.transaction_id))
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.withdrawal_id = r.id)
+                 ORDER BY settled_at"),
+            (Source::Refund, "SELECT id, amount, phone_number, status, transaction_id AS receipt_number, settled_at AS at
+                 FROM refunds r
+                 WHERE status = $1 AND settled_at BETWEEN $2 AND $3
+                   AND NOT EXISTS (SELECT 1 FROM mpesa_statement_lines l WHERE l.receipt_number = UPPER(r.transaction_id))
+                   AND NOT EXISTS (SELECT 1 FROM reconciliation_items i WHERE i.refund_id = r.id)
+                 ORDER BY settled_at"),
+        ];
+
+        let mut unaccounted = Vec::new();
+        for (source, query) in queries {
+            let records: Vec<InternalRecord> = sqlx::query_as(query)
+                .bind(PaymentStatus::Completed)
+                .bind(start)
+                .bind(end)
+                .fetch_all(&mut *conn)
+                .await
+                .map_err(|e| e.to_string())?;
+            unaccounted.extend(records.into_iter().map(|record| (source, record)));
+        }
+        Ok(unaccounted)
+    }
+
+    async fn insert_item(conn: &mut PgConnection, statement_id: Uuid, item: NewItem) -> Result<(), String> {
+        let status = if item.kind == ReconciliationKind::Matched {
+            ReconciliationStatus::Matched
+        } else {
+            ReconciliationStatus::Open
+        };
+        let record_id = |wanted: Source| item.record.filter(|(source, _)| *source == wanted).map(|(_, id)| id);
+
+        sqlx::query(
+            "INSERT INTO reconciliation_items
+             (statement_id, kind, status, receipt_number, statement_line_id, payment_id, disbursement_id, repayment_id,
+              withdrawal_id, refund_id, statement_amount, internal_amount, detail)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)"
+        )
+        .bind(statement_id)
+        .bind(item.kind)
+        .bind(status)
+        .bind(&item.receipt_number)
+        .bind(item.statement_line_id)
+        .bind(record_id(Source::Payment))
+        .bind(record_id(Source::Disbursement))
+        .bind(record_id(Source::Repayment))
+        .bind(record_id(Source::Withdrawal))
+        .bind(record_id(Source::Refund))
+        .bind(item.statement_amount)
+        .bind(item.internal_amount)
+        .bind(&item.detail)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+impl Source {
+    fn describe(&self) -> &'static str {
+        match self {
+            Source::Payment => "M-Pesa deposit",
+            Source::Disbursement => "Loan payout",
+            Source::Repayment => "Paybill loan repayment",
+            Source::Withdrawal => "Savings withdrawal",
+            Source::Refund => "Refund",
+        }
+    }
+}
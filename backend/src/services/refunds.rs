@@ -0,0 +1,448 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::models::{Money, PaymentStatus, Refund};
+use crate::services::blockchain::BlockchainService;
+use crate::services::ledger::LedgerService;
+use crate::services::mpesa::{B2cPaymentRequest, B2cResult, MpesaClient, MpesaError};
+use crate::services::outbox::{Outbox, OutboxEvent};
+
+const REFUND_COLUMNS: &str = "id, user_id, amount, phone_number, reason, loan_id, repayment_id, payment_id, status, attempt, \
+    callback_token, conversation_id, transaction_id, result_code, result_desc, sent_at, retry_at, unacknowledged_at, \
+    created_at, updated_at, settled_at";
+
+/// Payouts M-Pesa may turn down before the refund is given up on and left to staff.
+pub const MAX_ATTEMPTS: i32 = 5;
+/// Wait after the first payout was turned down; doubled after each further one.
+const RETRY_BASE_MINUTES: i64 = 15;
+/// Payouts without a result are handed to reconciliation after this long.
+const STALE_AFTER_MINUTES: i64 = 30;
+
+/// Money owed back to a member. The ledger must already owe it to them in refunds payable.
+#[derive(Debug, Clone)]
+pub struct NewRefund {
+    pub user_id: Uuid,
+    pub amount: Money,
+    pub phone_number: String,
+    pub reason: String,
+    pub loan_id: Option<Uuid>,
+    pub repayment_id: Option<Uuid>,
+    pub payment_id: Option<Uuid>,
+}
+
+/// Refunds paid out to members over M-Pesa B2C. A refund is owed from the moment it is
+/// requested and only leaves refunds payable once Daraja confirms its payout. Payouts M-Pesa
+/// turns down are sent again with backoff; one that never gets a result is settled by the
+/// M-Pesa statement, since it may have been paid.
+pub struct RefundService;
+
+impl RefundService {
+    /// How long to wait after payout number `attempt` was turned down. `None` once every attempt is used.
+    pub fn retry_delay(attempt: i32) -> Option<Duration> {
+        if attempt >= MAX_ATTEMPTS {
+            return None;
+        }
+        Some(Duration::minutes(RETRY_BASE_MINUTES << (attempt - 1).clamp(0, 16)))
+    }
+
+    /// Records a refund and queues its payout, on the transaction that made the money owed.
+    pub async fn request(conn: &mut PgConnection, new: &NewRefund) -> Result<Refund, String> {
+        let phone = MpesaClient::normalize_phone(&new.phone_number).map_err(|e| e.to_string())?;
+
+        let refund: Refund = sqlx::query_as(&format!(
+            "INSERT INTO refunds (user_id, amount, phone_number, reason, loan_id, repayment_id, payment_id, callback_token)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING {}",
+            REFUND_COLUMNS
+        ))
+        .bind(new.user_id)
+        .bind(new.amount)
+        .bind(&phone)
+        .bind(&new.reason)
+        .bind(new.loan_id)
+        .bind(new.repayment_id)
+        .bind(new.payment_id)
+        .bind(Uuid::new_v4().simple().to_string())
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        Outbox::enqueue(&mut *conn, &OutboxEvent::RefundPayout { refund_id: refund.id }).await?;
+        tracing::info!("[M-PESA] Refund {} of {} to {} requested: {}", refund.id, refund.amount, refund.phone_number, refund.reason);
+        Ok(refund)
+    }
+
+    /// Sends the next payout of a pending refund. Refunds with a payout in flight or a retry
+    /// not yet due are returned untouched, so a repeated call sends nothing.
+    pub async fn send(pool: &PgPool, mpesa: &MpesaClient, refund_id: Uuid, now: DateTime<Utc>) -> Result<Refund, String> {
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        let refund = Self::lock(&mut tx, refund_id).await?;
+        if refund.status != PaymentStatus::Pending || refund.sent_at.is_some() || refund.retry_at.is_some_and(|at| at > now) {
+            return Ok(refund);
+        }
+
+        let refund: Refund = sqlx::query_as(&format!(
+            "UPDATE refunds
+             SET attempt = attempt + 1, sent_at = $2, retry_at = NULL, conversation_id = NULL, updated_at = NOW()
+             WHERE id = $1 RETURNING {}",
+            REFUND_COLUMNS
+        ))
+        .bind(refund.id)
+        .bind(now)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+
+        let payment = mpesa
+            .b2c_payment(&B2cPaymentRequest {
+                originator_conversation_id: Self::originator_id(&refund),
+                phone_number: refund.phone_number.clone(),
+                amount: refund.amount,
+                remarks: format!("MicroFund refund {}", refund.id),
+                occasion: "Refund".to_string(),
+                callback_token: refund.callback_token.clone(),
+            })
+            .await;
+
+        match payment {
+            Ok(response) => sqlx::query_as(&format!(
+                "UPDATE refunds SET conversation_id = $3, updated_at = NOW() WHERE id = $1 AND attempt = $2 RETURNING {}",
+                REFUND_COLUMNS
+            ))
+            .bind(refund.id)
+            .bind(refund.attempt)
+            .bind(&response.conversation_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| e.to_string()),
+            // The request may still have reached Daraja; its result decides, or the statement does
+            Err(MpesaError::Transport(e)) => {
+                tracing::warn!("[M-PESA] B2C request for refund {} failed: {}", refund.id, e);
+                Ok(refund)
+            }
+            Err(e) => {
+                tracing::error!("[M-PESA] B2C payment for refund {} rejected: {}", refund.id, e);
+                let code = match &e {
+                    MpesaError::Rejected { code, .. } => Some(code.clone()),
+                    _ => None,
+                };
+                // A request M-Pesa finds invalid will not get better by sending it again
+                let retry = !matches!(e, MpesaError::InvalidRequest(_));
+                Self::fail(pool, refund.id, refund.attempt, code, &e.to_string(), retry, now).await
+            }
+        }
+    }
+
+    /// Applies a B2C result posted to the result URL of a refund. Results for a payout that is
+    /// no longer in flight are ignored, so repeated results are harmless. `None` if the token
+    /// belongs to no refund, so the result can be offered to loan payouts instead.
+    pub async fn handle_result(
+        pool: &PgPool,
+        callback_token: &str,
+        result: &B2cResult,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Refund>, String> {
+        let Some(refund) = Self::find_by_token(pool, callback_token).await? else {
+            return Ok(None);
+        };
+        let in_flight = refund.status == PaymentStatus::Pending && refund.sent_at.is_some();
+        if !in_flight || result.originator_conversation_id != Self::originator_id(&refund) {
+            if result.is_success() {
+                tracing::error!(
+                    "[M-PESA] Payout {} of refund {} was not in flight but M-Pesa paid it out ({:?})",
+                    result.originator_conversation_id, refund.id, result.receipt_number()
+                );
+            }
+            return Ok(Some(refund));
+        }
+
+        if !result.is_success() {
+            let code = Some(result.result_code.to_string());
+            return Self::fail(pool, refund.id, refund.attempt, code, &result.result_desc, true, now).await.map(Some);
+        }
+
+        let expected = MpesaClient::whole_shillings(refund.amount).map_err(|e| e.to_string())?;
+        if let Some(paid) = result.amount().filter(|paid| *paid != expected) {
+            // Left in flight for reconciliation rather than booking the wrong amount
+            return Err(format!(
+                "Result for refund {} reports KES {} paid out, expected KES {}",
+                refund.id, paid, expected
+            ));
+        }
+
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        let locked = Self::lock(&mut tx, refund.id).await?;
+        if locked.status != PaymentStatus::Pending || locked.attempt != refund.attempt || locked.sent_at.is_none() {
+            return Ok(Some(locked));
+        }
+
+        let receipt = result.receipt_number();
+        let completed = Self::complete(
+            &mut tx,
+            &locked,
+            Some(&result.conversation_id),
+            receipt.as_deref(),
+            Some(result.result_code.to_string()),
+            &result.result_desc,
+        )
+        .await?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(Some(completed))
+    }
+
+    /// Daraja reports that the payout timed out in its queue, so it was never made.
+    /// `None` if the token belongs to no refund.
+    pub async fn handle_timeout(pool: &PgPool, callback_token: &str, now: DateTime<Utc>) -> Result<Option<Refund>, String> {
+        let Some(refund) = Self::find_by_token(pool, callback_token).await? else {
+            return Ok(None);
+        };
+        if refund.status != PaymentStatus::Pending || refund.sent_at.is_none() {
+            return Ok(Some(refund));
+        }
+        Self::fail(pool, refund.id, refund.attempt, None, "The payment timed out in the M-Pesa queue", true, now)
+            .await
+            .map(Some)
+    }
+
+    /// Flags payouts that have had no result for too long, whether or not Daraja accepted them,
+    /// for reconciliation: they may have been paid, so they are not sent again until an M-Pesa
+    /// statement shows they were not. Then sends every refund whose retry is due. Returns how
+    /// many payouts were flagged or sent.
+    pub async fn run_due(pool: &PgPool, mpesa: &MpesaClient, now: DateTime<Utc>) -> Result<u64, String> {
+        let flagged: Vec<(Uuid, i32)> = sqlx::query_as(
+            "UPDATE refunds SET unacknowledged_at = $3, updated_at = NOW()
+             WHERE status = $1 AND sent_at < $2 AND unacknowledged_at IS NULL
+             RETURNING id, attempt"
+        )
+        .bind(PaymentStatus::Pending)
+        .bind(now - Duration::minutes(STALE_AFTER_MINUTES))
+        .bind(now)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for (refund_id, attempt) in &flagged {
+            tracing::warn!(
+                "[M-PESA] Payout {} of refund {} has had no result; left until the M-Pesa statement shows it",
+                attempt, refund_id
+            );
+        }
+
+        let due: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT id FROM refunds WHERE status = $1 AND sent_at IS NULL AND (retry_at IS NULL OR retry_at <= $2)
+             ORDER BY created_at LIMIT 100"
+        )
+        .bind(PaymentStatus::Pending)
+        .bind(now)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut handled = flagged.len() as u64;
+        for (refund_id,) in due {
+            match Self::send(pool, mpesa, refund_id, now).await {
+                Ok(_) => handled += 1,
+                Err(e) => tracing::warn!("[SCHEDULER] Could not send refund {}: {}", refund_id, e),
+            }
+        }
+
+        tracing::info!("[SCHEDULER] {} refund payouts flagged or sent", handled);
+        Ok(handled)
+    }
+
+    /// Completes a refund whose payout in flight an M-Pesa statement line shows was paid.
+    /// Refunds with no payout in flight are returned untouched.
+    pub async fn complete_from_statement(
+        conn: &mut PgConnection,
+        refund_id: Uuid,
+        receipt_number: &str,
+    ) -> Result<Refund, String> {
+        let refund = Self::lock(conn, refund_id).await?;
+        if refund.status != PaymentStatus::Pending || refund.sent_at.is_none() {
+            return Ok(refund);
+        }
+        Self::complete(conn, &refund, None, Some(receipt_number), None, "Paid out according to the M-Pesa statement").await
+    }
+
+    /// Gives up on unacknowledged payouts sent during a statement's period, from `start` to
+    /// `end`, that no line of it paid within `window`, so the refund is sent again. Any line
+    /// that could be the payout, such as one with a masked phone, keeps it waiting. Returns
+    /// the refunds released.
+    pub async fn release_unpaid(
+        conn: &mut PgConnection,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Uuid>, String> {
+        let unpaid: Vec<Refund> = sqlx::query_as(&format!(
+            "SELECT {} FROM refunds r
+             WHERE status = $1 AND unacknowledged_at IS NOT NULL AND sent_at BETWEEN $2 AND $3
+               AND NOT EXISTS (
+                   SELECT 1 FROM mpesa_statement_lines l
+                   WHERE l.withdrawn = r.amount AND l.completed_at BETWEEN r.sent_at AND r.sent_at + $4
+                     AND (l.phone_number IS NULL OR l.phone_number = r.phone_number))
+             FOR UPDATE",
+            REFUND_COLUMNS
+        ))
+        .bind(PaymentStatus::Pending)
+        .bind(start)
+        .bind(end - window)
+        .bind(window)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut released = Vec::new();
+        for refund in unpaid {
+            tracing::warn!("[M-PESA] Payout {} of refund {} is in no M-Pesa statement", refund.attempt, refund.id);
+            Self::fail_locked(conn, &refund, None, "Not paid out according to the M-Pesa statement", true, now).await?;
+            released.push(refund.id);
+        }
+        Ok(released)
+    }
+
+    pub async fn for_user(pool: &PgPool, user_id: Uuid) -> Result<Vec<Refund>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM refunds WHERE user_id = $1 ORDER BY created_at DESC LIMIT 50",
+            REFUND_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// What Daraja knows the current payout of a refund by.
+    fn originator_id(refund: &Refund) -> String {
+        format!("{}-{}", refund.id, refund.attempt)
+    }
+
+    async fn find_by_token(pool: &PgPool, callback_token: &str) -> Result<Option<Refund>, String> {
+        sqlx::query_as(&format!("SELECT {} FROM refunds WHERE callback_token = $1", REFUND_COLUMNS))
+            .bind(callback_token)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn lock(conn: &mut PgConnection, refund_id: Uuid) -> Result<Refund, String> {
+        sqlx::query_as(&format!("SELECT {} FROM refunds WHERE id = $1 FOR UPDATE", REFUND_COLUMNS))
+            .bind(refund_id)
+            .fetch_one(conn)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Ends payout `attempt` of a refund as not made, if it is still in flight.
+    async fn fail(
+        pool: &PgPool,
+        refund_id: Uuid,
+        attempt: i32,
+        result_code: Option<String>,
+        reason: &str,
+        retry: bool,
+        now: DateTime<Utc>,
+    ) -> Result<Refund, String> {
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        let refund = Self::lock(&mut tx, refund_id).await?;
+        if refund.status != PaymentStatus::Pending || refund.attempt != attempt || refund.sent_at.is_none() {
+            return Ok(refund);
+        }
+        let failed = Self::fail_locked(&mut tx, &refund, result_code, reason, retry, now).await?;
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(failed)
+    }
+
+    /// Schedules the next payout of a locked refund whose payout was not made, or gives the
+    /// refund up once there is no next one. A refund given up on stays owed in the ledger.
+    async fn fail_locked(
+        conn: &mut PgConnection,
+        refund: &Refund,
+        result_code: Option<String>,
+        reason: &str,
+        retry: bool,
+        now: DateTime<Utc>,
+    ) -> Result<Refund, String> {
+        let retry_at = Self::retry_delay(refund.attempt).filter(|_| retry).map(|delay| now + delay);
+        let status = if retry_at.is_some() { PaymentStatus::Pending } else { PaymentStatus::Failed };
+
+        let failed: Refund = sqlx::query_as(&format!(
+            "UPDATE refunds
+             SET status = $2, result_code = $3, result_desc = $4, retry_at = $5, sent_at = NULL, conversation_id = NULL,
+                 unacknowledged_at = NULL, updated_at = NOW(), settled_at = CASE WHEN $5::timestamptz IS NULL THEN NOW() END
+             WHERE id = $1 RETURNING {}",
+            REFUND_COLUMNS
+        ))
+        .bind(refund.id)
+        .bind(status)
+        .bind(&result_code)
+        .bind(reason)
+        .bind(retry_at)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        match retry_at {
+            Some(at) => tracing::warn!(
+                "[M-PESA] Payout {} of refund {} failed ({}); retrying at {}",
+                failed.attempt, failed.id, reason, at
+            ),
+            None => {
+                tracing::error!("[M-PESA] Giving up on refund {} after {} payouts: {}", failed.id, failed.attempt, reason);
+                Outbox::enqueue(&mut *conn, &OutboxEvent::NotifyMember {
+                    user_id: failed.user_id,
+                    subject: "We could not send your refund".to_string(),
+                    body: format!(
+                        "M-Pesa would not accept your MicroFund refund of {} to {}. Our team will contact you to pay it another way.",
+                        failed.amount, failed.phone_number
+                    ),
+                })
+                .await?;
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Books a locked refund as paid out: the platform no longer owes it.
+    async fn complete(
+        conn: &mut PgConnection,
+        refund: &Refund,
+        conversation_id: Option<&str>,
+        receipt_number: Option<&str>,
+        result_code: Option<String>,
+        result_desc: &str,
+    ) -> Result<Refund, String> {
+        let completed: Refund = sqlx::query_as(&format!(
+            "UPDATE refunds
+             SET status = $2, conversation_id = COALESCE($3, conversation_id), transaction_id = $4, result_code = $5,
+                 result_desc = $6, retry_at = NULL, updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 RETURNING {}",
+            REFUND_COLUMNS
+        ))
+        .bind(refund.id)
+        .bind(PaymentStatus::Completed)
+        .bind(conversation_id)
+        .bind(receipt_number)
+        .bind(&result_code)
+        .bind(result_desc)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        LedgerService::record_refund(conn, completed.id, completed.user_id, completed.amount).await?;
+
+        BlockchainService::log_to_ledger(&mut *conn, "REFUND", "Refund paid out", completed.amount).await?;
+
+        Outbox::enqueue(&mut *conn, &OutboxEvent::NotifyMember {
+            user_id: completed.user_id,
+            subject: "Your refund has been sent".to_string(),
+            body: format!("{} owed to you by MicroFund has been sent to M-Pesa {}.", completed.amount, completed.phone_number),
+        })
+        .await?;
+
+        tracing::info!("[M-PESA] Refund {} paid out to {}", completed.id, completed.phone_number);
+        Ok(completed)
+    }
+}
@@ -0,0 +1,562 @@
+use chrono::Utc;
+use sqlx::PgConnection;
+use uuid::Uuid;
+use serde::Serialize;
+use crate::middleware::AppError;
+use crate::models::{
+    Loan, LoanInstallment, LoanRepayment, LoanStatus, Money, OverpaymentDestination, RepaymentBucket, PLATFORM_CURRENCY,
+};
+use crate::services::blockchain::BlockchainService;
+use crate::services::ledger::{LedgerService, NewPosting};
+use crate::services::loan_lifecycle::LoanLifecycle;
+use crate::services::loan_schedule::LoanScheduleService;
+use crate::services::outbox::{Outbox, OutboxEvent};
+use crate::services::refunds::{NewRefund, RefundService};
+use crate::services::scoring::{ScoringService, ScoringWeights};
+
+const REPAYMENT_COLUMNS: &str = "id, loan_id, payer_id, amount, principal_paid, interest_paid, fee_paid, penalty_paid,
+    overpayment, overpayment_destination, savings_id, outstanding_after, mpesa_receipt_number, payment_id, created_at";
+
+/// Order in which a payment settles the components of each installment.
+/// Read once at startup from `REPAYMENT_ALLOCATION_ORDER`, e.g. "penalty,fee,interest,principal".
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllocationOrder(Vec<RepaymentBucket>);
+
+impl Default for AllocationOrder {
+    fn default() -> Self {
+        AllocationOrder(RepaymentBucket::ALL.to_vec())
+    }
+}
+
+impl AllocationOrder {
+    /// Parses a comma-separated list naming every bucket exactly once.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let buckets = value
+            .split(',')
+            .map(|name| {
+                RepaymentBucket::parse(name.trim())
+                    .ok_or_else(|| format!("Unknown repayment bucket '{}'", name.trim()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for bucket in RepaymentBucket::ALL {
+            if buckets.iter().filter(|b| **b == bucket).count() != 1 {
+                return Err(format!("Allocation order must name '{}' exactly once", bucket.as_str()));
+            }
+        }
+        Ok(AllocationOrder(buckets))
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("REPAYMENT_ALLOCATION_ORDER") {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn buckets(&self) -> &[RepaymentBucket] {
+        &self.0
+    }
+}
+
+/// Amounts per repayment bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BucketAmounts {
+    pub penalty: Money,
+    pub fee: Money,
+    pub interest: Money,
+    pub principal: Money,
+}
+
+impl BucketAmounts {
+    pub fn zero() -> Self {
+        let zero = Money::zero(PLATFORM_CURRENCY);
+        BucketAmounts { penalty: zero, fee: zero, interest: zero, principal: zero }
+    }
+
+    fn slot(&mut self, bucket: RepaymentBucket) -> &mut Money {
+        match bucket {
+            RepaymentBucket::Penalty => &mut self.penalty,
+            RepaymentBucket::Fee => &mut self.fee,
+            RepaymentBucket::Interest => &mut self.interest,
+            RepaymentBucket::Principal => &mut self.principal,
+        }
+    }
+
+    fn add(&mut self, bucket: RepaymentBucket, amount: Money) -> Result<(), String> {
+        let slot = self.slot(bucket);
+        *slot = slot.checked_add(amount).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    pub fn total(&self) -> Result<Money, String> {
+        Money::sum([self.penalty, self.fee, self.interest, self.principal], PLATFORM_CURRENCY)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// How one payment is spread over the schedule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub installments: Vec<(Uuid, BucketAmounts)>,
+    pub totals: BucketAmounts,
+    pub overpayment: Money,
+}
+
+/// Money received against a loan, confirmed by M-Pesa or, for paybill payments, by staff.
+#[derive(Debug, Clone)]
+pub struct NewRepayment {
+    pub loan_id: Uuid,
+    /// `None` when the money arrived outside the app.
+    pub payer_id: Option<Uuid>,
+    pub amount: Money,
+    pub overpayment_destination: OverpaymentDestination,
+    /// Savings goal for an overpayment; the borrower's most recent goal when `None`.
+    pub savings_id: Option<Uuid>,
+    /// Receipt of the M-Pesa payment the money came in with, checked during reconciliation.
+    pub mpesa_receipt_number: Option<String>,
+    /// The STK push payment that collected it, if any.
+    pub payment_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RepaymentReceipt {
+    pub repayment: LoanRepayment,
+    pub loan_status: LoanStatus,
+}
+
+pub struct RepaymentService;
+
+impl RepaymentService {
+    /// Spreads `amount` over the open installments, oldest first, settling the components
+    /// of each installment in `order` before moving to the next one. Whatever is left once
+    /// the whole schedule is settled is the overpayment.
+    pub fn allocate(amount: Money, installments: &[LoanInstallment], order: &AllocationOrder) -> Result<Allocation, String> {
+        let mut remaining = amount;
+        let mut allocation = Allocation {
+            installments: Vec::new(),
+            totals: BucketAmounts::zero(),
+            overpayment: Money::zero(amount.currency()),
+        };
+
+        let mut open: Vec<&LoanInstallment> = installments.iter().filter(|i| i.paid_at.is_none()).collect();
+        open.sort_by_key(|i| i.installment_number);
+
+        for installment in open {
+            if remaining.is_zero() {
+                break;
+            }
+            let mut applied = BucketAmounts::zero();
+            for bucket in order.buckets() {
+                let owed = installment.due(*bucket).checked_sub(installment.paid(*bucket)).map_err(|e| e.to_string())?;
+                let take = Money::new(owed.minor_units().min(remaining.minor_units()).max(0), amount.currency());
+                if take.is_zero() {
+                    continue;
+                }
+                applied.add(*bucket, take)?;
+                allocation.totals.add(*bucket, take)?;
+                remaining = remaining.checked_sub(take).map_err(|e| e.to_string())?;
+            }
+            if !applied.total()?.is_zero() {
+                allocation.installments.push((installment.id, applied));
+            }
+        }
+
+        allocation.overpayment = remaining;
+        Ok(allocation)
+    }
+
+    /// `Conflict` unless the loan has been paid out and not yet closed.
+    pub fn ensure_repayable(loan: &Loan) -> Result<(), AppError> {
+        if matches!(
+            loan.status,
+            LoanStatus::Disbursed | LoanStatus::InRepayment | LoanStatus::Overdue | LoanStatus::Defaulted
+        ) {
+            Ok(())
+        } else {
+            Err(AppError::Conflict(format!("A {} loan cannot take repayments", loan.status)))
+        }
+    }
+
+    /// Applies a payment to a loan inside the caller's transaction: allocates it against
+    /// the schedule, posts it to the ledger, credits any overpayment to savings or queues its
+    /// refund, and moves the loan to
+    /// `in_repayment` or, once nothing is outstanding, to `repaid`. Only for money that has
+    /// actually arrived: everything is booked as cash received.
+    pub async fn apply(
+        conn: &mut PgConnection,
+        payment: &NewRepayment,
+        order: &AllocationOrder,
+        weights: &ScoringWeights,
+    ) -> Result<RepaymentReceipt, AppError> {
+        Self::book(conn, payment, order, weights, false).await
+    }
+
+    /// Like `apply`, for a payment M-Pesa has already confirmed. Money that arrives after
+    /// the loan stopped taking repayments, say because an earlier payment repaid it, cannot
+    /// be turned away, so all of it goes to the payment's overpayment destination.
+    pub async fn apply_confirmed(
+        conn: &mut PgConnection,
+        payment: &NewRepayment,
+        order: &AllocationOrder,
+        weights: &ScoringWeights,
+    ) -> Result<RepaymentReceipt, AppError> {
+        Self::book(conn, payment, order, weights, true).await
+    }
+
+    async fn book(
+        conn: &mut PgConnection,
+        payment: &NewRepayment,
+        order: &AllocationOrder,
+        weights: &ScoringWeights,
+        confirmed: bool,
+    ) -> Result<RepaymentReceipt, AppError> {
+        let NewRepayment { loan_id, payer_id, amount, overpayment_destination, savings_id, mpesa_receipt_number, payment_id } =
+            payment.clone();
+        if amount.currency() != PLATFORM_CURRENCY || !amount.is_positive() {
+            return Err(AppError::BadRequest(format!(
+                "Repayments must be a positive {} amount",
+                PLATFORM_CURRENCY.code()
+            )));
+        }
+
+        let loan = LoanLifecycle::load_for_update(conn, loan_id).await?;
+        let owed = match Self::ensure_repayable(&loan) {
+            Ok(()) => true,
+            Err(_) if confirmed => {
+                tracing::warn!("Payment on {} loan {} goes to its overpayment destination", loan.status, loan_id);
+                false
+            }
+            Err(e) => return Err(e),
+        };
+
+        let (lender_id, allocation) = if owed {
+            let lender_id = loan.lender_id.ok_or_else(|| {
+                tracing::error!("Loan {} is {} without a lender", loan_id, loan.status);
+                AppError::InternalServerError
+            })?;
+
+            let installments = LoanScheduleService::installments(conn, loan_id).await.map_err(|e| {
+                tracing::error!("Failed to load schedule of loan {}: {:?}", loan_id, e);
+                AppError::InternalServerError
+            })?;
+
+            let allocation = Self::allocate(amount, &installments, order).map_err(|e| {
+                tracing::error!("Failed to allocate repayment on loan {}: {}", loan_id, e);
+                AppError::InternalServerError
+            })?;
+            (Some(lender_id), allocation)
+        } else {
+            (None, Allocation { installments: Vec::new(), totals: BucketAmounts::zero(), overpayment: amount })
+        };
+
+        for (installment_id, applied) in &allocation.installments {
+            sqlx::query(
+                "UPDATE loan_installments
+                 SET principal_paid = principal_paid + $2, interest_paid = interest_paid + $3,
+                     fee_paid = fee_paid + $4, penalty_paid = penalty_paid + $5
+                 WHERE id = $1"
+            )
+            .bind(installment_id)
+            .bind(applied.principal)
+            .bind(applied.interest)
+            .bind(applied.fee)
+            .bind(applied.penalty)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to update installment {}: {:?}", installment_id, e);
+                AppError::InternalServerError
+            })?;
+        }
+
+        sqlx::query(
+            "UPDATE loan_installments SET paid_at = NOW()
+             WHERE loan_id = $1 AND paid_at IS NULL
+               AND principal_paid + interest_paid + fee_paid + penalty_paid = total_due"
+        )
+        .bind(loan_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+
+        let refund_phone = if allocation.overpayment.is_positive() && overpayment_destination == OverpaymentDestination::Refund {
+            Some(Self::refund_phone(conn, &loan, payment_id).await?)
+        } else {
+            None
+        };
+
+        let overpayment_savings = if allocation.overpayment.is_positive() {
+            match overpayment_destination {
+                OverpaymentDestination::Savings => {
+                    Some(Self::credit_savings(conn, &loan, savings_id, allocation.overpayment).await?)
+                }
+                OverpaymentDestination::Refund => None,
+            }
+        } else {
+            None
+        };
+
+        let overpayment_credit = if allocation.overpayment.is_positive() {
+            let account = match overpayment_savings {
+                Some(savings_id) => LedgerService::savings_account(conn, savings_id, loan.user_id).await,
+                None => LedgerService::refunds_payable_account(conn, loan.user_id).await,
+            }
+            .map_err(|e| {
+                tracing::error!("Failed to open overpayment account: {}", e);
+                AppError::InternalServerError
+            })?;
+            Some(NewPosting::credit(account, allocation.overpayment))
+        } else {
+            None
+        };
+
+        let settled = Money::sum(
+            [allocation.totals.principal, allocation.totals.fee, allocation.totals.penalty],
+            PLATFORM_CURRENCY,
+        )
+        .map_err(|_| AppError::InternalServerError)?;
+
+        LedgerService::record_repayment(
+            conn,
+            loan_id,
+            loan.user_id,
+            lender_id,
+            settled,
+            allocation.totals.interest,
+            overpayment_credit,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to post repayment to ledger: {}", e);
+            AppError::InternalServerError
+        })?;
+
+        let outstanding: (Money,) = sqlx::query_as(
+            "SELECT COALESCE(SUM(total_due - principal_paid - interest_paid - fee_paid - penalty_paid), 0)::bigint
+             FROM loan_installments WHERE loan_id = $1"
+        )
+        .bind(loan_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+
+        let repayment: LoanRepayment = sqlx::query_as(&format!(
+            "INSERT INTO loan_repayments (loan_id, payer_id, amount, principal_paid, interest_paid, fee_paid, penalty_paid,
+                 overpayment, overpayment_destination, savings_id, outstanding_after, mpesa_receipt_number, payment_id)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+             RETURNING {}",
+            REPAYMENT_COLUMNS
+        ))
+        .bind(loan_id)
+        .bind(payer_id)
+        .bind(amount)
+        .bind(allocation.totals.principal)
+        .bind(allocation.totals.interest)
+        .bind(allocation.totals.fee)
+        .bind(allocation.totals.penalty)
+        .bind(allocation.overpayment)
+        .bind(allocation.overpayment.is_positive().then_some(overpayment_destination))
+        .bind(overpayment_savings)
+        .bind(outstanding.0)
+        .bind(&mpesa_receipt_number)
+        .bind(payment_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            if e.as_database_error().is_some_and(|d| d.is_unique_violation()) {
+                return AppError::Conflict("This M-Pesa receipt has already been used".to_string());
+            }
+            tracing::error!("Failed to record repayment: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+        if let Some(savings_id) = overpayment_savings {
+            // Linked to the repayment, whose receipt makes the credit withdrawable
+            sqlx::query(
+                "INSERT INTO savings_transactions (savings_id, amount, transaction_type, repayment_id) VALUES ($1, $2, $3, $4)"
+            )
+            .bind(savings_id)
+            .bind(allocation.overpayment)
+            .bind("loan_overpayment")
+            .bind(repayment.id)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        }
+
+        if let Some(phone_number) = refund_phone {
+            RefundService::request(conn, &NewRefund {
+                user_id: loan.user_id,
+                amount: allocation.overpayment,
+                phone_number,
+                reason: format!("Overpayment of loan {}", loan_id),
+                loan_id: Some(loan_id),
+                repayment_id: Some(repayment.id),
+                payment_id,
+            })
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to request refund of overpayment on loan {}: {}", loan_id, e);
+                AppError::InternalServerError
+            })?;
+        }
+
+        BlockchainService::log_to_ledger(
+            &mut *conn,
+            "REPAYMENT",
+            &format!("Repayment on loan {}", loan_id),
+            amount
+        ).await.map_err(|_| AppError::InternalServerError)?;
+
+        let body = match (owed, overpayment_destination) {
+            (true, _) => format!("We received a repayment of {} on your MicroFund loan. Still outstanding: {}.", amount, outstanding.0),
+            (false, OverpaymentDestination::Savings) => format!(
+                "Your MicroFund loan no longer takes repayments, so we added your payment of {} to your savings.",
+                amount
+            ),
+            (false, OverpaymentDestination::Refund) => format!(
+                "Your MicroFund loan no longer takes repayments, so we are sending your payment of {} back to you.",
+                amount
+            ),
+        };
+        Outbox::enqueue(&mut *conn, &OutboxEvent::NotifyMember {
+            user_id: loan.user_id,
+            subject: "Repayment received".to_string(),
+            body,
+        })
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+
+        let loan_status = if owed {
+            Self::advance_status(conn, &loan, payer_id, outstanding.0, weights).await?
+        } else {
+            loan.status
+        };
+
+        Ok(RepaymentReceipt { repayment, loan_status })
+    }
+
+    /// Closes the loan once nothing is outstanding; otherwise a first payment starts the
+    /// repayment phase and an overdue loan recovers when no installment is past due anymore.
+    async fn advance_status(
+        conn: &mut PgConnection,
+        loan: &Loan,
+        payer_id: Option<Uuid>,
+        outstanding: Money,
+        weights: &ScoringWeights,
+    ) -> Result<LoanStatus, AppError> {
+        if outstanding.is_zero() {
+            LoanLifecycle::transition(conn, loan.id, LoanStatus::Repaid, payer_id, None).await?;
+
+            ScoringService::rescore(conn, loan.user_id, weights, Utc::now(), "loan_repaid")
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to rescore user {}: {}", loan.user_id, e);
+                    AppError::InternalServerError
+                })?;
+
+            return Ok(LoanStatus::Repaid);
+        }
+
+        let next = match loan.status {
+            LoanStatus::Disbursed => Some(LoanStatus::InRepayment),
+            LoanStatus::Overdue => {
+                let (past_due,): (bool,) = sqlx::query_as(
+                    "SELECT EXISTS (SELECT 1 FROM loan_installments WHERE loan_id = $1 AND paid_at IS NULL AND due_date < NOW())"
+                )
+                .bind(loan.id)
+                .fetch_one(&mut *conn)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+                (!past_due).then_some(LoanStatus::InRepayment)
+            }
+            _ => None,
+        };
+
+        match next {
+            Some(status) => {
+                LoanLifecycle::transition(conn, loan.id, status, payer_id, Some("Partial repayment")).await?;
+                Ok(status)
+            }
+            None => Ok(loan.status),
+        }
+    }
+
+    /// Adds an overpayment to the chosen savings goal, or the borrower's most recent one,
+    /// opening a goal for it when the borrower has none. Returns the goal credited. Reached
+    /// only through `apply`, so the money has been received.
+    async fn credit_savings(
+        conn: &mut PgConnection,
+        loan: &Loan,
+        savings_id: Option<Uuid>,
+        amount: Money,
+    ) -> Result<Uuid, AppError> {
+        let existing: Option<(Uuid,)> = sqlx::query_as(
+            "SELECT id FROM savings WHERE user_id = $1 AND ($2::uuid IS NULL OR id = $2)
+             ORDER BY updated_at DESC NULLS LAST LIMIT 1"
+        )
+        .bind(loan.user_id)
+        .bind(savings_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+
+        let target = match (existing, savings_id) {
+            (Some((id,)), _) => id,
+            (None, Some(_)) => return Err(AppError::BadRequest("Savings goal not found".to_string())),
+            (None, None) => {
+                let (id,): (Uuid,) = sqlx::query_as(
+                    "INSERT INTO savings (user_id, goal_name) VALUES ($1, $2) RETURNING id"
+                )
+                .bind(loan.user_id)
+                .bind("Loan overpayments")
+                .fetch_one(&mut *conn)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+                id
+            }
+        };
+
+        sqlx::query("UPDATE savings SET amount = amount + $1, updated_at = NOW() WHERE id = $2")
+            .bind(amount)
+            .bind(target)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        Ok(target)
+    }
+
+    /// Where a refunded overpayment is paid out: the number the payment came from, or the
+    /// borrower's verified number for money paid to the paybill.
+    async fn refund_phone(conn: &mut PgConnection, loan: &Loan, payment_id: Option<Uuid>) -> Result<String, AppError> {
+        let phone: Option<(String,)> = match payment_id {
+            Some(payment_id) => sqlx::query_as("SELECT phone_number FROM payments WHERE id = $1")
+                .bind(payment_id)
+                .fetch_optional(&mut *conn)
+                .await,
+            None => sqlx::query_as(
+                "SELECT phone_number FROM users WHERE id = $1 AND phone_number IS NOT NULL AND phone_verified_at IS NOT NULL"
+            )
+            .bind(loan.user_id)
+            .fetch_optional(&mut *conn)
+            .await,
+        }
+        .map_err(|_| AppError::InternalServerError)?;
+
+        phone.map(|(phone,)| phone).ok_or_else(|| {
+            AppError::BadRequest("The borrower has no verified phone number to refund an overpayment to".to_string())
+        })
+    }
+
+    pub async fn repayments(conn: &mut PgConnection, loan_id: Uuid) -> Result<Vec<LoanRepayment>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM loan_repayments WHERE loan_id = $1 ORDER BY created_at",
+            REPAYMENT_COLUMNS
+        ))
+        .bind(loan_id)
+        .fetch_all(conn)
+        .await
+    }
+}
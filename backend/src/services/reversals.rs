@@ -0,0 +1,103 @@
+use serde::Serialize;
+use sqlx::PgConnection;
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::Money;
+use crate::services::blockchain::BlockchainService;
+use crate::services::ledger::LedgerService;
+use crate::services::withdrawals::WithdrawalService;
+
+/// A savings movement undone by staff.
+#[derive(Debug, Serialize)]
+pub struct Reversal {
+    /// The negative movement that undoes the original.
+    pub id: Uuid,
+    pub reverses_id: Uuid,
+    pub savings_id: Uuid,
+    pub amount: Money,
+    pub journal_entry_id: Uuid,
+}
+
+/// Undoing money movements that should not have been recorded, such as a deposit M-Pesa
+/// later reversed. Each is undone by a new movement and ledger entry; nothing is deleted.
+pub struct ReversalService;
+
+impl ReversalService {
+    /// Takes a deposit back out of its savings goal. The goal must still hold the money, beyond
+    /// what its pending withdrawals hold.
+    pub async fn reverse_savings_deposit(conn: &mut PgConnection, transaction_id: Uuid, actor_id: Uuid) -> Result<Reversal, AppError> {
+        let original: Option<(Uuid, Money, String, bool)> = sqlx::query_as(
+            "SELECT t.savings_id, t.amount, t.transaction_type,
+                    EXISTS (SELECT 1 FROM savings_transactions r WHERE r.reverses_id = t.id)
+             FROM savings_transactions t WHERE t.id = $1 FOR UPDATE"
+        )
+        .bind(transaction_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        let (savings_id, amount, transaction_type, reversed) = original.ok_or(AppError::NotFound)?;
+
+        if transaction_type != "deposit" {
+            return Err(AppError::BadRequest(format!("Only deposits can be reversed, not a {}", transaction_type)));
+        }
+        if reversed {
+            return Err(AppError::Conflict("The deposit is already reversed".to_string()));
+        }
+
+        // Locked like a withdrawal locks it, so neither can spend money the other takes out
+        let (owner_id, balance): (Uuid, Money) = sqlx::query_as("SELECT user_id, amount FROM savings WHERE id = $1 FOR UPDATE")
+            .bind(savings_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        let (_, confirmed, held) = WithdrawalService::balances(&mut *conn, owner_id, Some(savings_id))
+            .await
+            .map_err(|_| AppError::InternalServerError)?
+            .pop()
+            .ok_or(AppError::InternalServerError)?;
+        // Money pending withdrawals hold is on its way out and cannot be taken back as well
+        let available = WithdrawalService::available(balance, confirmed, held);
+        if available < amount {
+            return Err(AppError::Conflict(format!(
+                "The savings goal has {} available, less than the {} deposit",
+                available, amount
+            )));
+        }
+
+        sqlx::query("UPDATE savings SET amount = amount - $1, updated_at = NOW() WHERE id = $2")
+            .bind(amount)
+            .bind(savings_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        let negative = Money::new(-amount.minor_units(), amount.currency());
+        let (id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO savings_transactions (savings_id, amount, transaction_type, reverses_id) VALUES ($1, $2, $3, $4) RETURNING id"
+        )
+        .bind(savings_id)
+        .bind(negative)
+        .bind("reversal")
+        .bind(transaction_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to record reversal of {}: {:?}", transaction_id, e);
+            AppError::InternalServerError
+        })?;
+
+        let journal_entry_id = LedgerService::record_savings_reversal(conn, savings_id, owner_id, amount, actor_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to post reversal of {} to ledger: {}", transaction_id, e);
+                AppError::InternalServerError
+            })?;
+
+        BlockchainService::log_to_ledger(&mut *conn, "SAVINGS_REVERSAL", "Reversed deposit to savings goal", amount)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        tracing::info!("Deposit {} of {} to savings {} reversed by {}", transaction_id, amount, savings_id, actor_id);
+        Ok(Reversal { id, reverses_id: transaction_id, savings_id, amount, journal_entry_id })
+    }
+}
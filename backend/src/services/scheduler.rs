@@ -0,0 +1,286 @@
+use std::sync::Arc;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use crate::middleware::idempotency::IdempotencyStore;
+use crate::middleware::rate_limit::PostgresStore;
+use crate::services::anchor::Anchor;
+use crate::services::blockchain::BlockchainService;
+use crate::services::checkpoints::CheckpointService;
+use crate::services::clock::Clock;
+use crate::services::delinquency::{DelinquencyConfig, DelinquencyService};
+use crate::services::disbursements::DisbursementService;
+use crate::services::ledger_chain::{LedgerChain, LedgerSigner};
+use crate::services::mpesa::MpesaClient;
+use crate::services::outbox::OutboxDispatcher;
+use crate::services::payments::PaymentService;
+use crate::services::reconciliation::ReconciliationService;
+use crate::services::refunds::RefundService;
+use crate::services::repayments::AllocationOrder;
+use crate::services::scoring::ScoringWeights;
+use crate::services::solana::SolanaClient;
+use crate::services::withdrawals::WithdrawalService;
+
+/// How long a claimed run may take before another instance may take it over.
+const LEASE_SECONDS: i64 = 600;
+
+/// The recurring jobs of the platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Job {
+    MarkOverdue,
+    ApplyPenalties,
+    DefaultLoans,
+    ResolvePayments,
+    DisburseLoans,
+    PayRefunds,
+    ReconcileStatements,
+    ExpireWithdrawals,
+    PruneRateLimits,
+    PruneIdempotencyKeys,
+    SealLedger,
+    CheckpointLedger,
+    SyncBlockchain,
+    DispatchOutbox,
+}
+
+impl Job {
+    /// In the order they should run within a tick: penalties and defaults build on overdue flags,
+    /// checkpoints on sealed ledger entries.
+    pub const ALL: [Job; 14] = [
+        Job::MarkOverdue,
+        Job::ApplyPenalties,
+        Job::DefaultLoans,
+        Job::ResolvePayments,
+        Job::DisburseLoans,
+        Job::PayRefunds,
+        Job::ReconcileStatements,
+        Job::ExpireWithdrawals,
+        Job::PruneRateLimits,
+        Job::PruneIdempotencyKeys,
+        Job::SealLedger,
+        Job::CheckpointLedger,
+        Job::SyncBlockchain,
+        Job::DispatchOutbox,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Job::MarkOverdue => "mark_overdue",
+            Job::ApplyPenalties => "apply_penalties",
+            Job::DefaultLoans => "default_loans",
+            Job::ResolvePayments => "resolve_payments",
+            Job::DisburseLoans => "disburse_loans",
+            Job::PayRefunds => "pay_refunds",
+            Job::ReconcileStatements => "reconcile_statements",
+            Job::ExpireWithdrawals => "expire_withdrawals",
+            Job::PruneRateLimits => "prune_rate_limits",
+            Job::PruneIdempotencyKeys => "prune_idempotency_keys",
+            Job::SealLedger => "seal_ledger",
+            Job::CheckpointLedger => "checkpoint_ledger",
+            Job::SyncBlockchain => "sync_blockchain",
+            Job::DispatchOutbox => "dispatch_outbox",
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        match self {
+            Job::MarkOverdue => Duration::minutes(15),
+            Job::ApplyPenalties => Duration::minutes(15),
+            Job::DefaultLoans => Duration::hours(1),
+            Job::ResolvePayments => Duration::minutes(1),
+            Job::DisburseLoans => Duration::minutes(1),
+            Job::PayRefunds => Duration::minutes(5),
+            Job::ReconcileStatements => Duration::days(1),
+            Job::ExpireWithdrawals => Duration::minutes(5),
+            Job::PruneRateLimits => Duration::hours(1),
+            Job::PruneIdempotencyKeys => Duration::hours(1),
+            Job::SealLedger => Duration::minutes(1),
+            Job::CheckpointLedger => Duration::minutes(10),
+            Job::SyncBlockchain => Duration::minutes(1),
+            Job::DispatchOutbox => Duration::minutes(1),
+        }
+    }
+
+    /// Next run after one that started at `started_at`.
+    pub fn next_run_after(&self, started_at: DateTime<Utc>) -> DateTime<Utc> {
+        started_at + self.interval()
+    }
+}
+
+/// Runs jobs in-process. Schedule state lives in `scheduled_jobs`, so a restart picks up
+/// where the last process left off and several instances never run the same job at once.
+pub struct Scheduler {
+    pool: PgPool,
+    clock: Arc<dyn Clock>,
+    config: DelinquencyConfig,
+    weights: ScoringWeights,
+    mpesa: MpesaClient,
+    signer: LedgerSigner,
+    anchor: Option<Arc<dyn Anchor>>,
+    solana: Option<SolanaClient>,
+    outbox: Option<OutboxDispatcher>,
+    allocation_order: AllocationOrder,
+}
+
+impl Scheduler {
+    pub fn new(
+        pool: PgPool,
+        clock: Arc<dyn Clock>,
+        config: DelinquencyConfig,
+        weights: ScoringWeights,
+        mpesa: MpesaClient,
+        signer: LedgerSigner,
+        anchor: Option<Arc<dyn Anchor>>,
+    ) -> Self {
+        Scheduler {
+            pool,
+            clock,
+            config,
+            weights,
+            mpesa,
+            signer,
+            anchor,
+            solana: None,
+            outbox: None,
+            allocation_order: AllocationOrder::default(),
+        }
+    }
+
+    /// Records loans with the microfund program on Solana; without it the sync job does nothing.
+    pub fn with_solana(mut self, solana: Option<SolanaClient>) -> Self {
+        self.solana = solana;
+        self
+    }
+
+    /// Delivers side effects queued in the outbox; without it they wait for a process that has one.
+    pub fn with_outbox(mut self, outbox: OutboxDispatcher) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// How repayments confirmed by the stale payment job are allocated; the default order otherwise.
+    pub fn with_allocation_order(mut self, order: AllocationOrder) -> Self {
+        self.allocation_order = order;
+        self
+    }
+
+    /// Registers every job, keeping the schedule of jobs that already exist.
+    pub async fn register(&self) -> Result<(), String> {
+        let now = self.clock.now();
+        for job in Job::ALL {
+            sqlx::query(
+                "INSERT INTO scheduled_jobs (name, interval_seconds, next_run_at) VALUES ($1, $2, $3)
+                 ON CONFLICT (name) DO UPDATE SET interval_seconds = EXCLUDED.interval_seconds"
+            )
+            .bind(job.name())
+            .bind(job.interval().num_seconds() as i32)
+            .bind(now)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Runs every job that is due now. Returns the jobs that ran.
+    pub async fn tick(&self) -> Vec<Job> {
+        let mut ran = Vec::new();
+        for job in Job::ALL {
+            match self.run_if_due(job).await {
+                Ok(true) => ran.push(job),
+                Ok(false) => {}
+                Err(e) => tracing::error!("[SCHEDULER] Could not run job {}: {}", job.name(), e),
+            }
+        }
+        ran
+    }
+
+    /// Claims the job if it is due and not leased by another run, runs it and records the outcome.
+    async fn run_if_due(&self, job: Job) -> Result<bool, String> {
+        let now = self.clock.now();
+
+        let claimed = sqlx::query(
+            "UPDATE scheduled_jobs SET locked_until = $2, last_started_at = $3
+             WHERE name = $1 AND next_run_at <= $3 AND (locked_until IS NULL OR locked_until < $3)"
+        )
+        .bind(job.name())
+        .bind(now + Duration::seconds(LEASE_SECONDS))
+        .bind(now)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| e.to_string())?
+        .rows_affected();
+
+        if claimed == 0 {
+            return Ok(false);
+        }
+
+        let result = self.run(job, now).await;
+        let (status, affected, error) = match &result {
+            Ok(affected) => ("succeeded", Some(*affected as i64), None),
+            Err(e) => {
+                tracing::error!("[SCHEDULER] Job {} failed: {}", job.name(), e);
+                ("failed", None, Some(e.clone()))
+            }
+        };
+
+        sqlx::query(
+            "UPDATE scheduled_jobs
+             SET locked_until = NULL, next_run_at = $2, last_finished_at = $3, last_status = $4,
+                 last_error = $5, last_affected = $6, run_count = run_count + 1
+             WHERE name = $1"
+        )
+        .bind(job.name())
+        .bind(job.next_run_after(now))
+        .bind(self.clock.now())
+        .bind(status)
+        .bind(error)
+        .bind(affected)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        Ok(true)
+    }
+
+    async fn run(&self, job: Job, now: DateTime<Utc>) -> Result<u64, String> {
+        match job {
+            Job::MarkOverdue => DelinquencyService::mark_overdue(&self.pool, now, &self.weights).await,
+            Job::ApplyPenalties => DelinquencyService::apply_penalties(&self.pool, now, &self.config).await,
+            Job::DefaultLoans => DelinquencyService::default_loans(&self.pool, now, &self.config, &self.weights).await,
+            Job::ResolvePayments => {
+                PaymentService::resolve_stale(&self.pool, &self.mpesa, &self.allocation_order, &self.weights, now).await
+            }
+            Job::DisburseLoans => DisbursementService::run_due(&self.pool, &self.mpesa, now).await,
+            Job::PayRefunds => RefundService::run_due(&self.pool, &self.mpesa, now).await,
+            Job::ReconcileStatements => ReconciliationService::run_due(&self.pool, now).await,
+            Job::ExpireWithdrawals => WithdrawalService::escalate_stale(&self.pool, now).await,
+            Job::PruneRateLimits => PostgresStore::prune(&self.pool, now).await,
+            Job::PruneIdempotencyKeys => IdempotencyStore::prune(&self.pool, now).await,
+            Job::SealLedger => LedgerChain::seal_pending(&self.pool, &self.signer, now).await,
+            Job::CheckpointLedger => CheckpointService::run(&self.pool, &self.signer, self.anchor.as_deref(), now).await,
+            Job::SyncBlockchain => match &self.solana {
+                Some(solana) => BlockchainService::sync_loans(&self.pool, solana, now).await,
+                None => Ok(0),
+            },
+            Job::DispatchOutbox => match &self.outbox {
+                Some(outbox) => outbox.run_due(&self.pool, now).await,
+                None => Ok(0),
+            },
+        }
+    }
+
+    /// Checks for due jobs every `every` until the process exits.
+    pub fn start(self, every: std::time::Duration) {
+        tokio::spawn(async move {
+            if let Err(e) = self.register().await {
+                tracing::error!("[SCHEDULER] Failed to register jobs: {}", e);
+                return;
+            }
+            let mut ticker = tokio::time::interval(every);
+            loop {
+                ticker.tick().await;
+                self.tick().await;
+            }
+        });
+    }
+}
@@ -0,0 +1,432 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::PgConnection;
+use uuid::Uuid;
+use microfund_shared::Rounding;
+use crate::models::{LoanStatus, Money, PLATFORM_CURRENCY};
+
+/// Largest loan the platform books, in major units. A perfect score unlocks all of it.
+pub const MAX_LOAN_MAJOR: i64 = 5000;
+/// Scores run from 0 to this value.
+pub const MAX_SCORE: i32 = 1000;
+/// Months of savings history looked at for savings consistency.
+const SAVINGS_WINDOW_MONTHS: i64 = 6;
+/// Repaid loans after which the loan history factor is full.
+const FULL_LOAN_HISTORY: i64 = 5;
+/// Account age after which the account age factor is full.
+const FULL_ACCOUNT_AGE_DAYS: i64 = 365;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreFactorKind {
+    RepaymentTimeliness,
+    LoanHistory,
+    SavingsConsistency,
+    DefaultHistory,
+    AccountAge,
+}
+
+impl ScoreFactorKind {
+    pub const ALL: [ScoreFactorKind; 5] = [
+        ScoreFactorKind::RepaymentTimeliness,
+        ScoreFactorKind::LoanHistory,
+        ScoreFactorKind::SavingsConsistency,
+        ScoreFactorKind::DefaultHistory,
+        ScoreFactorKind::AccountAge,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScoreFactorKind::RepaymentTimeliness => "repayment_timeliness",
+            ScoreFactorKind::LoanHistory => "loan_history",
+            ScoreFactorKind::SavingsConsistency => "savings_consistency",
+            ScoreFactorKind::DefaultHistory => "default_history",
+            ScoreFactorKind::AccountAge => "account_age",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+}
+
+/// Relative weight of each factor. Read once at startup from `SCORING_WEIGHTS`,
+/// e.g. "repayment_timeliness=35,loan_history=15,savings_consistency=20,default_history=20,account_age=10".
+/// Factors left out keep their default weight.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoringWeights {
+    pub repayment_timeliness: u32,
+    pub loan_history: u32,
+    pub savings_consistency: u32,
+    pub default_history: u32,
+    pub account_age: u32,
+}
+
+impl Default for ScoringWeights {
+    fn default() -> Self {
+        ScoringWeights {
+            repayment_timeliness: 35,
+            loan_history: 15,
+            savings_consistency: 20,
+            default_history: 20,
+            account_age: 10,
+        }
+    }
+}
+
+impl ScoringWeights {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut weights = Self::default();
+        for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, weight) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected factor=weight, got '{}'", pair))?;
+            let kind = ScoreFactorKind::parse(name.trim())
+                .ok_or_else(|| format!("Unknown scoring factor '{}'", name.trim()))?;
+            let weight: u32 = weight
+                .trim()
+                .parse()
+                .map_err(|_| format!("Weight of '{}' must be a whole number", kind.as_str()))?;
+            *weights.slot(kind) = weight;
+        }
+        if weights.total() == 0 {
+            return Err("At least one scoring factor needs a weight".to_string());
+        }
+        Ok(weights)
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("SCORING_WEIGHTS") {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn weight(&self, kind: ScoreFactorKind) -> u32 {
+        match kind {
+            ScoreFactorKind::RepaymentTimeliness => self.repayment_timeliness,
+            ScoreFactorKind::LoanHistory => self.loan_history,
+            ScoreFactorKind::SavingsConsistency => self.savings_consistency,
+            ScoreFactorKind::DefaultHistory => self.default_history,
+            ScoreFactorKind::AccountAge => self.account_age,
+        }
+    }
+
+    fn slot(&mut self, kind: ScoreFactorKind) -> &mut u32 {
+        match kind {
+            ScoreFactorKind::RepaymentTimeliness => &mut self.repayment_timeliness,
+            ScoreFactorKind::LoanHistory => &mut self.loan_history,
+            ScoreFactorKind::SavingsConsistency => &mut self.savings_consistency,
+            ScoreFactorKind::DefaultHistory => &mut self.default_history,
+            ScoreFactorKind::AccountAge => &mut self.account_age,
+        }
+    }
+
+    pub fn total(&self) -> u32 {
+        ScoreFactorKind::ALL.iter().map(|kind| self.weight(*kind)).sum()
+    }
+}
+
+/// The borrower's track record the score is computed from.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScoringInputs {
+    pub installments_on_time: i64,
+    /// Installments paid after their due date, or still unpaid past it.
+    pub installments_late: i64,
+    pub loans_repaid: i64,
+    pub loans_overdue: i64,
+    /// Defaulted and written-off loans.
+    pub loans_defaulted: i64,
+    /// Of the last six months, how many had at least one savings deposit.
+    pub savings_months: i64,
+    pub account_age_days: i64,
+    /// Sum of the points staff added or took off by hand.
+    #[serde(default)]
+    pub manual_adjustment: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreFactor {
+    pub factor: ScoreFactorKind,
+    /// How well the borrower does on this factor, 0 to 100.
+    pub value: i32,
+    pub weight: u32,
+    /// Contribution to the final score.
+    pub points: i32,
+    pub explanation: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreBreakdown {
+    pub score: i32,
+    pub max_score: i32,
+    pub credit_limit: Money,
+    pub factors: Vec<ScoreFactor>,
+    /// Points staff added or took off on top of the factors.
+    pub adjustment: i32,
+    pub summary: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ScoreSnapshot {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub score: i32,
+    pub credit_limit: Money,
+    pub factors: Json<Vec<ScoreFactor>>,
+    pub weights: Json<ScoringWeights>,
+    pub inputs: Json<ScoringInputs>,
+    pub reason: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+pub struct ScoringService;
+
+impl ScoringService {
+    /// Scores each factor from 0 to 100 and combines them by weight into a score out of
+    /// `MAX_SCORE`. The credit limit is the same share of the largest loan, in whole units.
+    pub fn compute(inputs: &ScoringInputs, weights: &ScoringWeights) -> Result<ScoreBreakdown, String> {
+        let total_weight = weights.total() as i64;
+        if total_weight == 0 {
+            return Err("At least one scoring factor needs a weight".to_string());
+        }
+
+        let factors: Vec<(ScoreFactorKind, i64, String)> = ScoreFactorKind::ALL
+            .iter()
+            .map(|kind| {
+                let (value, explanation) = Self::factor_value(*kind, inputs);
+                (*kind, value.clamp(0, 100), explanation)
+            })
+            .collect();
+
+        let weighted: i64 = factors.iter().map(|(kind, value, _)| value * weights.weight(*kind) as i64).sum();
+        let adjustment = inputs.manual_adjustment.clamp(-(MAX_SCORE as i64), MAX_SCORE as i64);
+        let score = (weighted * MAX_SCORE as i64 / (100 * total_weight) + adjustment).clamp(0, MAX_SCORE as i64) as i32;
+
+        let factors: Vec<ScoreFactor> = factors
+            .into_iter()
+            .map(|(kind, value, explanation)| {
+                let weight = weights.weight(kind);
+                ScoreFactor {
+                    factor: kind,
+                    value: value as i32,
+                    weight,
+                    points: (value * weight as i64 * MAX_SCORE as i64 / (100 * total_weight)) as i32,
+                    explanation,
+                }
+            })
+            .collect();
+
+        let credit_limit = Self::credit_limit(score)?;
+        // The factor where closing the gap to 100 would add the most points
+        let weakest = factors
+            .iter()
+            .filter(|f| f.weight > 0 && f.value < 100)
+            .max_by_key(|f| (100 - f.value) * f.weight as i32);
+        let summary = match weakest {
+            Some(f) => format!(
+                "Your score of {}/{} allows loans up to {}. The biggest gain is available from {}: {}",
+                score, MAX_SCORE, credit_limit, f.factor.as_str().replace('_', " "), f.explanation
+            ),
+            None => format!("Your score of {}/{} allows loans up to {}.", score, MAX_SCORE, credit_limit),
+        };
+
+        Ok(ScoreBreakdown { score, max_score: MAX_SCORE, credit_limit, factors, adjustment: adjustment as i32, summary })
+    }
+
+    /// The share of the largest loan a score unlocks, rounded down to a whole unit.
+    pub fn credit_limit(score: i32) -> Result<Money, String> {
+        let max = Money::from_major(MAX_LOAN_MAJOR, PLATFORM_CURRENCY).map_err(|e| e.to_string())?;
+        let share = max
+            .mul_ratio(score.clamp(0, MAX_SCORE) as i64, MAX_SCORE as i64, Rounding::Down)
+            .map_err(|e| e.to_string())?;
+        let unit = Money::from_major(1, PLATFORM_CURRENCY).map_err(|e| e.to_string())?.minor_units();
+        Ok(Money::new(share.minor_units() / unit * unit, PLATFORM_CURRENCY))
+    }
+
+    fn factor_value(kind: ScoreFactorKind, inputs: &ScoringInputs) -> (i64, String) {
+        match kind {
+            ScoreFactorKind::RepaymentTimeliness => {
+                let total = inputs.installments_on_time + inputs.installments_late;
+                if total == 0 {
+                    (0, "No installments repaid yet.".to_string())
+                } else {
+                    (
+                        inputs.installments_on_time * 100 / total,
+                        format!("{} of {} installments paid on time.", inputs.installments_on_time, total),
+                    )
+                }
+            }
+            ScoreFactorKind::LoanHistory => (
+                inputs.loans_repaid.min(FULL_LOAN_HISTORY) * 100 / FULL_LOAN_HISTORY,
+                format!("{} loans fully repaid; {} count in full.", inputs.loans_repaid, FULL_LOAN_HISTORY),
+            ),
+            ScoreFactorKind::SavingsConsistency => (
+                inputs.savings_months.min(SAVINGS_WINDOW_MONTHS) * 100 / SAVINGS_WINDOW_MONTHS,
+                format!(
+                    "Saved in {} of the last {} months.",
+                    inputs.savings_months.min(SAVINGS_WINDOW_MONTHS),
+                    SAVINGS_WINDOW_MONTHS
+                ),
+            ),
+            ScoreFactorKind::DefaultHistory => {
+                let value = 100 - 50 * inputs.loans_defaulted - 25 * inputs.loans_overdue;
+                let explanation = if inputs.loans_defaulted == 0 && inputs.loans_overdue == 0 {
+                    "No overdue or defaulted loans.".to_string()
+                } else {
+                    format!(
+                        "{} defaulted and {} overdue loans.",
+                        inputs.loans_defaulted, inputs.loans_overdue
+                    )
+                };
+                (value, explanation)
+            }
+            ScoreFactorKind::AccountAge => (
+                inputs.account_age_days.clamp(0, FULL_ACCOUNT_AGE_DAYS) * 100 / FULL_ACCOUNT_AGE_DAYS,
+                format!("Member for {} days; a year counts in full.", inputs.account_age_days.max(0)),
+            ),
+        }
+    }
+
+    pub async fn gather_inputs(conn: &mut PgConnection, user_id: Uuid, now: DateTime<Utc>) -> Result<ScoringInputs, String> {
+        let (installments_on_time, installments_late): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*) FILTER (WHERE i.paid_at IS NOT NULL AND i.paid_at <= i.due_date),
+                    COUNT(*) FILTER (WHERE (i.paid_at IS NOT NULL AND i.paid_at > i.due_date)
+                                        OR (i.paid_at IS NULL AND i.due_date < $2))
+             FROM loan_installments i
+             JOIN loans l ON l.id = i.loan_id
+             WHERE l.user_id = $1"
+        )
+        .bind(user_id)
+        .bind(now)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let (loans_repaid, loans_overdue, loans_defaulted): (i64, i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*) FILTER (WHERE status = $2),
+                    COUNT(*) FILTER (WHERE status = $3),
+                    COUNT(*) FILTER (WHERE status IN ($4, $5))
+             FROM loans WHERE user_id = $1"
+        )
+        .bind(user_id)
+        .bind(LoanStatus::Repaid)
+        .bind(LoanStatus::Overdue)
+        .bind(LoanStatus::Defaulted)
+        .bind(LoanStatus::WrittenOff)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let (savings_months,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(DISTINCT date_trunc('month', t.created_at))
+             FROM savings_transactions t
+             JOIN savings s ON s.id = t.savings_id
+             WHERE s.user_id = $1 AND t.amount > 0
+               AND t.created_at > date_trunc('month', $2::timestamptz) - make_interval(months => $3)"
+        )
+        .bind(user_id)
+        .bind(now)
+        .bind((SAVINGS_WINDOW_MONTHS - 1) as i32)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let (created_at,): (Option<DateTime<Utc>>,) = sqlx::query_as("SELECT created_at FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let (manual_adjustment,): (i64,) = sqlx::query_as("SELECT COALESCE(SUM(points), 0)::bigint FROM score_adjustments WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(ScoringInputs {
+            installments_on_time,
+            installments_late,
+            loans_repaid,
+            loans_overdue,
+            loans_defaulted,
+            savings_months,
+            account_age_days: created_at.map(|c| (now - c).num_days()).unwrap_or(0),
+            manual_adjustment,
+        })
+    }
+
+    /// Adds `points` (negative to take off) to a member's score for good, and rescores them.
+    pub async fn adjust(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        points: i32,
+        reason: &str,
+        adjusted_by: Uuid,
+        weights: &ScoringWeights,
+        now: DateTime<Utc>,
+    ) -> Result<ScoreBreakdown, String> {
+        sqlx::query("INSERT INTO score_adjustments (user_id, points, reason, created_by, created_at) VALUES ($1, $2, $3, $4, $5)")
+            .bind(user_id)
+            .bind(points)
+            .bind(reason)
+            .bind(adjusted_by)
+            .bind(now)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Self::rescore(conn, user_id, weights, now, "manual_adjustment").await
+    }
+
+    /// Recomputes a user's score, stores a snapshot with its breakdown and keeps
+    /// `users.reputation_score` in step with the latest score.
+    pub async fn rescore(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        weights: &ScoringWeights,
+        now: DateTime<Utc>,
+        reason: &str,
+    ) -> Result<ScoreBreakdown, String> {
+        let inputs = Self::gather_inputs(conn, user_id, now).await?;
+        let breakdown = Self::compute(&inputs, weights)?;
+
+        sqlx::query(
+            "INSERT INTO credit_score_snapshots (user_id, score, credit_limit, factors, weights, inputs, reason, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+        )
+        .bind(user_id)
+        .bind(breakdown.score)
+        .bind(breakdown.credit_limit)
+        .bind(Json(&breakdown.factors))
+        .bind(Json(weights))
+        .bind(Json(&inputs))
+        .bind(reason)
+        .bind(now)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        sqlx::query("UPDATE users SET reputation_score = $2 WHERE id = $1")
+            .bind(user_id)
+            .bind(breakdown.score)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        tracing::info!("User {} scored {} ({})", user_id, breakdown.score, reason);
+        Ok(breakdown)
+    }
+
+    pub async fn snapshots(conn: &mut PgConnection, user_id: Uuid, limit: i64) -> Result<Vec<ScoreSnapshot>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT id, user_id, score, credit_limit, factors, weights, inputs, reason, created_at
+             FROM credit_score_snapshots WHERE user_id = $1 ORDER BY created_at DESC LIMIT $2"
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(conn)
+        .await
+    }
+}
@@ -0,0 +1,217 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::models::{Session, SessionRevocation};
+
+const SESSION_COLUMNS: &str =
+    "id, user_id, user_agent, ip_address, created_at, last_used_at, expires_at, revoked_at, revoked_reason";
+
+/// Where a login came from, shown in the member's session list.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+/// Login sessions and their rotating refresh tokens. A refresh token can be used once: using
+/// it hands out the next one, and a used token coming back revokes the whole session.
+pub struct SessionService;
+
+impl SessionService {
+    /// A new random refresh token, as handed to the client.
+    pub fn new_refresh_token() -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// What is stored in place of a refresh token.
+    pub fn hash_token(token: &str) -> String {
+        hex::encode(Sha256::digest(token.as_bytes()))
+    }
+
+    /// Opens a session for a member who just logged in. Returns it with its first refresh token.
+    pub async fn start(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        client: &ClientInfo,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<(Session, String), sqlx::Error> {
+        let session: Session = sqlx::query_as(&format!(
+            "INSERT INTO sessions (user_id, user_agent, ip_address, created_at, last_used_at, expires_at)
+             VALUES ($1, $2, $3, $4, $4, $5) RETURNING {}",
+            SESSION_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(client.user_agent.as_deref())
+        .bind(client.ip_address.as_deref())
+        .bind(now)
+        .bind(now + ttl)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let token = Self::add_refresh_token(conn, session.id, now).await?;
+        Ok((session, token))
+    }
+
+    /// Trades a refresh token for the next one. `Unauthorized` for unknown tokens and ended
+    /// sessions; a token that was already traded revokes its session.
+    pub async fn rotate(pool: &PgPool, refresh_token: &str, now: DateTime<Utc>) -> Result<(Session, String), AppError> {
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+
+        let found: Option<(Uuid, Uuid, Option<DateTime<Utc>>)> = sqlx::query_as(
+            "SELECT id, session_id, rotated_at FROM refresh_tokens WHERE token_hash = $1 FOR UPDATE"
+        )
+        .bind(Self::hash_token(refresh_token))
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to look up refresh token: {:?}", e);
+            AppError::InternalServerError
+        })?;
+        let (token_id, session_id, rotated_at) = found.ok_or(AppError::Unauthorized)?;
+
+        let session: Session = sqlx::query_as(&format!("SELECT {} FROM sessions WHERE id = $1 FOR UPDATE", SESSION_COLUMNS))
+            .bind(session_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        if !session.is_active(now) {
+            return Err(AppError::Unauthorized);
+        }
+
+        if rotated_at.is_some() {
+            // Only a copy of the token could still be around, so nobody can be trusted with the session
+            tracing::warn!("Refresh token reused in session {} of user {}; revoking it", session.id, session.user_id);
+            Self::end(&mut tx, session.id, SessionRevocation::RefreshTokenReuse, now)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+            tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+            return Err(AppError::Unauthorized);
+        }
+
+        sqlx::query("UPDATE refresh_tokens SET rotated_at = $1 WHERE id = $2")
+            .bind(now)
+            .bind(token_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        let session: Session = sqlx::query_as(&format!(
+            "UPDATE sessions SET last_used_at = $1 WHERE id = $2 RETURNING {}",
+            SESSION_COLUMNS
+        ))
+        .bind(now)
+        .bind(session.id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        let token = Self::add_refresh_token(&mut tx, session.id, now)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+        Ok((session, token))
+    }
+
+    /// Ends one of a member's sessions. False when it is not theirs or already ended.
+    pub async fn revoke(
+        pool: &PgPool,
+        user_id: Uuid,
+        session_id: Uuid,
+        reason: SessionRevocation,
+        now: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE sessions SET revoked_at = $1, revoked_reason = $2
+             WHERE id = $3 AND user_id = $4 AND revoked_at IS NULL"
+        )
+        .bind(now)
+        .bind(reason)
+        .bind(session_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Ends every session of a member except `keep`. Returns how many were ended.
+    pub async fn revoke_all(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        keep: Option<Uuid>,
+        reason: SessionRevocation,
+        now: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE sessions SET revoked_at = $1, revoked_reason = $2
+             WHERE user_id = $3 AND revoked_at IS NULL AND id IS DISTINCT FROM $4"
+        )
+        .bind(now)
+        .bind(reason)
+        .bind(user_id)
+        .bind(keep)
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Whether access tokens of a session may still be used: it is neither revoked nor expired
+    /// and its member is not frozen.
+    pub async fn is_valid(pool: &PgPool, user_id: Uuid, session_id: Uuid, now: DateTime<Utc>) -> Result<bool, sqlx::Error> {
+        let valid: Option<(bool,)> = sqlx::query_as(
+            "SELECT TRUE FROM sessions s JOIN users u ON u.id = s.user_id
+             WHERE s.id = $1 AND s.user_id = $2 AND s.revoked_at IS NULL AND s.expires_at > $3 AND u.frozen_at IS NULL"
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .bind(now)
+        .fetch_optional(pool)
+        .await?;
+        Ok(valid.is_some())
+    }
+
+    /// Sessions of a member that can still be refreshed, most recently used first.
+    pub async fn active_for_user(pool: &PgPool, user_id: Uuid, now: DateTime<Utc>) -> Result<Vec<Session>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM sessions WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > $2
+             ORDER BY last_used_at DESC",
+            SESSION_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(now)
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn add_refresh_token(conn: &mut PgConnection, session_id: Uuid, now: DateTime<Utc>) -> Result<String, sqlx::Error> {
+        let token = Self::new_refresh_token();
+        sqlx::query("INSERT INTO refresh_tokens (session_id, token_hash, created_at) VALUES ($1, $2, $3)")
+            .bind(session_id)
+            .bind(Self::hash_token(&token))
+            .bind(now)
+            .execute(conn)
+            .await?;
+        Ok(token)
+    }
+
+    async fn end(
+        conn: &mut PgConnection,
+        session_id: Uuid,
+        reason: SessionRevocation,
+        now: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE sessions SET revoked_at = $1, revoked_reason = $2 WHERE id = $3 AND revoked_at IS NULL")
+            .bind(now)
+            .bind(reason)
+            .bind(session_id)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
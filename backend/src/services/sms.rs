@@ -0,0 +1,57 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Sends text messages to members' phones. Numbers are in the `2547XXXXXXXX` form.
+#[async_trait]
+pub trait SmsProvider: Send + Sync {
+    async fn send(&self, phone_number: &str, message: &str) -> Result<(), String>;
+}
+
+/// Development stand-in that writes messages to the log instead of sending them.
+pub struct ConsoleSms;
+
+#[async_trait]
+impl SmsProvider for ConsoleSms {
+    async fn send(&self, phone_number: &str, message: &str) -> Result<(), String> {
+        tracing::info!("[SMS] To {}: {}", phone_number, message);
+        Ok(())
+    }
+}
+
+/// Development stand-in that appends messages to a file, one per line, for tests and tools to read.
+pub struct FileSms {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl SmsProvider for FileSms {
+    async fn send(&self, phone_number: &str, message: &str) -> Result<(), String> {
+        let line = format!("{}\t{}\t{}\n", Utc::now().to_rfc3339(), phone_number, message.replace('\n', " "));
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", self.path.display(), e))?;
+        let write_error = |e: std::io::Error| format!("Failed to write {}: {}", self.path.display(), e);
+        file.write_all(line.as_bytes()).await.map_err(write_error)?;
+        // Tokio finishes file writes in the background; flushing waits for the line to land
+        file.flush().await.map_err(write_error)
+    }
+}
+
+/// `SMS_PROVIDER` is `console` (the default) or `file`, which writes to `SMS_OUTBOX_FILE`
+/// (`sms_outbox.log` by default).
+pub fn provider_from_env() -> Result<Arc<dyn SmsProvider>, String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    match var("SMS_PROVIDER").as_deref().map(str::trim) {
+        None | Some("console") => Ok(Arc::new(ConsoleSms)),
+        Some("file") => Ok(Arc::new(FileSms {
+            path: var("SMS_OUTBOX_FILE").unwrap_or_else(|| "sms_outbox.log".to_string()).into(),
+        })),
+        Some(other) => Err(format!("Unknown SMS_PROVIDER '{}'", other)),
+    }
+}
@@ -0,0 +1,478 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{Signer, SigningKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use uuid::Uuid;
+use crate::models::Money;
+
+/// The program declared in `contracts/src/lib.rs`.
+pub const MICROFUND_PROGRAM_ID: &str = "Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS";
+/// Where `solana-test-validator` listens by default.
+const LOCAL_RPC_URL: &str = "http://127.0.0.1:8899";
+/// Longest loan description the program stores, in bytes (`MAX_DESCRIPTION_LEN`).
+pub const MAX_DESCRIPTION_LEN: usize = 196;
+/// Fee payer of the mock validator; it checks signatures, not balances.
+const MOCK_PAYER_SEED: [u8; 32] = [7; 32];
+/// How often a submitted transaction is looked up until it is confirmed.
+const CONFIRM_POLL: StdDuration = StdDuration::from_millis(500);
+
+/// A Solana account address: 32 bytes, shown in base58.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Pubkey(pub [u8; 32]);
+
+impl Pubkey {
+    pub const SYSTEM_PROGRAM: Pubkey = Pubkey([0; 32]);
+
+    /// The program-derived address of `seeds`: the first hash, counting the bump seed down
+    /// from 255, that is not a point on the Ed25519 curve and so has no private key.
+    pub fn find_program_address(seeds: &[&[u8]], program_id: &Pubkey) -> (Pubkey, u8) {
+        for bump in (0..=u8::MAX).rev() {
+            let mut hasher = Sha256::new();
+            for seed in seeds {
+                hasher.update(seed);
+            }
+            hasher.update([bump]);
+            hasher.update(program_id.0);
+            hasher.update(b"ProgramDerivedAddress");
+            let bytes: [u8; 32] = hasher.finalize().into();
+            if CompressedEdwardsY(bytes).decompress().is_none() {
+                return (Pubkey(bytes), bump);
+            }
+        }
+        // Each bump has about even odds of landing off the curve
+        unreachable!("No viable bump seed for a program address")
+    }
+}
+
+impl fmt::Display for Pubkey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&bs58::encode(self.0).into_string())
+    }
+}
+
+impl FromStr for Pubkey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        bs58::decode(s.trim())
+            .into_vec()
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(Pubkey)
+            .ok_or_else(|| format!("'{}' is not a Solana address", s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountMeta {
+    pub pubkey: Pubkey,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub program_id: Pubkey,
+    pub accounts: Vec<AccountMeta>,
+    pub data: Vec<u8>,
+}
+
+impl Instruction {
+    /// An Anchor instruction: the 8-byte discriminator of its name, then its Borsh-encoded arguments.
+    pub fn anchor(program_id: Pubkey, name: &str, args: &[u8], accounts: Vec<AccountMeta>) -> Self {
+        let mut data = anchor_discriminator(name).to_vec();
+        data.extend_from_slice(args);
+        Instruction { program_id, accounts, data }
+    }
+}
+
+/// First 8 bytes of SHA-256 of `global:<name>`, which Anchor matches instructions on.
+pub fn anchor_discriminator(name: &str) -> [u8; 8] {
+    let hash = Sha256::digest(format!("global:{}", name).as_bytes());
+    let mut discriminator = [0u8; 8];
+    discriminator.copy_from_slice(&hash[..8]);
+    discriminator
+}
+
+/// Solana's variable-length encoding of array lengths.
+pub fn encode_length(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// A legacy transaction with the payer as its only signer, in wire format. Returns it with
+/// its signature, which is also its id.
+pub fn build_transaction(payer: &SigningKey, instructions: &[Instruction], recent_blockhash: [u8; 32]) -> Result<(Vec<u8>, String), String> {
+    let payer_key = Pubkey(payer.verifying_key().to_bytes());
+
+    // Payer first, then signers before the rest and writable before read-only
+    let mut keys: Vec<AccountMeta> = vec![AccountMeta { pubkey: payer_key, is_signer: true, is_writable: true }];
+    let metas = instructions.iter().flat_map(|ix| {
+        ix.accounts.iter().cloned().chain(std::iter::once(AccountMeta { pubkey: ix.program_id, is_signer: false, is_writable: false }))
+    });
+    for meta in metas {
+        match keys.iter_mut().find(|key| key.pubkey == meta.pubkey) {
+            Some(key) => {
+                key.is_signer |= meta.is_signer;
+                key.is_writable |= meta.is_writable;
+            }
+            None => keys.push(meta),
+        }
+    }
+    if keys.iter().any(|key| key.is_signer && key.pubkey != payer_key) {
+        return Err("Only the fee payer can sign platform transactions".to_string());
+    }
+    keys.sort_by_key(|key| (!key.is_signer, !key.is_writable));
+
+    let index_of = |pubkey: &Pubkey| keys.iter().position(|key| key.pubkey == *pubkey).unwrap_or_default() as u8;
+    let mut message = vec![
+        1,
+        0,
+        keys.iter().filter(|key| !key.is_signer && !key.is_writable).count() as u8,
+    ];
+    encode_length(&mut message, keys.len());
+    for key in &keys {
+        message.extend_from_slice(&key.pubkey.0);
+    }
+    message.extend_from_slice(&recent_blockhash);
+    encode_length(&mut message, instructions.len());
+    for ix in instructions {
+        message.push(index_of(&ix.program_id));
+        encode_length(&mut message, ix.accounts.len());
+        message.extend(ix.accounts.iter().map(|meta| index_of(&meta.pubkey)));
+        encode_length(&mut message, ix.data.len());
+        message.extend_from_slice(&ix.data);
+    }
+
+    let signature = payer.sign(&message).to_bytes();
+    let mut transaction = Vec::with_capacity(1 + 64 + message.len());
+    encode_length(&mut transaction, 1);
+    transaction.extend_from_slice(&signature);
+    transaction.extend_from_slice(&message);
+    Ok((transaction, bs58::encode(signature).into_string()))
+}
+
+/// Whether the loan account data says the loan is repaid. None if the data is not a loan.
+pub fn loan_account_repaid(data: &[u8]) -> Option<bool> {
+    // Discriminator, borrower, amount and currency, then the description's length
+    let description_at = 8 + 32 + 8 + 3;
+    let len_bytes: [u8; 4] = data.get(description_at..description_at + 4)?.try_into().ok()?;
+    let repaid_at = description_at + 4 + u32::from_le_bytes(len_bytes) as usize;
+    data.get(repaid_at).map(|flag| *flag == 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SolanaMode {
+    /// A JSON-RPC node, such as `solana-test-validator` or a devnet endpoint.
+    Rpc,
+    /// The bundled stand-in validator, started in-process. No network or SOL needed.
+    Mock,
+}
+
+/// Node, program and key the backend records loans with. Read once at startup from the environment.
+#[derive(Clone)]
+pub struct SolanaConfig {
+    pub mode: SolanaMode,
+    pub rpc_url: String,
+    pub program_id: Pubkey,
+    /// Pays for and signs every transaction, as the borrower of record of every loan.
+    pub payer: SigningKey,
+    /// How long a submitted transaction may take to be confirmed.
+    pub confirm_timeout: StdDuration,
+}
+
+impl SolanaConfig {
+    /// Settings for the stand-in validator at `rpc_url`.
+    pub fn mock(rpc_url: &str) -> Self {
+        SolanaConfig {
+            mode: SolanaMode::Mock,
+            rpc_url: rpc_url.to_string(),
+            program_id: MICROFUND_PROGRAM_ID.parse().expect("valid program id"),
+            payer: SigningKey::from_bytes(&MOCK_PAYER_SEED),
+            confirm_timeout: StdDuration::from_secs(5),
+        }
+    }
+
+    /// `SOLANA_MODE` is `disabled` (the default: nothing goes on-chain), `mock` or `rpc`. RPC
+    /// mode talks to `SOLANA_RPC_URL` (a local `solana-test-validator` by default) and signs
+    /// with the `solana-keygen` key file at `SOLANA_KEYPAIR_PATH`. In mock mode the RPC URL is
+    /// filled in once the mock validator is up.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let mode = match var("SOLANA_MODE").as_deref().map(str::trim) {
+            None | Some("disabled") => return Ok(None),
+            Some("mock") => SolanaMode::Mock,
+            Some("rpc") => SolanaMode::Rpc,
+            Some(other) => return Err(format!("Unknown SOLANA_MODE '{}'", other)),
+        };
+
+        let mut config = SolanaConfig::mock("");
+        if let Some(program_id) = var("SOLANA_PROGRAM_ID") {
+            config.program_id = program_id.parse()?;
+        }
+        if let Some(seconds) = var("SOLANA_CONFIRM_TIMEOUT_SECONDS") {
+            let seconds = seconds.trim().parse().map_err(|_| "SOLANA_CONFIRM_TIMEOUT_SECONDS must be a whole number")?;
+            config.confirm_timeout = StdDuration::from_secs(seconds);
+        }
+        if mode == SolanaMode::Mock {
+            return Ok(Some(config));
+        }
+
+        config.mode = mode;
+        config.rpc_url = var("SOLANA_RPC_URL").unwrap_or_else(|| LOCAL_RPC_URL.to_string());
+        let path = var("SOLANA_KEYPAIR_PATH").ok_or("SOLANA_KEYPAIR_PATH must be set")?;
+        let file = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        config.payer = Self::parse_keypair(&file)?;
+        Ok(Some(config))
+    }
+
+    /// A `solana-keygen` key file: a JSON array of the 64 bytes of secret and public key.
+    pub fn parse_keypair(json: &str) -> Result<SigningKey, String> {
+        let bytes: Vec<u8> = serde_json::from_str(json).map_err(|_| "The Solana key file must be a JSON array of bytes")?;
+        let bytes: [u8; 64] = bytes.try_into().map_err(|_| "The Solana key file must hold 64 bytes")?;
+        SigningKey::from_keypair_bytes(&bytes).map_err(|_| "The Solana key file's public key does not match its secret key".to_string())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SolanaError {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("Solana could not be reached: {0}")]
+    Transport(String),
+    #[error("Solana rejected the request ({code}): {message}")]
+    Rpc { code: i64, message: String },
+    #[error("Transaction {signature} failed: {error}")]
+    Failed { signature: String, error: String },
+    #[error("Transaction {0} was not confirmed in time")]
+    Unconfirmed(String),
+}
+
+impl From<reqwest::Error> for SolanaError {
+    fn from(e: reqwest::Error) -> Self {
+        SolanaError::Transport(e.to_string())
+    }
+}
+
+/// A loan recorded on-chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnChainLoan {
+    pub address: Pubkey,
+    pub signature: String,
+}
+
+/// Builds, signs and submits instructions of the microfund program over JSON-RPC.
+#[derive(Clone)]
+pub struct SolanaClient {
+    inner: Arc<SolanaInner>,
+}
+
+struct SolanaInner {
+    config: SolanaConfig,
+    http: reqwest::Client,
+}
+
+impl SolanaClient {
+    pub fn new(config: SolanaConfig) -> Result<Self, SolanaError> {
+        let http = reqwest::Client::builder().timeout(StdDuration::from_secs(10)).build()?;
+        Ok(SolanaClient { inner: Arc::new(SolanaInner { config, http }) })
+    }
+
+    pub fn payer(&self) -> Pubkey {
+        Pubkey(self.inner.config.payer.verifying_key().to_bytes())
+    }
+
+    pub fn loan_address(&self, loan_id: Uuid) -> Pubkey {
+        let payer = self.payer();
+        Pubkey::find_program_address(&[b"loan", &payer.0, loan_id.as_bytes()], &self.inner.config.program_id).0
+    }
+
+    pub fn checkpoint_address(&self, last_sequence_number: u64) -> Pubkey {
+        let payer = self.payer();
+        Pubkey::find_program_address(
+            &[b"checkpoint", &payer.0, &last_sequence_number.to_le_bytes()],
+            &self.inner.config.program_id,
+        )
+        .0
+    }
+
+    /// Records a loan with `initialize_loan`. A loan already on-chain, say from an attempt that
+    /// landed but was never confirmed to us, is returned as it is.
+    pub async fn initialize_loan(&self, loan_id: Uuid, amount: Money, description: &str) -> Result<OnChainLoan, SolanaError> {
+        let address = self.loan_address(loan_id);
+        if self.account_data(&address).await?.is_some() {
+            let signature = self.latest_signature(&address).await?;
+            return Ok(OnChainLoan { address, signature });
+        }
+
+        let minor_units = u64::try_from(amount.minor_units())
+            .ok()
+            .filter(|units| *units > 0)
+            .ok_or_else(|| SolanaError::InvalidRequest(format!("Cannot record a loan of {}", amount)))?;
+        let mut end = description.len().min(MAX_DESCRIPTION_LEN);
+        while !description.is_char_boundary(end) {
+            end -= 1;
+        }
+        let description = &description[..end];
+
+        let mut args = loan_id.as_bytes().to_vec();
+        args.extend_from_slice(&minor_units.to_le_bytes());
+        args.extend_from_slice(&amount.currency().code_bytes());
+        args.extend_from_slice(&(description.len() as u32).to_le_bytes());
+        args.extend_from_slice(description.as_bytes());
+        let instruction = Instruction::anchor(
+            self.inner.config.program_id,
+            "initialize_loan",
+            &args,
+            vec![
+                AccountMeta { pubkey: address, is_signer: false, is_writable: true },
+                AccountMeta { pubkey: self.payer(), is_signer: true, is_writable: true },
+                AccountMeta { pubkey: Pubkey::SYSTEM_PROGRAM, is_signer: false, is_writable: false },
+            ],
+        );
+        let signature = self.send_and_confirm(instruction).await?;
+        Ok(OnChainLoan { address, signature })
+    }
+
+    /// Marks a recorded loan repaid with `repay_loan`. Returns the signature of the repayment,
+    /// also when an earlier attempt already marked it.
+    pub async fn repay_loan(&self, loan_id: Uuid) -> Result<String, SolanaError> {
+        let address = self.loan_address(loan_id);
+        let data = self
+            .account_data(&address)
+            .await?
+            .ok_or_else(|| SolanaError::InvalidRequest(format!("Loan {} is not on-chain", loan_id)))?;
+        if loan_account_repaid(&data) == Some(true) {
+            return self.latest_signature(&address).await;
+        }
+
+        let instruction = Instruction::anchor(
+            self.inner.config.program_id,
+            "repay_loan",
+            &[],
+            vec![
+                AccountMeta { pubkey: address, is_signer: false, is_writable: true },
+                AccountMeta { pubkey: self.payer(), is_signer: true, is_writable: false },
+            ],
+        );
+        self.send_and_confirm(instruction).await
+    }
+
+    /// Publishes a ledger checkpoint root with `anchor_checkpoint`, once per checkpoint.
+    pub async fn anchor_checkpoint(&self, first_sequence_number: u64, last_sequence_number: u64, merkle_root: [u8; 32]) -> Result<String, SolanaError> {
+        let address = self.checkpoint_address(last_sequence_number);
+        if self.account_data(&address).await?.is_some() {
+            return self.latest_signature(&address).await;
+        }
+
+        let mut args = first_sequence_number.to_le_bytes().to_vec();
+        args.extend_from_slice(&last_sequence_number.to_le_bytes());
+        args.extend_from_slice(&merkle_root);
+        let instruction = Instruction::anchor(
+            self.inner.config.program_id,
+            "anchor_checkpoint",
+            &args,
+            vec![
+                AccountMeta { pubkey: address, is_signer: false, is_writable: true },
+                AccountMeta { pubkey: self.payer(), is_signer: true, is_writable: true },
+                AccountMeta { pubkey: Pubkey::SYSTEM_PROGRAM, is_signer: false, is_writable: false },
+            ],
+        );
+        self.send_and_confirm(instruction).await
+    }
+
+    /// Submits the instruction in a fresh transaction and waits until the cluster confirms it.
+    async fn send_and_confirm(&self, instruction: Instruction) -> Result<String, SolanaError> {
+        let blockhash = self.rpc("getLatestBlockhash", json!([{ "commitment": "confirmed" }])).await?;
+        let blockhash: [u8; 32] = blockhash["value"]["blockhash"]
+            .as_str()
+            .and_then(|hash| bs58::decode(hash).into_vec().ok())
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| SolanaError::Transport("Malformed blockhash".to_string()))?;
+
+        let (transaction, signature) =
+            build_transaction(&self.inner.config.payer, &[instruction], blockhash).map_err(SolanaError::InvalidRequest)?;
+        self.rpc(
+            "sendTransaction",
+            json!([BASE64.encode(transaction), { "encoding": "base64", "preflightCommitment": "confirmed" }]),
+        )
+        .await?;
+
+        let deadline = tokio::time::Instant::now() + self.inner.config.confirm_timeout;
+        loop {
+            let statuses = self.rpc("getSignatureStatuses", json!([[signature], { "searchTransactionHistory": false }])).await?;
+            let status = &statuses["value"][0];
+            if !status.is_null() {
+                if !status["err"].is_null() {
+                    return Err(SolanaError::Failed { signature, error: status["err"].to_string() });
+                }
+                if matches!(status["confirmationStatus"].as_str(), Some("confirmed" | "finalized")) {
+                    return Ok(signature);
+                }
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(SolanaError::Unconfirmed(signature));
+            }
+            tokio::time::sleep(CONFIRM_POLL).await;
+        }
+    }
+
+    async fn account_data(&self, address: &Pubkey) -> Result<Option<Vec<u8>>, SolanaError> {
+        let account = self
+            .rpc("getAccountInfo", json!([address.to_string(), { "encoding": "base64", "commitment": "confirmed" }]))
+            .await?;
+        if account["value"].is_null() {
+            return Ok(None);
+        }
+        account["value"]["data"][0]
+            .as_str()
+            .and_then(|data| BASE64.decode(data).ok())
+            .map(Some)
+            .ok_or_else(|| SolanaError::Transport(format!("Malformed data of account {}", address)))
+    }
+
+    /// The newest transaction that touched the account.
+    async fn latest_signature(&self, address: &Pubkey) -> Result<String, SolanaError> {
+        let signatures = self
+            .rpc("getSignaturesForAddress", json!([address.to_string(), { "limit": 1, "commitment": "confirmed" }]))
+            .await?;
+        signatures[0]["signature"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| SolanaError::Transport(format!("No transactions found for account {}", address)))
+    }
+
+    async fn rpc(&self, method: &str, params: Value) -> Result<Value, SolanaError> {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let mut response: Value = self
+            .inner
+            .http
+            .post(&self.inner.config.rpc_url)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if let Some(error) = response.get("error") {
+            return Err(SolanaError::Rpc {
+                code: error["code"].as_i64().unwrap_or_default(),
+                message: error["message"].as_str().unwrap_or("Unknown error").to_string(),
+            });
+        }
+        Ok(response["result"].take())
+    }
+}
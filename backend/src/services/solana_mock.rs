@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::services::solana::{anchor_discriminator, Pubkey, SolanaConfig, MAX_DESCRIPTION_LEN};
+
+/// Anchor's `#[error_code]` numbering starts here.
+const ANCHOR_ERROR_OFFSET: u32 = 6000;
+
+#[derive(Debug, Clone)]
+pub struct MockTransaction {
+    pub signature: String,
+    pub slot: u64,
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub instruction: String,
+    pub accounts: Vec<Pubkey>,
+}
+
+struct MockState {
+    program_id: Pubkey,
+    slot: u64,
+    blockhashes: Vec<[u8; 32]>,
+    accounts: HashMap<Pubkey, Vec<u8>>,
+    transactions: Vec<MockTransaction>,
+    /// Requests answered with 503 before the node is "back".
+    unavailable_for: u32,
+}
+
+/// A stand-in for a Solana node on a local port that runs the microfund program in memory.
+/// Transactions are decoded, their signatures, blockhash and account addresses checked and
+/// their instructions applied as the Anchor program would, errors included. Used by the tests
+/// and by `SOLANA_MODE=mock`.
+pub struct MockValidator {
+    pub rpc_url: String,
+    // Inspected and steered by the tests
+    #[cfg_attr(not(test), allow(dead_code))]
+    state: Arc<Mutex<MockState>>,
+    handle: ServerHandle,
+}
+
+impl MockValidator {
+    /// Starts the server on a free port. Must be called inside an actix runtime.
+    pub async fn start() -> std::io::Result<Self> {
+        let state = Arc::new(Mutex::new(MockState {
+            program_id: SolanaConfig::mock("").program_id,
+            slot: 1,
+            blockhashes: vec![Sha256::digest(b"mock-genesis").into()],
+            accounts: HashMap::new(),
+            transactions: Vec::new(),
+            unavailable_for: 0,
+        }));
+
+        let data = web::Data::from(state.clone());
+        let server = HttpServer::new(move || App::new().app_data(data.clone()).route("/", web::post().to(rpc)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))?;
+
+        let rpc_url = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        Ok(MockValidator { rpc_url, state, handle })
+    }
+
+    /// A client configured for this node.
+    #[cfg(test)]
+    pub fn client(&self) -> crate::services::solana::SolanaClient {
+        crate::services::solana::SolanaClient::new(SolanaConfig::mock(&self.rpc_url)).expect("mock client")
+    }
+
+    /// The next `requests` requests fail with 503, as if the node were down.
+    #[cfg(test)]
+    pub fn set_unavailable_for(&self, requests: u32) {
+        self.state.lock().unwrap().unavailable_for = requests;
+    }
+
+    #[cfg(test)]
+    pub fn transactions(&self) -> Vec<MockTransaction> {
+        self.state.lock().unwrap().transactions.clone()
+    }
+
+    #[cfg(test)]
+    pub fn account(&self, address: &Pubkey) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().accounts.get(address).cloned()
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+    }
+}
+
+/// Why the instruction failed, as the node reports it.
+type Rejection = Value;
+
+fn custom_error(code: u32) -> Rejection {
+    json!({ "InstructionError": [0, { "Custom": code }] })
+}
+
+fn missing_signature() -> Rejection {
+    json!({ "InstructionError": [0, "MissingRequiredSignature"] })
+}
+
+struct Decoded {
+    signature: [u8; 64],
+    signers: usize,
+    keys: Vec<Pubkey>,
+    blockhash: [u8; 32],
+    program: Pubkey,
+    accounts: Vec<usize>,
+    data: Vec<u8>,
+}
+
+/// Reads a legacy transaction with one signature and one instruction, which is all the
+/// backend sends.
+fn decode(bytes: &[u8]) -> Option<Decoded> {
+    let mut reader = Reader { bytes, at: 0 };
+    if reader.length()? != 1 {
+        return None;
+    }
+    let signature: [u8; 64] = reader.take(64)?.try_into().ok()?;
+    let message = &bytes[reader.at..];
+    let signers = *reader.take(3)?.first()? as usize;
+    let keys = (0..reader.length()?)
+        .map(|_| reader.take(32).and_then(|key| key.try_into().ok()).map(Pubkey))
+        .collect::<Option<Vec<_>>>()?;
+    let blockhash: [u8; 32] = reader.take(32)?.try_into().ok()?;
+    if reader.length()? != 1 {
+        return None;
+    }
+    let program = *keys.get(*reader.take(1)?.first()? as usize)?;
+    let len = reader.length()?;
+    let accounts = reader.take(len)?.iter().map(|index| *index as usize).collect::<Vec<_>>();
+    let len = reader.length()?;
+    let data = reader.take(len)?.to_vec();
+    if accounts.iter().any(|index| *index >= keys.len()) || reader.at != bytes.len() {
+        return None;
+    }
+
+    let key = VerifyingKey::from_bytes(&keys.first()?.0).ok()?;
+    key.verify(message, &Signature::from_bytes(&signature)).ok()?;
+    Some(Decoded { signature, signers, keys, blockhash, program, accounts, data })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.at..self.at.checked_add(len)?)?;
+        self.at += len;
+        Some(slice)
+    }
+
+    fn length(&mut self) -> Option<usize> {
+        let mut len = 0;
+        for shift in [0, 7, 14] {
+            let byte = *self.take(1)?.first()?;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Some(len);
+            }
+        }
+        None
+    }
+}
+
+/// Runs the instruction against the program's accounts. Returns its name and the accounts it touched.
+fn execute(state: &mut MockState, tx: &Decoded) -> Result<(String, Vec<Pubkey>), Rejection> {
+    if tx.program != state.program_id {
+        return Err(json!({ "InstructionError": [0, "IncorrectProgramId"] }));
+    }
+    let account = |position: usize| tx.accounts.get(position).map(|index| (tx.keys[*index], *index < tx.signers));
+    let (Some(discriminator), args) = (tx.data.get(..8), tx.data.get(8..).unwrap_or_default()) else {
+        return Err(custom_error(100));
+    };
+    let now = chrono::Utc::now().timestamp();
+
+    if discriminator == anchor_discriminator("initialize_loan") {
+        let (Some((loan, _)), Some((borrower, true))) = (account(0), account(1)) else {
+            return Err(missing_signature());
+        };
+        let mut reader = Reader { bytes: args, at: 0 };
+        let fields = (|| {
+            let loan_id: [u8; 16] = reader.take(16)?.try_into().ok()?;
+            let amount = u64::from_le_bytes(reader.take(8)?.try_into().ok()?);
+            let currency: [u8; 3] = reader.take(3)?.try_into().ok()?;
+            let len = u32::from_le_bytes(reader.take(4)?.try_into().ok()?) as usize;
+            let description = std::str::from_utf8(reader.take(len)?).ok()?.to_string();
+            Some((loan_id, amount, currency, description))
+        })();
+        // Anchor's InstructionDidNotDeserialize
+        let Some((loan_id, amount, currency, description)) = fields else { return Err(custom_error(102)) };
+        // ConstraintSeeds
+        if Pubkey::find_program_address(&[b"loan", &borrower.0, &loan_id], &state.program_id).0 != loan {
+            return Err(custom_error(2006));
+        }
+        if state.accounts.contains_key(&loan) {
+            // The system program's AccountAlreadyInUse
+            return Err(custom_error(0));
+        }
+        // The program's LoanError, in order
+        if amount == 0 {
+            return Err(custom_error(ANCHOR_ERROR_OFFSET + 1));
+        }
+        if !currency.iter().all(|c| c.is_ascii_uppercase()) {
+            return Err(custom_error(ANCHOR_ERROR_OFFSET + 2));
+        }
+        if description.len() > MAX_DESCRIPTION_LEN {
+            return Err(custom_error(ANCHOR_ERROR_OFFSET + 3));
+        }
+
+        let mut data = account_discriminator("LoanAccount").to_vec();
+        data.extend_from_slice(&borrower.0);
+        data.extend_from_slice(&amount.to_le_bytes());
+        data.extend_from_slice(&currency);
+        data.extend_from_slice(&(description.len() as u32).to_le_bytes());
+        data.extend_from_slice(description.as_bytes());
+        data.push(0);
+        data.extend_from_slice(&now.to_le_bytes());
+        data.extend_from_slice(&0i64.to_le_bytes());
+        data.extend_from_slice(&loan_id);
+        state.accounts.insert(loan, data);
+        return Ok(("initialize_loan".to_string(), vec![loan, borrower]));
+    }
+
+    if discriminator == anchor_discriminator("repay_loan") {
+        let (Some((loan, _)), Some((borrower, true))) = (account(0), account(1)) else {
+            return Err(missing_signature());
+        };
+        // AccountNotInitialized
+        let Some(data) = state.accounts.get_mut(&loan) else { return Err(custom_error(3012)) };
+        // AccountDiscriminatorMismatch, for an account of another kind
+        if !data.starts_with(&account_discriminator("LoanAccount")) {
+            return Err(custom_error(3002));
+        }
+        // AccountDidNotDeserialize, for a loan account cut short
+        let description_len = data.get(51..55).and_then(|b| b.try_into().ok()).map(u32::from_le_bytes);
+        let Some(repaid_at) = description_len.map(|len| 55 + len as usize).filter(|at| data.len() >= at + 17) else {
+            return Err(custom_error(3003));
+        };
+        // ConstraintHasOne
+        if data[8..40] != borrower.0 {
+            return Err(custom_error(2001));
+        }
+        if data[repaid_at] == 1 {
+            return Err(custom_error(ANCHOR_ERROR_OFFSET));
+        }
+        data[repaid_at] = 1;
+        data[repaid_at + 9..repaid_at + 17].copy_from_slice(&now.to_le_bytes());
+        return Ok(("repay_loan".to_string(), vec![loan, borrower]));
+    }
+
+    if discriminator == anchor_discriminator("anchor_checkpoint") {
+        let (Some((checkpoint, _)), Some((authority, true))) = (account(0), account(1)) else {
+            return Err(missing_signature());
+        };
+        let [first, last] = [0, 8].map(|at| args.get(at..at + 8).and_then(|b| b.try_into().ok()).map(u64::from_le_bytes));
+        let (Some(first), Some(last), Some(root)) = (first, last, args.get(16..48)) else { return Err(custom_error(102)) };
+        if Pubkey::find_program_address(&[b"checkpoint", &authority.0, &last.to_le_bytes()], &state.program_id).0 != checkpoint {
+            return Err(custom_error(2006));
+        }
+        if state.accounts.contains_key(&checkpoint) {
+            // The system program's AccountAlreadyInUse
+            return Err(custom_error(0));
+        }
+        if first == 0 || last < first {
+            return Err(custom_error(ANCHOR_ERROR_OFFSET));
+        }
+
+        let mut data = account_discriminator("LedgerCheckpoint").to_vec();
+        data.extend_from_slice(&authority.0);
+        data.extend_from_slice(&first.to_le_bytes());
+        data.extend_from_slice(&last.to_le_bytes());
+        data.extend_from_slice(root);
+        data.extend_from_slice(&now.to_le_bytes());
+        state.accounts.insert(checkpoint, data);
+        return Ok(("anchor_checkpoint".to_string(), vec![checkpoint, authority]));
+    }
+
+    // InstructionFallbackNotFound
+    Err(custom_error(101))
+}
+
+fn account_discriminator(name: &str) -> [u8; 8] {
+    let hash = Sha256::digest(format!("account:{}", name).as_bytes());
+    hash[..8].try_into().expect("8 bytes")
+}
+
+fn rpc_error(id: &Value, code: i64, message: &str, data: Value) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message, "data": data } }))
+}
+
+async fn rpc(state: web::Data<Mutex<MockState>>, body: web::Json<Value>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if state.unavailable_for > 0 {
+        state.unavailable_for -= 1;
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+    let id = body["id"].clone();
+    let params = &body["params"];
+    let context = json!({ "slot": state.slot });
+
+    let result = match body["method"].as_str().unwrap_or_default() {
+        "getLatestBlockhash" => {
+            let blockhash = *state.blockhashes.last().expect("genesis blockhash");
+            json!({ "context": context, "value": { "blockhash": bs58::encode(blockhash).into_string(), "lastValidBlockHeight": state.slot + 150 } })
+        }
+        "sendTransaction" => {
+            let Some(tx) = params[0].as_str().and_then(|tx| BASE64.decode(tx).ok()).and_then(|tx| decode(&tx)) else {
+                return rpc_error(&id, -32602, "invalid transaction: failed to deserialize or verify", Value::Null);
+            };
+            let signature = bs58::encode(tx.signature).into_string();
+            if !state.blockhashes.contains(&tx.blockhash) {
+                return rpc_error(&id, -32002, "Transaction simulation failed: Blockhash not found", json!({ "err": "BlockhashNotFound" }));
+            }
+            if state.transactions.iter().any(|t| t.signature == signature) {
+                return rpc_error(&id, -32002, "Transaction simulation failed: This transaction has already been processed", json!({ "err": "AlreadyProcessed" }));
+            }
+            match execute(&mut state, &tx) {
+                Ok((instruction, accounts)) => {
+                    state.slot += 1;
+                    let slot = state.slot;
+                    let blockhash = Sha256::digest(slot.to_le_bytes()).into();
+                    state.blockhashes.push(blockhash);
+                    tracing::info!("[MOCK SOLANA] {} in slot {}: {}", instruction, slot, signature);
+                    state.transactions.push(MockTransaction { signature: signature.clone(), slot, instruction, accounts });
+                    json!(signature)
+                }
+                Err(err) => {
+                    let message = format!("Transaction simulation failed: Error processing Instruction 0: {}", err);
+                    return rpc_error(&id, -32002, &message, json!({ "err": err }));
+                }
+            }
+        }
+        "getSignatureStatuses" => {
+            let statuses: Vec<Value> = params[0]
+                .as_array()
+                .map(|signatures| {
+                    signatures
+                        .iter()
+                        .map(|signature| {
+                            state.transactions.iter().find(|t| Some(t.signature.as_str()) == signature.as_str()).map_or(Value::Null, |t| {
+                                json!({ "slot": t.slot, "confirmations": null, "err": null, "confirmationStatus": "finalized" })
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            json!({ "context": context, "value": statuses })
+        }
+        "getAccountInfo" => {
+            let value = params[0].as_str().and_then(|a| a.parse::<Pubkey>().ok()).and_then(|a| state.accounts.get(&a)).map_or(Value::Null, |data| {
+                json!({
+                    "data": [BASE64.encode(data), "base64"],
+                    "executable": false,
+                    "lamports": 2_000_000,
+                    "owner": state.program_id.to_string(),
+                    "rentEpoch": 0,
+                    "space": data.len(),
+                })
+            });
+            json!({ "context": context, "value": value })
+        }
+        "getSignaturesForAddress" => {
+            let Some(address) = params[0].as_str().and_then(|a| a.parse::<Pubkey>().ok()) else {
+                return rpc_error(&id, -32602, "Invalid param: Invalid", Value::Null);
+            };
+            let limit = params[1]["limit"].as_u64().unwrap_or(1000) as usize;
+            let signatures: Vec<Value> = state
+                .transactions
+                .iter()
+                .rev()
+                .filter(|t| t.accounts.contains(&address))
+                .take(limit)
+                .map(|t| json!({ "signature": t.signature, "slot": t.slot, "err": null, "memo": null, "confirmationStatus": "finalized" }))
+                .collect();
+            json!(signatures)
+        }
+        method => return rpc_error(&id, -32601, &format!("Method not found: {}", method), Value::Null),
+    };
+    HttpResponse::Ok().json(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+}
@@ -0,0 +1,388 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+use crate::middleware::AppError;
+use crate::services::lockout::LoginLockout;
+use crate::services::sessions::SessionService;
+
+/// Shown as the account's issuer in authenticator apps.
+pub const ISSUER: &str = "MicroFund Africa";
+pub const TOTP_DIGITS: usize = 6;
+pub const TOTP_STEP_SECONDS: u64 = 30;
+/// Codes from this many steps either side of now are accepted, for clocks that drift.
+const TOTP_SKEW_STEPS: i64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// Characters in a recovery code, which is shown in groups of four.
+const RECOVERY_CODE_LENGTH: usize = 16;
+/// No 0/o, 1/l/i, so codes read back from paper are not mistyped.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+/// A login waiting for its second factor lapses after this.
+pub const CHALLENGE_TTL_MINUTES: i64 = 5;
+/// Wrong codes before a login challenge stops working.
+pub const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+/// What an authenticator app needs to start producing codes.
+#[derive(Debug, Serialize)]
+pub struct Enrollment {
+    /// Base32, for typing into the app by hand.
+    pub secret: String,
+    /// `otpauth://` URI, shown as a QR code.
+    pub otpauth_uri: String,
+}
+
+/// Optional TOTP second factor. A member enrolls an authenticator app, confirms it with a
+/// first code and is handed recovery codes; from then on logins stop at a challenge until
+/// a code from the app, or an unused recovery code, is given.
+pub struct TwoFactorService;
+
+impl TwoFactorService {
+    /// A random 160-bit secret, base32 encoded.
+    pub fn new_secret() -> String {
+        let mut bytes = [0u8; 20];
+        OsRng.fill_bytes(&mut bytes);
+        match Secret::Raw(bytes.to_vec()).to_encoded() {
+            Secret::Encoded(secret) => secret,
+            Secret::Raw(_) => unreachable!("to_encoded always encodes"),
+        }
+    }
+
+    pub fn totp(secret: &str, account_name: &str) -> Result<TOTP, String> {
+        let bytes = Secret::Encoded(secret.to_string()).to_bytes().map_err(|e| e.to_string())?;
+        TOTP::new(
+            Algorithm::SHA1,
+            TOTP_DIGITS,
+            0,
+            TOTP_STEP_SECONDS,
+            bytes,
+            Some(ISSUER.to_string()),
+            // The otpauth label uses ':' to separate issuer and account
+            account_name.replace(':', ""),
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    /// The time step `code` belongs to, if it is valid around `now` and newer than
+    /// `last_step`, the last one accepted for the member.
+    pub fn matching_step(totp: &TOTP, code: &str, now: DateTime<Utc>, last_step: Option<i64>) -> Option<i64> {
+        let code = code.trim();
+        let current = now.timestamp() / TOTP_STEP_SECONDS as i64;
+        (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+            .filter(|step| last_step.is_none_or(|last| *step > last))
+            .find(|step| totp.check(code, *step as u64 * TOTP_STEP_SECONDS))
+    }
+
+    /// A fresh set of recovery codes, formatted `xxxx-xxxx-xxxx-xxxx`.
+    pub fn new_recovery_codes() -> Vec<String> {
+        let alphabet = RECOVERY_CODE_ALPHABET.len();
+        // Rejection sampling keeps every character equally likely
+        let limit = 256 - 256 % alphabet;
+        (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let mut chars = Vec::with_capacity(RECOVERY_CODE_LENGTH);
+                while chars.len() < RECOVERY_CODE_LENGTH {
+                    let byte = (OsRng.next_u32() & 0xff) as usize;
+                    if byte < limit {
+                        chars.push(RECOVERY_CODE_ALPHABET[byte % alphabet] as char);
+                    }
+                }
+                chars
+                    .chunks(4)
+                    .map(|group| group.iter().collect::<String>())
+                    .collect::<Vec<_>>()
+                    .join("-")
+            })
+            .collect()
+    }
+
+    /// What is stored in place of a recovery code. Case, spaces and dashes do not matter.
+    pub fn hash_recovery_code(code: &str) -> String {
+        let normalized: String = code
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        SessionService::hash_token(&normalized)
+    }
+
+    /// Starts enrolling an authenticator app. Starting again replaces the pending secret.
+    pub async fn begin_enrollment(pool: &PgPool, user_id: Uuid) -> Result<Enrollment, AppError> {
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+        let (username, enabled_at) = Self::lock_user(&mut tx, user_id).await?;
+        if enabled_at.is_some() {
+            return Err(AppError::Conflict("Two-factor authentication is already on".to_string()));
+        }
+
+        let secret = Self::new_secret();
+        let totp = Self::totp(&secret, &username).map_err(|e| {
+            tracing::error!("Failed to set up TOTP for user {}: {}", user_id, e);
+            AppError::InternalServerError
+        })?;
+        sqlx::query("UPDATE users SET totp_secret = $1, totp_last_step = NULL WHERE id = $2")
+            .bind(&secret)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+        Ok(Enrollment {
+            otpauth_uri: totp.get_url(),
+            secret,
+        })
+    }
+
+    /// Turns two-factor authentication on once the app produced a valid code. Returns the
+    /// recovery codes, which are only ever shown this once.
+    pub async fn confirm_enrollment(
+        pool: &PgPool,
+        user_id: Uuid,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, AppError> {
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+        let (username, enabled_at) = Self::lock_user(&mut tx, user_id).await?;
+        if enabled_at.is_some() {
+            return Err(AppError::Conflict("Two-factor authentication is already on".to_string()));
+        }
+
+        let (secret,): (Option<String>,) = sqlx::query_as("SELECT totp_secret FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        let secret = secret.ok_or_else(|| {
+            AppError::BadRequest("Set up an authenticator app before confirming it".to_string())
+        })?;
+        let totp = Self::totp(&secret, &username).map_err(|_| AppError::InternalServerError)?;
+        let step = Self::matching_step(&totp, code, now, None)
+            .ok_or_else(|| AppError::BadRequest("The code is not correct".to_string()))?;
+
+        sqlx::query("UPDATE users SET totp_enabled_at = $1, totp_last_step = $2 WHERE id = $3")
+            .bind(now)
+            .bind(step)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        let recovery_codes = Self::replace_recovery_codes(&mut tx, user_id, now).await?;
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+        tracing::info!("Two-factor authentication turned on for user {}", user_id);
+        Ok(recovery_codes)
+    }
+
+    /// Turns two-factor authentication off. Needs a current code or a recovery code.
+    pub async fn disable(pool: &PgPool, user_id: Uuid, code: &str, now: DateTime<Utc>) -> Result<(), AppError> {
+        let mut tx = Self::require_second_factor(pool, user_id, code, now).await?;
+
+        sqlx::query("UPDATE users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL WHERE id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+        tracing::info!("Two-factor authentication turned off for user {}", user_id);
+        Ok(())
+    }
+
+    /// Replaces the member's recovery codes, e.g. after using some. Needs a current code.
+    pub async fn regenerate_recovery_codes(
+        pool: &PgPool,
+        user_id: Uuid,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, AppError> {
+        let mut tx = Self::require_second_factor(pool, user_id, code, now).await?;
+        let recovery_codes = Self::replace_recovery_codes(&mut tx, user_id, now).await?;
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+        Ok(recovery_codes)
+    }
+
+    /// Records a login that passed its first factor. Returns the token the client answers
+    /// the challenge with.
+    pub async fn create_challenge(pool: &PgPool, user_id: Uuid, now: DateTime<Utc>) -> Result<String, AppError> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        sqlx::query("INSERT INTO login_challenges (user_id, token_hash, created_at, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(user_id)
+            .bind(SessionService::hash_token(&token))
+            .bind(now)
+            .bind(now + Duration::minutes(CHALLENGE_TTL_MINUTES))
+            .execute(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to record login challenge: {:?}", e);
+                AppError::InternalServerError
+            })?;
+        Ok(token)
+    }
+
+    /// Answers a login challenge with a code from the app or a recovery code. Returns the
+    /// member to log in; `Unauthorized` for anything else.
+    pub async fn complete_challenge(
+        pool: &PgPool,
+        challenge_token: &str,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Uuid, AppError> {
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+
+        let found: Option<(Uuid, Uuid, i32, DateTime<Utc>)> = sqlx::query_as(
+            "SELECT id, user_id, attempts, expires_at FROM login_challenges
+             WHERE token_hash = $1 AND consumed_at IS NULL FOR UPDATE"
+        )
+        .bind(SessionService::hash_token(challenge_token))
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        let (id, user_id, attempts, expires_at) = found.ok_or(AppError::Unauthorized)?;
+        if now >= expires_at || attempts >= MAX_CHALLENGE_ATTEMPTS {
+            return Err(AppError::Unauthorized);
+        }
+
+        LoginLockout::ensure_unlocked(&mut tx, user_id, now).await?;
+        if !Self::use_second_factor(&mut tx, user_id, code, now).await? {
+            sqlx::query("UPDATE login_challenges SET attempts = attempts + 1 WHERE id = $1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+            LoginLockout::record_failure(&mut tx, user_id, now).await?;
+            tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+            tracing::warn!("Wrong second factor for user {} ({} of {})", user_id, attempts + 1, MAX_CHALLENGE_ATTEMPTS);
+            return Err(AppError::Unauthorized);
+        }
+
+        sqlx::query("UPDATE login_challenges SET consumed_at = $1 WHERE id = $2")
+            .bind(now)
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+        LoginLockout::clear(&mut tx, user_id).await?;
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+        Ok(user_id)
+    }
+
+    async fn lock_user(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+    ) -> Result<(String, Option<DateTime<Utc>>), AppError> {
+        sqlx::query_as("SELECT username, totp_enabled_at FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_optional(conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?
+            .ok_or(AppError::NotFound)
+    }
+
+    /// Begins the transaction a change to the member's two-factor settings runs in, once `code`
+    /// checks out. A wrong code counts towards the login lockout, like one given at login, and
+    /// that is committed even though the change is refused.
+    async fn require_second_factor(
+        pool: &PgPool,
+        user_id: Uuid,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Transaction<'static, Postgres>, AppError> {
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+        let (_, enabled_at) = Self::lock_user(&mut tx, user_id).await?;
+        if enabled_at.is_none() {
+            return Err(AppError::BadRequest("Two-factor authentication is not on".to_string()));
+        }
+        LoginLockout::ensure_unlocked(&mut tx, user_id, now).await?;
+        if !Self::use_second_factor(&mut tx, user_id, code, now).await? {
+            LoginLockout::record_failure(&mut tx, user_id, now).await?;
+            tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+            tracing::warn!("Wrong second factor for user {} changing two-factor settings", user_id);
+            return Err(AppError::BadRequest("The code is not correct".to_string()));
+        }
+        Ok(tx)
+    }
+
+    /// Checks `code` against the member's app, then their unused recovery codes, and uses it up.
+    async fn use_second_factor(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, AppError> {
+        let enrolled: Option<(String, String, Option<i64>)> = sqlx::query_as(
+            "SELECT username, totp_secret, totp_last_step FROM users
+             WHERE id = $1 AND totp_enabled_at IS NOT NULL AND totp_secret IS NOT NULL FOR UPDATE"
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        let Some((username, secret, last_step)) = enrolled else {
+            return Ok(false);
+        };
+
+        let totp = Self::totp(&secret, &username).map_err(|e| {
+            tracing::error!("Stored TOTP secret of user {} is unusable: {}", user_id, e);
+            AppError::InternalServerError
+        })?;
+        if let Some(step) = Self::matching_step(&totp, code, now, last_step) {
+            sqlx::query("UPDATE users SET totp_last_step = $1 WHERE id = $2")
+                .bind(step)
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+            return Ok(true);
+        }
+
+        let used = sqlx::query(
+            "UPDATE recovery_codes SET used_at = $1 WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL"
+        )
+        .bind(now)
+        .bind(user_id)
+        .bind(Self::hash_recovery_code(code))
+        .execute(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        if used.rows_affected() > 0 {
+            tracing::warn!("User {} used a recovery code", user_id);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    async fn replace_recovery_codes(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, AppError> {
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        let codes = Self::new_recovery_codes();
+        for code in &codes {
+            sqlx::query("INSERT INTO recovery_codes (user_id, code_hash, created_at) VALUES ($1, $2, $3)")
+                .bind(user_id)
+                .bind(Self::hash_recovery_code(code))
+                .bind(now)
+                .execute(&mut *conn)
+                .await
+                .map_err(|_| AppError::InternalServerError)?;
+        }
+        Ok(codes)
+    }
+}
@@ -0,0 +1,490 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use uuid::Uuid;
+use crate::middleware::{AppError, AuthUser};
+use crate::middleware::authz::{self, Access};
+use crate::models::{Money, PaymentPurpose, PaymentStatus, Savings, SavingsGoal, SavingsWithdrawal};
+use crate::services::blockchain::BlockchainService;
+use crate::services::ledger::LedgerService;
+use crate::services::mpesa::{B2cPaymentRequest, B2cResult, MpesaClient, MpesaError};
+
+pub const SAVINGS_COLUMNS: &str = "id, user_id, amount, goal_name, target_amount, target_date, locked_until, created_at, updated_at";
+
+const WITHDRAWAL_COLUMNS: &str = "id, savings_id, user_id, amount, phone_number, status, callback_token, conversation_id, \
+    transaction_id, result_code, result_desc, created_at, updated_at, settled_at";
+
+/// Withdrawals with no result are handed to reconciliation after this long.
+const STALE_AFTER_MINUTES: i64 = 30;
+
+/// Savings paid back out to their owner over M-Pesa B2C. The amount is held from the moment
+/// the withdrawal is requested and only leaves the goal once Daraja confirms the payout;
+/// a failed payout releases the hold and the member can try again.
+pub struct WithdrawalService;
+
+impl WithdrawalService {
+    /// Savings goals of a member with what is available to withdraw and their progress.
+    pub async fn goals_for_user(pool: &PgPool, user_id: Uuid, today: NaiveDate) -> Result<Vec<SavingsGoal>, sqlx::Error> {
+        let savings: Vec<Savings> = sqlx::query_as(&format!(
+            "SELECT {} FROM savings WHERE user_id = $1 ORDER BY created_at",
+            SAVINGS_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        let balances = Self::balances(pool, user_id, None).await?;
+
+        Ok(savings
+            .into_iter()
+            .map(|savings| {
+                let available = balances
+                    .iter()
+                    .find(|(id, _, _)| *id == savings.id)
+                    .map(|(_, confirmed, held)| Self::available(savings.amount, *confirmed, *held))
+                    .unwrap_or_else(|| Money::zero(savings.amount.currency()));
+                SavingsGoal {
+                    available,
+                    progress_percent: savings.progress_percent(),
+                    locked: savings.is_locked(today),
+                    savings,
+                }
+            })
+            .collect())
+    }
+
+    /// What can be withdrawn from a goal holding `balance`: only money M-Pesa confirmed,
+    /// less what pending withdrawals hold. Never negative.
+    pub fn available(balance: Money, confirmed: Money, held: Money) -> Money {
+        let withdrawable = balance.minor_units().min(confirmed.minor_units()) - held.minor_units();
+        Money::new(withdrawable.max(0), balance.currency())
+    }
+
+    /// For each goal of the member, or just `savings_id`: the confirmed money it received net
+    /// of what left it, and what pending withdrawals hold. Deposits count once their payment has
+    /// a receipt, overpayment credits once their repayment has one; money going out always counts.
+    pub async fn balances<'e, E: PgExecutor<'e>>(
+        executor: E,
+        user_id: Uuid,
+        savings_id: Option<Uuid>,
+    ) -> Result<Vec<(Uuid, Money, Money)>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT s.id,
+                    (SELECT COALESCE(SUM(t.amount), 0) FROM savings_transactions t
+                     LEFT JOIN payments p ON p.id = t.payment_id
+                     LEFT JOIN loan_repayments r ON r.id = t.repayment_id
+                     WHERE t.savings_id = s.id
+                       AND (t.amount < 0 OR (p.status = $3 AND p.mpesa_receipt_number IS NOT NULL)
+                            OR r.mpesa_receipt_number IS NOT NULL))::bigint,
+                    (SELECT COALESCE(SUM(w.amount), 0) FROM savings_withdrawals w
+                     WHERE w.savings_id = s.id AND w.status = $4)::bigint
+             FROM savings s WHERE s.user_id = $1 AND ($2::uuid IS NULL OR s.id = $2)"
+        )
+        .bind(user_id)
+        .bind(savings_id)
+        .bind(PaymentStatus::Completed)
+        .bind(PaymentStatus::Pending)
+        .fetch_all(executor)
+        .await
+    }
+
+    /// Holds the amount on the goal and sends its B2C payout. The payout goes to `phone_number`,
+    /// which must be the member's verified number or one that paid into the goal, or to the
+    /// number of the latest confirmed deposit into the goal when none is given.
+    pub async fn start(
+        pool: &PgPool,
+        mpesa: &MpesaClient,
+        user: &AuthUser,
+        savings_id: Uuid,
+        amount: Money,
+        phone_number: Option<&str>,
+        today: NaiveDate,
+    ) -> Result<SavingsWithdrawal, AppError> {
+        let invalid = |e: MpesaError| AppError::BadRequest(e.to_string());
+        MpesaClient::whole_shillings(amount).map_err(invalid)?;
+        let phone = phone_number.map(MpesaClient::normalize_phone).transpose().map_err(invalid)?;
+
+        let mut tx = pool.begin().await.map_err(|_| AppError::InternalServerError)?;
+
+        // Locking the goal serializes withdrawals, so two of them cannot both pass the balance check
+        let savings: Savings = authz::load_for_update(&mut tx, savings_id, user, Access::Owner).await?;
+
+        if savings.is_locked(today) {
+            let until = savings.locked_until.map(|d| d.to_string()).unwrap_or_default();
+            return Err(AppError::Conflict(format!("This goal is locked until {}", until)));
+        }
+
+        let (_, confirmed, held) = Self::balances(&mut *tx, savings.user_id, Some(savings_id))
+            .await
+            .map_err(|_| AppError::InternalServerError)?
+            .pop()
+            .ok_or(AppError::InternalServerError)?;
+        let available = Self::available(savings.amount, confirmed, held);
+        if amount.minor_units() > available.minor_units() {
+            return Err(AppError::BadRequest(format!("Only {} is available to withdraw", available)));
+        }
+
+        let phone = Self::payout_phone(&mut tx, savings.user_id, savings_id, phone).await?;
+
+        // The row exists before the request so a fast result always finds it
+        let withdrawal: SavingsWithdrawal = sqlx::query_as(&format!(
+            "INSERT INTO savings_withdrawals (savings_id, user_id, amount, phone_number, callback_token)
+             VALUES ($1, $2, $3, $4, $5) RETURNING {}",
+            WITHDRAWAL_COLUMNS
+        ))
+        .bind(savings_id)
+        .bind(user.id)
+        .bind(amount)
+        .bind(&phone)
+        .bind(Uuid::new_v4().simple().to_string())
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to record withdrawal: {:?}", e);
+            AppError::InternalServerError
+        })?;
+
+        tx.commit().await.map_err(|_| AppError::InternalServerError)?;
+
+        let payment = mpesa
+            .b2c_payment(&B2cPaymentRequest {
+                originator_conversation_id: withdrawal.id.to_string(),
+                phone_number: phone,
+                amount,
+                remarks: format!("MicroFund savings {}", savings_id),
+                occasion: "Savings".to_string(),
+                callback_token: withdrawal.callback_token.clone(),
+            })
+            .await;
+
+        match payment {
+            Ok(response) => sqlx::query_as(&format!(
+                "UPDATE savings_withdrawals SET conversation_id = $2, updated_at = NOW() WHERE id = $1 RETURNING {}",
+                WITHDRAWAL_COLUMNS
+            ))
+            .bind(withdrawal.id)
+            .bind(&response.conversation_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to store conversation of withdrawal {}: {:?}", withdrawal.id, e);
+                AppError::InternalServerError
+            }),
+            // The request may still have reached Daraja; its result decides, or the stale check does
+            Err(MpesaError::Transport(e)) => {
+                tracing::warn!("[M-PESA] B2C request for withdrawal {} failed: {}", withdrawal.id, e);
+                Ok(withdrawal)
+            }
+            Err(e) => {
+                tracing::error!("[M-PESA] B2C payment for withdrawal {} rejected: {}", withdrawal.id, e);
+                Self::fail(pool, withdrawal.id, None, &e.to_string()).await.map_err(|_| AppError::InternalServerError)?;
+                Err(match e {
+                    MpesaError::InvalidRequest(message) => AppError::BadRequest(message),
+                    _ => AppError::InternalServerError,
+                })
+            }
+        }
+    }
+
+    /// Applies a B2C result posted to the result URL of a withdrawal. `None` if the token
+    /// belongs to no withdrawal, so the result can be offered to loan payouts instead.
+    pub async fn handle_result(
+        pool: &PgPool,
+        callback_token: &str,
+        result: &B2cResult,
+    ) -> Result<Option<SavingsWithdrawal>, String> {
+        let Some(withdrawal) = Self::find_by_token(pool, callback_token).await? else {
+            return Ok(None);
+        };
+        if withdrawal.id.to_string() != result.originator_conversation_id {
+            return Err(format!(
+                "B2C result for withdrawal {} names conversation {}",
+                withdrawal.id, result.originator_conversation_id
+            ));
+        }
+        if withdrawal.status != PaymentStatus::Pending {
+            if result.is_success() && withdrawal.status == PaymentStatus::Failed {
+                tracing::error!(
+                    "[M-PESA] Withdrawal {} was given up on but M-Pesa paid it out ({:?})",
+                    withdrawal.id, result.receipt_number()
+                );
+            }
+            return Ok(Some(withdrawal));
+        }
+
+        if !result.is_success() {
+            let code = Some(result.result_code.to_string());
+            return Self::fail(pool, withdrawal.id, code, &result.result_desc).await.map(Some);
+        }
+
+        let expected = MpesaClient::whole_shillings(withdrawal.amount).map_err(|e| e.to_string())?;
+        if let Some(paid) = result.amount().filter(|paid| *paid != expected) {
+            // Left pending, and so held, for reconciliation rather than booking the wrong amount
+            return Err(format!(
+                "Result for withdrawal {} reports KES {} paid out, expected KES {}",
+                withdrawal.id, paid, expected
+            ));
+        }
+
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        let withdrawal = Self::lock(&mut tx, withdrawal.id).await?;
+        if withdrawal.status != PaymentStatus::Pending {
+            return Ok(Some(withdrawal));
+        }
+
+        let completed: SavingsWithdrawal = sqlx::query_as(&format!(
+            "UPDATE savings_withdrawals
+             SET status = $2, conversation_id = $3, transaction_id = $4, result_code = $5, result_desc = $6,
+                 updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 RETURNING {}",
+            WITHDRAWAL_COLUMNS
+        ))
+        .bind(withdrawal.id)
+        .bind(PaymentStatus::Completed)
+        .bind(&result.conversation_id)
+        .bind(result.receipt_number())
+        .bind(result.result_code.to_string())
+        .bind(&result.result_desc)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        Self::debit_savings(&mut tx, &completed).await?;
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+        tracing::info!("[M-PESA] Savings withdrawal {} paid out to {}", completed.id, completed.phone_number);
+        Ok(Some(completed))
+    }
+
+    /// Daraja reports that the payout timed out in its queue, so it was never made.
+    /// `None` if the token belongs to no withdrawal.
+    pub async fn handle_timeout(pool: &PgPool, callback_token: &str) -> Result<Option<SavingsWithdrawal>, String> {
+        let Some(withdrawal) = Self::find_by_token(pool, callback_token).await? else {
+            return Ok(None);
+        };
+        if withdrawal.status != PaymentStatus::Pending {
+            return Ok(Some(withdrawal));
+        }
+        Self::fail(pool, withdrawal.id, None, "The payment timed out in the M-Pesa queue").await.map(Some)
+    }
+
+    /// Flags withdrawals that have had no result for too long, whether or not Daraja accepted
+    /// them, for reconciliation. They may still have been paid out, so the hold stays until an
+    /// M-Pesa statement shows whether they were. Returns how many were flagged.
+    pub async fn escalate_stale(pool: &PgPool, now: DateTime<Utc>) -> Result<u64, String> {
+        let flagged: Vec<(Uuid,)> = sqlx::query_as(
+            "UPDATE savings_withdrawals SET unacknowledged_at = $3, updated_at = NOW()
+             WHERE status = $1 AND unacknowledged_at IS NULL AND created_at < $2
+             RETURNING id"
+        )
+        .bind(PaymentStatus::Pending)
+        .bind(now - Duration::minutes(STALE_AFTER_MINUTES))
+        .bind(now)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for (withdrawal_id,) in &flagged {
+            tracing::warn!("[M-PESA] Withdrawal {} has had no result; held until the M-Pesa statement shows it", withdrawal_id);
+        }
+        tracing::info!("[SCHEDULER] {} unacknowledged savings withdrawals flagged for reconciliation", flagged.len());
+        Ok(flagged.len() as u64)
+    }
+
+    /// Completes a pending withdrawal that an M-Pesa statement line shows was paid out,
+    /// taking it off the goal. Withdrawals no longer pending are returned untouched.
+    pub async fn complete_from_statement(
+        conn: &mut PgConnection,
+        withdrawal_id: Uuid,
+        receipt_number: &str,
+    ) -> Result<SavingsWithdrawal, String> {
+        let withdrawal = Self::lock(conn, withdrawal_id).await?;
+        if withdrawal.status != PaymentStatus::Pending {
+            return Ok(withdrawal);
+        }
+
+        let completed: SavingsWithdrawal = sqlx::query_as(&format!(
+            "UPDATE savings_withdrawals
+             SET status = $2, transaction_id = $3, result_desc = $4, updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 RETURNING {}",
+            WITHDRAWAL_COLUMNS
+        ))
+        .bind(withdrawal.id)
+        .bind(PaymentStatus::Completed)
+        .bind(receipt_number)
+        .bind("Paid out according to the M-Pesa statement")
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        Self::debit_savings(conn, &completed).await?;
+        tracing::info!("[M-PESA] Savings withdrawal {} confirmed by statement receipt {}", completed.id, receipt_number);
+        Ok(completed)
+    }
+
+    /// Releases the hold of unacknowledged withdrawals made during a statement's period, from
+    /// `start` to `end`, that no line of it paid out within `window`. Any line that could be
+    /// the payout, such as one with a masked phone, keeps the hold. Returns those released.
+    pub async fn release_unpaid(
+        conn: &mut PgConnection,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        window: Duration,
+    ) -> Result<Vec<Uuid>, String> {
+        let released: Vec<(Uuid,)> = sqlx::query_as(
+            "UPDATE savings_withdrawals r
+             SET status = $2, result_desc = $3, updated_at = NOW(), settled_at = NOW()
+             WHERE status = $1 AND unacknowledged_at IS NOT NULL AND created_at BETWEEN $4 AND $5
+               AND NOT EXISTS (
+                   SELECT 1 FROM mpesa_statement_lines l
+                   WHERE l.withdrawn = r.amount AND l.completed_at BETWEEN r.created_at AND r.created_at + $6
+                     AND (l.phone_number IS NULL OR l.phone_number = r.phone_number))
+             RETURNING id"
+        )
+        .bind(PaymentStatus::Pending)
+        .bind(PaymentStatus::Failed)
+        .bind("Not paid out according to the M-Pesa statement")
+        .bind(start)
+        .bind(end - window)
+        .bind(window)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for (withdrawal_id,) in &released {
+            tracing::warn!("[M-PESA] Savings withdrawal {} is in no M-Pesa statement; hold released", withdrawal_id);
+        }
+        Ok(released.into_iter().map(|(id,)| id).collect())
+    }
+
+    pub async fn for_savings(pool: &PgPool, savings_id: Uuid) -> Result<Vec<SavingsWithdrawal>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {} FROM savings_withdrawals WHERE savings_id = $1 ORDER BY created_at DESC",
+            WITHDRAWAL_COLUMNS
+        ))
+        .bind(savings_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Checks where a payout may go: the owner's verified number or a number with a confirmed
+    /// deposit into the goal. Without a requested number, the latest depositing one, else
+    /// the verified one.
+    async fn payout_phone(
+        conn: &mut PgConnection,
+        owner_id: Uuid,
+        savings_id: Uuid,
+        requested: Option<String>,
+    ) -> Result<String, AppError> {
+        let known: Vec<(String,)> = sqlx::query_as(
+            "SELECT phone_number FROM (
+                 SELECT phone_number, settled_at AS at FROM payments
+                 WHERE savings_id = $2 AND purpose = $3 AND status = $4 AND mpesa_receipt_number IS NOT NULL
+                 UNION ALL
+                 SELECT phone_number, NULL FROM users
+                 WHERE id = $1 AND phone_number IS NOT NULL AND phone_verified_at IS NOT NULL
+             ) known ORDER BY at DESC NULLS LAST"
+        )
+        .bind(owner_id)
+        .bind(savings_id)
+        .bind(PaymentPurpose::SavingsDeposit)
+        .bind(PaymentStatus::Completed)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+        let mut known = known.into_iter().filter_map(|(phone,)| MpesaClient::normalize_phone(&phone).ok());
+
+        match requested {
+            Some(phone) if known.any(|known| known == phone) => Ok(phone),
+            Some(_) => Err(AppError::BadRequest(
+                "Payouts can only go to your verified phone number or one that has paid into this goal".to_string(),
+            )),
+            None => known.next().ok_or_else(|| {
+                AppError::BadRequest("Verify a phone number to withdraw with M-Pesa".to_string())
+            }),
+        }
+    }
+
+    async fn find_by_token(pool: &PgPool, callback_token: &str) -> Result<Option<SavingsWithdrawal>, String> {
+        sqlx::query_as(&format!("SELECT {} FROM savings_withdrawals WHERE callback_token = $1", WITHDRAWAL_COLUMNS))
+            .bind(callback_token)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn lock(conn: &mut PgConnection, withdrawal_id: Uuid) -> Result<SavingsWithdrawal, String> {
+        sqlx::query_as(&format!("SELECT {} FROM savings_withdrawals WHERE id = $1 FOR UPDATE", WITHDRAWAL_COLUMNS))
+            .bind(withdrawal_id)
+            .fetch_one(conn)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Marks a pending withdrawal failed, which releases the amount it held.
+    async fn fail(
+        pool: &PgPool,
+        withdrawal_id: Uuid,
+        result_code: Option<String>,
+        reason: &str,
+    ) -> Result<SavingsWithdrawal, String> {
+        let failed: Option<SavingsWithdrawal> = sqlx::query_as(&format!(
+            "UPDATE savings_withdrawals
+             SET status = $2, result_code = $3, result_desc = $4, updated_at = NOW(), settled_at = NOW()
+             WHERE id = $1 AND status = $5 RETURNING {}",
+            WITHDRAWAL_COLUMNS
+        ))
+        .bind(withdrawal_id)
+        .bind(PaymentStatus::Failed)
+        .bind(&result_code)
+        .bind(reason)
+        .bind(PaymentStatus::Pending)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        match failed {
+            Some(failed) => {
+                tracing::warn!("[M-PESA] Savings withdrawal {} failed: {}", failed.id, reason);
+                Ok(failed)
+            }
+            None => Self::find_by_id(pool, withdrawal_id).await,
+        }
+    }
+
+    async fn find_by_id(pool: &PgPool, withdrawal_id: Uuid) -> Result<SavingsWithdrawal, String> {
+        sqlx::query_as(&format!("SELECT {} FROM savings_withdrawals WHERE id = $1", WITHDRAWAL_COLUMNS))
+            .bind(withdrawal_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Takes a confirmed payout out of the goal.
+    async fn debit_savings(conn: &mut PgConnection, withdrawal: &SavingsWithdrawal) -> Result<(), String> {
+        let (owner_id,): (Uuid,) = sqlx::query_as(
+            "UPDATE savings SET amount = amount - $1, updated_at = NOW() WHERE id = $2 RETURNING user_id"
+        )
+        .bind(withdrawal.amount)
+        .bind(withdrawal.savings_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let movement = withdrawal.amount.checked_neg().map_err(|e| e.to_string())?;
+        sqlx::query(
+            "INSERT INTO savings_transactions (savings_id, amount, transaction_type, withdrawal_id) VALUES ($1, $2, $3, $4)"
+        )
+        .bind(withdrawal.savings_id)
+        .bind(movement)
+        .bind("withdrawal")
+        .bind(withdrawal.id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        LedgerService::record_savings_withdrawal(conn, withdrawal.savings_id, owner_id, withdrawal.amount).await?;
+
+        BlockchainService::log_to_ledger(&mut *conn, "SAVINGS_WITHDRAWAL", "Withdrawal from savings goal", withdrawal.amount).await?;
+        Ok(())
+    }
+}
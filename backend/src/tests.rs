@@ -5,10 +5,82 @@ mod tests {
     // Note: Integration tests would require a real DB pool, 
     // but we can unit test helper functions or logic here.
 
+    /// Connects to the migrated database in `DATABASE_URL` that the ignored tests run against.
+    async fn test_db() -> sqlx::PgPool {
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must point at a migrated database");
+        sqlx::postgres::PgPoolOptions::new().max_connections(5).connect(&database_url).await.unwrap()
+    }
+
+    /// Adds a member named after `role`, with `phone` as their verified number when given.
+    /// Tests share the database, so the username and email are made unique.
+    async fn insert_member(pool: &sqlx::PgPool, role: &str, phone: Option<&str>) -> uuid::Uuid {
+        let tag = uuid::Uuid::new_v4().simple().to_string();
+        let (id,): (uuid::Uuid,) = sqlx::query_as(
+            "INSERT INTO users (username, email, phone_number, phone_verified_at)
+             VALUES ($1, $2, $3, CASE WHEN $3::text IS NULL THEN NULL ELSE NOW() END) RETURNING id"
+        )
+        .bind(format!("{}-{}", role, &tag[..12]))
+        .bind(format!("{}-{}@example.com", role, tag))
+        .bind(phone)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        id
+    }
+
     #[test]
     fn test_token_logic() {
-        // Placeholder for testing JWT generation if we expose it
-        assert!(true);
+        use crate::middleware::auth::JwtConfig;
+        use crate::models::{Permission, Role};
+        use chrono::{Duration, Utc};
+        use uuid::Uuid;
+
+        let secret = "0123456789abcdef0123456789abcdef";
+        let jwt = JwtConfig::new(secret, "microfund-africa", "microfund-api", "k1", Duration::hours(1)).unwrap();
+        let user_id = Uuid::new_v4();
+
+        let session_id = Uuid::new_v4();
+        let token = jwt.issue(user_id, session_id, vec![Role::Member, Role::Admin], Utc::now()).unwrap();
+        let user = jwt.verify(&token).unwrap();
+        assert_eq!(user.id, user_id);
+        assert_eq!(user.session_id, session_id);
+        assert!(user.has_role(Role::Admin));
+        assert_eq!(user.require(Permission::Reconcile).unwrap(), user_id);
+
+        let member = jwt.verify(&jwt.issue(user_id, session_id, vec![Role::Member], Utc::now()).unwrap()).unwrap();
+        assert!(!member.has_role(Role::Admin));
+        assert!(member.require(Permission::Reconcile).is_err());
+
+        // Short secrets are refused outright
+        assert!(JwtConfig::new("secret", "microfund-africa", "microfund-api", "k1", Duration::hours(1)).is_err());
+    }
+
+    #[test]
+    fn test_token_validation() {
+        use crate::middleware::auth::JwtConfig;
+        use crate::models::Role;
+        use chrono::{Duration, Utc};
+        use uuid::Uuid;
+
+        let secret = "0123456789abcdef0123456789abcdef";
+        let config = |issuer, audience, key_id| {
+            JwtConfig::new(secret, issuer, audience, key_id, Duration::hours(1)).unwrap()
+        };
+        let jwt = config("microfund-africa", "microfund-api", "k1");
+        let user_id = Uuid::new_v4();
+        let token = |signer: &JwtConfig| signer.issue(user_id, Uuid::new_v4(), vec![Role::Member], Utc::now()).unwrap();
+
+        assert!(jwt.verify(&token(&jwt)).is_ok());
+        assert!(jwt.verify(&token(&config("someone-else", "microfund-api", "k1"))).is_err());
+        assert!(jwt.verify(&token(&config("microfund-africa", "another-api", "k1"))).is_err());
+        assert!(jwt.verify(&token(&config("microfund-africa", "microfund-api", "k2"))).is_err());
+
+        let other_key = JwtConfig::new("fedcba9876543210fedcba9876543210", "microfund-africa", "microfund-api", "k1", Duration::hours(1)).unwrap();
+        assert!(jwt.verify(&token(&other_key)).is_err());
+
+        let expired = jwt.issue(user_id, Uuid::new_v4(), vec![Role::Member], Utc::now() - Duration::hours(2)).unwrap();
+        assert!(jwt.verify(&expired).is_err());
+        assert!(jwt.verify("not-a-token").is_err());
     }
 
     #[test]
@@ -26,4 +98,2514 @@ mod tests {
         let parsed_hash = PasswordHash::new(&hash).unwrap();
         assert!(argon2.verify_password(password.as_bytes(), &parsed_hash).is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_balanced_journal_entry() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::ledger::{LedgerService, NewPosting};
+        use uuid::Uuid;
+
+        let kes = |minor| Money::new(minor, PLATFORM_CURRENCY);
+        let cash = Uuid::new_v4();
+        let savings = Uuid::new_v4();
+        let postings = [NewPosting::debit(cash, kes(1005)), NewPosting::credit(savings, kes(1005))];
+        assert!(LedgerService::validate_postings(&postings).is_ok());
+
+        let fee = Uuid::new_v4();
+        let split = [
+            NewPosting::debit(cash, kes(30)),
+            NewPosting::credit(savings, kes(10)),
+            NewPosting::credit(fee, kes(20)),
+        ];
+        assert!(LedgerService::validate_postings(&split).is_ok());
+    }
+
+    #[test]
+    fn test_unbalanced_journal_entry_rejected() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::ledger::{LedgerService, NewPosting};
+        use microfund_shared::Currency;
+        use uuid::Uuid;
+
+        let kes = |minor| Money::new(minor, PLATFORM_CURRENCY);
+        let cash = Uuid::new_v4();
+        let savings = Uuid::new_v4();
+        let unbalanced = [NewPosting::debit(cash, kes(1000)), NewPosting::credit(savings, kes(999))];
+        assert!(LedgerService::validate_postings(&unbalanced).is_err());
+
+        let single_leg = [NewPosting::debit(cash, kes(1000))];
+        assert!(LedgerService::validate_postings(&single_leg).is_err());
+
+        let zero_leg = [NewPosting::debit(cash, kes(0)), NewPosting::credit(savings, kes(0))];
+        assert!(LedgerService::validate_postings(&zero_leg).is_err());
+
+        let usd = Money::new(1000, Currency::USD);
+        let mixed = [NewPosting::debit(cash, kes(1000)), NewPosting::credit(savings, usd)];
+        assert!(LedgerService::validate_postings(&mixed).is_err());
+    }
+
+    #[test]
+    fn test_loan_amount_limits() {
+        use crate::handlers::loans::CreateLoanRequest;
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use microfund_shared::Currency;
+        use validator::Validate;
+
+        let request = |amount| CreateLoanRequest {
+            amount,
+            description: Some("Seeds".to_string()),
+            product_id: None,
+            phone_number: None,
+        };
+        assert!(request(Money::new(10010, PLATFORM_CURRENCY)).validate().is_ok());
+        assert!(request(Money::new(99, PLATFORM_CURRENCY)).validate().is_err());
+        assert!(request(Money::new(500_001, PLATFORM_CURRENCY)).validate().is_err());
+        assert!(request(Money::new(10010, Currency::USD)).validate().is_err());
+    }
+
+    #[test]
+    fn test_loan_status_transitions() {
+        use crate::models::LoanStatus::*;
+
+        // Happy path
+        assert!(Pending.can_transition_to(Funded));
+        assert!(Funded.can_transition_to(Disbursed));
+        assert!(Disbursed.can_transition_to(InRepayment));
+        assert!(InRepayment.can_transition_to(Repaid));
+        assert!(Overdue.can_transition_to(Defaulted));
+        assert!(Defaulted.can_transition_to(WrittenOff));
+
+        // A loan that was never funded cannot be repaid
+        assert!(!Pending.can_transition_to(Repaid));
+        assert!(!Pending.can_transition_to(Disbursed));
+        assert!(!Funded.can_transition_to(Repaid));
+        // Money has left the platform, so the loan can no longer be cancelled
+        assert!(!Disbursed.can_transition_to(Cancelled));
+        // Only overdue loans default
+        assert!(!InRepayment.can_transition_to(Defaulted));
+        // No self-transitions
+        assert!(!Pending.can_transition_to(Pending));
+    }
+
+    #[test]
+    fn test_terminal_loan_statuses() {
+        use crate::models::LoanStatus;
+        use crate::models::LoanStatus::*;
+
+        let all = [Pending, Funded, Disbursed, InRepayment, Repaid, Overdue, Defaulted, Cancelled, WrittenOff];
+        for from in [Repaid, Cancelled, WrittenOff].iter() {
+            assert!(all.iter().all(|to| !from.can_transition_to(*to)), "{} should be terminal", from);
+        }
+        for status in all {
+            assert_eq!(LoanStatus::parse(status.as_str()), Some(status));
+        }
+        assert_eq!(LoanStatus::parse("approved"), None);
+    }
+
+    #[test]
+    fn test_flat_weekly_schedule() {
+        use crate::models::{InterestMethod, LoanTerms, Money, RepaymentFrequency, PLATFORM_CURRENCY};
+        use crate::services::loan_schedule::LoanScheduleService;
+        use chrono::{Duration, TimeZone, Utc};
+
+        let terms = LoanTerms {
+            interest_method: InterestMethod::Flat,
+            interest_rate_bps: 2600,
+            origination_fee_bps: 100,
+            term_count: 8,
+            repayment_frequency: RepaymentFrequency::Weekly,
+        };
+        let start = Utc.with_ymd_and_hms(2026, 1, 5, 9, 0, 0).unwrap();
+        let principal = Money::new(100_001, PLATFORM_CURRENCY);
+        let schedule = LoanScheduleService::build(principal, &terms, start).unwrap();
+
+        assert_eq!(schedule.len(), 8);
+        assert_eq!(schedule[0].due_date, start + Duration::weeks(1));
+        assert_eq!(schedule[7].due_date, start + Duration::weeks(8));
+
+        let sum = |part: fn(&crate::services::loan_schedule::ScheduledInstallment) -> Money| {
+            Money::sum(schedule.iter().map(part), PLATFORM_CURRENCY).unwrap()
+        };
+        // 26% a year over 8 of 52 weeks is 4% of the principal; the fee is 1%
+        assert_eq!(sum(|i| i.principal_due), principal);
+        assert_eq!(sum(|i| i.interest_due).minor_units(), 4_000);
+        assert_eq!(sum(|i| i.fee_due).minor_units(), 1_000);
+        // Installments differ by at most a cent
+        let totals: Vec<i64> = schedule.iter().map(|i| i.total_due().unwrap().minor_units()).collect();
+        assert!(totals.iter().max().unwrap() - totals.iter().min().unwrap() <= 1);
+    }
+
+    #[test]
+    fn test_declining_balance_monthly_schedule() {
+        use crate::models::{InterestMethod, LoanTerms, Money, RepaymentFrequency, PLATFORM_CURRENCY};
+        use crate::services::loan_schedule::LoanScheduleService;
+        use chrono::{TimeZone, Utc};
+
+        let terms = LoanTerms {
+            interest_method: InterestMethod::DecliningBalance,
+            interest_rate_bps: 1200,
+            origination_fee_bps: 0,
+            term_count: 6,
+            repayment_frequency: RepaymentFrequency::Monthly,
+        };
+        let start = Utc.with_ymd_and_hms(2026, 1, 31, 9, 0, 0).unwrap();
+        let principal = Money::new(300_000, PLATFORM_CURRENCY);
+        let schedule = LoanScheduleService::build(principal, &terms, start).unwrap();
+
+        // Month ends are clamped rather than rolling into the next month
+        assert_eq!(schedule[0].due_date, Utc.with_ymd_and_hms(2026, 2, 28, 9, 0, 0).unwrap());
+        assert_eq!(schedule[5].due_date, Utc.with_ymd_and_hms(2026, 7, 31, 9, 0, 0).unwrap());
+
+        let principal_sum = Money::sum(schedule.iter().map(|i| i.principal_due), PLATFORM_CURRENCY).unwrap();
+        assert_eq!(principal_sum, principal);
+        // 1% a month on the full balance first, then less as the balance falls
+        assert_eq!(schedule[0].interest_due.minor_units(), 3_000);
+        assert!(schedule.windows(2).all(|w| w[1].interest_due <= w[0].interest_due));
+        // Equal installments, the last one absorbing rounding
+        let first = schedule[0].total_due().unwrap();
+        assert!(schedule[..5].iter().all(|i| i.total_due().unwrap() == first));
+        assert!(schedule[5].total_due().unwrap() <= first);
+        assert_eq!(first.minor_units(), 51_765);
+    }
+
+    #[test]
+    fn test_schedule_rejects_invalid_terms() {
+        use crate::models::{InterestMethod, LoanTerms, Money, RepaymentFrequency, PLATFORM_CURRENCY};
+        use crate::services::loan_schedule::LoanScheduleService;
+        use chrono::Utc;
+
+        let terms = LoanTerms {
+            interest_method: InterestMethod::Flat,
+            interest_rate_bps: 2400,
+            origination_fee_bps: 0,
+            term_count: 0,
+            repayment_frequency: RepaymentFrequency::Weekly,
+        };
+        let principal = Money::new(10_000, PLATFORM_CURRENCY);
+        assert!(LoanScheduleService::build(principal, &terms, Utc::now()).is_err());
+
+        let terms = LoanTerms { term_count: 4, ..terms };
+        assert!(LoanScheduleService::build(Money::zero(PLATFORM_CURRENCY), &terms, Utc::now()).is_err());
+        assert_eq!(LoanScheduleService::build(principal, &terms, Utc::now()).unwrap().len(), 4);
+    }
+
+    fn installment(number: i32, principal: i64, interest: i64, fee: i64, penalty: i64) -> crate::models::LoanInstallment {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        let kes = |minor| Money::new(minor, PLATFORM_CURRENCY);
+        crate::models::LoanInstallment {
+            id: uuid::Uuid::new_v4(),
+            loan_id: uuid::Uuid::nil(),
+            installment_number: number,
+            due_date: chrono::Utc::now(),
+            principal_due: kes(principal),
+            interest_due: kes(interest),
+            fee_due: kes(fee),
+            penalty_due: kes(penalty),
+            total_due: kes(principal + interest + fee + penalty),
+            principal_paid: kes(0),
+            interest_paid: kes(0),
+            fee_paid: kes(0),
+            penalty_paid: kes(0),
+            paid_at: None,
+            overdue_at: None,
+        }
+    }
+
+    #[test]
+    fn test_repayment_allocation_order() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::repayments::{AllocationOrder, RepaymentService};
+
+        let kes = |minor| Money::new(minor, PLATFORM_CURRENCY);
+        let schedule = [installment(2, 1000, 100, 20, 0), installment(1, 1000, 100, 20, 50)];
+
+        // Default order: the oldest installment's penalty, fee and interest before its principal
+        let allocation = RepaymentService::allocate(kes(500), &schedule, &AllocationOrder::default()).unwrap();
+        assert_eq!(allocation.installments.len(), 1);
+        assert_eq!(allocation.installments[0].0, schedule[1].id);
+        assert_eq!(allocation.totals.penalty, kes(50));
+        assert_eq!(allocation.totals.fee, kes(20));
+        assert_eq!(allocation.totals.interest, kes(100));
+        assert_eq!(allocation.totals.principal, kes(330));
+        assert!(allocation.overpayment.is_zero());
+
+        // Principal first
+        let order = AllocationOrder::parse("principal, interest, fee, penalty").unwrap();
+        let allocation = RepaymentService::allocate(kes(1100), &schedule, &order).unwrap();
+        assert_eq!(allocation.totals.principal, kes(1000));
+        assert_eq!(allocation.totals.interest, kes(100));
+        assert!(allocation.totals.penalty.is_zero());
+    }
+
+    #[test]
+    fn test_repayment_overpayment() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::repayments::{AllocationOrder, RepaymentService};
+
+        let kes = |minor| Money::new(minor, PLATFORM_CURRENCY);
+        let mut paid = installment(1, 1000, 100, 0, 0);
+        paid.paid_at = Some(chrono::Utc::now());
+        let mut partly_paid = installment(2, 1000, 100, 0, 0);
+        partly_paid.interest_paid = kes(100);
+        partly_paid.principal_paid = kes(400);
+
+        let allocation = RepaymentService::allocate(kes(750), &[paid, partly_paid], &AllocationOrder::default()).unwrap();
+        assert_eq!(allocation.totals.principal, kes(600));
+        assert!(allocation.totals.interest.is_zero());
+        assert_eq!(allocation.overpayment, kes(150));
+        assert_eq!(allocation.totals.total().unwrap().checked_add(allocation.overpayment).unwrap(), kes(750));
+    }
+
+    #[test]
+    fn test_repayments_are_collected_over_mpesa() {
+        use crate::middleware::AppError;
+        use crate::models::{Loan, LoanStatus, Money, OverpaymentDestination, PaymentPurpose, PLATFORM_CURRENCY};
+        use crate::services::payments::NewPayment;
+        use crate::services::repayments::RepaymentService;
+        use uuid::Uuid;
+
+        // A repayment is a payment like a deposit, applied to the loan once M-Pesa confirms it
+        let (user_id, loan_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let amount = Money::from_major(250, PLATFORM_CURRENCY).unwrap();
+        let payment = NewPayment::loan_repayment(user_id, loan_id, amount, "0712345678", OverpaymentDestination::Refund, None);
+        assert_eq!(payment.purpose, PaymentPurpose::LoanRepayment);
+        assert_eq!(payment.loan_id, Some(loan_id));
+        assert_eq!(payment.overpayment_destination, Some(OverpaymentDestination::Refund));
+        assert_eq!(PaymentPurpose::parse("loan_repayment"), Some(PaymentPurpose::LoanRepayment));
+        assert!(NewPayment::savings_deposit(user_id, Uuid::new_v4(), amount, "0712345678").loan_id.is_none());
+        let funding = NewPayment::loan_funding(user_id, loan_id, amount, "0712345678");
+        assert_eq!((funding.purpose, funding.loan_id, funding.overpayment_destination), (PaymentPurpose::LoanFunding, Some(loan_id), None));
+        assert_eq!(PaymentPurpose::parse("loan_funding"), Some(PaymentPurpose::LoanFunding));
+
+        let loan = |status| Loan {
+            id: loan_id,
+            user_id,
+            lender_id: Some(Uuid::new_v4()),
+            amount,
+            status,
+            description: None,
+            created_at: None,
+            repaid_at: None,
+            product_id: None,
+            due_date: None,
+            chain_account: None,
+            chain_loan_signature: None,
+            chain_repay_signature: None,
+        };
+        for status in [LoanStatus::Disbursed, LoanStatus::InRepayment, LoanStatus::Overdue, LoanStatus::Defaulted] {
+            assert!(RepaymentService::ensure_repayable(&loan(status)).is_ok(), "{}", status);
+        }
+        for status in [LoanStatus::Pending, LoanStatus::Funded, LoanStatus::Repaid] {
+            assert!(matches!(RepaymentService::ensure_repayable(&loan(status)), Err(AppError::Conflict(_))), "{}", status);
+        }
+    }
+
+    #[test]
+    fn test_allocation_order_parsing() {
+        use crate::models::RepaymentBucket;
+        use crate::services::repayments::AllocationOrder;
+
+        let order = AllocationOrder::parse("interest,penalty,fee,principal").unwrap();
+        assert_eq!(order.buckets()[0], RepaymentBucket::Interest);
+        assert!(AllocationOrder::parse("penalty,fee,interest").is_err());
+        assert!(AllocationOrder::parse("penalty,fee,interest,principal,fee").is_err());
+        assert!(AllocationOrder::parse("penalty,fees,interest,principal").is_err());
+    }
+
+    #[test]
+    fn test_delinquency_rules() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::clock::{Clock, FixedClock};
+        use crate::services::delinquency::DelinquencyConfig;
+        use chrono::{Duration, TimeZone, Utc};
+
+        let config = DelinquencyConfig { grace_period_days: 14, ..DelinquencyConfig::default() };
+        let clock = FixedClock::new(Utc.with_ymd_and_hms(2026, 3, 1, 0, 0, 0).unwrap());
+        let due = Utc.with_ymd_and_hms(2026, 2, 20, 0, 0, 0).unwrap();
+
+        // Nine days late is inside the grace period, fifteen days late is not
+        assert!(due >= config.default_cutoff(clock.now()));
+        clock.advance(Duration::days(6));
+        assert!(due < config.default_cutoff(clock.now()));
+
+        // 5% of the unpaid principal and interest, rounded to the cent
+        let penalty = config.late_penalty(Money::new(12_345, PLATFORM_CURRENCY)).unwrap();
+        assert_eq!(penalty.minor_units(), 617);
+    }
+
+    #[test]
+    fn test_scheduled_job_cadence() {
+        use crate::services::scheduler::Job;
+        use chrono::{Duration, TimeZone, Utc};
+
+        let started = Utc.with_ymd_and_hms(2026, 3, 1, 10, 0, 0).unwrap();
+        assert_eq!(Job::ALL[0], Job::MarkOverdue);
+        assert_eq!(Job::DefaultLoans.next_run_after(started), started + Duration::hours(1));
+        let names: Vec<&str> = Job::ALL.iter().map(|j| j.name()).collect();
+        assert_eq!(names, ["mark_overdue", "apply_penalties", "default_loans", "resolve_payments", "disburse_loans", "pay_refunds", "reconcile_statements", "expire_withdrawals", "prune_rate_limits", "prune_idempotency_keys", "seal_ledger", "checkpoint_ledger", "sync_blockchain", "dispatch_outbox"]);
+    }
+
+    /// Runs the delinquency jobs over a loan while a fixed clock moves past its due dates.
+    /// Needs a migrated database in `DATABASE_URL`.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_delinquency_jobs() {
+        use crate::models::{InterestMethod, LoanStatus, LoanTerms, Money, RepaymentFrequency, PLATFORM_CURRENCY};
+        use crate::services::clock::{Clock, FixedClock};
+        use crate::services::delinquency::{DelinquencyConfig, DelinquencyService};
+        use crate::services::loan_schedule::LoanScheduleService;
+        use crate::services::scoring::ScoringWeights;
+        use chrono::{Duration, Utc};
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+        let config = DelinquencyConfig { grace_period_days: 14, late_penalty_bps: 500 };
+        let weights = ScoringWeights::default();
+        let clock = FixedClock::new(Utc::now());
+
+        let mut ids = Vec::new();
+        for name in ["borrower", "lender"] {
+            ids.push(insert_member(&pool, name, None).await);
+        }
+        let principal = Money::from_major(4_000, PLATFORM_CURRENCY).unwrap();
+        let (loan_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO loans (user_id, lender_id, amount, status) VALUES ($1, $2, $3, 'in_repayment') RETURNING id"
+        )
+        .bind(ids[0])
+        .bind(ids[1])
+        .bind(principal)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let terms = LoanTerms {
+            interest_method: InterestMethod::Flat,
+            interest_rate_bps: 2600,
+            origination_fee_bps: 0,
+            term_count: 4,
+            repayment_frequency: RepaymentFrequency::Weekly,
+        };
+        let schedule = LoanScheduleService::build(principal, &terms, clock.now()).unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        LoanScheduleService::insert_schedule(&mut conn, loan_id, &schedule).await.unwrap();
+        drop(conn);
+
+        let run_jobs = |now| {
+            let (pool, config, weights) = (&pool, &config, &weights);
+            async move {
+                DelinquencyService::mark_overdue(pool, now, weights).await.unwrap();
+                DelinquencyService::apply_penalties(pool, now, config).await.unwrap();
+                DelinquencyService::default_loans(pool, now, config, weights).await.unwrap();
+            }
+        };
+        let status = || async {
+            let (status,): (LoanStatus,) = sqlx::query_as("SELECT status FROM loans WHERE id = $1")
+                .bind(loan_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            status
+        };
+        let penalties = || async {
+            let rows: Vec<(i32, bool, Money)> = sqlx::query_as(
+                "SELECT installment_number, overdue_at IS NOT NULL, penalty_due FROM loan_installments
+                 WHERE loan_id = $1 ORDER BY installment_number"
+            )
+            .bind(loan_id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            let (entries,): (i64,) = sqlx::query_as(
+                "SELECT COUNT(*) FROM journal_entries WHERE reference_id = $1 AND entry_type = 'LATE_PENALTY'"
+            )
+            .bind(loan_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            (rows, entries)
+        };
+
+        // Nothing is due in the first week
+        run_jobs(clock.now()).await;
+        assert_eq!(status().await, LoanStatus::InRepayment);
+        assert_eq!(penalties().await.1, 0);
+
+        // A day after the first due date the installment is overdue and penalised, once
+        clock.advance(Duration::days(8));
+        run_jobs(clock.now()).await;
+        run_jobs(clock.now()).await;
+        assert_eq!(status().await, LoanStatus::Overdue);
+        let (rows, entries) = penalties().await;
+        let first_unpaid = schedule[0].principal_due.checked_add(schedule[0].interest_due).unwrap();
+        assert_eq!(rows[0], (1, true, config.late_penalty(first_unpaid).unwrap()));
+        assert!(rows[1..].iter().all(|(_, overdue, penalty)| !overdue && penalty.is_zero()));
+        assert_eq!(entries, 1);
+
+        // Eight days late is still inside the grace period; the second installment is now late too
+        clock.advance(Duration::days(7));
+        run_jobs(clock.now()).await;
+        assert_eq!(status().await, LoanStatus::Overdue);
+        let (rows, entries) = penalties().await;
+        assert!(rows[1].1 && rows[1].2.is_positive());
+        assert_eq!(entries, 2);
+
+        // Fifteen days late the loan defaults, without charging anything twice
+        clock.advance(Duration::days(7));
+        run_jobs(clock.now()).await;
+        run_jobs(clock.now()).await;
+        assert_eq!(status().await, LoanStatus::Defaulted);
+        assert_eq!(penalties().await.1, 3);
+
+        let (history,): (Vec<String>,) = sqlx::query_as(
+            "SELECT ARRAY_AGG(to_status ORDER BY created_at) FROM loan_status_history WHERE loan_id = $1"
+        )
+        .bind(loan_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(history, ["overdue", "defaulted"]);
+    }
+
+    #[test]
+    fn test_credit_score_breakdown() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::scoring::{ScoreFactorKind, ScoringInputs, ScoringService, ScoringWeights};
+
+        let weights = ScoringWeights::default();
+
+        // A brand new member only earns the clean default history
+        let fresh = ScoringService::compute(&ScoringInputs::default(), &weights).unwrap();
+        assert_eq!(fresh.score, 200);
+        assert_eq!(fresh.credit_limit, Money::from_major(1000, PLATFORM_CURRENCY).unwrap());
+        assert_eq!(fresh.factors.iter().map(|f| f.points).sum::<i32>(), 200);
+        assert!(fresh.summary.contains("repayment timeliness"));
+
+        let seasoned = ScoringInputs {
+            installments_on_time: 9,
+            installments_late: 1,
+            loans_repaid: 2,
+            savings_months: 6,
+            account_age_days: 400,
+            ..ScoringInputs::default()
+        };
+        let breakdown = ScoringService::compute(&seasoned, &weights).unwrap();
+        let timeliness = breakdown.factors.iter().find(|f| f.factor == ScoreFactorKind::RepaymentTimeliness).unwrap();
+        assert_eq!(timeliness.value, 90);
+        assert!(breakdown.score > fresh.score);
+
+        // A default costs more than anything else in the track record
+        let defaulted = ScoringService::compute(&ScoringInputs { loans_defaulted: 1, ..seasoned }, &weights).unwrap();
+        assert_eq!(breakdown.score - defaulted.score, 100);
+    }
+
+    #[test]
+    fn test_scoring_weights_and_limits() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::scoring::{ScoringService, ScoringWeights};
+
+        let weights = ScoringWeights::parse("loan_history=30, account_age=0").unwrap();
+        assert_eq!(weights.loan_history, 30);
+        assert_eq!(weights.repayment_timeliness, 35);
+        assert_eq!(weights.total(), 105);
+        assert!(ScoringWeights::parse("loan_history").is_err());
+        assert!(ScoringWeights::parse("karma=10").is_err());
+        assert!(ScoringWeights::parse("repayment_timeliness=0,loan_history=0,savings_consistency=0,default_history=0,account_age=0").is_err());
+
+        // Limits are whole shillings, rounded down
+        assert_eq!(ScoringService::credit_limit(333).unwrap(), Money::from_major(1665, PLATFORM_CURRENCY).unwrap());
+        assert_eq!(ScoringService::credit_limit(1).unwrap(), Money::from_major(5, PLATFORM_CURRENCY).unwrap());
+        assert_eq!(ScoringService::credit_limit(1200).unwrap(), Money::from_major(5000, PLATFORM_CURRENCY).unwrap());
+    }
+
+    #[test]
+    fn test_daraja_password_phone_and_amount() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::mpesa::MpesaClient;
+        use chrono::{TimeZone, Utc};
+
+        // Timestamps are in East Africa Time
+        let timestamp = MpesaClient::timestamp(Utc.with_ymd_and_hms(2026, 1, 6, 21, 30, 5).unwrap());
+        assert_eq!(timestamp, "20260107003005");
+        assert_eq!(MpesaClient::password("174379", "passkey", "20260107003005"), "MTc0Mzc5cGFzc2tleTIwMjYwMTA3MDAzMDA1");
+
+        assert_eq!(MpesaClient::normalize_phone("0712 345 678").unwrap(), "254712345678");
+        assert_eq!(MpesaClient::normalize_phone("+254112345678").unwrap(), "254112345678");
+        assert_eq!(MpesaClient::normalize_phone("712345678").unwrap(), "254712345678");
+        assert!(MpesaClient::normalize_phone("0812345678").is_err());
+        assert!(MpesaClient::normalize_phone("25471234567").is_err());
+
+        assert_eq!(MpesaClient::whole_shillings(Money::from_major(150, PLATFORM_CURRENCY).unwrap()).unwrap(), 150);
+        assert!(MpesaClient::whole_shillings(Money::new(15_050, PLATFORM_CURRENCY)).is_err());
+        assert!(MpesaClient::whole_shillings(Money::zero(PLATFORM_CURRENCY)).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_daraja_stk_flows_against_mock() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::mpesa::{StkPushRequest, StkStatus};
+        use crate::services::mpesa_mock::{MockDaraja, MockScenario};
+
+        let mock = MockDaraja::start().await.unwrap();
+        let client = mock.client();
+        let push = |phone: &str| StkPushRequest {
+            phone_number: phone.to_string(),
+            amount: Money::from_major(50, PLATFORM_CURRENCY).unwrap(),
+            account_reference: "MicroFund".to_string(),
+            description: "Savings".to_string(),
+        };
+
+        mock.set_scenario("254711000002", MockScenario::InsufficientFunds);
+        mock.set_scenario("254711000003", MockScenario::Cancelled);
+        mock.set_scenario("254711000004", MockScenario::Timeout);
+        let expected = [
+            ("0711000001", StkStatus::Completed),
+            ("0711000002", StkStatus::InsufficientFunds),
+            ("0711000003", StkStatus::Cancelled),
+            ("0711000004", StkStatus::Timeout),
+        ];
+        for (phone, status) in expected {
+            let response = client.stk_push(&push(phone)).await.unwrap();
+            let result = client.stk_query(&response.checkout_request_id).await.unwrap();
+            assert_eq!(result.status, status, "{}", phone);
+        }
+
+        // One token served every request
+        assert_eq!(mock.token_requests(), 1);
+        assert_eq!(mock.pushes()[0].amount, 50);
+        assert_eq!(mock.pushes()[0].phone_number, "254711000001");
+
+        // Daraja answers "still processing" until the customer has responded
+        mock.set_pending_queries(2);
+        let response = client.stk_push(&push("0711000005")).await.unwrap();
+        for _ in 0..2 {
+            assert_eq!(client.stk_query(&response.checkout_request_id).await.unwrap().status, StkStatus::Pending);
+        }
+        assert_eq!(client.stk_query(&response.checkout_request_id).await.unwrap().status, StkStatus::Completed);
+
+        // A token Daraja no longer accepts is replaced once
+        mock.expire_tokens();
+        client.stk_push(&push("0711000006")).await.unwrap();
+        assert_eq!(mock.token_requests(), 2);
+
+        assert!(client.stk_query("ws_CO_unknown").await.is_err());
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn test_stk_callback_parsing() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::mpesa::{StkCallbackBody, StkPushRequest, StkStatus};
+        use crate::services::mpesa_mock::{callback_body, MockDaraja, MockScenario};
+
+        // A successful payment as the mock (and Daraja) reports it
+        let mock = MockDaraja::start().await.unwrap();
+        mock.set_scenario("254711000009", MockScenario::Cancelled);
+        let client = mock.client();
+        for phone in ["0711000008", "0711000009"] {
+            client
+                .stk_push(&StkPushRequest {
+                    phone_number: phone.to_string(),
+                    amount: Money::from_major(250, PLATFORM_CURRENCY).unwrap(),
+                    account_reference: "MicroFund".to_string(),
+                    description: "Savings".to_string(),
+                })
+                .await
+                .unwrap();
+        }
+        let pushes = mock.pushes();
+
+        let paid: StkCallbackBody = serde_json::from_value(callback_body(&pushes[0])).unwrap();
+        let paid = paid.body.stk_callback;
+        assert_eq!(paid.checkout_request_id, pushes[0].checkout_request_id);
+        assert_eq!(paid.status(), StkStatus::Completed);
+        assert_eq!(paid.amount(), Some(250));
+        assert_eq!(paid.receipt_number().map(|r| r.len()), Some(10));
+
+        let cancelled: StkCallbackBody = serde_json::from_value(callback_body(&pushes[1])).unwrap();
+        assert_eq!(cancelled.body.stk_callback.status(), StkStatus::Cancelled);
+        assert_eq!(cancelled.body.stk_callback.receipt_number(), None);
+        mock.stop().await;
+
+        // Daraja sends amounts as decimals
+        let body = r#"{"Body":{"stkCallback":{"MerchantRequestID":"29115-34620561-1","CheckoutRequestID":"ws_CO_191220191020363925",
+            "ResultCode":0,"ResultDesc":"The service request is processed successfully.","CallbackMetadata":{"Item":[
+            {"Name":"Amount","Value":1.00},{"Name":"MpesaReceiptNumber","Value":"NLJ7RT61SV"},
+            {"Name":"TransactionDate","Value":20191219102115},{"Name":"PhoneNumber","Value":254708374149}]}}}}"#;
+        let parsed: StkCallbackBody = serde_json::from_str(body).unwrap();
+        assert_eq!(parsed.body.stk_callback.amount(), Some(1));
+        assert_eq!(parsed.body.stk_callback.receipt_number().as_deref(), Some("NLJ7RT61SV"));
+    }
+
+    #[actix_web::test]
+    async fn test_daraja_b2c_payouts_against_mock() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::mpesa::{B2cPaymentRequest, B2cResultBody, MpesaError};
+        use crate::services::mpesa_mock::{b2c_result_body, MockDaraja, MockScenario};
+
+        let mock = MockDaraja::start().await.unwrap();
+        let client = mock.client();
+        let payout = |phone: &str, amount| B2cPaymentRequest {
+            originator_conversation_id: format!("attempt-{}", phone),
+            phone_number: phone.to_string(),
+            amount,
+            remarks: "MicroFund loan".to_string(),
+            occasion: "Loan".to_string(),
+            callback_token: "token".to_string(),
+        };
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+
+        mock.set_scenario("254711000012", MockScenario::InsufficientFunds);
+        let response = client.b2c_payment(&payout("0711000011", kes(1500))).await.unwrap();
+        assert_eq!(response.originator_conversation_id, "attempt-0711000011");
+        client.b2c_payment(&payout("0711000012", kes(700))).await.unwrap();
+
+        // Shillings and cents cannot be paid out
+        let cents = client.b2c_payment(&payout("0711000013", Money::new(70_050, PLATFORM_CURRENCY))).await;
+        assert!(matches!(cents, Err(MpesaError::InvalidRequest(_))));
+
+        let payouts = mock.payouts();
+        assert_eq!(payouts.len(), 2);
+        assert_eq!(payouts[0].amount, 1500);
+        assert_eq!(payouts[0].phone_number, "254711000011");
+        assert_eq!(payouts[0].conversation_id, response.conversation_id);
+
+        let paid: B2cResultBody = serde_json::from_value(b2c_result_body(&payouts[0])).unwrap();
+        assert!(paid.result.is_success());
+        assert_eq!(paid.result.amount(), Some(1500));
+        assert_eq!(paid.result.receipt_number(), Some(payouts[0].transaction_id.clone()));
+
+        let short: B2cResultBody = serde_json::from_value(b2c_result_body(&payouts[1])).unwrap();
+        assert!(!short.result.is_success());
+        assert_eq!(short.result.amount(), None);
+        mock.stop().await;
+    }
+
+    #[test]
+    fn test_b2c_result_parsing_and_retry_backoff() {
+        use crate::services::disbursements::{DisbursementService, MAX_ATTEMPTS};
+        use crate::services::mpesa::B2cResultBody;
+        use chrono::Duration;
+
+        // As documented by Safaricom, with a decimal amount
+        let body = r#"{"Result":{"ResultType":0,"ResultCode":0,"ResultDesc":"The service request is processed successfully.",
+            "OriginatorConversationID":"10571-7910404-1","ConversationID":"AG_20191219_00004e48cf7e3533f581",
+            "TransactionID":"NLJ41HAY6Q","ResultParameters":{"ResultParameter":[
+            {"Key":"TransactionAmount","Value":10.00},{"Key":"TransactionReceipt","Value":"NLJ41HAY6Q"},
+            {"Key":"ReceiverPartyPublicName","Value":"254708374149 - John Doe"}]},
+            "ReferenceData":{"ReferenceItem":{"Key":"QueueTimeoutURL","Value":"https://internalsandbox.safaricom.co.ke"}}}}"#;
+        let parsed: B2cResultBody = serde_json::from_str(body).unwrap();
+        assert!(parsed.result.is_success());
+        assert_eq!(parsed.result.amount(), Some(10));
+        assert_eq!(parsed.result.receipt_number().as_deref(), Some("NLJ41HAY6Q"));
+
+        let failed = r#"{"Result":{"ResultType":0,"ResultCode":2001,"ResultDesc":"The initiator information is invalid.",
+            "OriginatorConversationID":"29112-34801843-1","ConversationID":"AG_20191219_00006c6fddb15123addf",
+            "TransactionID":"NLJ0000000"}}"#;
+        let failed: B2cResultBody = serde_json::from_str(failed).unwrap();
+        assert!(!failed.result.is_success());
+
+        // Waits double after each failed attempt until the funding is reversed
+        assert_eq!(DisbursementService::retry_delay(1), Some(Duration::minutes(5)));
+        assert_eq!(DisbursementService::retry_delay(2), Some(Duration::minutes(10)));
+        assert_eq!(DisbursementService::retry_delay(MAX_ATTEMPTS), None);
+    }
+
+    #[actix_web::test]
+    async fn test_mpesa_statement_parsing() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::mpesa::B2cPaymentRequest;
+        use crate::services::mpesa_mock::MockDaraja;
+        use crate::services::reconciliation::ReconciliationService;
+        use chrono::{TimeZone, Utc};
+
+        // Laid out like a portal export: account summary first, thousands separators, masked numbers
+        let export = "Account Name:,MICROFUND AFRICA LTD\n\
+            Time Period:,01-03-2026 - 01-03-2026\n\
+            \n\
+            Receipt No.,Completion Time,Initiation Time,Details,Transaction Status,Paid In,Withdrawn,Balance,Balance Confirmed,Reason Type,Other Party Info,Linked Transaction ID,A/C No.\n\
+            ,,,,,,,,,,,,\n\
+            rc12abc3de,01-03-2026 09:15:02,01-03-2026 09:15:02,Pay Bill from 254712345678 - JANE DOE Acc. LOAN,Completed,\"1,500.00\",,\"21,500.00\",true,Pay Bill Online,254712345678 - JANE DOE,,LOAN\n\
+            RC12ABC3DF,2026-03-01 10:00:00,2026-03-01 10:00:00,Business Payment to 2547****678 - JOHN DOE,Completed,,-700.00,\"20,800.00\",true,Business Payment,2547****678 - JOHN DOE,,\n\
+            RC12ABC3DG,2026-03-01 11:00:00,2026-03-01 11:00:00,Pay Bill from 254712345678,Failed,20.00,,,,Pay Bill Online,254712345678,,\n";
+        let lines = ReconciliationService::parse_statement(export).unwrap();
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].receipt_number, "RC12ABC3DE");
+        // East Africa Time is three hours ahead of UTC
+        assert_eq!(lines[0].completed_at, Utc.with_ymd_and_hms(2026, 3, 1, 6, 15, 2).unwrap());
+        assert_eq!(lines[0].paid_in, kes(1500));
+        assert!(lines[0].withdrawn.is_zero());
+        assert_eq!(lines[0].phone_number.as_deref(), Some("254712345678"));
+        assert_eq!(lines[1].withdrawn, kes(700));
+        assert_eq!(lines[1].phone_number, None);
+        assert_eq!(lines[2].transaction_status, "Failed");
+
+        assert!(ReconciliationService::parse_statement("Date,Amount\n2026-03-01,10\n").is_err());
+        let bad_amount = "Receipt No.,Completion Time,Transaction Status,Paid In,Withdrawn\nX1,2026-03-01 10:00:00,Completed,abc,\n";
+        assert!(ReconciliationService::parse_statement(bad_amount).is_err());
+
+        // The bundled mock serves its successful payouts in the same layout
+        let mock = MockDaraja::start().await.unwrap();
+        mock.client()
+            .b2c_payment(&B2cPaymentRequest {
+                originator_conversation_id: "attempt-1".to_string(),
+                phone_number: "0711000021".to_string(),
+                amount: kes(2500),
+                remarks: "MicroFund loan".to_string(),
+                occasion: "Loan".to_string(),
+                callback_token: "token".to_string(),
+            })
+            .await
+            .unwrap();
+        let lines = ReconciliationService::parse_statement(&mock.statement_csv()).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].receipt_number, mock.payouts()[0].transaction_id);
+        assert_eq!(lines[0].withdrawn, kes(2500));
+        assert_eq!(lines[0].phone_number.as_deref(), Some("254711000021"));
+        mock.stop().await;
+    }
+
+    #[test]
+    fn test_reconciliation_comparison() {
+        use crate::models::{Money, PaymentStatus, ReconciliationKind, ReconciliationResolution, PLATFORM_CURRENCY};
+        use crate::services::reconciliation::ReconciliationService;
+
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+        let phone = Some("254712345678");
+
+        assert_eq!(ReconciliationService::compare(kes(100), phone, kes(100), phone, PaymentStatus::Completed), None);
+        // Masked statement numbers are not held against a record
+        assert_eq!(ReconciliationService::compare(kes(100), None, kes(100), phone, PaymentStatus::Completed), None);
+
+        let amount = ReconciliationService::compare(kes(100), phone, kes(90), phone, PaymentStatus::Completed).unwrap();
+        assert!(amount.contains("KES 100.00") && amount.contains("KES 90.00"));
+
+        let everything = ReconciliationService::compare(kes(100), Some("254700000000"), kes(100), phone, PaymentStatus::Failed).unwrap();
+        assert!(everything.contains("254700000000"));
+        assert!(everything.contains("failed here"));
+        assert!(!everything.contains("KES"));
+
+        assert_eq!(ReconciliationKind::parse("missing_in_statement"), Some(ReconciliationKind::MissingInStatement));
+        let resolution: ReconciliationResolution = serde_json::from_str("\"written_off\"").unwrap();
+        assert_eq!(resolution, ReconciliationResolution::WrittenOff);
+    }
+
+    /// Refunds an overpayment over the mock Daraja: a payout M-Pesa turns down is sent again,
+    /// and one that never gets a result waits for the M-Pesa statement to settle it.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_refund_payouts() {
+        use crate::models::{InterestMethod, LoanTerms, Money, OverpaymentDestination, PaymentStatus, RepaymentFrequency, PLATFORM_CURRENCY};
+        use crate::services::loan_schedule::LoanScheduleService;
+        use crate::services::mpesa::B2cResultBody;
+        use crate::services::mpesa_mock::{b2c_result_body, MockDaraja, MockScenario};
+        use crate::services::reconciliation::{ParsedStatementLine, ReconciliationService};
+        use crate::services::refunds::RefundService;
+        use crate::services::repayments::{AllocationOrder, NewRepayment, RepaymentService};
+        use crate::services::scoring::ScoringWeights;
+        use chrono::{DateTime, Duration, Utc};
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+        let mock = MockDaraja::start().await.unwrap();
+        let mpesa = mock.client();
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+
+        let tag = Uuid::new_v4();
+        let phone = format!("2547{:08}", tag.as_u128() % 100_000_000);
+        let name = tag.simple().to_string();
+        let borrower = insert_member(&pool, "borrower", Some(&phone)).await;
+        let lender = insert_member(&pool, "lender", None).await;
+
+        let principal = kes(1_000);
+        let (loan_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO loans (user_id, lender_id, amount, status) VALUES ($1, $2, $3, 'in_repayment') RETURNING id"
+        )
+        .bind(borrower)
+        .bind(lender)
+        .bind(principal)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let terms = LoanTerms {
+            interest_method: InterestMethod::Flat,
+            interest_rate_bps: 1000,
+            origination_fee_bps: 0,
+            term_count: 2,
+            repayment_frequency: RepaymentFrequency::Weekly,
+        };
+        let schedule = LoanScheduleService::build(principal, &terms, Utc::now()).unwrap();
+        let owed = schedule.iter().map(|i| i.total_due().unwrap().minor_units()).sum::<i64>();
+        let mut conn = pool.acquire().await.unwrap();
+        LoanScheduleService::insert_schedule(&mut conn, loan_id, &schedule).await.unwrap();
+        drop(conn);
+
+        // Paid to the paybill with KES 300 too much, which the borrower wants back
+        let mut tx = pool.begin().await.unwrap();
+        let repayment = NewRepayment {
+            loan_id,
+            payer_id: None,
+            amount: Money::new(owed, PLATFORM_CURRENCY).checked_add(kes(300)).unwrap(),
+            overpayment_destination: OverpaymentDestination::Refund,
+            savings_id: None,
+            mpesa_receipt_number: Some(format!("R{}", &name[..9]).to_uppercase()),
+            payment_id: None,
+        };
+        RepaymentService::apply(&mut tx, &repayment, &AllocationOrder::default(), &ScoringWeights::default()).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let refund = RefundService::for_user(&pool, borrower).await.unwrap().pop().unwrap();
+        assert_eq!((refund.amount, refund.phone_number.as_str(), refund.status, refund.attempt), (kes(300), phone.as_str(), PaymentStatus::Pending, 0));
+        let (queued,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM outbox_events WHERE payload->>'refund_id' = $1")
+            .bind(refund.id.to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(queued, 1);
+
+        let load = || async {
+            RefundService::for_user(&pool, borrower).await.unwrap().pop().unwrap()
+        };
+        // Other tests' refunds may go out through the same jobs
+        let payouts = || mock.payouts().into_iter().filter(|p| p.phone_number == phone).collect::<Vec<_>>();
+        let result = |index: usize| -> B2cResultBody { serde_json::from_value(b2c_result_body(&payouts()[index])).unwrap() };
+        let statement = |lines: Vec<(DateTime<Utc>, Money, Money, Option<String>)>| {
+            let pool = &pool;
+            async move {
+                let lines: Vec<ParsedStatementLine> = lines
+                    .into_iter()
+                    .map(|(completed_at, paid_in, withdrawn, phone_number)| ParsedStatementLine {
+                        receipt_number: Uuid::new_v4().simple().to_string()[..10].to_uppercase(),
+                        completed_at,
+                        details: None,
+                        transaction_status: "Completed".to_string(),
+                        paid_in,
+                        withdrawn,
+                        other_party: None,
+                        phone_number,
+                    })
+                    .collect();
+                let imported = ReconciliationService::import(pool, None, &lines, lender).await.unwrap();
+                ReconciliationService::reconcile(pool, imported.id, Utc::now()).await.unwrap();
+            }
+        };
+
+        // M-Pesa turns the first payout down; sending again while it is in flight sends nothing
+        mock.set_scenario(&phone, MockScenario::InsufficientFunds);
+        let now = Utc::now();
+        let sent = RefundService::send(&pool, &mpesa, refund.id, now).await.unwrap();
+        assert_eq!(sent.attempt, 1);
+        assert_eq!(RefundService::send(&pool, &mpesa, refund.id, now).await.unwrap().attempt, 1);
+        assert_eq!(payouts().len(), 1);
+        let failed = RefundService::handle_result(&pool, &sent.callback_token, &result(0).result, now).await.unwrap().unwrap();
+        assert_eq!(failed.status, PaymentStatus::Pending);
+        assert!(failed.sent_at.is_none() && failed.retry_at.is_some());
+
+        // Sent again once the retry is due; this time no result ever comes
+        mock.set_scenario(&phone, MockScenario::Success);
+        let later = now + Duration::minutes(16);
+        RefundService::run_due(&pool, &mpesa, later).await.unwrap();
+        assert_eq!((load().await.attempt, payouts().len()), (2, 2));
+        RefundService::run_due(&pool, &mpesa, later + Duration::minutes(31)).await.unwrap();
+        let waiting = load().await;
+        assert!(waiting.unacknowledged_at.is_some());
+        RefundService::run_due(&pool, &mpesa, later + Duration::hours(2)).await.unwrap();
+        assert_eq!(payouts().len(), 2);
+
+        // A statement over its time without the payout shows it was not made, so it goes out again
+        let sent_at = waiting.sent_at.unwrap();
+        statement(vec![
+            (sent_at - Duration::hours(1), kes(7), kes(0), None),
+            (sent_at + Duration::hours(25), kes(7), kes(0), None),
+        ])
+        .await;
+        let released = load().await;
+        assert_eq!((released.status, released.sent_at, released.unacknowledged_at), (PaymentStatus::Pending, None, None));
+        RefundService::run_due(&pool, &mpesa, released.retry_at.unwrap()).await.unwrap();
+        let resent = load().await;
+        assert_eq!((resent.attempt, payouts().len()), (3, 3));
+
+        // The next statement shows the third payout was made
+        assert_eq!(payouts()[2].amount, 300);
+        statement(vec![(resent.sent_at.unwrap() + Duration::minutes(1), kes(0), kes(300), Some(phone.clone()))]).await;
+        let completed = load().await;
+        assert_eq!(completed.status, PaymentStatus::Completed);
+        assert!(completed.transaction_id.is_some());
+
+        // A result arriving after that changes nothing
+        let late = RefundService::handle_result(&pool, &sent.callback_token, &result(2).result, Utc::now()).await.unwrap().unwrap();
+        assert_eq!((late.status, late.transaction_id), (PaymentStatus::Completed, completed.transaction_id));
+
+        // Nothing is owed to the borrower anymore
+        let (owed,): (i64,) = sqlx::query_as(
+            "SELECT COALESCE(SUM(p.amount), 0)::bigint FROM postings p JOIN ledger_accounts a ON a.id = p.account_id WHERE a.code = $1"
+        )
+        .bind(format!("REFUNDS_PAYABLE:{}", borrower))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(owed, 0);
+
+        mock.stop().await;
+    }
+
+    /// A loan payout attempt that never gets a result is not paid out again until the M-Pesa
+    /// statement shows it was not made, and one the statement shows was made pays out the loan.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_unacknowledged_disbursements() {
+        use crate::models::{InterestMethod, LoanStatus, LoanTerms, Money, PaymentStatus, RepaymentFrequency, PLATFORM_CURRENCY};
+        use crate::services::disbursements::DisbursementService;
+        use crate::services::loan_lifecycle::LoanLifecycle;
+        use crate::services::loan_schedule::LoanScheduleService;
+        use crate::services::mpesa_mock::MockDaraja;
+        use crate::services::reconciliation::{ParsedStatementLine, ReconciliationService};
+        use chrono::{DateTime, Duration, Utc};
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+        let mock = MockDaraja::start().await.unwrap();
+        let mpesa = mock.client();
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+
+        let tag = Uuid::new_v4();
+        let phone = format!("2541{:08}", tag.as_u128() % 100_000_000);
+        let borrower = insert_member(&pool, "borrower", None).await;
+        let lender = insert_member(&pool, "lender", None).await;
+
+        let principal = kes(1_000);
+        let (loan_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO loans (user_id, lender_id, amount, status, disbursement_phone) VALUES ($1, $2, $3, 'pending', $4) RETURNING id"
+        )
+        .bind(borrower)
+        .bind(lender)
+        .bind(principal)
+        .bind(&phone)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let terms = LoanTerms {
+            interest_method: InterestMethod::Flat,
+            interest_rate_bps: 1000,
+            origination_fee_bps: 0,
+            term_count: 2,
+            repayment_frequency: RepaymentFrequency::Weekly,
+        };
+        let schedule = LoanScheduleService::build(principal, &terms, Utc::now()).unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        LoanScheduleService::insert_schedule(&mut conn, loan_id, &schedule).await.unwrap();
+        LoanLifecycle::transition(&mut conn, loan_id, LoanStatus::Funded, Some(lender), None).await.unwrap();
+        drop(conn);
+
+        // Other tests' loans may be paid out through the same jobs
+        let payouts = || mock.payouts().into_iter().filter(|p| p.phone_number == phone).count();
+        let attempts = || async { DisbursementService::for_loan(&pool, loan_id).await.unwrap() };
+        let loan_status = || async {
+            let (status,): (LoanStatus,) = sqlx::query_as("SELECT status FROM loans WHERE id = $1")
+                .bind(loan_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            status
+        };
+        let statement = |lines: Vec<(DateTime<Utc>, Money, Option<String>)>| {
+            let pool = &pool;
+            async move {
+                let lines: Vec<ParsedStatementLine> = lines
+                    .into_iter()
+                    .map(|(completed_at, withdrawn, phone_number)| ParsedStatementLine {
+                        receipt_number: Uuid::new_v4().simple().to_string()[..10].to_uppercase(),
+                        completed_at,
+                        details: None,
+                        transaction_status: "Completed".to_string(),
+                        paid_in: Money::zero(PLATFORM_CURRENCY),
+                        withdrawn,
+                        other_party: None,
+                        phone_number,
+                    })
+                    .collect();
+                let imported = ReconciliationService::import(pool, None, &lines, lender).await.unwrap();
+                ReconciliationService::reconcile(pool, imported.id, Utc::now()).await.unwrap();
+            }
+        };
+
+        // Daraja accepts the first attempt but its result never comes
+        let first = DisbursementService::start(&pool, &mpesa, loan_id).await.unwrap();
+        assert!(first.conversation_id.is_some());
+        let now = Utc::now();
+        DisbursementService::run_due(&pool, &mpesa, now + Duration::minutes(31)).await.unwrap();
+        let waiting = attempts().await;
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].status, PaymentStatus::Pending);
+        assert!(waiting[0].unacknowledged_at.is_some());
+        DisbursementService::run_due(&pool, &mpesa, now + Duration::hours(3)).await.unwrap();
+        assert_eq!((payouts(), attempts().await.len(), loan_status().await), (1, 1, LoanStatus::Funded));
+
+        // A statement over its time without the payout fails it, and the next attempt goes out
+        let created_at = first.created_at.unwrap();
+        statement(vec![
+            (created_at - Duration::hours(1), kes(7), None),
+            (created_at + Duration::hours(25), kes(7), None),
+        ])
+        .await;
+        let released = attempts().await;
+        assert_eq!(released[0].status, PaymentStatus::Failed);
+        let retry_at = released[0].retry_at.unwrap();
+        DisbursementService::run_due(&pool, &mpesa, retry_at).await.unwrap();
+        let second = attempts().await.pop().unwrap();
+        assert_eq!((second.attempt, payouts()), (2, 2));
+
+        // The next statement shows the second attempt was paid
+        DisbursementService::run_due(&pool, &mpesa, Utc::now() + Duration::minutes(31)).await.unwrap();
+        statement(vec![(second.created_at.unwrap() + Duration::minutes(1), principal, Some(phone.clone()))]).await;
+        let paid = attempts().await.pop().unwrap();
+        assert_eq!(paid.status, PaymentStatus::Completed);
+        assert!(paid.transaction_id.is_some());
+        assert_eq!((payouts(), loan_status().await), (2, LoanStatus::Disbursed));
+
+        mock.stop().await;
+    }
+
+    /// A loan is only funded once M-Pesa confirms the lender's payment, and capital that
+    /// cannot be used is refunded to the number it came from.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_loan_funding_payments() {
+        use crate::middleware::AppError;
+        use crate::models::{LoanStatus, Money, PaymentStatus, PLATFORM_CURRENCY};
+        use crate::services::disbursements::DisbursementService;
+        use crate::services::mpesa::StkStatus;
+        use crate::services::mpesa_mock::MockDaraja;
+        use crate::services::payments::{NewPayment, PaymentResult, PaymentService};
+        use crate::services::refunds::RefundService;
+        use crate::services::repayments::AllocationOrder;
+        use crate::services::scoring::ScoringWeights;
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+        let mock = MockDaraja::start().await.unwrap();
+        let mpesa = mock.client();
+        let amount = Money::from_major(800, PLATFORM_CURRENCY).unwrap();
+
+        let borrower = insert_member(&pool, "borrower", None).await;
+        let lender = insert_member(&pool, "lender", None).await;
+        let other = insert_member(&pool, "other", None).await;
+        let new_loan = || async {
+            let (id,): (Uuid,) = sqlx::query_as(
+                "INSERT INTO loans (user_id, amount, status, disbursement_phone) VALUES ($1, $2, 'pending', '254711000009') RETURNING id"
+            )
+            .bind(borrower)
+            .bind(amount)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            id
+        };
+        let confirm = |checkout: String| {
+            let pool = &pool;
+            async move {
+                let result = PaymentResult {
+                    status: StkStatus::Completed,
+                    result_code: Some("0".to_string()),
+                    result_desc: "The service request is processed successfully.".to_string(),
+                    receipt_number: Some(format!("F{}", &Uuid::new_v4().simple().to_string()[..9]).to_uppercase()),
+                };
+                PaymentService::settle(pool, &checkout, &result, &AllocationOrder::default(), &ScoringWeights::default())
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+        let balance = |code: String| {
+            let pool = &pool;
+            async move {
+                let (sum,): (i64,) = sqlx::query_as(
+                    "SELECT COALESCE(SUM(p.amount), 0)::bigint FROM postings p JOIN ledger_accounts a ON a.id = p.account_id WHERE a.code = $1"
+                )
+                .bind(code)
+                .fetch_one(pool)
+                .await
+                .unwrap();
+                sum
+            }
+        };
+        let loan_of = |loan_id: Uuid| {
+            let pool = &pool;
+            async move {
+                let loan: (LoanStatus, Option<Uuid>) = sqlx::query_as("SELECT status, lender_id FROM loans WHERE id = $1")
+                    .bind(loan_id)
+                    .fetch_one(pool)
+                    .await
+                    .unwrap();
+                loan
+            }
+        };
+
+        // Nothing moves until M-Pesa confirms the lender's payment, and one lender pays at a time
+        let loan_id = new_loan().await;
+        let payment = PaymentService::start(&pool, &mpesa, &NewPayment::loan_funding(lender, loan_id, amount, "0711000021")).await.unwrap();
+        assert_eq!(payment.status, PaymentStatus::Pending);
+        assert_eq!(loan_of(loan_id).await, (LoanStatus::Pending, None));
+        let second = PaymentService::start(&pool, &mpesa, &NewPayment::loan_funding(other, loan_id, amount, "0711000022")).await;
+        assert!(matches!(second, Err(AppError::Conflict(_))));
+
+        let funded = confirm(payment.checkout_request_id.clone().unwrap()).await;
+        assert_eq!(funded.status, PaymentStatus::Completed);
+        assert_eq!(loan_of(loan_id).await, (LoanStatus::Funded, Some(lender)));
+        assert_eq!(balance(format!("LENDER_PAYABLE:{}", lender)).await, -amount.minor_units());
+        let (payouts,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM outbox_events WHERE event_type = 'loan_payout' AND payload->>'loan_id' = $1"
+        )
+        .bind(loan_id.to_string())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(payouts, 1);
+
+        // Capital for a loan withdrawn while the lender was paying goes back to them
+        let withdrawn = new_loan().await;
+        let late = PaymentService::start(&pool, &mpesa, &NewPayment::loan_funding(other, withdrawn, amount, "0711000022")).await.unwrap();
+        sqlx::query("UPDATE loans SET status = 'cancelled' WHERE id = $1").bind(withdrawn).execute(&pool).await.unwrap();
+        assert_eq!(confirm(late.checkout_request_id.clone().unwrap()).await.status, PaymentStatus::Completed);
+        assert_eq!(loan_of(withdrawn).await, (LoanStatus::Cancelled, None));
+        let refund = RefundService::for_user(&pool, other).await.unwrap().pop().unwrap();
+        assert_eq!((refund.amount, refund.phone_number.as_str(), refund.payment_id), (amount, "254711000022", Some(late.id)));
+        assert_eq!(balance(format!("LENDER_PAYABLE:{}", other)).await, 0);
+        assert_eq!(balance(format!("REFUNDS_PAYABLE:{}", other)).await, -amount.minor_units());
+
+        // A funded loan that cannot be paid out refunds its lender
+        sqlx::query("UPDATE loans SET disbursement_phone = NULL WHERE id = $1").bind(loan_id).execute(&pool).await.unwrap();
+        assert!(DisbursementService::start(&pool, &mpesa, loan_id).await.is_err());
+        assert_eq!(loan_of(loan_id).await, (LoanStatus::Pending, None));
+        let refund = RefundService::for_user(&pool, lender).await.unwrap().pop().unwrap();
+        assert_eq!((refund.amount, refund.phone_number.as_str(), refund.payment_id), (amount, "254711000021", Some(payment.id)));
+        assert_eq!(balance(format!("LENDER_PAYABLE:{}", lender)).await, 0);
+
+        mock.stop().await;
+    }
+
+    /// STK repayments M-Pesa confirms after the loan was repaid go to savings or back to the
+    /// borrower instead of being left pending.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_late_stk_repayments() {
+        use crate::models::{LoanStatus, Money, OverpaymentDestination, PaymentStatus, PLATFORM_CURRENCY};
+        use crate::services::mpesa::StkStatus;
+        use crate::services::payments::{PaymentResult, PaymentService};
+        use crate::services::refunds::RefundService;
+        use crate::services::repayments::AllocationOrder;
+        use crate::services::scoring::ScoringWeights;
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+
+        let borrower = insert_member(&pool, "borrower", None).await;
+        let lender = insert_member(&pool, "lender", None).await;
+        let (loan_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO loans (user_id, lender_id, amount, status) VALUES ($1, $2, $3, 'repaid') RETURNING id"
+        )
+        .bind(borrower)
+        .bind(lender)
+        .bind(kes(1_000))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let settle = |destination: OverpaymentDestination, amount: Money| {
+            let pool = &pool;
+            async move {
+                let checkout = format!("ws_CO_{}", Uuid::new_v4().simple());
+                sqlx::query(
+                    "INSERT INTO payments (user_id, purpose, loan_id, overpayment_destination, amount, phone_number, checkout_request_id)
+                     VALUES ($1, 'loan_repayment', $2, $3, $4, '254711000001', $5)"
+                )
+                .bind(borrower)
+                .bind(loan_id)
+                .bind(destination)
+                .bind(amount)
+                .bind(&checkout)
+                .execute(pool)
+                .await
+                .unwrap();
+                let result = PaymentResult {
+                    status: StkStatus::Completed,
+                    result_code: Some("0".to_string()),
+                    result_desc: "The service request is processed successfully.".to_string(),
+                    receipt_number: Some(format!("L{}", &Uuid::new_v4().simple().to_string()[..9]).to_uppercase()),
+                };
+                PaymentService::settle(pool, &checkout, &result, &AllocationOrder::default(), &ScoringWeights::default())
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+
+        let saved = settle(OverpaymentDestination::Savings, kes(200)).await;
+        assert_eq!(saved.status, PaymentStatus::Completed);
+        let (goal,): (Money,) = sqlx::query_as(
+            "SELECT s.amount FROM savings s JOIN savings_transactions t ON t.savings_id = s.id
+             JOIN loan_repayments r ON r.id = t.repayment_id WHERE r.payment_id = $1"
+        )
+        .bind(saved.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(goal, kes(200));
+
+        let refunded = settle(OverpaymentDestination::Refund, kes(150)).await;
+        assert_eq!(refunded.status, PaymentStatus::Completed);
+        let refund = RefundService::for_user(&pool, borrower).await.unwrap().pop().unwrap();
+        assert_eq!((refund.amount, refund.payment_id, refund.phone_number.as_str()), (kes(150), Some(refunded.id), "254711000001"));
+
+        // Neither payment touched the loan or its lender
+        let (status,): (LoanStatus,) = sqlx::query_as("SELECT status FROM loans WHERE id = $1")
+            .bind(loan_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(status, LoanStatus::Repaid);
+        let (receivable,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM postings p JOIN ledger_accounts a ON a.id = p.account_id WHERE a.code IN ($1, $2)"
+        )
+        .bind(format!("LOANS_RECEIVABLE:{}", borrower))
+        .bind(format!("LENDER_PAYABLE:{}", lender))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(receivable, 0);
+    }
+
+    /// Payments M-Pesa confirmed without a receipt are completed from the statement line that
+    /// pays them, apart from the STK queries for payments whose callback never came.
+    /// Needs a migrated database in `DATABASE_URL`.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_confirmed_payments_take_statement_receipts() {
+        use crate::models::PaymentStatus;
+        use crate::services::mpesa_mock::MockDaraja;
+        use crate::services::payments::PaymentService;
+        use crate::services::repayments::AllocationOrder;
+        use crate::services::scoring::ScoringWeights;
+        use chrono::{Duration, Utc};
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+        let mock = MockDaraja::start().await.unwrap();
+
+        let tag = Uuid::new_v4().simple().to_string();
+        let phone = format!("2547{:08}", Uuid::new_v4().as_u128() % 100_000_000);
+        let member = insert_member(&pool, "saver", None).await;
+        let (savings_id,): (Uuid,) = sqlx::query_as("INSERT INTO savings (user_id, goal_name) VALUES ($1, 'Land') RETURNING id")
+            .bind(member)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        let now = Utc::now();
+        let (confirmed_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO payments (user_id, purpose, savings_id, amount, phone_number, checkout_request_id, confirmed_at, created_at)
+             VALUES ($1, 'savings_deposit', $2, 25000, $3, $4, $5, $5) RETURNING id"
+        )
+        .bind(member)
+        .bind(savings_id)
+        .bind(&phone)
+        .bind(format!("ws_CO_{}", tag))
+        .bind(now - Duration::hours(2))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let (unsent_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO payments (user_id, purpose, savings_id, amount, phone_number, created_at)
+             VALUES ($1, 'savings_deposit', $2, 10000, $3, $4) RETURNING id"
+        )
+        .bind(member)
+        .bind(savings_id)
+        .bind(&phone)
+        .bind(now - Duration::hours(1))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let (client, order, weights) = (mock.client(), AllocationOrder::default(), ScoringWeights::default());
+        let resolve = || PaymentService::resolve_stale(&pool, &client, &order, &weights, now);
+        let status = |id: Uuid| {
+            let pool = pool.clone();
+            async move {
+                let row: (PaymentStatus, Option<String>) = sqlx::query_as("SELECT status, mpesa_receipt_number FROM payments WHERE id = $1")
+                    .bind(id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+                row
+            }
+        };
+
+        // Without a statement line the confirmed payment waits, while the one never sent is given up on
+        resolve().await.unwrap();
+        assert_eq!(status(confirmed_id).await, (PaymentStatus::Pending, None));
+        assert_eq!(status(unsent_id).await.0, PaymentStatus::Failed);
+
+        let (statement_id,): (Uuid,) = sqlx::query_as("INSERT INTO mpesa_statements (imported_by, line_count) VALUES ($1, 1) RETURNING id")
+            .bind(member)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let receipt = format!("S{}", &tag[..9]).to_uppercase();
+        sqlx::query(
+            "INSERT INTO mpesa_statement_lines (statement_id, receipt_number, completed_at, transaction_status, paid_in, phone_number)
+             VALUES ($1, $2, $3, 'Completed', 25000, $4)"
+        )
+        .bind(statement_id)
+        .bind(&receipt)
+        .bind(now - Duration::minutes(110))
+        .bind(&phone)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        resolve().await.unwrap();
+        assert_eq!(status(confirmed_id).await, (PaymentStatus::Completed, Some(receipt)));
+        let (balance,): (i64,) = sqlx::query_as("SELECT amount FROM savings WHERE id = $1")
+            .bind(savings_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(balance, 25000);
+        mock.stop().await;
+    }
+
+    #[test]
+    fn test_savings_goal_progress_and_lock() {
+        use crate::models::{Money, Savings, PLATFORM_CURRENCY};
+        use chrono::NaiveDate;
+        use uuid::Uuid;
+
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+        let date = |d| NaiveDate::from_ymd_opt(2026, 3, d).unwrap();
+        let goal = |amount, target: Option<Money>, locked_until| Savings {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            amount,
+            goal_name: Some("School fees".to_string()),
+            target_amount: target,
+            target_date: Some(date(31)),
+            locked_until,
+            created_at: None,
+            updated_at: None,
+        };
+
+        assert_eq!(goal(kes(0), Some(kes(1000)), None).progress_percent(), Some(0));
+        // Whole percent, rounded down, so a goal only shows 100 once it is reached
+        assert_eq!(goal(Money::new(99_999, PLATFORM_CURRENCY), Some(kes(1000)), None).progress_percent(), Some(99));
+        assert_eq!(goal(kes(250), Some(kes(1000)), None).progress_percent(), Some(25));
+        assert_eq!(goal(kes(2500), Some(kes(1000)), None).progress_percent(), Some(100));
+        assert_eq!(goal(kes(250), None, None).progress_percent(), None);
+
+        let locked = goal(kes(250), None, Some(date(15)));
+        assert!(locked.is_locked(date(14)));
+        assert!(!locked.is_locked(date(15)));
+        assert!(!goal(kes(250), None, None).is_locked(date(1)));
+    }
+
+    #[test]
+    fn test_withdrawable_savings() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::withdrawals::WithdrawalService;
+
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+
+        assert_eq!(WithdrawalService::available(kes(500), kes(500), kes(0)), kes(500));
+        assert_eq!(WithdrawalService::available(kes(500), kes(500), kes(200)), kes(300));
+        // Money M-Pesa has not confirmed stays in the goal but cannot be paid out
+        assert_eq!(WithdrawalService::available(kes(500), kes(350), kes(0)), kes(350));
+        assert_eq!(WithdrawalService::available(kes(500), kes(350), kes(100)), kes(250));
+        assert_eq!(WithdrawalService::available(kes(500), kes(0), kes(0)), kes(0));
+        assert_eq!(WithdrawalService::available(kes(500), kes(100), kes(200)), kes(0));
+    }
+
+    /// A deposit can only be reversed out of money pending withdrawals do not already hold.
+    /// Needs a migrated database in `DATABASE_URL`.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_deposit_reversal_respects_withdrawal_holds() {
+        use crate::middleware::AppError;
+        use crate::services::reversals::ReversalService;
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+
+        let tag = Uuid::new_v4().simple().to_string();
+        let member = insert_member(&pool, "saver", None).await;
+        let (savings_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO savings (user_id, goal_name, amount) VALUES ($1, 'Land', 50000) RETURNING id"
+        )
+        .bind(member)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let (payment_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO payments (user_id, purpose, savings_id, amount, phone_number, status, mpesa_receipt_number)
+             VALUES ($1, 'savings_deposit', $2, 50000, '254712345678', 'completed', $3) RETURNING id"
+        )
+        .bind(member)
+        .bind(savings_id)
+        .bind(format!("R{}", &tag[..9]).to_uppercase())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let (deposit_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO savings_transactions (savings_id, amount, transaction_type, payment_id)
+             VALUES ($1, 50000, 'deposit', $2) RETURNING id"
+        )
+        .bind(savings_id)
+        .bind(payment_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let (withdrawal_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO savings_withdrawals (savings_id, user_id, amount, phone_number, callback_token)
+             VALUES ($1, $2, 30000, '254712345678', $3) RETURNING id"
+        )
+        .bind(savings_id)
+        .bind(member)
+        .bind(&tag)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        // KES 300 of the 500 is on its way out to M-Pesa, so the deposit cannot come back out
+        let mut tx = pool.begin().await.unwrap();
+        let refused = ReversalService::reverse_savings_deposit(&mut tx, deposit_id, member).await;
+        assert!(matches!(refused, Err(AppError::Conflict(_))), "{:?}", refused.map(|r| r.id));
+        tx.rollback().await.unwrap();
+
+        // Once the payout fails the hold is gone and the deposit can be taken back in full
+        sqlx::query("UPDATE savings_withdrawals SET status = 'failed' WHERE id = $1")
+            .bind(withdrawal_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        ReversalService::reverse_savings_deposit(&mut tx, deposit_id, member).await.unwrap();
+        tx.commit().await.unwrap();
+        let (balance,): (i64,) = sqlx::query_as("SELECT amount FROM savings WHERE id = $1")
+            .bind(savings_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(balance, 0);
+
+        // Whatever the application misses, the database keeps goals from going below zero
+        let negative = sqlx::query("UPDATE savings SET amount = amount - 1 WHERE id = $1")
+            .bind(savings_id)
+            .execute(&pool)
+            .await;
+        assert!(negative.is_err());
+    }
+
+    /// Withdrawals Daraja accepted but never answered are flagged for reconciliation just like
+    /// those it never acknowledged. Needs a migrated database in `DATABASE_URL`.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_stale_withdrawals_are_flagged() {
+        use crate::services::withdrawals::WithdrawalService;
+        use chrono::{DateTime, Duration, Utc};
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+
+        let tag = Uuid::new_v4().simple().to_string();
+        let member = insert_member(&pool, "saver", None).await;
+        let (savings_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO savings (user_id, goal_name, amount) VALUES ($1, 'Land', 50000) RETURNING id"
+        )
+        .bind(member)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let now = Utc::now();
+        let mut withdrawals = Vec::new();
+        for (suffix, conversation_id, age) in [("a", Some(format!("AG_{}", tag)), 45), ("n", None, 45), ("r", None, 5)] {
+            let (id,): (Uuid,) = sqlx::query_as(
+                "INSERT INTO savings_withdrawals (savings_id, user_id, amount, phone_number, callback_token, conversation_id, created_at)
+                 VALUES ($1, $2, 10000, '254712345678', $3, $4, $5) RETURNING id"
+            )
+            .bind(savings_id)
+            .bind(member)
+            .bind(format!("{}{}", tag, suffix))
+            .bind(conversation_id)
+            .bind(now - Duration::minutes(age))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            withdrawals.push(id);
+        }
+
+        WithdrawalService::escalate_stale(&pool, now).await.unwrap();
+
+        let flagged: Vec<(Uuid, Option<DateTime<Utc>>)> = sqlx::query_as(
+            "SELECT id, unacknowledged_at FROM savings_withdrawals WHERE savings_id = $1"
+        )
+        .bind(savings_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let flagged = |id: Uuid| flagged.iter().any(|(w, at)| *w == id && at.is_some());
+        // Accepted or not, half an hour without a result hands the payout to reconciliation
+        assert!(flagged(withdrawals[0]));
+        assert!(flagged(withdrawals[1]));
+        assert!(!flagged(withdrawals[2]));
+    }
+
+    #[test]
+    fn test_savings_goal_json() {
+        use crate::models::{Money, PaymentStatus, Savings, SavingsGoal, SavingsWithdrawal, PLATFORM_CURRENCY};
+        use chrono::NaiveDate;
+        use uuid::Uuid;
+
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+        let savings = Savings {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            amount: kes(500),
+            goal_name: Some("Land".to_string()),
+            target_amount: Some(kes(2000)),
+            target_date: NaiveDate::from_ymd_opt(2026, 12, 1),
+            locked_until: None,
+            created_at: None,
+            updated_at: None,
+        };
+        let goal = SavingsGoal {
+            available: kes(300),
+            progress_percent: savings.progress_percent(),
+            locked: false,
+            savings,
+        };
+        let json = serde_json::to_value(&goal).unwrap();
+        assert_eq!(json["goal_name"], "Land");
+        assert_eq!(json["target_date"], "2026-12-01");
+        assert_eq!(json["available"]["minor_units"], 30_000);
+        assert_eq!(json["progress_percent"], 25);
+
+        let withdrawal = SavingsWithdrawal {
+            id: Uuid::new_v4(),
+            savings_id: goal.savings.id,
+            user_id: goal.savings.user_id,
+            amount: kes(200),
+            phone_number: "254712345678".to_string(),
+            status: PaymentStatus::Pending,
+            callback_token: "secret".to_string(),
+            conversation_id: None,
+            transaction_id: None,
+            result_code: None,
+            result_desc: None,
+            created_at: None,
+            updated_at: None,
+            settled_at: None,
+        };
+        let json = serde_json::to_value(&withdrawal).unwrap();
+        assert_eq!(json["status"], "pending");
+        // The token authenticates Daraja's result, so members never see it
+        assert!(json.get("callback_token").is_none());
+    }
+
+    /// Sends each loan, savings and payment route a request for another member's record,
+    /// through the same middleware as the server. Needs a migrated database in `DATABASE_URL`.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_routes_refuse_other_members_records() {
+        use crate::handlers;
+        use crate::middleware::auth::{authenticate, JwtConfig};
+        use crate::middleware::rate_limit::{MemoryStore, RateLimiter};
+        use crate::models::Role;
+        use crate::services::mpesa_mock::MockDaraja;
+        use crate::services::repayments::AllocationOrder;
+        use crate::services::scoring::ScoringWeights;
+        use actix_web::http::{Method, StatusCode};
+        use actix_web::{test, web, App};
+        use chrono::{Duration, Utc};
+        use serde_json::json;
+        use std::sync::Arc;
+        use uuid::Uuid;
+
+        let pool = test_db().await;
+        let jwt = JwtConfig::new("0123456789abcdef0123456789abcdef", "microfund-africa", "microfund-api", "k1", Duration::hours(1)).unwrap();
+        let mock = MockDaraja::start().await.unwrap();
+
+        let mut tokens = Vec::new();
+        let mut ids = Vec::new();
+        for name in ["borrower", "lender", "stranger"] {
+            let id = insert_member(&pool, name, None).await;
+            let (session_id,): (Uuid,) = sqlx::query_as("INSERT INTO sessions (user_id, expires_at) VALUES ($1, $2) RETURNING id")
+                .bind(id)
+                .bind(Utc::now() + Duration::days(1))
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            tokens.push(jwt.issue(id, session_id, vec![Role::Member], Utc::now()).unwrap());
+            ids.push(id);
+        }
+        let (borrower, lender) = (ids[0], ids[1]);
+        let (borrower_token, lender_token, stranger_token) = (&tokens[0], &tokens[1], &tokens[2]);
+
+        let (loan_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO loans (user_id, lender_id, amount, status) VALUES ($1, $2, 1000000, 'in_repayment') RETURNING id"
+        )
+        .bind(borrower)
+        .bind(lender)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let (savings_id,): (Uuid,) = sqlx::query_as("INSERT INTO savings (user_id, goal_name) VALUES ($1, 'School fees') RETURNING id")
+            .bind(borrower)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let (payment_id,): (Uuid,) = sqlx::query_as(
+            "INSERT INTO payments (user_id, purpose, savings_id, amount, phone_number, status)
+             VALUES ($1, 'savings_deposit', $2, 10000, '254712345678', 'pending') RETURNING id"
+        )
+        .bind(borrower)
+        .bind(savings_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(jwt.clone()))
+                .app_data(web::Data::new(mock.client()))
+                .app_data(web::Data::new(RateLimiter::new(Arc::new(MemoryStore::default()))))
+                .app_data(web::Data::new(AllocationOrder::default()))
+                .app_data(web::Data::new(ScoringWeights::default()))
+                .service(web::scope("/api").wrap(actix_web::middleware::from_fn(authenticate)).configure(handlers::config)),
+        )
+        .await;
+
+        let amount = json!({"minor_units": 10000, "currency": "KES"});
+        let phone = "254712345678";
+        let routes = [
+            (Method::POST, "/api/loans/repay".to_string(), Some(json!({"loan_id": loan_id, "phone_number": phone}))),
+            (Method::POST, format!("/api/loans/{}/repayments", loan_id), Some(json!({"amount": amount, "phone_number": phone}))),
+            (Method::POST, format!("/api/loans/{}/cancel", loan_id), Some(json!({}))),
+            (Method::GET, format!("/api/loans/{}/repayments", loan_id), None),
+            (Method::GET, format!("/api/loans/{}/disbursements", loan_id), None),
+            (Method::GET, format!("/api/loans/{}/history", loan_id), None),
+            (Method::GET, format!("/api/loans/{}/schedule", loan_id), None),
+            (Method::POST, format!("/api/savings/{}/deposit", savings_id), Some(json!({"amount": amount, "phone_number": phone}))),
+            (Method::POST, format!("/api/savings/{}/withdraw", savings_id), Some(json!({"amount": amount, "phone_number": phone}))),
+            (Method::GET, format!("/api/savings/{}/withdrawals", savings_id), None),
+            (Method::GET, format!("/api/payments/{}", payment_id), None),
+        ];
+        let send = |method: &Method, uri: &str, body: &Option<serde_json::Value>, token: &str| {
+            let request = test::TestRequest::default()
+                .method(method.clone())
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {}", token)));
+            match body {
+                Some(body) => request.set_json(body).to_request(),
+                None => request.to_request(),
+            }
+        };
+
+        for (method, uri, body) in &routes {
+            let response = test::call_service(&app, send(method, uri, body, stranger_token)).await;
+            assert!(
+                matches!(response.status(), StatusCode::FORBIDDEN | StatusCode::NOT_FOUND),
+                "{} {} answered a stranger with {}", method, uri, response.status()
+            );
+        }
+
+        // The lender is a party to the loan and may look at it, but not act on it
+        for (method, uri, body) in routes.iter().filter(|(_, uri, _)| uri.starts_with("/api/loans")) {
+            let response = test::call_service(&app, send(method, uri, body, lender_token)).await;
+            if *method == Method::GET {
+                assert_eq!(response.status(), StatusCode::OK, "{} {} refused the lender", method, uri);
+            } else {
+                assert!(
+                    matches!(response.status(), StatusCode::FORBIDDEN | StatusCode::NOT_FOUND),
+                    "{} {} let the lender act with {}", method, uri, response.status()
+                );
+            }
+        }
+
+        // Nothing was collected or paid out on the strangers' say-so
+        let (payments,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM payments WHERE user_id = ANY($1)")
+            .bind(&ids)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(payments, 1);
+
+        // Lists only ever hold the caller's own records
+        for uri in ["/api/savings", "/api/payments"] {
+            let response = test::call_service(&app, send(&Method::GET, uri, &None, stranger_token)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let listed: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(listed, json!([]), "{} lists another member's records", uri);
+        }
+
+        // The owner gets through, so the refusals above are not a broken setup
+        let response = test::call_service(&app, send(&Method::GET, &format!("/api/payments/{}", payment_id), &None, borrower_token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        mock.stop().await;
+    }
+
+    #[test]
+    fn test_refresh_token_hashing() {
+        use crate::services::sessions::SessionService;
+        use std::collections::HashSet;
+
+        let tokens: HashSet<String> = (0..100).map(|_| SessionService::new_refresh_token()).collect();
+        assert_eq!(tokens.len(), 100);
+        // 32 random bytes, URL-safe base64 without padding
+        assert!(tokens.iter().all(|t| t.len() == 43 && !t.contains(['+', '/', '='])));
+
+        let token = tokens.iter().next().unwrap();
+        let hash = SessionService::hash_token(token);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, SessionService::hash_token(token));
+        assert_ne!(&hash, token);
+        assert_eq!(
+            SessionService::hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_session_activity() {
+        use crate::models::{Session, SessionRevocation, SessionSummary};
+        use chrono::{Duration, Utc};
+        use uuid::Uuid;
+
+        let now = Utc::now();
+        let session = Session {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            user_agent: Some("Mozilla/5.0".to_string()),
+            ip_address: Some("196.201.214.200".to_string()),
+            created_at: Some(now - Duration::days(1)),
+            last_used_at: Some(now),
+            expires_at: now + Duration::days(29),
+            revoked_at: None,
+            revoked_reason: None,
+        };
+        assert!(session.is_active(now));
+        assert!(!session.is_active(now + Duration::days(29)));
+
+        let reused = Session {
+            revoked_at: Some(now),
+            revoked_reason: Some(SessionRevocation::RefreshTokenReuse),
+            ..session.clone()
+        };
+        assert!(!reused.is_active(now));
+
+        let json = serde_json::to_value(SessionSummary { session, current: true }).unwrap();
+        assert_eq!(json["current"], true);
+        assert_eq!(json["user_agent"], "Mozilla/5.0");
+        assert!(json["revoked_reason"].is_null());
+    }
+
+    #[test]
+    fn test_otp_codes() {
+        use crate::services::otp::{OtpService, CODE_LENGTH};
+        use std::collections::HashSet;
+
+        let codes: Vec<String> = (0..200).map(|_| OtpService::generate_code()).collect();
+        assert!(codes.iter().all(|c| c.len() == CODE_LENGTH && c.chars().all(|d| d.is_ascii_digit())));
+        assert!(codes.iter().collect::<HashSet<_>>().len() > 190);
+
+        // Stored salted, so the same code never hashes the same way twice
+        let hash = OtpService::hash_code("042917").unwrap();
+        assert_ne!(hash, OtpService::hash_code("042917").unwrap());
+        assert!(!hash.contains("042917"));
+        assert!(OtpService::code_matches("042917", &hash));
+        assert!(OtpService::code_matches(" 042917 ", &hash));
+        assert!(!OtpService::code_matches("042918", &hash));
+        assert!(!OtpService::code_matches("42917", &hash));
+        assert!(!OtpService::code_matches("042917", "not-a-hash"));
+    }
+
+    #[actix_web::test]
+    async fn test_file_sms_provider() {
+        use crate::models::User;
+        use crate::services::sms::{FileSms, SmsProvider};
+        use chrono::Utc;
+        use uuid::Uuid;
+
+        let path = std::env::temp_dir().join(format!("sms-{}.log", Uuid::new_v4()));
+        let sms = FileSms { path: path.clone() };
+        sms.send("254712345678", "Your code is 123456.\nDo not share it.").await.unwrap();
+        sms.send("254700000001", "Second").await.unwrap();
+
+        let outbox = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let lines: Vec<&str> = outbox.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("\t254712345678\tYour code is 123456. Do not share it."));
+        assert!(lines[1].contains("254700000001"));
+
+        // Only a verified number is used for M-Pesa by default
+        let mut user = User {
+            id: Uuid::new_v4(),
+            username: "wanjiku".to_string(),
+            email: None,
+            password_hash: None,
+            reputation_score: 0,
+            created_at: None,
+            phone_number: Some("254712345678".to_string()),
+            phone_verified_at: None,
+            totp_enabled_at: None,
+        };
+        assert_eq!(user.verified_phone(), None);
+        user.phone_verified_at = Some(Utc::now());
+        assert_eq!(user.verified_phone(), Some("254712345678"));
+    }
+
+    #[test]
+    fn test_totp_codes() {
+        use crate::services::two_factor::{TwoFactorService, TOTP_STEP_SECONDS};
+        use chrono::{TimeZone, Utc};
+
+        let secret = TwoFactorService::new_secret();
+        assert_eq!(secret.len(), 32);
+        let totp = TwoFactorService::totp(&secret, "wanjiku").unwrap();
+        assert!(totp.get_url().starts_with("otpauth://totp/MicroFund%20Africa:wanjiku?secret="));
+
+        let now = Utc.with_ymd_and_hms(2026, 1, 8, 12, 0, 10).unwrap();
+        let step = now.timestamp() / TOTP_STEP_SECONDS as i64;
+        let code = totp.generate(now.timestamp() as u64);
+        assert_eq!(TwoFactorService::matching_step(&totp, &code, now, None), Some(step));
+        assert_eq!(TwoFactorService::matching_step(&totp, &format!(" {} ", code), now, None), Some(step));
+
+        // A code from the previous step still works for a slow clock, but not two steps back
+        let previous = totp.generate(now.timestamp() as u64 - TOTP_STEP_SECONDS);
+        assert_eq!(TwoFactorService::matching_step(&totp, &previous, now, None), Some(step - 1));
+        let stale = totp.generate(now.timestamp() as u64 - 2 * TOTP_STEP_SECONDS);
+        assert_eq!(TwoFactorService::matching_step(&totp, &stale, now, None), None);
+
+        // A code already used cannot be replayed
+        assert_eq!(TwoFactorService::matching_step(&totp, &code, now, Some(step)), None);
+        assert_eq!(TwoFactorService::matching_step(&totp, &previous, now, Some(step - 1)), None);
+        assert_eq!(TwoFactorService::matching_step(&totp, "000000x", now, None), None);
+    }
+
+    /// Wrong codes given to turn two-factor off count towards the lockout like wrong codes at
+    /// login, so the settings cannot be used to guess codes. Needs a migrated database in `DATABASE_URL`.
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_two_factor_settings_count_wrong_codes() {
+        use crate::middleware::AppError;
+        use crate::services::two_factor::{TwoFactorService, TOTP_STEP_SECONDS};
+        use chrono::Utc;
+
+        let pool = test_db().await;
+        let member = insert_member(&pool, "guarded", None).await;
+        let secret = TwoFactorService::new_secret();
+        let (username,): (String,) = sqlx::query_as(
+            "UPDATE users SET totp_secret = $2, totp_enabled_at = NOW() WHERE id = $1 RETURNING username"
+        )
+        .bind(member)
+        .bind(&secret)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let totp = TwoFactorService::totp(&secret, &username).unwrap();
+        let now = Utc::now();
+        let wrong = totp.generate(now.timestamp() as u64 - 10 * TOTP_STEP_SECONDS);
+
+        for _ in 0..5 {
+            let refused = TwoFactorService::disable(&pool, member, &wrong, now).await;
+            assert!(matches!(refused, Err(AppError::BadRequest(_))));
+        }
+        let (failures,): (i32,) = sqlx::query_as("SELECT failed_logins FROM users WHERE id = $1")
+            .bind(member)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(failures, 5);
+
+        // Locked out now, so even the right code has to wait
+        let right = totp.generate(now.timestamp() as u64);
+        let locked = TwoFactorService::regenerate_recovery_codes(&pool, member, &right, now).await;
+        assert!(matches!(locked, Err(AppError::TooManyRequests { .. })));
+        let (enabled,): (bool,) = sqlx::query_as("SELECT totp_enabled_at IS NOT NULL FROM users WHERE id = $1")
+            .bind(member)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(enabled);
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        use crate::services::two_factor::{TwoFactorService, RECOVERY_CODE_COUNT};
+        use std::collections::HashSet;
+
+        let codes = TwoFactorService::new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(codes.iter().collect::<HashSet<_>>().len(), RECOVERY_CODE_COUNT);
+        for code in &codes {
+            let groups: Vec<&str> = code.split('-').collect();
+            assert_eq!(groups.len(), 4);
+            assert!(groups.iter().all(|g| g.len() == 4));
+            assert!(!code.contains(['0', 'o', '1', 'l', 'i']));
+        }
+
+        // Members may type codes back in capitals, without dashes or with spaces
+        let code = &codes[0];
+        let hash = TwoFactorService::hash_recovery_code(code);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(TwoFactorService::hash_recovery_code(&code.to_uppercase()), hash);
+        assert_eq!(TwoFactorService::hash_recovery_code(&code.replace('-', " ")), hash);
+        assert_ne!(TwoFactorService::hash_recovery_code(&codes[1]), hash);
+    }
+
+    #[test]
+    fn test_password_service() {
+        use crate::services::passwords::{PasswordResetConfig, PasswordService};
+
+        let hash = PasswordService::hash("secret123").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert_ne!(PasswordService::hash("secret123").unwrap(), hash, "hashes are salted");
+        assert!(PasswordService::matches("secret123", &hash));
+        assert!(!PasswordService::matches("secret124", &hash));
+        assert!(!PasswordService::matches("secret123", "not a hash"));
+
+        let config = PasswordResetConfig { reset_url: "https://app.microfund.africa/reset-password".to_string() };
+        assert_eq!(config.link("abc_123"), "https://app.microfund.africa/reset-password?token=abc_123");
+    }
+
+    #[actix_web::test]
+    async fn test_notifier_contacts() {
+        use crate::models::User;
+        use crate::services::email::FileEmail;
+        use crate::services::notifier::{Contact, Notifier};
+        use crate::services::sms::FileSms;
+        use chrono::Utc;
+        use std::sync::Arc;
+        use uuid::Uuid;
+
+        let mut user = User {
+            id: Uuid::new_v4(),
+            username: "wanjiku".to_string(),
+            email: Some("wanjiku@example.com".to_string()),
+            password_hash: None,
+            reputation_score: 0,
+            created_at: None,
+            phone_number: Some("254712345678".to_string()),
+            phone_verified_at: None,
+            totp_enabled_at: None,
+        };
+        // An unverified number is never messaged
+        assert_eq!(Contact::for_user(&user), Some(Contact::Email("wanjiku@example.com".to_string())));
+        user.phone_verified_at = Some(Utc::now());
+        assert_eq!(Contact::for_user(&user), Some(Contact::Sms("254712345678".to_string())));
+        user.phone_verified_at = None;
+        user.email = None;
+        assert_eq!(Contact::for_user(&user), None);
+
+        let sms_path = std::env::temp_dir().join(format!("sms-{}.log", Uuid::new_v4()));
+        let email_path = std::env::temp_dir().join(format!("email-{}.log", Uuid::new_v4()));
+        let notifier = Notifier {
+            sms: Arc::new(FileSms { path: sms_path.clone() }),
+            email: Arc::new(FileEmail { path: email_path.clone() }),
+        };
+        notifier.send(&Contact::Sms("254712345678".to_string()), "Subject", "By text").await.unwrap();
+        notifier.send(&Contact::Email("a@example.com".to_string()), "Reset", "By\nemail").await.unwrap();
+
+        let sms = std::fs::read_to_string(&sms_path).unwrap();
+        let email = std::fs::read_to_string(&email_path).unwrap();
+        std::fs::remove_file(&sms_path).ok();
+        std::fs::remove_file(&email_path).ok();
+        assert!(sms.ends_with("\t254712345678\tBy text\n"));
+        assert!(!sms.contains("Subject"));
+        assert!(email.ends_with("\ta@example.com\tReset\tBy email\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_token_bucket() {
+        use crate::middleware::rate_limit::{Bucket, Decision, MemoryStore, Quota, RateLimitPolicy, RateLimiter};
+        use chrono::{Duration, TimeZone, Utc};
+        use std::sync::Arc;
+        use uuid::Uuid;
+
+        let now = Utc.with_ymd_and_hms(2026, 3, 1, 10, 0, 0).unwrap();
+        let quota = Quota { burst: 2, period_seconds: 8 };
+        let mut bucket = Bucket::full(quota, now);
+        assert_eq!(bucket.take(quota, now), Decision::Allowed);
+        assert_eq!(bucket.take(quota, now), Decision::Allowed);
+        // One token comes back every 4 seconds
+        assert_eq!(bucket.take(quota, now + Duration::seconds(1)), Decision::Limited { retry_after: 3 });
+        assert_eq!(bucket.take(quota, now + Duration::seconds(4)), Decision::Allowed);
+
+        let limiter = RateLimiter::new(Arc::new(MemoryStore::default()));
+        let policy = RateLimitPolicy::new("money", Quota::per_minute(10)).per_account(Quota::per_minute(1));
+        let member = Uuid::new_v4();
+        assert!(limiter.check(&policy, Some("10.0.0.1"), Some(member), now).await.is_ok());
+        // The member is limited from any address; others on the same address are not
+        assert!(limiter.check(&policy, Some("10.0.0.2"), Some(member), now).await.is_err());
+        assert!(limiter.check(&policy, Some("10.0.0.1"), Some(Uuid::new_v4()), now).await.is_ok());
+    }
+
+    #[test]
+    fn test_login_lockout_durations() {
+        use crate::middleware::AppError;
+        use crate::services::lockout::{LoginLockout, FREE_ATTEMPTS};
+        use actix_web::http::StatusCode;
+        use actix_web::ResponseError;
+        use chrono::Duration;
+
+        assert_eq!(LoginLockout::lock_duration(FREE_ATTEMPTS - 1), None);
+        assert_eq!(LoginLockout::lock_duration(FREE_ATTEMPTS), Some(Duration::seconds(60)));
+        assert_eq!(LoginLockout::lock_duration(FREE_ATTEMPTS + 2), Some(Duration::seconds(240)));
+        assert_eq!(LoginLockout::lock_duration(1000), Some(Duration::seconds(3600)));
+
+        let response = AppError::TooManyRequests { retry_after: 60 }.error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "60");
+    }
+
+    #[test]
+    fn test_staff_permissions() {
+        use crate::middleware::auth::JwtConfig;
+        use crate::middleware::AppError;
+        use crate::models::{Permission, Role};
+        use chrono::{Duration, Utc};
+        use uuid::Uuid;
+
+        assert_eq!(Role::Admin.permissions(), &Permission::ALL);
+        assert!(Role::Member.permissions().is_empty());
+        assert!(Role::LoanOfficer.permissions().contains(&Permission::ReviewLoans));
+        assert!(!Role::Auditor.permissions().contains(&Permission::FreezeAccounts));
+        assert_eq!(Role::parse("loan_officer"), Some(Role::LoanOfficer));
+
+        let jwt = JwtConfig::new("0123456789abcdef0123456789abcdef", "microfund-africa", "microfund-api", "k1", Duration::hours(1)).unwrap();
+        let verify = |roles| jwt.verify(&jwt.issue(Uuid::new_v4(), Uuid::new_v4(), roles, Utc::now()).unwrap()).unwrap();
+        let auditor = verify(vec![Role::Member, Role::Auditor]);
+        assert!(auditor.require(Permission::ViewAuditLog).is_ok());
+        assert!(auditor.require(Permission::ViewLedger).is_ok());
+        assert!(!Role::LoanOfficer.permissions().contains(&Permission::ViewLedger));
+        // Staff are told they lack the permission; members never learn the route exists
+        assert!(matches!(auditor.require(Permission::ReverseTransactions), Err(AppError::Forbidden)));
+        assert!(matches!(verify(vec![Role::Member]).require(Permission::ViewMembers), Err(AppError::NotFound)));
+    }
+
+    #[test]
+    fn test_loan_approval_and_score_adjustment() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::loan_review::LoanReviewConfig;
+        use crate::services::scoring::{ScoringInputs, ScoringService, ScoringWeights};
+
+        let kes = |major| Money::from_major(major, PLATFORM_CURRENCY).unwrap();
+        let config = LoanReviewConfig { approval_above: Some(kes(500)) };
+        assert!(!config.requires_approval(kes(500)));
+        assert!(config.requires_approval(kes(501)));
+        assert!(!LoanReviewConfig::default().requires_approval(kes(1_000_000)));
+
+        let weights = ScoringWeights::default();
+        let base = ScoringService::compute(&ScoringInputs::default(), &weights).unwrap();
+        let raised = ScoringService::compute(&ScoringInputs { manual_adjustment: 150, ..ScoringInputs::default() }, &weights).unwrap();
+        assert_eq!(raised.adjustment, 150);
+        assert_eq!(raised.score, base.score + 150);
+        let sunk = ScoringService::compute(&ScoringInputs { manual_adjustment: -5000, ..ScoringInputs::default() }, &weights).unwrap();
+        assert_eq!(sunk.score, 0);
+    }
+
+    #[test]
+    fn test_ledger_chain_verification() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::ledger_chain::{ChainEntry, ChainProblem, ChainWalk, LedgerSigner, GENESIS_HASH};
+        use chrono::{Duration, TimeZone, Utc};
+        use uuid::Uuid;
+
+        let signer = LedgerSigner::from_seed_hex(&"11".repeat(32)).unwrap();
+        let started = Utc.with_ymd_and_hms(2026, 3, 1, 10, 0, 0).unwrap();
+        let mut chain: Vec<ChainEntry> = Vec::new();
+        for number in 1..=3 {
+            let mut entry = ChainEntry {
+                id: Uuid::new_v4(),
+                sequence_number: number,
+                activity_type: "REPAYMENT".to_string(),
+                description: format!("Repayment {}", number),
+                amount: Money::new(1_000 * number, PLATFORM_CURRENCY),
+                created_at: started + Duration::minutes(number),
+                prev_hash: chain.last().map_or(GENESIS_HASH.to_string(), |e| e.entry_hash.clone()),
+                entry_hash: String::new(),
+                signature: String::new(),
+            };
+            signer.seal(&mut entry);
+            chain.push(entry);
+        }
+        let first_break = |entries: &[ChainEntry]| {
+            let mut walk = ChainWalk::new(signer.verifying_key());
+            entries.iter().all(|entry| walk.check(entry));
+            walk.report(0).first_break.map(|b| (b.sequence_number, b.problem))
+        };
+
+        let report = {
+            let mut walk = ChainWalk::new(signer.verifying_key());
+            assert!(chain.iter().all(|entry| walk.check(entry)));
+            walk.report(2)
+        };
+        assert!(report.valid);
+        assert_eq!(report.head_hash.as_deref(), Some(chain[2].entry_hash.as_str()));
+
+        let mut altered = chain.clone();
+        altered[1].amount = Money::new(1, PLATFORM_CURRENCY);
+        assert_eq!(first_break(&altered), Some((2, ChainProblem::Altered)));
+
+        // Re-hashing an altered entry does not help without the key, and breaks the next link
+        let mut resealed = altered.clone();
+        resealed[1].entry_hash = resealed[1].compute_hash();
+        assert_eq!(first_break(&resealed), Some((2, ChainProblem::BadSignature)));
+        let forger = LedgerSigner::from_seed_hex(&"22".repeat(32)).unwrap();
+        forger.seal(&mut resealed[1]);
+        assert_eq!(first_break(&resealed), Some((2, ChainProblem::BadSignature)));
+
+        let removed = [chain[0].clone(), chain[2].clone()];
+        assert_eq!(first_break(&removed), Some((3, ChainProblem::Gap)));
+        let mut relinked = chain.clone();
+        relinked[2].prev_hash = chain[0].entry_hash.clone();
+        assert_eq!(first_break(&relinked), Some((3, ChainProblem::BrokenLink)));
+    }
+
+    #[test]
+    fn test_ledger_keys() {
+        use crate::services::ledger_chain::{parse_verifying_key, LedgerSigner};
+
+        let signer = LedgerSigner::from_seed_hex(&"11".repeat(32)).unwrap();
+        let public_key = hex::encode(signer.verifying_key().as_bytes());
+        assert_eq!(parse_verifying_key(&public_key).unwrap(), signer.verifying_key());
+        assert!(LedgerSigner::from_seed_hex("abcd").is_err());
+        assert!(LedgerSigner::from_seed_hex(&"zz".repeat(32)).is_err());
+        assert!(parse_verifying_key(&"11".repeat(31)).is_err());
+    }
+
+    #[test]
+    fn test_merkle_inclusion_proofs() {
+        use crate::services::merkle::{inclusion_proof, leaf_hash, root, verify_inclusion, Hash};
+
+        // RFC 6962 values: the empty tree and the leaf hash of empty data
+        assert_eq!(hex::encode(root(&[])), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex::encode(leaf_hash(b"")), "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d");
+
+        for size in 1..=17u8 {
+            let leaves: Vec<Hash> = (0..size).map(|i| leaf_hash(&[i])).collect();
+            let tree_root = root(&leaves);
+            for index in 0..leaves.len() {
+                let path = inclusion_proof(&leaves, index);
+                assert!(verify_inclusion(&leaves[index], index as u64, size as u64, &path, &tree_root), "{} of {}", index, size);
+                if size > 1 {
+                    let other = (index + 1) % leaves.len();
+                    assert!(!verify_inclusion(&leaves[other], index as u64, size as u64, &path, &tree_root));
+                    assert!(!verify_inclusion(&leaves[index], index as u64, size as u64, &path, &root(&leaves[1..])));
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_checkpoint_proof_and_file_anchor() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::anchor::{Anchor, FileAnchor};
+        use crate::services::checkpoints::{InclusionProof, LedgerCheckpoint};
+        use crate::services::ledger_chain::{ChainEntry, LedgerSigner, GENESIS_HASH};
+        use crate::services::merkle::{inclusion_proof, leaf_hash, root, Hash};
+        use chrono::{TimeZone, Utc};
+        use uuid::Uuid;
+
+        let signer = LedgerSigner::from_seed_hex(&"33".repeat(32)).unwrap();
+        let created_at = Utc.with_ymd_and_hms(2026, 3, 1, 10, 0, 0).unwrap();
+        let mut entries: Vec<ChainEntry> = Vec::new();
+        for number in 1..=5 {
+            let mut entry = ChainEntry {
+                id: Uuid::new_v4(),
+                sequence_number: number,
+                activity_type: "SAVINGS_DEPOSIT".to_string(),
+                description: "Deposit to savings goal".to_string(),
+                amount: Money::new(500, PLATFORM_CURRENCY),
+                created_at,
+                prev_hash: entries.last().map_or(GENESIS_HASH.to_string(), |e| e.entry_hash.clone()),
+                entry_hash: String::new(),
+                signature: String::new(),
+            };
+            signer.seal(&mut entry);
+            entries.push(entry);
+        }
+        let leaves: Vec<Hash> = entries.iter().map(|e| leaf_hash(&hex::decode(&e.entry_hash).unwrap())).collect();
+        let merkle_root = hex::encode(root(&leaves));
+        let checkpoint = LedgerCheckpoint {
+            id: Uuid::new_v4(),
+            first_sequence_number: 1,
+            last_sequence_number: 5,
+            signature: signer.sign(LedgerCheckpoint::signed_message(1, 5, &merkle_root).as_bytes()),
+            merkle_root,
+            created_at,
+            anchor_backend: None,
+            anchor_reference: None,
+            anchored_at: None,
+        };
+        let mut proof = InclusionProof {
+            entry: entries[3].clone(),
+            leaf_index: 3,
+            leaf_hash: hex::encode(leaves[3]),
+            path: inclusion_proof(&leaves, 3).iter().map(hex::encode).collect(),
+            checkpoint: checkpoint.clone(),
+            public_key: hex::encode(signer.verifying_key().as_bytes()),
+        };
+        assert!(proof.verify(&signer.verifying_key()));
+        assert!(!proof.verify(&LedgerSigner::from_seed_hex(&"44".repeat(32)).unwrap().verifying_key()));
+        proof.entry.description = "Deposit to someone else".to_string();
+        assert!(!proof.verify(&signer.verifying_key()));
+
+        let path = std::env::temp_dir().join(format!("anchors-{}.log", Uuid::new_v4()));
+        let anchor = FileAnchor { path: path.clone() };
+        let reference = anchor.publish(&checkpoint).await.unwrap();
+        let published: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&path).unwrap().trim()).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!(reference.ends_with("#5"));
+        assert_eq!(published["merkle_root"], checkpoint.merkle_root.as_str());
+    }
+
+    #[test]
+    fn test_solana_wire_format() {
+        use crate::services::solana::{anchor_discriminator, build_transaction, encode_length, loan_account_repaid, Instruction, AccountMeta, Pubkey, SolanaConfig, MICROFUND_PROGRAM_ID};
+        use curve25519_dalek::edwards::CompressedEdwardsY;
+        use ed25519_dalek::{Signature, SigningKey, Verifier};
+
+        // The discriminators Anchor generates for the program in contracts/
+        assert_eq!(anchor_discriminator("initialize_loan"), [235, 149, 178, 147, 146, 178, 207, 151]);
+        assert_eq!(anchor_discriminator("repay_loan"), [224, 93, 144, 77, 61, 17, 137, 54]);
+        assert_eq!(anchor_discriminator("anchor_checkpoint"), [92, 11, 134, 251, 148, 216, 238, 55]);
+        assert_eq!(Pubkey::SYSTEM_PROGRAM.to_string(), "11111111111111111111111111111111");
+        let program: Pubkey = MICROFUND_PROGRAM_ID.parse().unwrap();
+        assert_eq!(program.to_string(), MICROFUND_PROGRAM_ID);
+        assert!("not-base58!".parse::<Pubkey>().is_err());
+
+        for (len, encoded) in [(0, vec![0]), (127, vec![0x7f]), (128, vec![0x80, 0x01]), (16384, vec![0x80, 0x80, 0x01])] {
+            let mut out = Vec::new();
+            encode_length(&mut out, len);
+            assert_eq!(out, encoded);
+        }
+
+        // Program addresses are deterministic and never a point with a private key
+        let payer = SigningKey::from_bytes(&[7; 32]);
+        let loan_id = uuid::Uuid::new_v4();
+        let (address, bump) = Pubkey::find_program_address(&[b"loan", payer.verifying_key().as_bytes(), loan_id.as_bytes()], &program);
+        assert_eq!(Pubkey::find_program_address(&[b"loan", payer.verifying_key().as_bytes(), loan_id.as_bytes()], &program), (address, bump));
+        assert!(CompressedEdwardsY(address.0).decompress().is_none());
+
+        // Payer first, the writable loan account next, read-only programs last
+        let instruction = Instruction::anchor(program, "repay_loan", &[], vec![
+            AccountMeta { pubkey: address, is_signer: false, is_writable: true },
+            AccountMeta { pubkey: Pubkey(payer.verifying_key().to_bytes()), is_signer: true, is_writable: false },
+        ]);
+        let (transaction, signature) = build_transaction(&payer, &[instruction], [9; 32]).unwrap();
+        let message = &transaction[65..];
+        assert_eq!(&message[..4], &[1, 0, 1, 3]);
+        assert_eq!(&message[4..36], payer.verifying_key().as_bytes());
+        assert_eq!(&message[36..68], &address.0);
+        assert_eq!(&message[68..100], &program.0);
+        assert_eq!(&message[132..], &[1, 2, 2, 1, 0, 8, 224, 93, 144, 77, 61, 17, 137, 54]);
+        let signature = Signature::from_slice(&bs58::decode(signature).into_vec().unwrap()).unwrap();
+        assert!(payer.verifying_key().verify(message, &signature).is_ok());
+
+        // A loan account: discriminator, borrower, amount, currency, description, then the flag
+        let mut data = vec![0u8; 8 + 32 + 8 + 3];
+        data.extend_from_slice(&5u32.to_le_bytes());
+        data.extend_from_slice(b"Stock");
+        data.push(1);
+        assert_eq!(loan_account_repaid(&data), Some(true));
+        assert_eq!(loan_account_repaid(&data[..20]), None);
+
+        let keypair = format!("{:?}", payer.to_keypair_bytes().to_vec());
+        assert_eq!(SolanaConfig::parse_keypair(&keypair).unwrap().verifying_key(), payer.verifying_key());
+        assert!(SolanaConfig::parse_keypair("[1, 2, 3]").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_solana_client_against_mock() {
+        use crate::models::{Money, PLATFORM_CURRENCY};
+        use crate::services::blockchain::BlockchainService;
+        use crate::services::solana::{loan_account_repaid, SolanaError};
+        use crate::services::solana_mock::MockValidator;
+        use chrono::Duration;
+        use uuid::Uuid;
+
+        let mock = MockValidator::start().await.unwrap();
+        let client = mock.client();
+        let loan_id = Uuid::new_v4();
+        let amount = Money::from_major(2_500, PLATFORM_CURRENCY).unwrap();
+
+        let recorded = client.initialize_loan(loan_id, amount, &"Sewing machine ".repeat(20)).await.unwrap();
+        assert_eq!(recorded.address, client.loan_address(loan_id));
+        let account = mock.account(&recorded.address).unwrap();
+        assert_eq!(u64::from_le_bytes(account[40..48].try_into().unwrap()), 250_000);
+        assert_eq!(&account[48..51], b"KES");
+        assert_eq!(loan_account_repaid(&account), Some(false));
+
+        // A retry finds the loan already recorded and sends nothing
+        assert_eq!(client.initialize_loan(loan_id, amount, "Sewing machine").await.unwrap(), recorded);
+        assert_eq!(mock.transactions().len(), 1);
+
+        let repaid = client.repay_loan(loan_id).await.unwrap();
+        assert_eq!(loan_account_repaid(&mock.account(&recorded.address).unwrap()), Some(true));
+        assert_eq!(client.repay_loan(loan_id).await.unwrap(), repaid);
+        assert!(client.repay_loan(Uuid::new_v4()).await.is_err());
+
+        let anchored = client.anchor_checkpoint(1, 5, [3; 32]).await.unwrap();
+        assert_eq!(client.anchor_checkpoint(1, 5, [3; 32]).await.unwrap(), anchored);
+        let instructions: Vec<String> = mock.transactions().into_iter().map(|t| t.instruction).collect();
+        assert_eq!(instructions, ["initialize_loan", "repay_loan", "anchor_checkpoint"]);
+
+        // The program rejects what it would reject on a real cluster
+        let error = client.initialize_loan(Uuid::new_v4(), Money::new(0, PLATFORM_CURRENCY), "Nothing").await.unwrap_err();
+        assert!(matches!(error, SolanaError::InvalidRequest(_)));
+        let error = client.anchor_checkpoint(0, 6, [3; 32]).await.unwrap_err();
+        assert!(matches!(error, SolanaError::Rpc { code: -32002, .. }));
+
+        mock.set_unavailable_for(1);
+        let error = client.initialize_loan(Uuid::new_v4(), amount, "Sewing machine").await.unwrap_err();
+        assert!(matches!(error, SolanaError::Transport(_)));
+
+        assert_eq!(BlockchainService::retry_delay(1), Duration::seconds(30));
+        assert_eq!(BlockchainService::retry_delay(3), Duration::minutes(2));
+        assert_eq!(BlockchainService::retry_delay(20), Duration::hours(1));
+        mock.stop().await;
+    }
+
+    #[test]
+    fn test_outbox_events_and_backoff() {
+        use crate::models::{OutboxStatus, Permission, Role};
+        use crate::services::outbox::{Outbox, OutboxEvent, MAX_ATTEMPTS};
+        use chrono::Duration;
+        use uuid::Uuid;
+
+        // Stored as tagged JSON, so the payload says what it is on its own
+        let loan_id = Uuid::new_v4();
+        let payout = OutboxEvent::LoanPayout { loan_id };
+        let stored = serde_json::to_value(&payout).unwrap();
+        assert_eq!(stored, serde_json::json!({ "type": "loan_payout", "loan_id": loan_id }));
+        assert_eq!(serde_json::from_value::<OutboxEvent>(stored).unwrap(), payout);
+        let notice: OutboxEvent = serde_json::from_str(&format!(
+            r#"{{"type":"notify_member","user_id":"{}","subject":"Deposit received","body":"Thanks"}}"#,
+            Uuid::new_v4()
+        ))
+        .unwrap();
+        assert_eq!(notice.event_type(), "notify_member");
+        assert!(serde_json::from_str::<OutboxEvent>(r#"{"type":"launch_rockets"}"#).is_err());
+
+        assert_eq!(Outbox::retry_delay(1), Some(Duration::seconds(30)));
+        assert_eq!(Outbox::retry_delay(4), Some(Duration::minutes(4)));
+        assert_eq!(Outbox::retry_delay(MAX_ATTEMPTS - 1), Some(Duration::hours(1)));
+        assert_eq!(Outbox::retry_delay(MAX_ATTEMPTS), None);
+
+        assert_eq!(OutboxStatus::parse("dead"), Some(OutboxStatus::Dead));
+        assert_eq!(OutboxStatus::parse("lost"), None);
+        assert!(Role::Admin.permissions().contains(&Permission::RedeliverEvents));
+        assert!(!Role::Auditor.permissions().contains(&Permission::RedeliverEvents));
+    }
+
+    #[actix_web::test]
+    async fn test_idempotency_keys() {
+        use crate::middleware::idempotency::{fingerprint, is_replayable, Idempotency, IDEMPOTENCY_KEY};
+        use crate::middleware::AppError;
+        use actix_web::http::StatusCode;
+        use actix_web::{test, web, App, HttpResponse, ResponseError};
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+
+        // The same request always hashes the same; another body or path does not
+        let deposit = fingerprint("POST", "/api/savings/1/deposit", br#"{"amount":500}"#);
+        assert_eq!(deposit.len(), 64);
+        assert_eq!(deposit, fingerprint("POST", "/api/savings/1/deposit", br#"{"amount":500}"#));
+        assert_ne!(deposit, fingerprint("POST", "/api/savings/1/deposit", br#"{"amount":5000}"#));
+        assert_ne!(deposit, fingerprint("POST", "/api/savings/2/deposit", br#"{"amount":500}"#));
+        assert_eq!(
+            AppError::UnprocessableEntity("reused".to_string()).error_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        // Answers to the request itself are replayed; being throttled or crossing another request is not
+        for status in [StatusCode::OK, StatusCode::CREATED, StatusCode::BAD_REQUEST, StatusCode::FORBIDDEN, StatusCode::NOT_FOUND] {
+            assert!(is_replayable(status), "{}", status);
+        }
+        for status in [StatusCode::TOO_MANY_REQUESTS, StatusCode::CONFLICT, StatusCode::INTERNAL_SERVER_ERROR, StatusCode::BAD_GATEWAY] {
+            assert!(!is_replayable(status), "{}", status);
+        }
+
+        // Without a key, or from an anonymous caller, requests go straight through
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let app = test::init_service(App::new().wrap(Idempotency).route(
+            "/deposit",
+            web::post().to(move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    HttpResponse::Ok().finish()
+                }
+            }),
+        ))
+        .await;
+        for key in [None, Some("7d0f1c2e-retry")] {
+            let mut req = test::TestRequest::post().uri("/deposit");
+            if let Some(key) = key {
+                req = req.insert_header((IDEMPOTENCY_KEY, key));
+            }
+            assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::OK);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // A blank or oversized key is refused before anything runs
+        for key in [" ".to_string(), "k".repeat(256)] {
+            let req = test::TestRequest::post().uri("/deposit").insert_header((IDEMPOTENCY_KEY, key)).to_request();
+            let err = test::try_call_service(&app, req).await.unwrap_err();
+            assert_eq!(err.error_response().status(), StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_idempotency_key_lease() {
+        use crate::middleware::idempotency::{Claim, IdempotencyStore, StoredResponse};
+        use chrono::{Duration, Utc};
+
+        let pool = test_db().await;
+        let user_id = insert_member(&pool, "retry", None).await;
+        let now = Utc::now();
+
+        let Claim::New(first) = IdempotencyStore::claim(&pool, user_id, "k1", "a", now).await.unwrap() else {
+            panic!("a new key is claimed");
+        };
+        // While the first request holds the key, a retry waits and another request is refused
+        let soon = now + Duration::seconds(30);
+        assert_eq!(IdempotencyStore::claim(&pool, user_id, "k1", "a", soon).await.unwrap(), Claim::InProgress);
+        assert_eq!(IdempotencyStore::claim(&pool, user_id, "k1", "b", soon).await.unwrap(), Claim::Mismatch);
+
+        // The first request died: once its lease runs out, a retry takes the key over, and only one does
+        let later = now + Duration::minutes(5);
+        assert_eq!(IdempotencyStore::claim(&pool, user_id, "k1", "b", later).await.unwrap(), Claim::Mismatch);
+        let Claim::New(second) = IdempotencyStore::claim(&pool, user_id, "k1", "a", later).await.unwrap() else {
+            panic!("a retry takes over a key whose lease ran out");
+        };
+        assert_eq!(IdempotencyStore::claim(&pool, user_id, "k1", "a", later).await.unwrap(), Claim::InProgress);
+
+        // The first request turns out to be alive after all; it can neither answer nor free the key it lost
+        let response = StoredResponse { status: 200, content_type: None, body: b"{}".to_vec() };
+        let stale = StoredResponse { status: 201, content_type: None, body: b"late".to_vec() };
+        assert!(!IdempotencyStore::complete(&pool, user_id, "k1", first, &stale, later).await.unwrap());
+        IdempotencyStore::release(&pool, user_id, "k1", first).await.unwrap();
+        assert_eq!(IdempotencyStore::claim(&pool, user_id, "k1", "a", later).await.unwrap(), Claim::InProgress);
+
+        // An answered key is replayed however long ago it was answered
+        assert!(IdempotencyStore::complete(&pool, user_id, "k1", second, &response, later).await.unwrap());
+        let much_later = now + Duration::hours(2);
+        assert_eq!(IdempotencyStore::claim(&pool, user_id, "k1", "a", much_later).await.unwrap(), Claim::Completed(response));
+    }
+}
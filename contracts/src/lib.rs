@@ -3,21 +3,39 @@ use anchor_lang::prelude::*;
 // This is a unique identifier for the MicroFund smart contract on the Solana blockchain.
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
+/// Longest loan description stored on-chain, in bytes.
+pub const MAX_DESCRIPTION_LEN: usize = 196;
+
 #[program]
 pub mod microfund {
     use super::*;
 
     /// Initializes a new microloan on the blockchain.
     /// This provides a transparent, immutable record of the debt obligation.
-    pub fn initialize_loan(ctx: Context<InitializeLoan>, amount: u64, description: String) -> Result<()> {
+    /// `amount` is in minor units of `currency` (e.g. KES cents), matching the backend's `Money`.
+    /// `loan_id` is the backend's loan UUID; the account address derives from it, so each loan
+    /// is recorded at most once however often the backend retries.
+    pub fn initialize_loan(
+        ctx: Context<InitializeLoan>,
+        loan_id: [u8; 16],
+        amount: u64,
+        currency: [u8; 3],
+        description: String,
+    ) -> Result<()> {
+        require!(amount > 0, LoanError::InvalidAmount);
+        require!(currency.iter().all(|c| c.is_ascii_uppercase()), LoanError::InvalidCurrency);
+        require!(description.len() <= MAX_DESCRIPTION_LEN, LoanError::DescriptionTooLong);
+
         let loan = &mut ctx.accounts.loan;
         loan.borrower = *ctx.accounts.borrower.key;
         loan.amount = amount;
+        loan.currency = currency;
         loan.description = description;
         loan.repaid = false;
         loan.created_at = Clock::get()?.unix_timestamp;
+        loan.loan_id = loan_id;
         
-        msg!("Loan initialized for amount: {}", amount);
+        msg!("Loan initialized for amount: {} minor units", amount);
         Ok(())
     }
 
@@ -34,14 +52,48 @@ pub mod microfund {
         msg!("Loan repaid successfully");
         Ok(())
     }
+
+    /// Publishes the Merkle root of a run of platform ledger entries, numbered
+    /// `first_sequence_number` to `last_sequence_number`, so anyone can check an entry's
+    /// inclusion proof against the chain. One account per run; roots cannot be overwritten.
+    pub fn anchor_checkpoint(
+        ctx: Context<AnchorCheckpoint>,
+        first_sequence_number: u64,
+        last_sequence_number: u64,
+        merkle_root: [u8; 32],
+    ) -> Result<()> {
+        require!(
+            first_sequence_number > 0 && last_sequence_number >= first_sequence_number,
+            CheckpointError::InvalidRange
+        );
+
+        let checkpoint = &mut ctx.accounts.checkpoint;
+        checkpoint.authority = *ctx.accounts.authority.key;
+        checkpoint.first_sequence_number = first_sequence_number;
+        checkpoint.last_sequence_number = last_sequence_number;
+        checkpoint.merkle_root = merkle_root;
+        checkpoint.anchored_at = Clock::get()?.unix_timestamp;
+
+        msg!("Ledger checkpoint #{}-#{} anchored", first_sequence_number, last_sequence_number);
+        Ok(())
+    }
 }
 
 /// Accounts required for the 'initialize_loan' instruction.
 #[derive(Accounts)]
+#[instruction(loan_id: [u8; 16])]
 pub struct InitializeLoan<'info> {
-    // We initialize a new account for each loan. 
+    // We initialize a new account for each loan, at an address derived from the borrower
+    // and the backend's loan id. Members hold no Solana keys, so the platform key signs
+    // as borrower of record.
     // Space is calculated based on the fields in the LoanAccount struct.
-    #[account(init, payer = borrower, space = 8 + 32 + 8 + 200 + 1 + 8 + 8)]
+    #[account(
+        init,
+        payer = borrower,
+        space = 8 + 32 + 8 + 3 + (4 + MAX_DESCRIPTION_LEN) + 1 + 8 + 8 + 16,
+        seeds = [b"loan", borrower.key().as_ref(), &loan_id],
+        bump
+    )]
     pub loan: Account<'info, LoanAccount>,
     #[account(mut)]
     pub borrower: Signer<'info>,
@@ -57,19 +109,62 @@ pub struct RepayLoan<'info> {
     pub borrower: Signer<'info>,
 }
 
+/// Accounts required for the 'anchor_checkpoint' instruction.
+#[derive(Accounts)]
+#[instruction(first_sequence_number: u64, last_sequence_number: u64)]
+pub struct AnchorCheckpoint<'info> {
+    // Derived from the platform authority and the last entry number, so each run
+    // is anchored exactly once and can be looked up without an index.
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + 32 + 8 + 8 + 32 + 8,
+        seeds = [b"checkpoint", authority.key().as_ref(), &last_sequence_number.to_le_bytes()],
+        bump
+    )]
+    pub checkpoint: Account<'info, LedgerCheckpoint>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 /// Data structure for storing loan information on-chain.
 #[account]
 pub struct LoanAccount {
     pub borrower: Pubkey,    // Public key of the user who took the loan
-    pub amount: u64,          // Amount borrowed in lamports (or simulated units)
+    pub amount: u64,          // Amount borrowed in minor units of `currency` (e.g. KES cents)
+    pub currency: [u8; 3],    // ISO 4217 code, e.g. b"KES"
     pub description: String,  // Metadata about the loan's purpose
     pub repaid: bool,         // Repayment status
     pub created_at: i64,      // Timestamp of loan creation
     pub repaid_at: i64,       // Timestamp of loan repayment
+    pub loan_id: [u8; 16],    // The backend's loan UUID
+}
+
+/// Merkle root of a run of platform ledger entries, as published by the backend.
+#[account]
+pub struct LedgerCheckpoint {
+    pub authority: Pubkey,          // Platform key that published the root
+    pub first_sequence_number: u64, // First ledger entry under the root
+    pub last_sequence_number: u64,  // Last ledger entry under the root
+    pub merkle_root: [u8; 32],      // RFC 6962 Merkle root of the entries' hashes
+    pub anchored_at: i64,           // Timestamp of publication
 }
 
 #[error_code]
 pub enum LoanError {
     #[msg("This loan has already been repaid.")]
     AlreadyRepaid,
+    #[msg("Loan amount must be greater than zero.")]
+    InvalidAmount,
+    #[msg("Currency must be a three-letter ISO 4217 code.")]
+    InvalidCurrency,
+    #[msg("Loan description is too long.")]
+    DescriptionTooLong,
+}
+
+#[error_code]
+pub enum CheckpointError {
+    #[msg("The checkpoint must cover at least one entry, numbered from 1.")]
+    InvalidRange,
 }
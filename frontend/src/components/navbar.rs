@@ -1,7 +1,8 @@
 use yew::prelude::*;
 use yew_router::prelude::*;
 use crate::Route;
-use crate::services::storage::{get_token, remove_token};
+use crate::services::api;
+use crate::services::storage::get_token;
 
 use crate::app_context::{AppContext, Theme};
 use crate::utils::i18n::{Language, t};
@@ -15,8 +16,11 @@ pub fn navbar() -> Html {
     let logout = {
         let navigator = navigator.clone();
         Callback::from(move |_| {
-            remove_token();
-            navigator.push(&Route::Home);
+            let navigator = navigator.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                api::logout().await;
+                navigator.push(&Route::Home);
+            });
         })
     };
 
@@ -59,6 +63,7 @@ pub fn navbar() -> Html {
                     html! {
                         <>
                             <Link<Route> to={Route::Dashboard} style="color: white; text-decoration: none;">{ t("dashboard", &context.lang) }</Link<Route>>
+                            <Link<Route> to={Route::Security} style="color: white; text-decoration: none;">{ t("security", &context.lang) }</Link<Route>>
                             <button onclick={logout} style="background: none; border: 1px solid white; color: white; padding: 0.3rem 0.8rem; border-radius: 4px; cursor: pointer;">{ t("logout", &context.lang) }</button>
                         </>
                     }
@@ -13,6 +13,10 @@ use pages::home::Home;
 use pages::login::Login;
 use pages::register::Register;
 use pages::dashboard::Dashboard;
+use pages::forgot_password::ForgotPassword;
+use pages::reset_password::ResetPassword;
+use pages::security::Security;
+use pages::two_factor_login::TwoFactorLogin;
 
 #[derive(Clone, Routable, PartialEq)]
 enum Route {
@@ -20,10 +24,18 @@ enum Route {
     Home,
     #[at("/login")]
     Login,
+    #[at("/login/2fa")]
+    TwoFactorLogin,
+    #[at("/password/forgot")]
+    ForgotPassword,
+    #[at("/reset-password")]
+    ResetPassword,
     #[at("/register")]
     Register,
     #[at("/dashboard")]
     Dashboard,
+    #[at("/security")]
+    Security,
     #[not_found]
     #[at("/404")]
     NotFound,
@@ -33,8 +45,12 @@ fn switch(routes: Route) -> Html {
     match routes {
         Route::Home => html! { <Home /> },
         Route::Login => html! { <Login /> },
+        Route::TwoFactorLogin => html! { <TwoFactorLogin /> },
+        Route::ForgotPassword => html! { <ForgotPassword /> },
+        Route::ResetPassword => html! { <ResetPassword /> },
         Route::Register => html! { <Register /> },
         Route::Dashboard => html! { <Dashboard /> },
+        Route::Security => html! { <Security /> },
         Route::NotFound => html! { <h1>{ "404 - Not Found" }</h1> },
     }
 }
@@ -6,11 +6,12 @@ use uuid::Uuid;
 use crate::app_context::AppContext;
 use crate::utils::i18n::t;
 use crate::components::notifications::NotificationType;
+use microfund_shared::{Money, PLATFORM_CURRENCY};
 
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct Loan {
     pub id: Uuid,
-    pub amount: f64,
+    pub amount: Money,
     pub status: String,
     pub description: Option<String>,
 }
@@ -21,40 +22,67 @@ pub struct UserProfile {
     pub reputation_score: i32,
 }
 
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct CreditScore {
+    pub score: i32,
+    pub max_score: i32,
+    pub credit_limit: Money,
+    pub summary: String,
+}
+
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct MarketplaceLoan {
     pub id: Uuid,
     pub borrower_username: String,
-    pub amount: f64,
+    pub amount: Money,
     pub description: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct Savings {
     pub id: Uuid,
-    pub amount: f64,
+    pub amount: Money,
     pub goal_name: Option<String>,
+    #[serde(default)]
+    pub target_amount: Option<Money>,
+    #[serde(default)]
+    pub progress_percent: Option<u8>,
+    #[serde(default)]
+    pub locked_until: Option<String>,
+    #[serde(default)]
+    pub locked: bool,
 }
 
 #[derive(Serialize)]
-struct CreateLoanRequest { amount: f64, description: String }
+struct CreateLoanRequest { amount: Money, description: String, phone_number: Option<String> }
 
 #[derive(Serialize)]
-struct CreateSavingsRequest { goal_name: String }
+struct CreateSavingsRequest { goal_name: String, target_amount: Option<Money> }
 
 #[derive(Serialize)]
-struct DepositRequest { amount: f64, phone_number: Option<String> }
+struct DepositRequest { amount: Money, phone_number: Option<String> }
 
 #[derive(Serialize)]
-struct RepayRequest { loan_id: Uuid }
+struct WithdrawRequest { amount: Money, phone_number: Option<String> }
+
+/// A deposit waiting for the customer to approve the M-Pesa prompt.
+#[derive(Deserialize)]
+struct PendingPayment { status: String }
+
+#[derive(Serialize)]
+struct RepayRequest { loan_id: Uuid, phone_number: Option<String> }
+
+#[derive(Serialize)]
+struct FundRequest { phone_number: Option<String> }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct PlatformTransaction {
     pub id: Uuid,
+    pub sequence_number: Option<i64>,
     pub activity_type: String,
     pub description: String,
-    pub amount: f64,
-    pub signature: String,
+    pub amount: Money,
+    pub signature: Option<String>,
 }
 
 #[function_component(Dashboard)]
@@ -64,11 +92,14 @@ pub fn dashboard() -> Html {
     let marketplace = use_state(|| Vec::<MarketplaceLoan>::new());
     let savings = use_state(|| get_cache::<Vec<Savings>>("cache_savings").unwrap_or_default());
     let ledger = use_state(|| Vec::<PlatformTransaction>::new());
-    let profile = use_state(|| get_cache::<UserProfile>("cache_profile").unwrap_or(UserProfile { username: "".to_string(), reputation_score: 100 }));
+    let profile = use_state(|| get_cache::<UserProfile>("cache_profile").unwrap_or(UserProfile { username: "".to_string(), reputation_score: 0 }));
+    let credit = use_state(|| get_cache::<CreditScore>("cache_credit_score"));
     
-    let loan_amount = use_state(|| 0.0);
+    let loan_amount = use_state(|| "".to_string());
     let loan_desc = use_state(|| "".to_string());
+    let loan_phone = use_state(|| "".to_string());
     let savings_goal = use_state(|| "".to_string());
+    let savings_target = use_state(|| "".to_string());
     let phone_number = use_state(|| "".to_string());
 
     let fetch_data = {
@@ -77,12 +108,14 @@ pub fn dashboard() -> Html {
         let marketplace = marketplace.clone();
         let ledger = ledger.clone();
         let profile = profile.clone();
+        let credit = credit.clone();
         Callback::from(move |_| {
             let loans = loans.clone();
             let savings = savings.clone();
             let marketplace = marketplace.clone();
             let ledger = ledger.clone();
             let profile = profile.clone();
+            let credit = credit.clone();
             wasm_bindgen_futures::spawn_local(async move {
                 if let Ok(data) = get::<Vec<Loan>>("/loans").await {
                     set_cache("cache_loans", &data);
@@ -102,6 +135,10 @@ pub fn dashboard() -> Html {
                     set_cache("cache_profile", &data);
                     profile.set(data);
                 }
+                if let Ok(data) = get::<CreditScore>("/auth/profile/score").await {
+                    set_cache("cache_credit_score", &data);
+                    credit.set(Some(data));
+                }
             });
         })
     };
@@ -117,22 +154,34 @@ pub fn dashboard() -> Html {
     let on_loan_submit = {
         let amount = loan_amount.clone();
         let desc = loan_desc.clone();
+        let phone = loan_phone.clone();
         let fetch_data = fetch_data.clone();
         let context = context.clone();
         Callback::from(move |e: SubmitEvent| {
             e.prevent_default();
-            let amount_val = *amount;
             let desc_val = (*desc).clone();
+            let phone_val = (*phone).clone();
             let fetch_data = fetch_data.clone();
             let context = context.clone();
 
-            if amount_val <= 0.0 {
-                context.add_notification.emit(("Amount must be greater than zero".to_string(), NotificationType::Error));
-                return;
-            }
+            let amount_val = match Money::parse(&amount, PLATFORM_CURRENCY) {
+                Ok(amount) if amount.is_positive() => amount,
+                Ok(_) => {
+                    context.add_notification.emit(("Amount must be greater than zero".to_string(), NotificationType::Error));
+                    return;
+                }
+                Err(e) => {
+                    context.add_notification.emit((e.to_string(), NotificationType::Error));
+                    return;
+                }
+            };
 
             wasm_bindgen_futures::spawn_local(async move {
-                match post::<_, Uuid>("/loans", &CreateLoanRequest { amount: amount_val, description: desc_val }).await {
+                match post::<_, Uuid>("/loans", &CreateLoanRequest {
+                    amount: amount_val,
+                    description: desc_val,
+                    phone_number: if phone_val.is_empty() { None } else { Some(phone_val) },
+                }).await {
                     Ok(_) => {
                         context.add_notification.emit(("Loan requested successfully!".to_string(), NotificationType::Success));
                         fetch_data.emit(());
@@ -145,11 +194,13 @@ pub fn dashboard() -> Html {
 
     let on_savings_submit = {
         let goal = savings_goal.clone();
+        let target = savings_target.clone();
         let fetch_data = fetch_data.clone();
         let context = context.clone();
         Callback::from(move |e: SubmitEvent| {
             e.prevent_default();
             let goal_val = (*goal).clone();
+            let target_val = (*target).clone();
             let fetch_data = fetch_data.clone();
             let context = context.clone();
 
@@ -157,9 +208,20 @@ pub fn dashboard() -> Html {
                 context.add_notification.emit(("Please enter a goal name".to_string(), NotificationType::Error));
                 return;
             }
+            let target_amount = if target_val.trim().is_empty() {
+                None
+            } else {
+                match Money::parse(&target_val, PLATFORM_CURRENCY) {
+                    Ok(m) if m.is_positive() => Some(m),
+                    _ => {
+                        context.add_notification.emit(("Please enter a valid target amount".to_string(), NotificationType::Error));
+                        return;
+                    }
+                }
+            };
 
             wasm_bindgen_futures::spawn_local(async move {
-                match post::<_, Uuid>("/savings", &CreateSavingsRequest { goal_name: goal_val }).await {
+                match post::<_, Uuid>("/savings", &CreateSavingsRequest { goal_name: goal_val, target_amount }).await {
                     Ok(_) => {
                         context.add_notification.emit(("Savings goal created!".to_string(), NotificationType::Success));
                         fetch_data.emit(());
@@ -170,6 +232,7 @@ pub fn dashboard() -> Html {
         })
     };
 
+    let deposit_amount = Money::from_major(10, PLATFORM_CURRENCY).unwrap();
     let deposit = |id: Uuid| {
         let fetch_data = fetch_data.clone();
         let phone = phone_number.clone();
@@ -179,14 +242,39 @@ pub fn dashboard() -> Html {
             let phone_val = (*phone).clone();
             let context = context.clone();
             wasm_bindgen_futures::spawn_local(async move {
-                match post::<_, String>(&format!("/savings/{}/deposit", id), &DepositRequest { 
-                    amount: 10.0, 
+                match post::<_, PendingPayment>(&format!("/savings/{}/deposit", id), &DepositRequest { 
+                    amount: deposit_amount, 
                     phone_number: if phone_val.is_empty() { None } else { Some(phone_val) }
                 }).await {
-                    Ok(_) => {
-                        context.add_notification.emit(("Deposit initiated! Check your phone for STK push.".to_string(), NotificationType::Info));
+                    Ok(payment) if payment.status == "pending" => {
+                        context.add_notification.emit(("Check your phone and enter your M-Pesa PIN. Your savings update once M-Pesa confirms.".to_string(), NotificationType::Info));
                         fetch_data.emit(());
                     }
+                    Ok(_) => context.add_notification.emit(("The M-Pesa payment could not be started".to_string(), NotificationType::Error)),
+                    Err(e) => context.add_notification.emit((format!("Error: {}", e), NotificationType::Error)),
+                }
+            });
+        })
+    };
+
+    let withdraw = |id: Uuid| {
+        let fetch_data = fetch_data.clone();
+        let phone = phone_number.clone();
+        let context = context.clone();
+        Callback::from(move |_| {
+            let fetch_data = fetch_data.clone();
+            let phone_val = (*phone).clone();
+            let context = context.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                match post::<_, PendingPayment>(&format!("/savings/{}/withdraw", id), &WithdrawRequest {
+                    amount: deposit_amount,
+                    phone_number: if phone_val.is_empty() { None } else { Some(phone_val) }
+                }).await {
+                    Ok(payment) if payment.status == "pending" => {
+                        context.add_notification.emit(("Your withdrawal is on its way to you over M-Pesa.".to_string(), NotificationType::Info));
+                        fetch_data.emit(());
+                    }
+                    Ok(_) => context.add_notification.emit(("The M-Pesa payout could not be started".to_string(), NotificationType::Error)),
                     Err(e) => context.add_notification.emit((format!("Error: {}", e), NotificationType::Error)),
                 }
             });
@@ -195,16 +283,22 @@ pub fn dashboard() -> Html {
 
     let repay = |id: Uuid| {
         let fetch_data = fetch_data.clone();
+        let phone = phone_number.clone();
         let context = context.clone();
         Callback::from(move |_| {
             let fetch_data = fetch_data.clone();
+            let phone_val = (*phone).clone();
             let context = context.clone();
             wasm_bindgen_futures::spawn_local(async move {
-                match post::<_, String>("/loans/repay", &RepayRequest { loan_id: id }).await {
-                    Ok(_) => {
-                        context.add_notification.emit(("Loan repaid successfully! Your Trust Score increased.".to_string(), NotificationType::Success));
+                match post::<_, PendingPayment>("/loans/repay", &RepayRequest {
+                    loan_id: id,
+                    phone_number: if phone_val.is_empty() { None } else { Some(phone_val) }
+                }).await {
+                    Ok(payment) if payment.status == "pending" => {
+                        context.add_notification.emit(("Check your phone and enter your M-Pesa PIN. Your loan is marked repaid once M-Pesa confirms.".to_string(), NotificationType::Info));
                         fetch_data.emit(());
                     }
+                    Ok(_) => context.add_notification.emit(("The M-Pesa payment could not be started".to_string(), NotificationType::Error)),
                     Err(e) => context.add_notification.emit((format!("Error: {}", e), NotificationType::Error)),
                 }
             });
@@ -213,41 +307,49 @@ pub fn dashboard() -> Html {
 
     let fund_loan = |id: Uuid| {
         let fetch_data = fetch_data.clone();
+        let phone = phone_number.clone();
         let context = context.clone();
         Callback::from(move |_| {
             let fetch_data = fetch_data.clone();
+            let phone_val = (*phone).clone();
             let context = context.clone();
             wasm_bindgen_futures::spawn_local(async move {
-                match post::<_, String>(&format!("/loans/{}/fund", id), &()).await {
-                    Ok(_) => {
-                        context.add_notification.emit(("You funded a neighbor's loan! Impact increased.".to_string(), NotificationType::Success));
+                match post::<_, PendingPayment>(&format!("/loans/{}/fund", id), &FundRequest {
+                    phone_number: if phone_val.is_empty() { None } else { Some(phone_val) }
+                }).await {
+                    Ok(payment) if payment.status == "pending" => {
+                        context.add_notification.emit(("Check your phone and enter your M-Pesa PIN. The loan is funded and sent to your neighbor once M-Pesa confirms.".to_string(), NotificationType::Info));
                         fetch_data.emit(());
                     }
+                    Ok(_) => context.add_notification.emit(("The M-Pesa payment could not be started".to_string(), NotificationType::Error)),
                     Err(e) => context.add_notification.emit((format!("Error: {}", e), NotificationType::Error)),
                 }
             });
         })
     };
 
-    let total_borrowed: f64 = loans.iter().map(|l| l.amount).sum();
-    let total_saved: f64 = savings.iter().map(|s| s.amount).sum();
+    let total_borrowed = Money::sum(loans.iter().map(|l| l.amount), PLATFORM_CURRENCY).unwrap_or(Money::zero(PLATFORM_CURRENCY));
+    let total_saved = Money::sum(savings.iter().map(|s| s.amount), PLATFORM_CURRENCY).unwrap_or(Money::zero(PLATFORM_CURRENCY));
 
-    // Simple SVG Visualization for Savings vs Borrowing
+    // Simple SVG Visualization for Savings vs Borrowing (bar widths only, amounts stay exact)
     let chart_width = 200;
     let chart_height = 20;
-    let max_val = if total_borrowed > total_saved { total_borrowed } else { total_saved };
-    let borrow_width = if max_val > 0.0 { (total_borrowed / max_val) * chart_width as f64 } else { 0.0 };
-    let savings_width = if max_val > 0.0 { (total_saved / max_val) * chart_width as f64 } else { 0.0 };
-
-    let (tier, tier_color, next_tier_score) = if profile.reputation_score < 200 {
-        ("BRONZE", "#cd7f32", 200)
-    } else if profile.reputation_score < 500 {
-        ("SILVER", "#c0c0c0", 500)
+    let max_val = total_borrowed.minor_units().max(total_saved.minor_units()) as f64;
+    let borrow_width = if max_val > 0.0 { (total_borrowed.minor_units() as f64 / max_val) * chart_width as f64 } else { 0.0 };
+    let savings_width = if max_val > 0.0 { (total_saved.minor_units() as f64 / max_val) * chart_width as f64 } else { 0.0 };
+
+    // The limit and explanation come from the scoring engine; the cached profile score is a fallback.
+    let trust_score = credit.as_ref().map(|c| c.score).unwrap_or(profile.reputation_score);
+    let (tier, tier_color, next_tier_score) = if trust_score < 400 {
+        ("BRONZE", "#cd7f32", 400)
+    } else if trust_score < 700 {
+        ("SILVER", "#c0c0c0", 700)
     } else {
         ("GOLD", "#ffd700", 1000)
     };
 
-    let loan_limit = (profile.reputation_score as f64) * 2.0;
+    let loan_limit = credit.as_ref().map(|c| c.credit_limit).unwrap_or(Money::zero(PLATFORM_CURRENCY));
+    let score_summary = credit.as_ref().map(|c| c.summary.clone()).unwrap_or_default();
 
     html! {
         <div class="dashboard-container" style="padding: 0 1rem;">
@@ -261,12 +363,13 @@ pub fn dashboard() -> Html {
                 </div>
                 <div class="summary-item">
                     <h4>{ t("trust_score", &context.lang) }</h4>
-                    <p style="color: #2ecc71;">{ profile.reputation_score }</p>
-                    <p style="font-size: 0.6rem; color: #7f8c8d;">{ format!("Next Tier: {}/{}", profile.reputation_score, next_tier_score) }</p>
+                    <p style="color: #2ecc71;">{ trust_score }</p>
+                    <p style="font-size: 0.6rem; color: #7f8c8d;">{ format!("Next Tier: {}/{}", trust_score, next_tier_score) }</p>
                 </div>
                 <div class="summary-item">
                     <h4>{ "Max Loan Limit" }</h4>
-                    <p style="color: #3498db;">{ format!("${:.2}", loan_limit) }</p>
+                    <p style="color: #3498db;">{ loan_limit.to_string() }</p>
+                    <p style="font-size: 0.6rem; color: #7f8c8d;">{ score_summary }</p>
                 </div>
                 <div class="summary-item">
                     <h4>{ "Balance View" }</h4>
@@ -289,10 +392,15 @@ pub fn dashboard() -> Html {
                             <div style="font-size: 0.7rem; padding: 0.5rem; border-bottom: 1px solid #eee; font-family: monospace;">
                                 <div style="display: flex; justify-content: space-between; font-weight: bold;">
                                     <span>{ &tx.activity_type }</span>
-                                    <span style="color: #2ecc71;">{ format!("+${:.2}", tx.amount) }</span>
+                                    <span style="color: #2ecc71;">{ format!("+{}", tx.amount) }</span>
                                 </div>
                                 <div style="color: #7f8c8d; margin: 2px 0;">{ &tx.description }</div>
-                                <div style="color: #3498db; overflow: hidden; text-overflow: ellipsis;">{ format!("Sig: {}", tx.signature) }</div>
+                                <div style="color: #3498db; overflow: hidden; text-overflow: ellipsis;">
+                                    { match (tx.sequence_number, &tx.signature) {
+                                        (Some(number), Some(signature)) => format!("#{} Sig: {}", number, signature),
+                                        _ => "Sealing...".to_string(),
+                                    } }
+                                </div>
                             </div>
                         })}
                     </div>
@@ -305,7 +413,7 @@ pub fn dashboard() -> Html {
                         { for marketplace.iter().map(|m| html! {
                             <div class="stat-card" style="text-align: left; display: flex; justify-content: space-between; align-items: center; border-left-color: #3498db;">
                                 <div>
-                                    <p style="margin: 0; font-weight: bold;">{ format!("${:.2}", m.amount) }</p>
+                                    <p style="margin: 0; font-weight: bold;">{ m.amount.to_string() }</p>
                                     <p style="margin: 0.2rem 0; font-size: 0.8rem;">{ format!("By: @{}", m.borrower_username) }</p>
                                     <p style="margin: 0; font-size: 0.8rem; color: #7f8c8d;">{ m.description.clone().unwrap_or_default() }</p>
                                 </div>
@@ -318,8 +426,9 @@ pub fn dashboard() -> Html {
                 <section class="section-card">
                     <h3>{ t("microloans", &context.lang) }</h3>
                     <form onsubmit={on_loan_submit} style="margin-bottom: 1.5rem;">
-                        <input type="number" placeholder="Amount ($)" oninput={let a = loan_amount.clone(); Callback::from(move |e: InputEvent| a.set(e.target_unchecked_into::<web_sys::HtmlInputElement>().value().parse().unwrap_or(0.0)))} />
+                        <input type="number" step="1" placeholder={format!("Amount ({})", PLATFORM_CURRENCY.code())} oninput={let a = loan_amount.clone(); Callback::from(move |e: InputEvent| a.set(e.target_unchecked_into::<web_sys::HtmlInputElement>().value()))} />
                         <input type="text" placeholder="Purpose (e.g. Seeds, Repair)" oninput={let d = loan_desc.clone(); Callback::from(move |e: InputEvent| d.set(e.target_unchecked_into::<web_sys::HtmlInputElement>().value()))} />
+                        <input type="tel" placeholder="M-Pesa number to receive the loan (07XX XXX XXX)" oninput={let p = loan_phone.clone(); Callback::from(move |e: InputEvent| p.set(e.target_unchecked_into::<web_sys::HtmlInputElement>().value()))} />
                         <button type="submit">{ t("request_loan", &context.lang) }</button>
                     </form>
                     
@@ -327,18 +436,19 @@ pub fn dashboard() -> Html {
                         { for loans.iter().map(|loan| {
                             let status_class = match loan.status.as_str() {
                                 "pending" => "status-pending",
-                                "approved" => "status-approved",
+                                "funded" | "disbursed" | "in_repayment" => "status-approved",
                                 "repaid" => "status-repaid",
                                 _ => "",
                             };
+                            let repayable = matches!(loan.status.as_str(), "disbursed" | "in_repayment" | "overdue" | "defaulted");
                             html! {
                                 <div class="stat-card" style="text-align: left; display: flex; justify-content: space-between; align-items: center; border-left-color: #e74c3c;">
                                     <div>
-                                        <p style="margin: 0; font-weight: bold;">{ format!("${:.2}", loan.amount) }</p>
+                                        <p style="margin: 0; font-weight: bold;">{ loan.amount.to_string() }</p>
                                         <p style="margin: 0.2rem 0; font-size: 0.8rem; color: #7f8c8d;">{ loan.description.clone().unwrap_or_default() }</p>
                                         <span class={classes!("status-badge", status_class)}>{ &loan.status }</span>
                                     </div>
-                                    { if loan.status != "repaid" {
+                                    { if repayable {
                                         html! { <button onclick={repay(loan.id)} class="btn-secondary" style="width: auto; font-size: 0.8rem;">{ t("repay", &context.lang) }</button> }
                                     } else { html! {} }}
                                 </div>
@@ -355,6 +465,7 @@ pub fn dashboard() -> Html {
                     </div>
                     <form onsubmit={on_savings_submit} style="margin-bottom: 1.5rem;">
                         <input type="text" placeholder="Goal Name (e.g. School Fees)" oninput={let g = savings_goal.clone(); Callback::from(move |e: InputEvent| g.set(e.target_unchecked_into::<web_sys::HtmlInputElement>().value()))} />
+                        <input type="text" placeholder="Target in KES (optional)" oninput={let t = savings_target.clone(); Callback::from(move |e: InputEvent| t.set(e.target_unchecked_into::<web_sys::HtmlInputElement>().value()))} />
                         <button type="submit" class="btn-secondary">{ t("create_goal", &context.lang) }</button>
                     </form>
 
@@ -362,10 +473,19 @@ pub fn dashboard() -> Html {
                         { for savings.iter().map(|s| html! {
                             <div class="stat-card" style="text-align: left; display: flex; justify-content: space-between; align-items: center; border-left-color: #2ecc71;">
                                 <div>
-                                    <p style="margin: 0; font-weight: bold;">{ format!("${:.2}", s.amount) }</p>
+                                    <p style="margin: 0; font-weight: bold;">{ s.amount.to_string() }</p>
                                     <p style="margin: 0.2rem 0; font-size: 0.9rem;">{ s.goal_name.clone().unwrap_or_default() }</p>
+                                    if let (Some(target), Some(percent)) = (s.target_amount, s.progress_percent) {
+                                        <p style="margin: 0; font-size: 0.8rem; color: #666;">{ format!("{}% of {}", percent, target) }</p>
+                                    }
+                                    if s.locked {
+                                        <p style="margin: 0; font-size: 0.8rem; color: #666;">{ format!("Locked until {}", s.locked_until.clone().unwrap_or_default()) }</p>
+                                    }
+                                </div>
+                                <div style="display: flex; gap: 0.3rem;">
+                                    <button onclick={deposit(s.id)} class="btn" style="width: auto; font-size: 0.8rem;">{ format!("+{}", deposit_amount.format_amount()) }</button>
+                                    <button onclick={withdraw(s.id)} class="btn-secondary" style="width: auto; font-size: 0.8rem;" disabled={s.locked}>{ format!("-{}", deposit_amount.format_amount()) }</button>
                                 </div>
-                                <button onclick={deposit(s.id)} class="btn" style="width: auto; font-size: 0.8rem;">{ "+$10" }</button>
                             </div>
                         })}
                     </div>
@@ -0,0 +1,69 @@
+use yew::prelude::*;
+use yew_router::prelude::*;
+use crate::Route;
+use crate::services::api::post;
+use serde::{Deserialize, Serialize};
+use web_sys::HtmlInputElement;
+
+#[derive(Serialize)]
+struct ForgotPasswordRequest {
+    account: String,
+}
+
+#[derive(Deserialize)]
+struct ResetSent {
+    expires_in: i64,
+}
+
+#[function_component(ForgotPassword)]
+pub fn forgot_password() -> Html {
+    let account = use_state(|| "".to_string());
+    let message = use_state(|| None::<String>);
+
+    let onsubmit = {
+        let account = account.clone();
+        let message = message.clone();
+
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            let account_val = (*account).clone();
+            let message = message.clone();
+
+            wasm_bindgen_futures::spawn_local(async move {
+                let res: Result<ResetSent, String> = post("/auth/password/forgot", &ForgotPasswordRequest {
+                    account: account_val,
+                }).await;
+
+                match res {
+                    Ok(sent) => message.set(Some(format!(
+                        "If that account exists, a reset link is on its way to its phone or email. It expires in {} minutes.",
+                        sent.expires_in / 60
+                    ))),
+                    Err(e) => message.set(Some(e)),
+                }
+            });
+        })
+    };
+
+    html! {
+        <div class="login-page">
+            <h2>{ "Forgot Password" }</h2>
+            <p>{ "Enter your username, email or phone number and we will send you a link to set a new password." }</p>
+            { if let Some(msg) = &*message {
+                html! { <p style="color: #2980b9;">{ msg }</p> }
+            } else {
+                html! {}
+            }}
+            <form {onsubmit}>
+                <input
+                    type="text"
+                    placeholder="Username, email or phone number"
+                    value={(*account).clone()}
+                    oninput={let a = account.clone(); Callback::from(move |e: InputEvent| a.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                />
+                <button type="submit">{ "Send reset link" }</button>
+            </form>
+            <p><Link<Route> to={Route::Login}>{ "Back to login" }</Link<Route>></p>
+        </div>
+    }
+}
@@ -6,12 +6,13 @@ use crate::app_context::AppContext;
 use crate::utils::i18n::t;
 
 use crate::services::api::get;
+use microfund_shared::Money;
 
 #[derive(serde::Deserialize, Default, Clone, PartialEq)]
 struct PlatformStats {
     total_users: i64,
-    total_loans_value: f64,
-    total_savings_value: f64,
+    total_loans_value: Money,
+    total_savings_value: Money,
     active_p2p_deals: i64,
 }
 
@@ -50,11 +51,11 @@ pub fn home() -> Html {
                     <p>{ "Users" }</p>
                 </div>
                 <div>
-                    <h3>{ format!("${:.0}", stats.total_loans_value) }</h3>
+                    <h3>{ stats.total_loans_value.to_string() }</h3>
                     <p>{ "Loans" }</p>
                 </div>
                 <div>
-                    <h3>{ format!("${:.0}", stats.total_savings_value) }</h3>
+                    <h3>{ stats.total_savings_value.to_string() }</h3>
                     <p>{ "Saved" }</p>
                 </div>
                 <div>
@@ -2,7 +2,7 @@ use yew::prelude::*;
 use yew_router::prelude::*;
 use crate::Route;
 use crate::services::api::post;
-use crate::services::storage::set_token;
+use crate::services::storage::{set_login_challenge, set_session};
 use serde::{Deserialize, Serialize};
 use web_sys::HtmlInputElement;
 
@@ -12,22 +12,76 @@ struct LoginRequest {
     password: String,
 }
 
+#[derive(Serialize)]
+struct OtpRequest {
+    phone_number: String,
+    purpose: String,
+}
+
+#[derive(Serialize)]
+struct OtpLoginRequest {
+    phone_number: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct OtpSent {
+    expires_in: i64,
+}
+
 #[derive(Deserialize)]
 struct AuthResponse {
     token: String,
+    refresh_token: String,
     user_id: String,
 }
 
+/// Members with two-factor authentication get a challenge to answer instead of tokens.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LoginResponse {
+    TwoFactorRequired { challenge_token: String },
+    Authenticated(AuthResponse),
+}
+
 #[function_component(Login)]
 pub fn login() -> Html {
     let navigator = use_navigator().unwrap();
     let username = use_state(|| "".to_string());
     let password = use_state(|| "".to_string());
+    let use_phone = use_state(|| false);
+    let phone = use_state(|| "".to_string());
+    let code = use_state(|| "".to_string());
     let error = use_state(|| None::<String>);
 
+    let send_code = {
+        let phone = phone.clone();
+        let error = error.clone();
+
+        Callback::from(move |_| {
+            let phone_val = (*phone).clone();
+            let error = error.clone();
+
+            wasm_bindgen_futures::spawn_local(async move {
+                let res: Result<OtpSent, String> = post("/auth/otp", &OtpRequest {
+                    phone_number: phone_val,
+                    purpose: "login".to_string(),
+                }).await;
+
+                match res {
+                    Ok(sent) => error.set(Some(format!("Code sent. It expires in {} minutes.", sent.expires_in / 60))),
+                    Err(e) => error.set(Some(e)),
+                }
+            });
+        })
+    };
+
     let onsubmit = {
         let username = username.clone();
         let password = password.clone();
+        let use_phone = use_phone.clone();
+        let phone = phone.clone();
+        let code = code.clone();
         let error = error.clone();
         let navigator = navigator.clone();
         
@@ -35,20 +89,34 @@ pub fn login() -> Html {
             e.prevent_default();
             let username_val = (*username).clone();
             let password_val = (*password).clone();
+            let use_phone = *use_phone;
+            let phone_val = (*phone).clone();
+            let code_val = (*code).clone();
             let error = error.clone();
             let navigator = navigator.clone();
 
             wasm_bindgen_futures::spawn_local(async move {
-                let res: Result<AuthResponse, String> = post("/auth/login", &LoginRequest {
-                    username: username_val,
-                    password: password_val,
-                }).await;
+                let res: Result<LoginResponse, String> = if use_phone {
+                    post("/auth/login/otp", &OtpLoginRequest {
+                        phone_number: phone_val,
+                        code: code_val,
+                    }).await
+                } else {
+                    post("/auth/login", &LoginRequest {
+                        username: username_val,
+                        password: password_val,
+                    }).await
+                };
 
                 match res {
-                    Ok(auth) => {
-                        set_token(&auth.token);
+                    Ok(LoginResponse::Authenticated(auth)) => {
+                        set_session(&auth.token, &auth.refresh_token);
                         navigator.push(&Route::Dashboard);
                     }
+                    Ok(LoginResponse::TwoFactorRequired { challenge_token }) => {
+                        set_login_challenge(&challenge_token);
+                        navigator.push(&Route::TwoFactorLogin);
+                    }
                     Err(e) => error.set(Some(e)),
                 }
             });
@@ -64,6 +132,15 @@ pub fn login() -> Html {
         })
     };
 
+    let toggle_method = {
+        let use_phone = use_phone.clone();
+        let error = error.clone();
+        Callback::from(move |_| {
+            use_phone.set(!*use_phone);
+            error.set(None);
+        })
+    };
+
     html! {
         <div class="login-page">
             <h2>{ "Login" }</h2>
@@ -73,21 +150,50 @@ pub fn login() -> Html {
                 html! {}
             }}
             <form {onsubmit}>
-                <input 
-                    type="text" 
-                    placeholder="Username" 
-                    value={(*username).clone()} 
-                    oninput={let u = username.clone(); Callback::from(move |e: InputEvent| u.set(e.target_unchecked_into::<HtmlInputElement>().value()))} 
-                />
-                <input 
-                    type="password" 
-                    placeholder="Password" 
-                    value={(*password).clone()} 
-                    oninput={let p = password.clone(); Callback::from(move |e: InputEvent| p.set(e.target_unchecked_into::<HtmlInputElement>().value()))} 
-                />
+                { if *use_phone {
+                    html! {
+                        <>
+                            <input
+                                type="tel"
+                                placeholder="Phone number (07XX XXX XXX)"
+                                value={(*phone).clone()}
+                                oninput={let ph = phone.clone(); Callback::from(move |e: InputEvent| ph.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                            />
+                            <button type="button" onclick={send_code}>{ "Send code" }</button>
+                            <input
+                                type="text"
+                                inputmode="numeric"
+                                placeholder="6-digit code"
+                                value={(*code).clone()}
+                                oninput={let c = code.clone(); Callback::from(move |e: InputEvent| c.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                            />
+                        </>
+                    }
+                } else {
+                    html! {
+                        <>
+                            <input 
+                                type="text" 
+                                placeholder="Username" 
+                                value={(*username).clone()} 
+                                oninput={let u = username.clone(); Callback::from(move |e: InputEvent| u.set(e.target_unchecked_into::<HtmlInputElement>().value()))} 
+                            />
+                            <input 
+                                type="password" 
+                                placeholder="Password" 
+                                value={(*password).clone()} 
+                                oninput={let p = password.clone(); Callback::from(move |e: InputEvent| p.set(e.target_unchecked_into::<HtmlInputElement>().value()))} 
+                            />
+                        </>
+                    }
+                }}
                 <button type="submit">{ "Login" }</button>
             </form>
+            <button onclick={toggle_method} style="background: #7f8c8d; margin-top: 1rem;">
+                { if *use_phone { "Log in with a password instead" } else { "Log in with a code sent to my phone" } }
+            </button>
             <button onclick={login_demo} style="background: #7f8c8d; margin-top: 1rem;">{ "Use Demo Account" }</button>
+            <p><Link<Route> to={Route::ForgotPassword}>{ "Forgot your password?" }</Link<Route>></p>
             <p>{ "Don't have an account? " }<Link<Route> to={Route::Register}>{ "Register here" }</Link<Route>></p>
         </div>
     }
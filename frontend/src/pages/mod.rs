@@ -2,3 +2,7 @@ pub mod home;
 pub mod login;
 pub mod register;
 pub mod dashboard;
+pub mod security;
+pub mod two_factor_login;
+pub mod forgot_password;
+pub mod reset_password;
@@ -2,65 +2,137 @@ use yew::prelude::*;
 use yew_router::prelude::*;
 use crate::Route;
 use crate::services::api::post;
-use crate::services::storage::set_token;
+use crate::services::storage::set_session;
 use serde::{Deserialize, Serialize};
 use web_sys::HtmlInputElement;
 
 #[derive(Serialize)]
 struct RegisterRequest {
     username: String,
-    email: String,
-    password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phone_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OtpRequest {
+    phone_number: String,
+    purpose: String,
+}
+
+#[derive(Deserialize)]
+struct OtpSent {
+    expires_in: i64,
 }
 
 #[derive(Deserialize)]
 struct AuthResponse {
     token: String,
+    refresh_token: String,
     user_id: String,
 }
 
 #[function_component(Register)]
 pub fn register() -> Html {
     let navigator = use_navigator().unwrap();
+    let use_phone = use_state(|| true);
     let username = use_state(|| "".to_string());
+    let phone = use_state(|| "".to_string());
+    let code = use_state(|| "".to_string());
+    let code_sent = use_state(|| false);
     let email = use_state(|| "".to_string());
     let password = use_state(|| "".to_string());
     let error = use_state(|| None::<String>);
 
+    let send_code = {
+        let phone = phone.clone();
+        let code_sent = code_sent.clone();
+        let error = error.clone();
+
+        Callback::from(move |_| {
+            let phone_val = (*phone).clone();
+            let code_sent = code_sent.clone();
+            let error = error.clone();
+
+            wasm_bindgen_futures::spawn_local(async move {
+                let res: Result<OtpSent, String> = post("/auth/otp", &OtpRequest {
+                    phone_number: phone_val,
+                    purpose: "signup".to_string(),
+                }).await;
+
+                match res {
+                    Ok(sent) => {
+                        code_sent.set(true);
+                        error.set(Some(format!("Code sent. It expires in {} minutes.", sent.expires_in / 60)));
+                    }
+                    Err(e) => error.set(Some(e)),
+                }
+            });
+        })
+    };
+
     let onsubmit = {
+        let use_phone = use_phone.clone();
         let username = username.clone();
+        let phone = phone.clone();
+        let code = code.clone();
         let email = email.clone();
         let password = password.clone();
         let error = error.clone();
         let navigator = navigator.clone();
-        
+
         Callback::from(move |e: SubmitEvent| {
             e.prevent_default();
+            let use_phone = *use_phone;
             let username_val = (*username).clone();
+            let phone_val = (*phone).clone();
+            let code_val = (*code).clone();
             let email_val = (*email).clone();
             let password_val = (*password).clone();
             let error = error.clone();
             let navigator = navigator.clone();
 
             wasm_bindgen_futures::spawn_local(async move {
-                if password_val.len() < 6 {
-                    error.set(Some("Password must be at least 6 characters long".to_string()));
-                    return;
-                }
-                if !email_val.contains('@') {
-                    error.set(Some("Please enter a valid email address".to_string()));
-                    return;
-                }
+                let request = if use_phone {
+                    if code_val.trim().is_empty() {
+                        error.set(Some("Enter the code we sent to your phone".to_string()));
+                        return;
+                    }
+                    RegisterRequest {
+                        username: username_val,
+                        phone_number: Some(phone_val),
+                        code: Some(code_val),
+                        email: None,
+                        password: None,
+                    }
+                } else {
+                    if password_val.len() < 6 {
+                        error.set(Some("Password must be at least 6 characters long".to_string()));
+                        return;
+                    }
+                    if !email_val.contains('@') {
+                        error.set(Some("Please enter a valid email address".to_string()));
+                        return;
+                    }
+                    RegisterRequest {
+                        username: username_val,
+                        phone_number: None,
+                        code: None,
+                        email: Some(email_val),
+                        password: Some(password_val),
+                    }
+                };
 
-                let res: Result<AuthResponse, String> = post("/auth/register", &RegisterRequest {
-                    username: username_val,
-                    email: email_val,
-                    password: password_val,
-                }).await;
+                let res: Result<AuthResponse, String> = post("/auth/register", &request).await;
 
                 match res {
                     Ok(auth) => {
-                        set_token(&auth.token);
+                        set_session(&auth.token, &auth.refresh_token);
                         navigator.push(&Route::Dashboard);
                     }
                     Err(e) => error.set(Some(e)),
@@ -69,6 +141,15 @@ pub fn register() -> Html {
         })
     };
 
+    let toggle_method = {
+        let use_phone = use_phone.clone();
+        let error = error.clone();
+        Callback::from(move |_| {
+            use_phone.set(!*use_phone);
+            error.set(None);
+        })
+    };
+
     html! {
         <div class="register-page">
             <h2>{ "Create Account" }</h2>
@@ -79,27 +160,57 @@ pub fn register() -> Html {
                 html! {}
             }}
             <form {onsubmit}>
-                <input 
-                    type="text" 
-                    placeholder="Username" 
-                    value={(*username).clone()} 
-                    oninput={let u = username.clone(); Callback::from(move |e: InputEvent| u.set(e.target_unchecked_into::<HtmlInputElement>().value()))} 
-                />
-                <input 
-                    type="email" 
-                    placeholder="Email" 
-                    value={(*email).clone()} 
-                    oninput={let em = email.clone(); Callback::from(move |e: InputEvent| em.set(e.target_unchecked_into::<HtmlInputElement>().value()))} 
-                />
-                <input 
-                    type="password" 
-                    placeholder="Password" 
-                    value={(*password).clone()} 
-                    oninput={let p = password.clone(); Callback::from(move |e: InputEvent| p.set(e.target_unchecked_into::<HtmlInputElement>().value()))} 
+                <input
+                    type="text"
+                    placeholder="Username"
+                    value={(*username).clone()}
+                    oninput={let u = username.clone(); Callback::from(move |e: InputEvent| u.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
                 />
+                { if *use_phone {
+                    html! {
+                        <>
+                            <input
+                                type="tel"
+                                placeholder="Phone number (07XX XXX XXX)"
+                                value={(*phone).clone()}
+                                oninput={let ph = phone.clone(); Callback::from(move |e: InputEvent| ph.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                            />
+                            <button type="button" onclick={send_code}>
+                                { if *code_sent { "Send a new code" } else { "Send code" } }
+                            </button>
+                            <input
+                                type="text"
+                                inputmode="numeric"
+                                placeholder="6-digit code"
+                                value={(*code).clone()}
+                                oninput={let c = code.clone(); Callback::from(move |e: InputEvent| c.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                            />
+                        </>
+                    }
+                } else {
+                    html! {
+                        <>
+                            <input
+                                type="email"
+                                placeholder="Email"
+                                value={(*email).clone()}
+                                oninput={let em = email.clone(); Callback::from(move |e: InputEvent| em.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                            />
+                            <input
+                                type="password"
+                                placeholder="Password"
+                                value={(*password).clone()}
+                                oninput={let p = password.clone(); Callback::from(move |e: InputEvent| p.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                            />
+                        </>
+                    }
+                }}
                 <button type="submit">{ "Register" }</button>
             </form>
+            <button onclick={toggle_method} style="background: #7f8c8d; margin-top: 1rem;">
+                { if *use_phone { "Use email and password instead" } else { "Use a phone number instead" } }
+            </button>
             <p>{ "Already have an account? " }<Link<Route> to={Route::Login}>{ "Login here" }</Link<Route>></p>
         </div>
     }
-}
\ No newline at end of file
+}
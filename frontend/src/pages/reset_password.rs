@@ -0,0 +1,98 @@
+use yew::prelude::*;
+use yew_router::prelude::*;
+use crate::Route;
+use crate::services::api::post_no_content;
+use serde::{Deserialize, Serialize};
+use web_sys::HtmlInputElement;
+
+/// The reset link carries its token as `?token=`.
+#[derive(Deserialize)]
+struct ResetQuery {
+    token: String,
+}
+
+#[derive(Serialize)]
+struct ResetPasswordRequest {
+    token: String,
+    new_password: String,
+}
+
+#[function_component(ResetPassword)]
+pub fn reset_password() -> Html {
+    let navigator = use_navigator().unwrap();
+    let token = use_location().and_then(|location| location.query::<ResetQuery>().ok()).map(|q| q.token);
+    let password = use_state(|| "".to_string());
+    let confirm = use_state(|| "".to_string());
+    let error = use_state(|| None::<String>);
+
+    let Some(token) = token else {
+        return html! {
+            <div class="login-page">
+                <h2>{ "Reset Password" }</h2>
+                <p>{ "This link is incomplete. " }<Link<Route> to={Route::ForgotPassword}>{ "Ask for a new one" }</Link<Route>></p>
+            </div>
+        };
+    };
+
+    let onsubmit = {
+        let password = password.clone();
+        let confirm = confirm.clone();
+        let error = error.clone();
+        let navigator = navigator.clone();
+
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            let token = token.clone();
+            let password_val = (*password).clone();
+            let confirm_val = (*confirm).clone();
+            let error = error.clone();
+            let navigator = navigator.clone();
+
+            wasm_bindgen_futures::spawn_local(async move {
+                if password_val.len() < 6 {
+                    error.set(Some("Password must be at least 6 characters long".to_string()));
+                    return;
+                }
+                if password_val != confirm_val {
+                    error.set(Some("The passwords do not match".to_string()));
+                    return;
+                }
+
+                match post_no_content("/auth/password/reset", &ResetPasswordRequest {
+                    token,
+                    new_password: password_val,
+                }).await {
+                    Ok(()) => navigator.push(&Route::Login),
+                    Err(_) => error.set(Some("This link is not valid or has expired. Ask for a new one.".to_string())),
+                }
+            });
+        })
+    };
+
+    html! {
+        <div class="login-page">
+            <h2>{ "Reset Password" }</h2>
+            <p>{ "Choose a new password. You will be signed out on all your devices." }</p>
+            { if let Some(err) = &*error {
+                html! { <p style="color: red;">{ err }</p> }
+            } else {
+                html! {}
+            }}
+            <form {onsubmit}>
+                <input
+                    type="password"
+                    placeholder="New password"
+                    value={(*password).clone()}
+                    oninput={let p = password.clone(); Callback::from(move |e: InputEvent| p.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                />
+                <input
+                    type="password"
+                    placeholder="Confirm new password"
+                    value={(*confirm).clone()}
+                    oninput={let c = confirm.clone(); Callback::from(move |e: InputEvent| c.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                />
+                <button type="submit">{ "Set password" }</button>
+            </form>
+        </div>
+    }
+}
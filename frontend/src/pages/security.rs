@@ -0,0 +1,285 @@
+use yew::prelude::*;
+use crate::services::api::{get, post, post_no_content};
+use serde::{Deserialize, Serialize};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use web_sys::HtmlInputElement;
+
+#[derive(Deserialize)]
+struct Profile {
+    two_factor_enabled: bool,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+struct Enrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+struct CodeRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+/// The `otpauth://` URI as a QR code for authenticator apps to scan.
+fn qr_code(uri: &str) -> Html {
+    match QrCode::new(uri.as_bytes()) {
+        Ok(code) => {
+            let image = code.render::<svg::Color>().min_dimensions(200, 200).build();
+            Html::from_html_unchecked(AttrValue::from(image))
+        }
+        Err(_) => html! {},
+    }
+}
+
+/// Changing the password; the member's other devices are signed out.
+#[function_component(ChangePasswordForm)]
+fn change_password_form() -> Html {
+    let current = use_state(|| "".to_string());
+    let new_password = use_state(|| "".to_string());
+    let message = use_state(|| None::<String>);
+
+    let onsubmit = {
+        let current = current.clone();
+        let new_password = new_password.clone();
+        let message = message.clone();
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            let current = current.clone();
+            let new_password = new_password.clone();
+            let message = message.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                if new_password.len() < 6 {
+                    message.set(Some("Password must be at least 6 characters long".to_string()));
+                    return;
+                }
+                let request = ChangePasswordRequest {
+                    current_password: (*current).clone(),
+                    new_password: (*new_password).clone(),
+                };
+                match post_no_content("/auth/password/change", &request).await {
+                    Ok(()) => {
+                        current.set("".to_string());
+                        new_password.set("".to_string());
+                        message.set(Some("Password changed. Your other devices have been signed out.".to_string()));
+                    }
+                    Err(_) => message.set(Some("The current password is not correct.".to_string())),
+                }
+            });
+        })
+    };
+
+    html! {
+        <div class="change-password">
+            <h3>{ "Change Password" }</h3>
+            { if let Some(msg) = &*message {
+                html! { <p style="color: #2980b9;">{ msg }</p> }
+            } else {
+                html! {}
+            }}
+            <form {onsubmit}>
+                <input
+                    type="password"
+                    placeholder="Current password"
+                    value={(*current).clone()}
+                    oninput={let c = current.clone(); Callback::from(move |e: InputEvent| c.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                />
+                <input
+                    type="password"
+                    placeholder="New password"
+                    value={(*new_password).clone()}
+                    oninput={let p = new_password.clone(); Callback::from(move |e: InputEvent| p.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                />
+                <button type="submit">{ "Change password" }</button>
+            </form>
+        </div>
+    }
+}
+
+/// Account security: the password and two-factor authentication with an authenticator app.
+#[function_component(Security)]
+pub fn security() -> Html {
+    let enabled = use_state(|| None::<bool>);
+    let enrollment = use_state(|| None::<Enrollment>);
+    let recovery_codes = use_state(Vec::<String>::new);
+    let code = use_state(|| "".to_string());
+    let message = use_state(|| None::<String>);
+
+    {
+        let enabled = enabled.clone();
+        use_effect_with((), move |_| {
+            wasm_bindgen_futures::spawn_local(async move {
+                if let Ok(profile) = get::<Profile>("/auth/profile").await {
+                    enabled.set(Some(profile.two_factor_enabled));
+                }
+            });
+            || ()
+        });
+    }
+
+    let start_setup = {
+        let enrollment = enrollment.clone();
+        let message = message.clone();
+        Callback::from(move |_| {
+            let enrollment = enrollment.clone();
+            let message = message.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                match post::<_, Enrollment>("/auth/2fa/setup", &serde_json::json!({})).await {
+                    Ok(e) => {
+                        enrollment.set(Some(e));
+                        message.set(None);
+                    }
+                    Err(e) => message.set(Some(e)),
+                }
+            });
+        })
+    };
+
+    let confirm = {
+        let enabled = enabled.clone();
+        let enrollment = enrollment.clone();
+        let recovery_codes = recovery_codes.clone();
+        let code = code.clone();
+        let message = message.clone();
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            let enabled = enabled.clone();
+            let enrollment = enrollment.clone();
+            let recovery_codes = recovery_codes.clone();
+            let code = code.clone();
+            let message = message.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let res: Result<RecoveryCodes, String> = post("/auth/2fa/confirm", &CodeRequest { code: (*code).clone() }).await;
+                match res {
+                    Ok(codes) => {
+                        enabled.set(Some(true));
+                        enrollment.set(None);
+                        recovery_codes.set(codes.recovery_codes);
+                        code.set("".to_string());
+                        message.set(Some("Two-factor authentication is on.".to_string()));
+                    }
+                    Err(_) => message.set(Some("That code did not work. Check your phone's clock and try again.".to_string())),
+                }
+            });
+        })
+    };
+
+    let new_recovery_codes = {
+        let recovery_codes = recovery_codes.clone();
+        let code = code.clone();
+        let message = message.clone();
+        Callback::from(move |_| {
+            let recovery_codes = recovery_codes.clone();
+            let code = code.clone();
+            let message = message.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let res: Result<RecoveryCodes, String> = post("/auth/2fa/recovery-codes", &CodeRequest { code: (*code).clone() }).await;
+                match res {
+                    Ok(codes) => {
+                        recovery_codes.set(codes.recovery_codes);
+                        code.set("".to_string());
+                        message.set(Some("Your old recovery codes no longer work.".to_string()));
+                    }
+                    Err(_) => message.set(Some("That code did not work.".to_string())),
+                }
+            });
+        })
+    };
+
+    let disable = {
+        let enabled = enabled.clone();
+        let recovery_codes = recovery_codes.clone();
+        let code = code.clone();
+        let message = message.clone();
+        Callback::from(move |_| {
+            let enabled = enabled.clone();
+            let recovery_codes = recovery_codes.clone();
+            let code = code.clone();
+            let message = message.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                match post_no_content("/auth/2fa/disable", &CodeRequest { code: (*code).clone() }).await {
+                    Ok(()) => {
+                        enabled.set(Some(false));
+                        recovery_codes.set(Vec::new());
+                        code.set("".to_string());
+                        message.set(Some("Two-factor authentication is off.".to_string()));
+                    }
+                    Err(_) => message.set(Some("That code did not work.".to_string())),
+                }
+            });
+        })
+    };
+
+    let code_input = html! {
+        <input
+            type="text"
+            autocomplete="one-time-code"
+            placeholder="Code from your app"
+            value={(*code).clone()}
+            oninput={let c = code.clone(); Callback::from(move |e: InputEvent| c.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+        />
+    };
+
+    html! {
+        <div class="security-page">
+            <h2>{ "Security" }</h2>
+            <ChangePasswordForm />
+            <h3>{ "Two-Factor Authentication" }</h3>
+            <p>{ "Ask for a code from an authenticator app (such as Google Authenticator) every time you log in." }</p>
+            { if let Some(msg) = &*message {
+                html! { <p style="color: #2980b9;">{ msg }</p> }
+            } else {
+                html! {}
+            }}
+            { if !recovery_codes.is_empty() {
+                html! {
+                    <div class="recovery-codes">
+                        <h3>{ "Recovery Codes" }</h3>
+                        <p>{ "Each code logs you in once if you lose your phone. Write them down now; they will not be shown again." }</p>
+                        <ul style="font-family: monospace;">
+                            { for recovery_codes.iter().map(|c| html! { <li>{ c }</li> }) }
+                        </ul>
+                    </div>
+                }
+            } else {
+                html! {}
+            }}
+            { match (*enabled, &*enrollment) {
+                (None, _) => html! { <p>{ "Loading..." }</p> },
+                (Some(false), None) => html! {
+                    <button onclick={start_setup}>{ "Set up authenticator app" }</button>
+                },
+                (Some(false), Some(e)) => html! {
+                    <>
+                        <p>{ "Scan this code with your authenticator app, then enter the code it shows." }</p>
+                        { qr_code(&e.otpauth_uri) }
+                        <p>{ "Can't scan it? Enter this key instead: " }<code>{ &e.secret }</code></p>
+                        <form onsubmit={confirm}>
+                            { code_input }
+                            <button type="submit">{ "Turn on" }</button>
+                        </form>
+                    </>
+                },
+                (Some(true), _) => html! {
+                    <>
+                        <p>{ "Two-factor authentication is on. Enter a code from your app to change it." }</p>
+                        { code_input }
+                        <button onclick={new_recovery_codes}>{ "New recovery codes" }</button>
+                        <button onclick={disable} style="background: #c0392b; margin-left: 1rem;">{ "Turn off" }</button>
+                    </>
+                },
+            }}
+        </div>
+    }
+}
@@ -0,0 +1,90 @@
+use yew::prelude::*;
+use yew_router::prelude::*;
+use crate::Route;
+use crate::services::api::post;
+use crate::services::storage::{get_login_challenge, remove_login_challenge, set_session};
+use serde::{Deserialize, Serialize};
+use web_sys::HtmlInputElement;
+
+#[derive(Serialize)]
+struct TwoFactorLoginRequest {
+    challenge_token: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    token: String,
+    refresh_token: String,
+    user_id: String,
+}
+
+/// Second step of logging in, for members with two-factor authentication on.
+#[function_component(TwoFactorLogin)]
+pub fn two_factor_login() -> Html {
+    let navigator = use_navigator().unwrap();
+    let code = use_state(|| "".to_string());
+    let error = use_state(|| None::<String>);
+
+    let Some(challenge_token) = get_login_challenge() else {
+        return html! {
+            <div class="login-page">
+                <h2>{ "Two-Step Verification" }</h2>
+                <p>{ "Your login has timed out. " }<Link<Route> to={Route::Login}>{ "Log in again" }</Link<Route>></p>
+            </div>
+        };
+    };
+
+    let onsubmit = {
+        let code = code.clone();
+        let error = error.clone();
+        let navigator = navigator.clone();
+
+        Callback::from(move |e: SubmitEvent| {
+            e.prevent_default();
+            let challenge_token = challenge_token.clone();
+            let code_val = (*code).clone();
+            let error = error.clone();
+            let navigator = navigator.clone();
+
+            wasm_bindgen_futures::spawn_local(async move {
+                let res: Result<AuthResponse, String> = post("/auth/login/2fa", &TwoFactorLoginRequest {
+                    challenge_token,
+                    code: code_val,
+                }).await;
+
+                match res {
+                    Ok(auth) => {
+                        remove_login_challenge();
+                        set_session(&auth.token, &auth.refresh_token);
+                        navigator.push(&Route::Dashboard);
+                    }
+                    Err(_) => error.set(Some("That code did not work. Try the newest code from your app.".to_string())),
+                }
+            });
+        })
+    };
+
+    html! {
+        <div class="login-page">
+            <h2>{ "Two-Step Verification" }</h2>
+            <p>{ "Enter the 6-digit code from your authenticator app, or one of your recovery codes." }</p>
+            { if let Some(err) = &*error {
+                html! { <p style="color: red;">{ err }</p> }
+            } else {
+                html! {}
+            }}
+            <form {onsubmit}>
+                <input
+                    type="text"
+                    autocomplete="one-time-code"
+                    placeholder="Code"
+                    value={(*code).clone()}
+                    oninput={let c = code.clone(); Callback::from(move |e: InputEvent| c.set(e.target_unchecked_into::<HtmlInputElement>().value()))}
+                />
+                <button type="submit">{ "Verify" }</button>
+            </form>
+            <p><Link<Route> to={Route::Login}>{ "Back to login" }</Link<Route>></p>
+        </div>
+    }
+}
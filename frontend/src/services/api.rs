@@ -1,8 +1,23 @@
-use gloo_net::http::Request;
+use gloo_net::http::{Request, RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
-use crate::services::storage::{get_token, set_token};
+use crate::services::storage::{get_refresh_token, get_token, remove_token, set_session};
 
 const API_BASE_URL: &str = "http://127.0.0.1:8080/api";
+/// Times a POST is sent when the connection drops before an answer comes back. Every try
+/// carries the same `Idempotency-Key`, so a try the server already answered gets that answer
+/// back rather than running again.
+const MAX_POST_ATTEMPTS: u32 = 3;
+
+#[derive(Serialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenPair {
+    token: String,
+    refresh_token: String,
+}
 
 pub async fn post<T, R>(path: &str, body: &T) -> Result<R, String>
 where
@@ -10,21 +25,22 @@ where
     R: for<'de> Deserialize<'de>,
 {
     let url = format!("{}{}", API_BASE_URL, path);
-    let mut request = Request::post(&url);
+    let response = send_post(&url, body).await?;
 
-    if let Some(token) = get_token() {
-        request = request.header("Authorization", &format!("Bearer {}", token));
+    if response.ok() {
+        response.json::<R>().await.map_err(|e| e.to_string())
+    } else {
+        Err(format!("Error: {}", response.status()))
     }
+}
 
-    let response = request
-        .json(body)
-        .map_err(|e| e.to_string())?
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
+/// For endpoints that answer `204 No Content`.
+pub async fn post_no_content<T: Serialize>(path: &str, body: &T) -> Result<(), String> {
+    let url = format!("{}{}", API_BASE_URL, path);
+    let response = send_post(&url, body).await?;
 
     if response.ok() {
-        response.json::<R>().await.map_err(|e| e.to_string())
+        Ok(())
     } else {
         Err(format!("Error: {}", response.status()))
     }
@@ -35,16 +51,7 @@ where
     R: for<'de> Deserialize<'de>,
 {
     let url = format!("{}{}", API_BASE_URL, path);
-    let mut request = Request::get(&url);
-
-    if let Some(token) = get_token() {
-        request = request.header("Authorization", &format!("Bearer {}", token));
-    }
-
-    let response = request
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
+    let response = send(|| authorized(Request::get(&url)).build().map_err(|e| e.to_string())).await?;
 
     if response.ok() {
         response.json::<R>().await.map_err(|e| e.to_string())
@@ -52,3 +59,88 @@ where
         Err(format!("Error: {}", response.status()))
     }
 }
+
+/// Ends the session on the server too, so its refresh token stops working.
+pub async fn logout() {
+    if get_token().is_some() {
+        let url = format!("{}/auth/logout", API_BASE_URL);
+        let _ = authorized(Request::post(&url)).send().await;
+    }
+    remove_token();
+}
+
+fn authorized(request: RequestBuilder) -> RequestBuilder {
+    match get_token() {
+        Some(token) => request.header("Authorization", &format!("Bearer {}", token)),
+        None => request,
+    }
+}
+
+/// Sends a POST under a fresh idempotency key, sending it again with the same key if the
+/// connection fails.
+async fn send_post<T: Serialize>(url: &str, body: &T) -> Result<Response, String> {
+    let key = idempotency_key()?;
+    let mut attempt = 1;
+    loop {
+        let result = send(|| {
+            authorized(Request::post(url))
+                .header("Idempotency-Key", &key)
+                .json(body)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        match result {
+            Err(_) if attempt < MAX_POST_ATTEMPTS => attempt += 1,
+            result => return result,
+        }
+    }
+}
+
+/// 128 random bits from the browser, hex encoded.
+fn idempotency_key() -> Result<String, String> {
+    let crypto = web_sys::window()
+        .ok_or("No window")?
+        .crypto()
+        .map_err(|_| "Web Crypto is not available".to_string())?;
+    let mut bytes = [0u8; 16];
+    crypto
+        .get_random_values_with_u8_array(&mut bytes)
+        .map_err(|_| "Failed to generate an idempotency key".to_string())?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Sends a request, renewing the access token and trying once more if it has expired.
+async fn send(build: impl Fn() -> Result<Request, String>) -> Result<Response, String> {
+    let response = build()?.send().await.map_err(|e| e.to_string())?;
+    if response.status() == 401 && refresh_session().await {
+        return build()?.send().await.map_err(|e| e.to_string());
+    }
+    Ok(response)
+}
+
+/// Trades the stored refresh token for a new pair. Forgets the session when that fails.
+async fn refresh_session() -> bool {
+    let Some(refresh_token) = get_refresh_token() else {
+        return false;
+    };
+
+    let url = format!("{}/auth/refresh", API_BASE_URL);
+    let pair = match Request::post(&url).json(&RefreshRequest { refresh_token }) {
+        Ok(request) => match request.send().await {
+            Ok(response) if response.ok() => response.json::<TokenPair>().await.ok(),
+            _ => None,
+        },
+        Err(_) => None,
+    };
+
+    match pair {
+        Some(pair) => {
+            set_session(&pair.token, &pair.refresh_token);
+            true
+        }
+        None => {
+            remove_token();
+            false
+        }
+    }
+}
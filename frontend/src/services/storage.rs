@@ -1,17 +1,39 @@
-use gloo_storage::{LocalStorage, Storage};
+use gloo_storage::{LocalStorage, SessionStorage, Storage};
 
 const TOKEN_KEY: &str = "microfund_token";
+const REFRESH_TOKEN_KEY: &str = "microfund_refresh_token";
+const LOGIN_CHALLENGE_KEY: &str = "microfund_login_challenge";
 
-pub fn set_token(token: &str) {
+/// Keeps the access token and the refresh token that renews it.
+pub fn set_session(token: &str, refresh_token: &str) {
     let _ = LocalStorage::set(TOKEN_KEY, token);
+    let _ = LocalStorage::set(REFRESH_TOKEN_KEY, refresh_token);
 }
 
 pub fn get_token() -> Option<String> {
     LocalStorage::get(TOKEN_KEY).ok()
 }
 
+pub fn get_refresh_token() -> Option<String> {
+    LocalStorage::get(REFRESH_TOKEN_KEY).ok()
+}
+
 pub fn remove_token() {
     LocalStorage::delete(TOKEN_KEY);
+    LocalStorage::delete(REFRESH_TOKEN_KEY);
+}
+
+/// Holds a login waiting for its two-factor code. Kept for this tab only.
+pub fn set_login_challenge(challenge_token: &str) {
+    let _ = SessionStorage::set(LOGIN_CHALLENGE_KEY, challenge_token);
+}
+
+pub fn get_login_challenge() -> Option<String> {
+    SessionStorage::get(LOGIN_CHALLENGE_KEY).ok()
+}
+
+pub fn remove_login_challenge() {
+    SessionStorage::delete(LOGIN_CHALLENGE_KEY);
 }
 
 pub fn set_cache<T: serde::Serialize>(key: &str, data: &T) {
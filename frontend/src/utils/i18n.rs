@@ -17,6 +17,7 @@ const TRANSLATIONS: &[Translation] = &[
     Translation { key: "register", en: "Register", sw: "Jisajili" },
     Translation { key: "dashboard", en: "Dashboard", sw: "Dashibodi" },
     Translation { key: "logout", en: "Logout", sw: "Ondoka" },
+    Translation { key: "security", en: "Security", sw: "Usalama" },
     Translation { key: "trust_score", en: "Trust Score", sw: "Alama ya Imani" },
     Translation { key: "total_impact", en: "Total Impact", sw: "Jumla ya Athari" },
     Translation { key: "marketplace", en: "P2P Marketplace", sw: "Soko la P2P" },
@@ -0,0 +1,57 @@
+-- Migration for the Double-Entry Accounting Ledger
+-- Every movement of money is a journal entry made of postings that sum to zero.
+-- Postings are signed: positive amounts are debits, negative amounts are credits.
+
+CREATE TABLE IF NOT EXISTS ledger_accounts (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    code VARCHAR(100) UNIQUE NOT NULL, -- "PLATFORM_CASH", "SAVINGS:<savings_id>", "LOANS_RECEIVABLE:<user_id>"
+    name VARCHAR(255) NOT NULL,
+    account_type VARCHAR(20) NOT NULL, -- asset, liability, equity, income, expense
+    owner_id UUID REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT ledger_accounts_type_check
+        CHECK (account_type IN ('asset', 'liability', 'equity', 'income', 'expense'))
+);
+
+CREATE TABLE IF NOT EXISTS journal_entries (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    entry_type VARCHAR(100) NOT NULL, -- "LOAN_FUNDING", "REPAYMENT", "SAVINGS_DEPOSIT"
+    description TEXT NOT NULL,
+    reference_id UUID, -- loan or savings goal the entry belongs to
+    created_by UUID REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS postings (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    journal_entry_id UUID NOT NULL REFERENCES journal_entries(id),
+    account_id UUID NOT NULL REFERENCES ledger_accounts(id),
+    amount DECIMAL NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT postings_nonzero_check CHECK (amount <> 0)
+);
+
+CREATE INDEX IF NOT EXISTS idx_postings_journal_entry ON postings(journal_entry_id);
+CREATE INDEX IF NOT EXISTS idx_postings_account ON postings(account_id);
+
+-- Reject any transaction that leaves a journal entry unbalanced.
+-- The trigger is deferred so all postings of an entry can be inserted before the check runs at COMMIT.
+CREATE OR REPLACE FUNCTION check_journal_entry_balanced() RETURNS TRIGGER AS $$
+BEGIN
+    IF (SELECT COALESCE(SUM(amount), 0) FROM postings WHERE journal_entry_id = NEW.journal_entry_id) <> 0 THEN
+        RAISE EXCEPTION 'Journal entry % is unbalanced', NEW.journal_entry_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS postings_balanced ON postings;
+CREATE CONSTRAINT TRIGGER postings_balanced
+    AFTER INSERT OR UPDATE ON postings
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE FUNCTION check_journal_entry_balanced();
+
+-- System accounts
+INSERT INTO ledger_accounts (code, name, account_type)
+VALUES ('PLATFORM_CASH', 'Platform Cash (M-Pesa)', 'asset')
+ON CONFLICT (code) DO NOTHING;
@@ -0,0 +1,27 @@
+-- Migration to store money as exact integer minor units (cents) of the platform currency (KES)
+-- DECIMAL amounts were read back as float8 and lost cents; BIGINT maps 1:1 to `Money`.
+
+-- The marketplace view selects loans.* and blocks the column type change
+DROP VIEW IF EXISTS marketplace;
+
+ALTER TABLE loans ALTER COLUMN amount TYPE BIGINT USING ROUND(amount * 100)::BIGINT;
+
+ALTER TABLE savings ALTER COLUMN amount DROP DEFAULT;
+ALTER TABLE savings ALTER COLUMN amount TYPE BIGINT USING ROUND(amount * 100)::BIGINT;
+ALTER TABLE savings ALTER COLUMN amount SET DEFAULT 0;
+
+ALTER TABLE savings_transactions ALTER COLUMN amount TYPE BIGINT USING ROUND(amount * 100)::BIGINT;
+ALTER TABLE platform_transactions ALTER COLUMN amount TYPE BIGINT USING ROUND(amount * 100)::BIGINT;
+ALTER TABLE postings ALTER COLUMN amount TYPE BIGINT USING ROUND(amount * 100)::BIGINT;
+
+COMMENT ON COLUMN loans.amount IS 'Minor units (cents) of the platform currency';
+COMMENT ON COLUMN savings.amount IS 'Minor units (cents) of the platform currency';
+COMMENT ON COLUMN savings_transactions.amount IS 'Minor units (cents) of the platform currency';
+COMMENT ON COLUMN platform_transactions.amount IS 'Minor units (cents) of the platform currency';
+COMMENT ON COLUMN postings.amount IS 'Minor units (cents) of the platform currency; positive = debit, negative = credit';
+
+CREATE OR REPLACE VIEW marketplace AS
+SELECT l.*, u.username as borrower_username
+FROM loans l
+JOIN users u ON l.user_id = u.id
+WHERE l.status = 'pending';
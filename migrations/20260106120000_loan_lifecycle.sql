@@ -0,0 +1,28 @@
+-- Migration for the Loan Lifecycle State Machine
+-- "approved" meant "funded and handed to the borrower"; it is now "disbursed".
+UPDATE loans SET status = 'disbursed' WHERE status = 'approved';
+
+ALTER TABLE loans DROP CONSTRAINT IF EXISTS loans_status_check;
+ALTER TABLE loans ADD CONSTRAINT loans_status_check CHECK (status IN (
+    'pending', 'funded', 'disbursed', 'in_repayment', 'repaid',
+    'overdue', 'defaulted', 'cancelled', 'written_off'
+));
+
+-- Every status change, who made it and when
+CREATE TABLE IF NOT EXISTS loan_status_history (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    loan_id UUID NOT NULL REFERENCES loans(id),
+    from_status VARCHAR(50), -- NULL when the loan was created
+    to_status VARCHAR(50) NOT NULL,
+    actor_id UUID REFERENCES users(id), -- NULL for system jobs
+    note TEXT,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_loan_status_history_loan ON loan_status_history(loan_id, created_at);
+
+-- Backfill the current status of existing loans as their first history entry
+INSERT INTO loan_status_history (loan_id, from_status, to_status, actor_id, note, created_at)
+SELECT id, NULL, status, NULL, 'Backfilled from existing loan', created_at
+FROM loans
+WHERE NOT EXISTS (SELECT 1 FROM loan_status_history h WHERE h.loan_id = loans.id);
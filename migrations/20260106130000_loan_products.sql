@@ -0,0 +1,56 @@
+-- Migration for Loan Products and Repayment Schedules
+-- Rates are annual, in basis points (2400 = 24% a year).
+CREATE TABLE IF NOT EXISTS loan_products (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    code VARCHAR(50) UNIQUE NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    interest_method VARCHAR(20) NOT NULL CHECK (interest_method IN ('flat', 'declining_balance')),
+    interest_rate_bps INTEGER NOT NULL CHECK (interest_rate_bps >= 0),
+    origination_fee_bps INTEGER NOT NULL DEFAULT 0 CHECK (origination_fee_bps >= 0),
+    term_count INTEGER NOT NULL CHECK (term_count > 0),
+    repayment_frequency VARCHAR(20) NOT NULL CHECK (repayment_frequency IN ('weekly', 'monthly')),
+    is_default BOOLEAN NOT NULL DEFAULT FALSE,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- At most one product is picked when a borrower does not choose one
+CREATE UNIQUE INDEX IF NOT EXISTS idx_loan_products_default ON loan_products(is_default) WHERE is_default;
+
+INSERT INTO loan_products (code, name, interest_method, interest_rate_bps, origination_fee_bps, term_count, repayment_frequency, is_default)
+VALUES
+    ('MARKET_WEEKLY', 'Market Trader (8 weeks)', 'flat', 2400, 100, 8, 'weekly', TRUE),
+    ('BUSINESS_MONTHLY', 'Small Business (6 months)', 'declining_balance', 1800, 150, 6, 'monthly', FALSE)
+ON CONFLICT (code) DO NOTHING;
+
+-- Terms are copied onto the loan so product changes never alter an existing schedule
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS product_id UUID REFERENCES loan_products(id);
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS interest_method VARCHAR(20) NOT NULL DEFAULT 'flat';
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS interest_rate_bps INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS origination_fee_bps INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS term_count INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS repayment_frequency VARCHAR(20) NOT NULL DEFAULT 'monthly';
+
+-- due_date is now the maturity of the schedule and is set explicitly
+ALTER TABLE loans ALTER COLUMN due_date DROP DEFAULT;
+
+CREATE TABLE IF NOT EXISTS loan_installments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    loan_id UUID NOT NULL REFERENCES loans(id),
+    installment_number INTEGER NOT NULL CHECK (installment_number > 0),
+    due_date TIMESTAMPTZ NOT NULL,
+    principal_due BIGINT NOT NULL CHECK (principal_due >= 0),
+    interest_due BIGINT NOT NULL CHECK (interest_due >= 0),
+    fee_due BIGINT NOT NULL CHECK (fee_due >= 0),
+    total_due BIGINT NOT NULL CHECK (total_due = principal_due + interest_due + fee_due),
+    paid_at TIMESTAMPTZ,
+    UNIQUE (loan_id, installment_number)
+);
+
+CREATE INDEX IF NOT EXISTS idx_loan_installments_due ON loan_installments(due_date) WHERE paid_at IS NULL;
+
+-- Existing loans become a single interest-free installment due at their old due date
+INSERT INTO loan_installments (loan_id, installment_number, due_date, principal_due, interest_due, fee_due, total_due, paid_at)
+SELECT id, 1, COALESCE(due_date, created_at + INTERVAL '30 days', NOW() + INTERVAL '30 days'), amount, 0, 0, amount, repaid_at
+FROM loans
+WHERE NOT EXISTS (SELECT 1 FROM loan_installments i WHERE i.loan_id = loans.id);
@@ -0,0 +1,44 @@
+-- Migration for Partial Repayments
+-- Installments track what has been paid against each component. Penalties are
+-- assessed onto installments once they fall overdue.
+ALTER TABLE loan_installments ADD COLUMN IF NOT EXISTS penalty_due BIGINT NOT NULL DEFAULT 0 CHECK (penalty_due >= 0);
+ALTER TABLE loan_installments ADD COLUMN IF NOT EXISTS principal_paid BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE loan_installments ADD COLUMN IF NOT EXISTS interest_paid BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE loan_installments ADD COLUMN IF NOT EXISTS fee_paid BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE loan_installments ADD COLUMN IF NOT EXISTS penalty_paid BIGINT NOT NULL DEFAULT 0;
+
+-- Installments settled by a full repayment before this migration
+UPDATE loan_installments
+SET principal_paid = principal_due, interest_paid = interest_due, fee_paid = fee_due
+WHERE paid_at IS NOT NULL;
+
+-- The original total_due check spans several columns, so Postgres named it loan_installments_check
+ALTER TABLE loan_installments DROP CONSTRAINT IF EXISTS loan_installments_check;
+ALTER TABLE loan_installments ADD CONSTRAINT loan_installments_total_due_check
+    CHECK (total_due = principal_due + interest_due + fee_due + penalty_due);
+ALTER TABLE loan_installments ADD CONSTRAINT loan_installments_paid_check CHECK (
+    principal_paid BETWEEN 0 AND principal_due
+    AND interest_paid BETWEEN 0 AND interest_due
+    AND fee_paid BETWEEN 0 AND fee_due
+    AND penalty_paid BETWEEN 0 AND penalty_due
+);
+
+-- Every payment received against a loan and how it was allocated
+CREATE TABLE IF NOT EXISTS loan_repayments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    loan_id UUID NOT NULL REFERENCES loans(id),
+    payer_id UUID REFERENCES users(id), -- NULL when received outside the app
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    principal_paid BIGINT NOT NULL DEFAULT 0,
+    interest_paid BIGINT NOT NULL DEFAULT 0,
+    fee_paid BIGINT NOT NULL DEFAULT 0,
+    penalty_paid BIGINT NOT NULL DEFAULT 0,
+    overpayment BIGINT NOT NULL DEFAULT 0,
+    overpayment_destination VARCHAR(20) CHECK (overpayment_destination IN ('savings', 'refund')),
+    savings_id UUID REFERENCES savings(id),
+    outstanding_after BIGINT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    CHECK (amount = principal_paid + interest_paid + fee_paid + penalty_paid + overpayment)
+);
+
+CREATE INDEX IF NOT EXISTS idx_loan_repayments_loan ON loan_repayments(loan_id, created_at);
@@ -0,0 +1,23 @@
+-- Migration for the Background Job Scheduler
+-- One row per recurring job. next_run_at survives restarts; locked_until is a lease so
+-- only one backend instance runs a job at a time, and a crashed run is retried once it expires.
+CREATE TABLE IF NOT EXISTS scheduled_jobs (
+    name VARCHAR(100) PRIMARY KEY,
+    interval_seconds INTEGER NOT NULL CHECK (interval_seconds > 0),
+    next_run_at TIMESTAMPTZ NOT NULL,
+    locked_until TIMESTAMPTZ,
+    last_started_at TIMESTAMPTZ,
+    last_finished_at TIMESTAMPTZ,
+    last_status VARCHAR(20) CHECK (last_status IN ('succeeded', 'failed')),
+    last_error TEXT,
+    last_affected BIGINT,
+    run_count BIGINT NOT NULL DEFAULT 0
+);
+
+-- Delinquency tracking on installments
+ALTER TABLE loan_installments ADD COLUMN IF NOT EXISTS overdue_at TIMESTAMPTZ;
+ALTER TABLE loan_installments ADD COLUMN IF NOT EXISTS penalty_assessed_at TIMESTAMPTZ;
+
+-- The original total_due check (auto-named loan_installments_check) predates penalties and was
+-- left behind when loan_installments_total_due_check replaced it; it rejects any penalty.
+ALTER TABLE loan_installments DROP CONSTRAINT IF EXISTS loan_installments_check;
@@ -0,0 +1,21 @@
+-- Migration for the Credit Scoring Engine
+-- Every computed score with the factor breakdown, weights and inputs behind it
+CREATE TABLE IF NOT EXISTS credit_score_snapshots (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    score INTEGER NOT NULL CHECK (score BETWEEN 0 AND 1000),
+    credit_limit BIGINT NOT NULL,
+    factors JSONB NOT NULL,
+    weights JSONB NOT NULL,
+    inputs JSONB NOT NULL,
+    reason VARCHAR(50) NOT NULL, -- loan_request, loan_repaid, loan_overdue, loan_defaulted, profile_request
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_credit_score_snapshots_user ON credit_score_snapshots(user_id, created_at DESC);
+
+-- reputation_score now caches the latest credit score (0 to 1000). Members start with
+-- no score until their first scoring.
+ALTER TABLE users ALTER COLUMN reputation_score SET DEFAULT 0;
+UPDATE users SET reputation_score = 0 WHERE reputation_score IS NULL;
+ALTER TABLE users ALTER COLUMN reputation_score SET NOT NULL;
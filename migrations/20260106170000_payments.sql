@@ -0,0 +1,27 @@
+-- Migration for M-Pesa Payments
+-- Every STK push we send. A deposit only reaches its savings goal once its payment
+-- is completed, either by the Daraja callback or by polling STK query.
+CREATE TABLE IF NOT EXISTS payments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    purpose VARCHAR(30) NOT NULL CHECK (purpose IN ('savings_deposit')),
+    savings_id UUID REFERENCES savings(id),
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    phone_number VARCHAR(12) NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'completed', 'failed')),
+    checkout_request_id VARCHAR(100) UNIQUE, -- NULL until Daraja accepts the push
+    merchant_request_id VARCHAR(100),
+    result_code VARCHAR(20),
+    result_desc TEXT,
+    mpesa_receipt_number VARCHAR(30) UNIQUE,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    settled_at TIMESTAMPTZ,
+    CHECK (purpose <> 'savings_deposit' OR savings_id IS NOT NULL)
+);
+
+CREATE INDEX IF NOT EXISTS idx_payments_pending ON payments(created_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS idx_payments_user ON payments(user_id, created_at DESC);
+
+-- Savings movements point at the payment that funded them
+ALTER TABLE savings_transactions ADD COLUMN IF NOT EXISTS payment_id UUID UNIQUE REFERENCES payments(id);
@@ -0,0 +1,30 @@
+-- Migration for M-Pesa Loan Disbursements
+-- Every B2C payout attempt of a funded loan. A loan only becomes `disbursed` once
+-- Daraja confirms its payout; failed attempts are retried and eventually given up on.
+CREATE TABLE IF NOT EXISTS loan_disbursements (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    loan_id UUID NOT NULL REFERENCES loans(id),
+    attempt INTEGER NOT NULL CHECK (attempt > 0), -- counted from the latest funding of the loan
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    phone_number VARCHAR(12) NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'completed', 'failed')),
+    -- Secret part of the result and timeout URLs handed to Daraja for this attempt
+    callback_token VARCHAR(64) NOT NULL UNIQUE,
+    conversation_id VARCHAR(100), -- NULL until Daraja accepts the request
+    transaction_id VARCHAR(30) UNIQUE,
+    result_code VARCHAR(20),
+    result_desc TEXT,
+    -- When a failed attempt should be followed by the next one; NULL once given up on
+    retry_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    settled_at TIMESTAMPTZ
+);
+
+-- At most one payout of a loan in flight at a time
+CREATE UNIQUE INDEX IF NOT EXISTS idx_loan_disbursements_one_pending ON loan_disbursements(loan_id) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS idx_loan_disbursements_loan ON loan_disbursements(loan_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_loan_disbursements_retry ON loan_disbursements(retry_at) WHERE retry_at IS NOT NULL;
+
+-- Number the borrower receives the loan on
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS disbursement_phone VARCHAR(12);
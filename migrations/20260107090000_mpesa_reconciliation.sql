@@ -0,0 +1,62 @@
+-- Migration for M-Pesa Statement Reconciliation
+-- Organisation statements exported from the M-Pesa portal are imported line by line and
+-- matched against what we recorded: deposits, loan payouts and repayments.
+
+-- Platform operators, who import statements and resolve reconciliation exceptions
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Repayments made to the paybill outside the app carry the receipt the borrower gave us
+ALTER TABLE loan_repayments ADD COLUMN IF NOT EXISTS mpesa_receipt_number VARCHAR(30) UNIQUE;
+
+CREATE TABLE IF NOT EXISTS mpesa_statements (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    file_name VARCHAR(255),
+    imported_by UUID REFERENCES users(id),
+    period_start TIMESTAMPTZ,
+    period_end TIMESTAMPTZ,
+    line_count INTEGER NOT NULL DEFAULT 0,
+    -- Lines already imported with an earlier, overlapping statement
+    duplicate_count INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    reconciled_at TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS mpesa_statement_lines (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    statement_id UUID NOT NULL REFERENCES mpesa_statements(id),
+    receipt_number VARCHAR(30) NOT NULL UNIQUE,
+    completed_at TIMESTAMPTZ NOT NULL,
+    details TEXT,
+    transaction_status VARCHAR(30) NOT NULL,
+    paid_in BIGINT NOT NULL DEFAULT 0 CHECK (paid_in >= 0),
+    withdrawn BIGINT NOT NULL DEFAULT 0 CHECK (withdrawn >= 0),
+    other_party TEXT,
+    phone_number VARCHAR(12), -- NULL when the statement masks or omits it
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_mpesa_statement_lines_statement ON mpesa_statement_lines(statement_id, completed_at);
+
+CREATE TABLE IF NOT EXISTS reconciliation_items (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    statement_id UUID NOT NULL REFERENCES mpesa_statements(id),
+    kind VARCHAR(30) NOT NULL CHECK (kind IN ('matched', 'missing_internally', 'missing_in_statement', 'mismatched')),
+    status VARCHAR(20) NOT NULL CHECK (status IN ('matched', 'open', 'resolved')),
+    receipt_number VARCHAR(30),
+    statement_line_id UUID REFERENCES mpesa_statement_lines(id),
+    payment_id UUID REFERENCES payments(id),
+    disbursement_id UUID REFERENCES loan_disbursements(id),
+    repayment_id UUID REFERENCES loan_repayments(id),
+    statement_amount BIGINT,
+    internal_amount BIGINT,
+    detail TEXT NOT NULL,
+    resolution VARCHAR(30) CHECK (resolution IN ('adjusted', 'written_off', 'not_an_issue')),
+    resolution_note TEXT,
+    resolved_by UUID REFERENCES users(id),
+    resolved_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    CHECK (status <> 'resolved' OR resolution IS NOT NULL)
+);
+
+CREATE INDEX IF NOT EXISTS idx_reconciliation_items_statement ON reconciliation_items(statement_id, kind);
+CREATE INDEX IF NOT EXISTS idx_reconciliation_items_open ON reconciliation_items(created_at) WHERE status = 'open';
@@ -0,0 +1,33 @@
+-- Migration for Savings Goals and Withdrawals
+-- Goals get an optional target and lock, and members can take their savings back out
+-- over M-Pesa. A withdrawal only leaves the goal once Daraja confirms its B2C payout.
+ALTER TABLE savings ADD COLUMN IF NOT EXISTS target_amount BIGINT CHECK (target_amount > 0);
+ALTER TABLE savings ADD COLUMN IF NOT EXISTS target_date DATE;
+-- Withdrawals are refused before this date
+ALTER TABLE savings ADD COLUMN IF NOT EXISTS locked_until DATE;
+
+CREATE TABLE IF NOT EXISTS savings_withdrawals (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    savings_id UUID NOT NULL REFERENCES savings(id),
+    user_id UUID NOT NULL REFERENCES users(id),
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    phone_number VARCHAR(12) NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'completed', 'failed')),
+    -- Secret part of the result and timeout URLs handed to Daraja for this payout
+    callback_token VARCHAR(64) NOT NULL UNIQUE,
+    conversation_id VARCHAR(100), -- NULL until Daraja accepts the request
+    transaction_id VARCHAR(30) UNIQUE,
+    result_code VARCHAR(20),
+    result_desc TEXT,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    settled_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_savings_withdrawals_savings ON savings_withdrawals(savings_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_savings_withdrawals_pending ON savings_withdrawals(created_at) WHERE status = 'pending';
+
+ALTER TABLE savings_transactions ADD COLUMN IF NOT EXISTS withdrawal_id UUID UNIQUE REFERENCES savings_withdrawals(id);
+
+-- Withdrawals show up in M-Pesa statements like loan payouts do
+ALTER TABLE reconciliation_items ADD COLUMN IF NOT EXISTS withdrawal_id UUID REFERENCES savings_withdrawals(id);
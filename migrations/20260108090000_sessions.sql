@@ -0,0 +1,29 @@
+-- Migration for Sessions and Refresh Tokens
+-- Each login opens a session. Access tokens are short-lived; the session is kept alive by a
+-- refresh token that is replaced on every use. Only SHA-256 hashes of refresh tokens are stored.
+CREATE TABLE IF NOT EXISTS sessions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    user_agent TEXT,
+    ip_address VARCHAR(45),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    -- The session cannot be refreshed past this, however active it is
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    revoked_reason VARCHAR(30) CHECK (revoked_reason IN ('logout', 'revoked', 'refresh_token_reuse'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions(user_id, created_at) WHERE revoked_at IS NULL;
+
+-- Every refresh token a session has been given. A token that was already rotated coming back
+-- means it was stolen, so the whole session is revoked.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    session_id UUID NOT NULL REFERENCES sessions(id),
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    rotated_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_session ON refresh_tokens(session_id);
@@ -0,0 +1,22 @@
+-- Migration for Phone Sign-up and One-Time Codes
+-- Members can sign up and log in with a mobile number confirmed by an SMS code, so email and
+-- password become optional.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS phone_number VARCHAR(12) UNIQUE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS phone_verified_at TIMESTAMPTZ;
+ALTER TABLE users ALTER COLUMN email DROP NOT NULL;
+ALTER TABLE users ALTER COLUMN password_hash DROP NOT NULL;
+ALTER TABLE users ADD CONSTRAINT users_email_or_phone CHECK (email IS NOT NULL OR phone_number IS NOT NULL);
+
+CREATE TABLE IF NOT EXISTS otp_codes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    phone_number VARCHAR(12) NOT NULL,
+    purpose VARCHAR(20) NOT NULL CHECK (purpose IN ('signup', 'login')),
+    -- Argon2 hash; the code itself only ever goes out by SMS
+    code_hash VARCHAR(255) NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ NOT NULL,
+    consumed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_otp_codes_phone ON otp_codes(phone_number, purpose, created_at);
@@ -0,0 +1,20 @@
+-- Migration for Password Reset and Change
+-- A forgotten password is reset with a single-use token sent to the member's phone or email.
+-- Only SHA-256 hashes of the tokens are stored.
+CREATE TABLE IF NOT EXISTS password_resets (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    -- Where the token was sent
+    channel VARCHAR(10) NOT NULL CHECK (channel IN ('sms', 'email')),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_password_resets_user ON password_resets(user_id, created_at);
+
+-- A new password signs out the member's other sessions
+ALTER TABLE sessions DROP CONSTRAINT IF EXISTS sessions_revoked_reason_check;
+ALTER TABLE sessions ADD CONSTRAINT sessions_revoked_reason_check
+    CHECK (revoked_reason IN ('logout', 'revoked', 'refresh_token_reuse', 'password_change'));
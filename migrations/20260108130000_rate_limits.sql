@@ -0,0 +1,14 @@
+-- Migration for Rate Limiting and Login Lockout
+-- Token buckets shared by every instance when RATE_LIMIT_STORE=postgres. A bucket holds up to
+-- the route group's burst of tokens and refills over time; each request takes one.
+CREATE TABLE IF NOT EXISTS rate_limit_buckets (
+    key VARCHAR(200) PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_rate_limit_buckets_updated ON rate_limit_buckets(updated_at);
+
+-- Failed logins in a row. Past a few, the account is locked for longer after each one.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS failed_logins INT NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ;
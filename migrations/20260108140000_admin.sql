@@ -0,0 +1,72 @@
+-- Migration for Roles, the Admin API and the Audit Log
+-- Staff roles on top of being a member. Replaces users.is_admin.
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id UUID NOT NULL REFERENCES users(id),
+    role VARCHAR(30) NOT NULL CHECK (role IN ('admin', 'loan_officer', 'auditor')),
+    granted_by UUID REFERENCES users(id), -- NULL when granted outside the app
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, role)
+);
+
+INSERT INTO user_roles (user_id, role)
+SELECT id, 'admin' FROM users WHERE is_admin
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE users DROP COLUMN IF EXISTS is_admin;
+
+-- Frozen members cannot log in, and their sessions are ended
+ALTER TABLE users ADD COLUMN IF NOT EXISTS frozen_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS frozen_reason TEXT;
+
+ALTER TABLE sessions DROP CONSTRAINT IF EXISTS sessions_revoked_reason_check;
+ALTER TABLE sessions ADD CONSTRAINT sessions_revoked_reason_check
+    CHECK (revoked_reason IN ('logout', 'revoked', 'refresh_token_reuse', 'password_change', 'account_frozen'));
+
+-- Loans above LOAN_APPROVAL_ABOVE stay off the marketplace until a loan officer approves them
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS requires_approval BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS approved_by UUID REFERENCES users(id);
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS approved_at TIMESTAMPTZ;
+
+-- A reversal is a negative savings movement pointing at the deposit it undoes; each deposit
+-- is reversed at most once
+ALTER TABLE savings_transactions ADD COLUMN IF NOT EXISTS reverses_id UUID UNIQUE REFERENCES savings_transactions(id);
+
+-- Manual corrections to credit scores, added on top of the computed score at every rescoring
+CREATE TABLE IF NOT EXISTS score_adjustments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    points INTEGER NOT NULL CHECK (points <> 0 AND points BETWEEN -1000 AND 1000),
+    reason TEXT NOT NULL,
+    created_by UUID NOT NULL REFERENCES users(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_score_adjustments_user ON score_adjustments(user_id);
+
+-- Every admin action: who did what to which record, and why
+CREATE TABLE IF NOT EXISTS admin_audit_log (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    actor_id UUID NOT NULL REFERENCES users(id),
+    action VARCHAR(50) NOT NULL,
+    target_type VARCHAR(50) NOT NULL,
+    target_id UUID,
+    reason TEXT,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_admin_audit_log_created ON admin_audit_log(created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_admin_audit_log_target ON admin_audit_log(target_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_admin_audit_log_actor ON admin_audit_log(actor_id, created_at DESC);
+
+-- The log is append-only, including for the admins it records
+CREATE OR REPLACE FUNCTION reject_audit_log_change() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'admin_audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS admin_audit_log_append_only ON admin_audit_log;
+CREATE TRIGGER admin_audit_log_append_only
+    BEFORE UPDATE OR DELETE ON admin_audit_log
+    FOR EACH ROW EXECUTE FUNCTION reject_audit_log_change();
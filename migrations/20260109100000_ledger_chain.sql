@@ -0,0 +1,43 @@
+-- Migration for Hash-Chained Platform Ledger
+-- Entries are sealed in order by the seal_ledger job: numbered, hashed over their content and
+-- the previous entry's hash, and signed with the server's Ed25519 key. The signatures stored
+-- until now were placeholders and are dropped; those entries are sealed on the first run.
+ALTER TABLE platform_transactions ALTER COLUMN signature DROP NOT NULL;
+UPDATE platform_transactions SET signature = NULL, created_at = COALESCE(created_at, CURRENT_TIMESTAMP);
+ALTER TABLE platform_transactions ALTER COLUMN created_at SET NOT NULL;
+
+ALTER TABLE platform_transactions
+    ADD COLUMN IF NOT EXISTS sequence_number BIGINT UNIQUE,
+    ADD COLUMN IF NOT EXISTS prev_hash CHAR(64),
+    ADD COLUMN IF NOT EXISTS entry_hash CHAR(64),
+    ADD COLUMN IF NOT EXISTS sealed_at TIMESTAMPTZ;
+
+ALTER TABLE platform_transactions DROP CONSTRAINT IF EXISTS platform_transactions_seal_check;
+ALTER TABLE platform_transactions ADD CONSTRAINT platform_transactions_seal_check CHECK (
+    (sequence_number IS NULL AND prev_hash IS NULL AND entry_hash IS NULL AND signature IS NULL AND sealed_at IS NULL)
+    OR (sequence_number > 0 AND prev_hash IS NOT NULL AND entry_hash IS NOT NULL AND signature IS NOT NULL AND sealed_at IS NOT NULL)
+);
+
+CREATE INDEX IF NOT EXISTS idx_platform_transactions_unsealed
+    ON platform_transactions (created_at, id) WHERE sequence_number IS NULL;
+
+-- Entries are never changed or deleted. The one update allowed is sealing an unsealed entry,
+-- which fills in the seal columns and leaves the content alone.
+CREATE OR REPLACE FUNCTION reject_platform_transaction_change() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.sequence_number IS NULL
+       AND NEW.id = OLD.id
+       AND NEW.activity_type = OLD.activity_type
+       AND NEW.description = OLD.description
+       AND NEW.amount = OLD.amount
+       AND NEW.created_at = OLD.created_at THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'platform_transactions is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS platform_transactions_append_only ON platform_transactions;
+CREATE TRIGGER platform_transactions_append_only
+    BEFORE UPDATE OR DELETE ON platform_transactions
+    FOR EACH ROW EXECUTE FUNCTION reject_platform_transaction_change();
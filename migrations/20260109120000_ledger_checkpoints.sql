@@ -0,0 +1,41 @@
+-- Migration for Ledger Checkpoints
+-- Signed Merkle roots over consecutive runs of sealed platform_transactions, each published
+-- through the configured anchor backend.
+CREATE TABLE IF NOT EXISTS ledger_checkpoints (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    first_sequence_number BIGINT NOT NULL UNIQUE,
+    last_sequence_number BIGINT NOT NULL UNIQUE,
+    merkle_root CHAR(64) NOT NULL,
+    signature VARCHAR(128) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    anchor_backend VARCHAR(20),
+    anchor_reference TEXT,
+    anchored_at TIMESTAMPTZ,
+    anchor_attempts INT NOT NULL DEFAULT 0,
+    last_anchor_error TEXT,
+    CHECK (first_sequence_number > 0 AND last_sequence_number >= first_sequence_number)
+);
+
+CREATE INDEX IF NOT EXISTS idx_ledger_checkpoints_unanchored
+    ON ledger_checkpoints (last_sequence_number) WHERE anchored_at IS NULL;
+
+-- Roots never change once signed; only the anchoring columns are filled in.
+CREATE OR REPLACE FUNCTION reject_ledger_checkpoint_change() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.anchored_at IS NULL
+       AND NEW.id = OLD.id
+       AND NEW.first_sequence_number = OLD.first_sequence_number
+       AND NEW.last_sequence_number = OLD.last_sequence_number
+       AND NEW.merkle_root = OLD.merkle_root
+       AND NEW.signature = OLD.signature
+       AND NEW.created_at = OLD.created_at THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'ledger_checkpoints is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS ledger_checkpoints_append_only ON ledger_checkpoints;
+CREATE TRIGGER ledger_checkpoints_append_only
+    BEFORE UPDATE OR DELETE ON ledger_checkpoints
+    FOR EACH ROW EXECUTE FUNCTION reject_ledger_checkpoint_change();
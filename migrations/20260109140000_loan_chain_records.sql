@@ -0,0 +1,12 @@
+-- Migration for Solana Loan Records
+-- Where each loan is recorded by the microfund program, and the state of the job putting it there.
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS chain_account VARCHAR(44);
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS chain_loan_signature VARCHAR(88);
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS chain_repay_signature VARCHAR(88);
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS chain_attempts INT NOT NULL DEFAULT 0;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS chain_next_attempt_at TIMESTAMPTZ;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS chain_last_error TEXT;
+
+-- Loans the sync job still has to record or mark repaid
+CREATE INDEX IF NOT EXISTS idx_loans_chain_pending ON loans (chain_next_attempt_at)
+    WHERE chain_loan_signature IS NULL OR (status = 'repaid' AND chain_repay_signature IS NULL);
@@ -0,0 +1,17 @@
+-- Migration for Idempotency Keys
+-- The first answer to each `Idempotency-Key` a member sends on a money-moving request, replayed
+-- when the client retries. `response_status` stays NULL while the first request is running.
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    key VARCHAR(255) NOT NULL,
+    -- SHA-256 of the method, path and body, so the key cannot be reused for another request
+    fingerprint VARCHAR(64) NOT NULL,
+    response_status SMALLINT,
+    response_content_type VARCHAR(100),
+    response_body BYTEA,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    completed_at TIMESTAMPTZ,
+    PRIMARY KEY (user_id, key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_created ON idempotency_keys(created_at);
@@ -0,0 +1,16 @@
+-- Migration for Collected Loan Repayments
+-- Members repay with an STK push like a deposit; the repayment is only applied to the loan
+-- once M-Pesa confirms the payment.
+ALTER TABLE payments DROP CONSTRAINT IF EXISTS payments_purpose_check;
+ALTER TABLE payments ADD CONSTRAINT payments_purpose_check CHECK (purpose IN ('savings_deposit', 'loan_repayment'));
+
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS loan_id UUID REFERENCES loans(id);
+-- Where a repayment beyond the outstanding balance goes; `savings_id` then names the goal
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS overpayment_destination VARCHAR(20)
+    CHECK (overpayment_destination IN ('savings', 'refund'));
+
+ALTER TABLE payments DROP CONSTRAINT IF EXISTS payments_loan_repayment_check;
+ALTER TABLE payments ADD CONSTRAINT payments_loan_repayment_check CHECK (purpose <> 'loan_repayment' OR loan_id IS NOT NULL);
+
+-- The payment a repayment was collected with; NULL for paybill payments recorded by staff
+ALTER TABLE loan_repayments ADD COLUMN IF NOT EXISTS payment_id UUID UNIQUE REFERENCES payments(id);
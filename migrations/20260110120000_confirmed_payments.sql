@@ -0,0 +1,4 @@
+-- Migration for Confirmed Payments Awaiting a Receipt
+-- An STK query can confirm a payment without giving its receipt. Such a payment stays pending
+-- until an M-Pesa statement line supplies the receipt, and is never expired meanwhile.
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS confirmed_at TIMESTAMPTZ;
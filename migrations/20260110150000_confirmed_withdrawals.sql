@@ -0,0 +1,4 @@
+-- Migration for Withdrawing Only Confirmed Savings
+-- Only money M-Pesa confirmed can be withdrawn: deposits with a receipt, and overpayments of
+-- repayments with a receipt. This links an overpayment credit to the repayment it came from.
+ALTER TABLE savings_transactions ADD COLUMN IF NOT EXISTS repayment_id UUID UNIQUE REFERENCES loan_repayments(id);
@@ -0,0 +1,8 @@
+-- Migration for Unacknowledged Withdrawals
+-- A payout request that failed in transit may still have reached Daraja. Instead of releasing
+-- its hold, such a withdrawal is flagged and left to the M-Pesa statement: a matching line
+-- completes it, a statement covering its time without one releases the hold.
+ALTER TABLE savings_withdrawals ADD COLUMN IF NOT EXISTS unacknowledged_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_savings_withdrawals_unacknowledged ON savings_withdrawals(created_at)
+    WHERE status = 'pending' AND unacknowledged_at IS NOT NULL;
@@ -0,0 +1,5 @@
+-- Migration for Role Revocation Sessions
+-- Access tokens carry the member's roles, so taking a role away ends the member's sessions.
+ALTER TABLE sessions DROP CONSTRAINT IF EXISTS sessions_revoked_reason_check;
+ALTER TABLE sessions ADD CONSTRAINT sessions_revoked_reason_check
+    CHECK (revoked_reason IN ('logout', 'revoked', 'refresh_token_reuse', 'password_change', 'account_frozen', 'role_revoked'));
@@ -0,0 +1,6 @@
+-- Migration for Idempotency Key Leases
+-- A request holds its key until `locked_until`. If it dies without answering (the server
+-- restarted mid-request), a retry with the same key and body takes the key over once the
+-- lease runs out instead of getting `409` until the key expires. Keys claimed before this
+-- column existed have no lease and can be taken over straight away.
+ALTER TABLE idempotency_keys ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ;
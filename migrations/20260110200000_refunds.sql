@@ -0,0 +1,36 @@
+-- Migration for Refunds
+-- Money owed back to a member, such as a loan overpayment they asked to have refunded, is paid
+-- out to them over M-Pesa B2C. A refund is retried with backoff while Daraja turns its payouts
+-- down; a payout Daraja never answers is left to the M-Pesa statement, like a withdrawal.
+CREATE TABLE IF NOT EXISTS refunds (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    phone_number VARCHAR(12) NOT NULL,
+    reason TEXT NOT NULL,
+    loan_id UUID REFERENCES loans(id),
+    repayment_id UUID UNIQUE REFERENCES loan_repayments(id),
+    payment_id UUID REFERENCES payments(id),
+    status VARCHAR(20) NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'completed', 'failed')),
+    -- Payouts sent so far; Daraja sees attempt n as "<id>-<n>"
+    attempt INT NOT NULL DEFAULT 0,
+    -- Secret part of the result and timeout URLs handed to Daraja for this refund
+    callback_token VARCHAR(64) NOT NULL UNIQUE,
+    conversation_id VARCHAR(100), -- NULL until Daraja accepts the payout in flight
+    transaction_id VARCHAR(30) UNIQUE,
+    result_code VARCHAR(20),
+    result_desc TEXT,
+    -- Set while a payout waits for its result
+    sent_at TIMESTAMPTZ,
+    retry_at TIMESTAMPTZ,
+    unacknowledged_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    settled_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_refunds_user ON refunds(user_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_refunds_pending ON refunds(created_at) WHERE status = 'pending';
+
+-- Refunds show up in M-Pesa statements like withdrawals do
+ALTER TABLE reconciliation_items ADD COLUMN IF NOT EXISTS refund_id UUID REFERENCES refunds(id);
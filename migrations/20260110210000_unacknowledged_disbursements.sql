@@ -0,0 +1,9 @@
+-- Migration for Unacknowledged Disbursements
+-- A loan payout attempt with no result after a while, whether or not Daraja accepted it, may
+-- still have been paid. Instead of failing it and paying the loan out again, such an attempt
+-- is flagged and left to the M-Pesa statement: a matching line completes it, a statement
+-- covering its time without one fails it so the next attempt can go out.
+ALTER TABLE loan_disbursements ADD COLUMN IF NOT EXISTS unacknowledged_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_loan_disbursements_unacknowledged ON loan_disbursements(created_at)
+    WHERE status = 'pending' AND unacknowledged_at IS NOT NULL;
@@ -0,0 +1,12 @@
+-- Migration for Loan Funding Payments
+-- A lender pays a loan's capital in with an STK push like a deposit. The loan is only funded,
+-- and paid out, once M-Pesa confirms the payment; capital that cannot be used is refunded.
+ALTER TABLE payments DROP CONSTRAINT IF EXISTS payments_purpose_check;
+ALTER TABLE payments ADD CONSTRAINT payments_purpose_check CHECK (purpose IN ('savings_deposit', 'loan_repayment', 'loan_funding'));
+
+ALTER TABLE payments DROP CONSTRAINT IF EXISTS payments_loan_funding_check;
+ALTER TABLE payments ADD CONSTRAINT payments_loan_funding_check CHECK (purpose <> 'loan_funding' OR loan_id IS NOT NULL);
+
+-- One lender at a time pays for a loan
+CREATE UNIQUE INDEX IF NOT EXISTS idx_payments_pending_funding ON payments(loan_id)
+    WHERE purpose = 'loan_funding' AND status = 'pending';
@@ -0,0 +1,5 @@
+-- Migration for Non-Negative Savings
+-- A goal can never hold less than nothing; a reversal or payout that would take it below zero
+-- is refused by the database even if a check in the application misses it.
+ALTER TABLE savings DROP CONSTRAINT IF EXISTS savings_amount_check;
+ALTER TABLE savings ADD CONSTRAINT savings_amount_check CHECK (amount >= 0);
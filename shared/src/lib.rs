@@ -0,0 +1,7 @@
+//! Types shared by the MicroFund backend, the Yew frontend and the on-chain program.
+
+pub mod money;
+#[cfg(test)]
+mod tests;
+
+pub use money::{Currency, Money, MoneyError, Rounding, PLATFORM_CURRENCY};
@@ -0,0 +1,374 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Currencies the platform knows how to represent. Amounts are always held as an
+/// integer number of the currency's minor unit (cents for KES, whole shillings for UGX).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum Currency {
+    #[default]
+    KES,
+    TZS,
+    UGX,
+    USD,
+}
+
+/// Currency all balances are booked in. M-Pesa settles in Kenyan shillings.
+pub const PLATFORM_CURRENCY: Currency = Currency::KES;
+
+impl Currency {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Currency::KES => "KES",
+            Currency::TZS => "TZS",
+            Currency::UGX => "UGX",
+            Currency::USD => "USD",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "KES" => Some(Currency::KES),
+            "TZS" => Some(Currency::TZS),
+            "UGX" => Some(Currency::UGX),
+            "USD" => Some(Currency::USD),
+            _ => None,
+        }
+    }
+
+    /// Number of decimal places between the major and the minor unit (ISO 4217).
+    pub fn exponent(&self) -> u32 {
+        match self {
+            Currency::UGX => 0,
+            Currency::KES | Currency::TZS | Currency::USD => 2,
+        }
+    }
+
+    /// ISO code as fixed-size bytes, the form stored in the on-chain `LoanAccount`.
+    pub fn code_bytes(&self) -> [u8; 3] {
+        let mut bytes = [0u8; 3];
+        bytes.copy_from_slice(self.code().as_bytes());
+        bytes
+    }
+
+    fn minor_per_major(&self) -> i64 {
+        10i64.pow(self.exponent())
+    }
+}
+
+/// How to resolve a result that falls between two minor units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// Ties go to the even neighbour (banker's rounding). Default for interest accrual.
+    HalfEven,
+    /// Ties go away from zero.
+    HalfUp,
+    /// Truncate toward zero.
+    Down,
+    /// Always away from zero when there is a remainder.
+    Up,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoneyError {
+    CurrencyMismatch(Currency, Currency),
+    Overflow,
+    InvalidFormat(String),
+    TooPrecise(Currency),
+    Negative,
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::CurrencyMismatch(a, b) => write!(f, "Cannot combine {} with {}", a.code(), b.code()),
+            MoneyError::Overflow => write!(f, "Amount is out of range"),
+            MoneyError::InvalidFormat(input) => write!(f, "'{}' is not a valid amount", input),
+            MoneyError::TooPrecise(currency) => write!(
+                f,
+                "{} amounts allow at most {} decimal places",
+                currency.code(),
+                currency.exponent()
+            ),
+            MoneyError::Negative => write!(f, "Amount cannot be negative"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+/// An exact amount of money: an integer count of minor units tagged with its currency.
+/// Negative values are allowed so ledger credits can be represented directly.
+/// The default is zero in the platform currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Money {
+    minor_units: i64,
+    currency: Currency,
+}
+
+impl Money {
+    pub const fn new(minor_units: i64, currency: Currency) -> Self {
+        Self { minor_units, currency }
+    }
+
+    pub const fn zero(currency: Currency) -> Self {
+        Self::new(0, currency)
+    }
+
+    /// Whole major units, e.g. `Money::from_major(10, KES)` is KES 10.00.
+    pub fn from_major(major: i64, currency: Currency) -> Result<Self, MoneyError> {
+        major
+            .checked_mul(currency.minor_per_major())
+            .map(|minor| Self::new(minor, currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn minor_units(&self) -> i64 {
+        self.minor_units
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.minor_units == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.minor_units > 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.minor_units < 0
+    }
+
+    pub fn abs(&self) -> Self {
+        Self::new(self.minor_units.abs(), self.currency)
+    }
+
+    pub fn checked_add(&self, other: Money) -> Result<Self, MoneyError> {
+        self.same_currency(&other)?;
+        self.minor_units
+            .checked_add(other.minor_units)
+            .map(|minor| Self::new(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_sub(&self, other: Money) -> Result<Self, MoneyError> {
+        self.same_currency(&other)?;
+        self.minor_units
+            .checked_sub(other.minor_units)
+            .map(|minor| Self::new(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_neg(&self) -> Result<Self, MoneyError> {
+        self.minor_units
+            .checked_neg()
+            .map(|minor| Self::new(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// Sums amounts that must all be in `currency`.
+    pub fn sum<I: IntoIterator<Item = Money>>(amounts: I, currency: Currency) -> Result<Self, MoneyError> {
+        amounts
+            .into_iter()
+            .try_fold(Self::zero(currency), |total, amount| total.checked_add(amount))
+    }
+
+    /// Multiplies by `numerator / denominator`, rounding the result to a whole minor unit.
+    /// Used for interest and fee calculations, e.g. `mul_ratio(150, 10_000, ..)` for 1.5%.
+    pub fn mul_ratio(&self, numerator: i64, denominator: i64, rounding: Rounding) -> Result<Self, MoneyError> {
+        if denominator == 0 {
+            return Err(MoneyError::Overflow);
+        }
+        let product = self.minor_units as i128 * numerator as i128;
+        let minor = divide_rounded(product, denominator as i128, rounding);
+        i64::try_from(minor)
+            .map(|minor| Self::new(minor, self.currency))
+            .map_err(|_| MoneyError::Overflow)
+    }
+
+    /// Rate expressed in basis points (1/100th of a percent).
+    pub fn percent_bps(&self, bps: i64, rounding: Rounding) -> Result<Self, MoneyError> {
+        self.mul_ratio(bps, 10_000, rounding)
+    }
+
+    /// Splits the amount into `parts` amounts that differ by at most one minor unit and
+    /// add back up exactly. The leftover minor units go to the first parts.
+    pub fn allocate(&self, parts: usize) -> Vec<Money> {
+        if parts == 0 {
+            return Vec::new();
+        }
+        let parts_i = parts as i64;
+        let base = self.minor_units / parts_i;
+        let remainder = self.minor_units % parts_i;
+        let step = remainder.signum();
+        (0..parts_i)
+            .map(|i| {
+                let extra = if i < remainder.abs() { step } else { 0 };
+                Self::new(base + extra, self.currency)
+            })
+            .collect()
+    }
+
+    /// Parses a decimal string such as "1250.5" exactly. Inputs with more decimal places
+    /// than the currency allows are rejected rather than silently rounded.
+    pub fn parse(input: &str, currency: Currency) -> Result<Self, MoneyError> {
+        let (_, _, fraction) = split_decimal(input)?;
+        if fraction.len() as u32 > currency.exponent() {
+            return Err(MoneyError::TooPrecise(currency));
+        }
+        // No digits beyond the minor unit, so the rounding rule never applies.
+        Self::parse_rounded(input, currency, Rounding::Down)
+    }
+
+    /// Parses a decimal string, rounding any digits beyond the currency's minor unit.
+    pub fn parse_rounded(input: &str, currency: Currency, rounding: Rounding) -> Result<Self, MoneyError> {
+        let (negative, whole, fraction) = split_decimal(input)?;
+        let exponent = currency.exponent() as usize;
+
+        let mut digits = String::with_capacity(whole.len() + fraction.len().max(exponent));
+        digits.push_str(if whole.is_empty() { "0" } else { whole });
+        digits.push_str(fraction);
+        for _ in fraction.len()..exponent {
+            digits.push('0');
+        }
+
+        let extra_digits = fraction.len().saturating_sub(exponent) as u32;
+        let scaled: i128 = digits.parse().map_err(|_| MoneyError::Overflow)?;
+        let signed = if negative { -scaled } else { scaled };
+        let divisor = 10i128.checked_pow(extra_digits).ok_or(MoneyError::Overflow)?;
+        let minor = divide_rounded(signed, divisor, rounding);
+
+        i64::try_from(minor)
+            .map(|minor| Self::new(minor, currency))
+            .map_err(|_| MoneyError::Overflow)
+    }
+
+    /// Amount without the currency code, e.g. "1250.50".
+    pub fn format_amount(&self) -> String {
+        let exponent = self.currency.exponent();
+        let sign = if self.minor_units < 0 { "-" } else { "" };
+        let abs = self.minor_units.unsigned_abs();
+        if exponent == 0 {
+            return format!("{}{}", sign, abs);
+        }
+        let scale = 10u64.pow(exponent);
+        format!("{}{}.{:0width$}", sign, abs / scale, abs % scale, width = exponent as usize)
+    }
+
+    /// Unsigned minor units for the on-chain `LoanAccount.amount` field.
+    pub fn to_chain_units(&self) -> Result<u64, MoneyError> {
+        u64::try_from(self.minor_units).map_err(|_| MoneyError::Negative)
+    }
+
+    fn same_currency(&self, other: &Money) -> Result<(), MoneyError> {
+        if self.currency != other.currency {
+            return Err(MoneyError::CurrencyMismatch(self.currency, other.currency));
+        }
+        Ok(())
+    }
+}
+
+impl PartialOrd for Money {
+    /// Amounts in different currencies are not comparable.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(self.minor_units.cmp(&other.minor_units))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.currency.code(), self.format_amount())
+    }
+}
+
+/// Splits "-12.345" into (true, "12", "345"), validating that only digits are present.
+fn split_decimal(input: &str) -> Result<(bool, &str, &str), MoneyError> {
+    let trimmed = input.trim();
+    let (negative, unsigned) = match trimmed.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, trimmed),
+    };
+    let (whole, fraction) = match unsigned.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (unsigned, ""),
+    };
+    let valid = !(whole.is_empty() && fraction.is_empty())
+        && whole.chars().all(|c| c.is_ascii_digit())
+        && fraction.chars().all(|c| c.is_ascii_digit());
+    if !valid {
+        return Err(MoneyError::InvalidFormat(input.to_string()));
+    }
+    Ok((negative, whole, fraction))
+}
+
+/// Integer division of `numerator` by a positive `denominator` using the given rounding rule.
+fn divide_rounded(numerator: i128, denominator: i128, rounding: Rounding) -> i128 {
+    let (numerator, denominator) = if denominator < 0 { (-numerator, -denominator) } else { (numerator, denominator) };
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    if remainder == 0 {
+        return quotient;
+    }
+
+    let away = quotient + numerator.signum();
+    let twice_remainder = remainder.abs() * 2;
+    match rounding {
+        Rounding::Down => quotient,
+        Rounding::Up => away,
+        Rounding::HalfUp => if twice_remainder >= denominator { away } else { quotient },
+        Rounding::HalfEven => match twice_remainder.cmp(&denominator) {
+            Ordering::Greater => away,
+            Ordering::Less => quotient,
+            Ordering::Equal => if quotient % 2 == 0 { quotient } else { away },
+        },
+    }
+}
+
+#[cfg(feature = "sqlx")]
+mod sqlx_support {
+    use super::{Money, PLATFORM_CURRENCY};
+    use sqlx::encode::IsNull;
+    use sqlx::error::BoxDynError;
+    use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
+    use sqlx::{Decode, Encode, Postgres, Type};
+
+    // Money columns are BIGINT minor units of the platform currency.
+
+    impl Type<Postgres> for Money {
+        fn type_info() -> PgTypeInfo {
+            <i64 as Type<Postgres>>::type_info()
+        }
+
+        fn compatible(ty: &PgTypeInfo) -> bool {
+            <i64 as Type<Postgres>>::compatible(ty)
+        }
+    }
+
+    impl Encode<'_, Postgres> for Money {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+            if self.currency != PLATFORM_CURRENCY {
+                return Err(format!(
+                    "Cannot store {} amounts; the ledger is kept in {}",
+                    self.currency.code(),
+                    PLATFORM_CURRENCY.code()
+                )
+                .into());
+            }
+            <i64 as Encode<Postgres>>::encode_by_ref(&self.minor_units, buf)
+        }
+    }
+
+    impl<'r> Decode<'r, Postgres> for Money {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            let minor_units = <i64 as Decode<Postgres>>::decode(value)?;
+            Ok(Money::new(minor_units, PLATFORM_CURRENCY))
+        }
+    }
+}
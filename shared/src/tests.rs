@@ -0,0 +1,102 @@
+use crate::money::{Currency, Money, MoneyError, Rounding};
+
+fn kes(minor: i64) -> Money {
+    Money::new(minor, Currency::KES)
+}
+
+#[test]
+fn test_parse_exact() {
+    assert_eq!(Money::parse("100.10", Currency::KES), Ok(kes(10010)));
+    assert_eq!(Money::parse("0.1", Currency::KES), Ok(kes(10)));
+    assert_eq!(Money::parse("25", Currency::KES), Ok(kes(2500)));
+    assert_eq!(Money::parse(".5", Currency::KES), Ok(kes(50)));
+    assert_eq!(Money::parse("-3.07", Currency::KES), Ok(kes(-307)));
+    assert_eq!(Money::parse("1500", Currency::UGX), Ok(Money::new(1500, Currency::UGX)));
+}
+
+#[test]
+fn test_parse_rejects_bad_input() {
+    assert_eq!(Money::parse("1.005", Currency::KES), Err(MoneyError::TooPrecise(Currency::KES)));
+    assert_eq!(Money::parse("10.5", Currency::UGX), Err(MoneyError::TooPrecise(Currency::UGX)));
+    assert!(matches!(Money::parse("1e3", Currency::KES), Err(MoneyError::InvalidFormat(_))));
+    assert!(matches!(Money::parse("", Currency::KES), Err(MoneyError::InvalidFormat(_))));
+    assert!(matches!(Money::parse("12.3.4", Currency::KES), Err(MoneyError::InvalidFormat(_))));
+    assert_eq!(Money::parse("99999999999999999999", Currency::KES), Err(MoneyError::Overflow));
+}
+
+#[test]
+fn test_parse_rounding_rules() {
+    let half_even = |s| Money::parse_rounded(s, Currency::KES, Rounding::HalfEven).unwrap();
+    let half_up = |s| Money::parse_rounded(s, Currency::KES, Rounding::HalfUp).unwrap();
+    let down = |s| Money::parse_rounded(s, Currency::KES, Rounding::Down).unwrap();
+    let up = |s| Money::parse_rounded(s, Currency::KES, Rounding::Up).unwrap();
+
+    // Ties
+    assert_eq!(half_even("0.125"), kes(12));
+    assert_eq!(half_even("0.135"), kes(14));
+    assert_eq!(half_up("0.125"), kes(13));
+    assert_eq!(half_even("-0.125"), kes(-12));
+    assert_eq!(half_up("-0.125"), kes(-13));
+
+    // Non-ties
+    assert_eq!(half_even("0.1251"), kes(13));
+    assert_eq!(half_up("0.1249"), kes(12));
+    assert_eq!(down("0.129"), kes(12));
+    assert_eq!(down("-0.129"), kes(-12));
+    assert_eq!(up("0.121"), kes(13));
+    assert_eq!(up("-0.121"), kes(-13));
+}
+
+#[test]
+fn test_interest_ratio_rounding() {
+    // 1.5% of KES 333.33 = 4.99995
+    assert_eq!(kes(33333).percent_bps(150, Rounding::HalfEven), Ok(kes(500)));
+    assert_eq!(kes(33333).percent_bps(150, Rounding::Down), Ok(kes(499)));
+    // KES 0.05 / 2 = 0.025 -> tie
+    assert_eq!(kes(5).mul_ratio(1, 2, Rounding::HalfEven), Ok(kes(2)));
+    assert_eq!(kes(5).mul_ratio(1, 2, Rounding::HalfUp), Ok(kes(3)));
+    assert_eq!(kes(7).mul_ratio(1, 2, Rounding::HalfEven), Ok(kes(4)));
+    assert_eq!(kes(100).mul_ratio(1, 0, Rounding::HalfEven), Err(MoneyError::Overflow));
+}
+
+#[test]
+fn test_allocate_preserves_total() {
+    let parts = kes(10000).allocate(3);
+    assert_eq!(parts, vec![kes(3334), kes(3333), kes(3333)]);
+    assert_eq!(Money::sum(parts, Currency::KES), Ok(kes(10000)));
+
+    let negative = kes(-101).allocate(4);
+    assert_eq!(Money::sum(negative.clone(), Currency::KES), Ok(kes(-101)));
+    assert_eq!(negative[0], kes(-26));
+}
+
+#[test]
+fn test_arithmetic_is_checked() {
+    assert_eq!(kes(150).checked_add(kes(250)), Ok(kes(400)));
+    assert_eq!(kes(150).checked_sub(kes(250)), Ok(kes(-100)));
+    assert_eq!(
+        kes(1).checked_add(Money::new(1, Currency::USD)),
+        Err(MoneyError::CurrencyMismatch(Currency::KES, Currency::USD))
+    );
+    assert_eq!(kes(i64::MAX).checked_add(kes(1)), Err(MoneyError::Overflow));
+    assert_eq!(kes(1).partial_cmp(&Money::new(1, Currency::USD)), None);
+    assert!(kes(2) > kes(1));
+}
+
+#[test]
+fn test_formatting_and_chain_units() {
+    assert_eq!(kes(10010).to_string(), "KES 100.10");
+    assert_eq!(kes(-5).to_string(), "KES -0.05");
+    assert_eq!(Money::new(1500, Currency::UGX).to_string(), "UGX 1500");
+    assert_eq!(Money::from_major(10, Currency::KES), Ok(kes(1000)));
+    assert_eq!(kes(10010).to_chain_units(), Ok(10010));
+    assert_eq!(kes(-1).to_chain_units(), Err(MoneyError::Negative));
+    assert_eq!(Currency::KES.code_bytes(), *b"KES");
+}
+
+#[test]
+fn test_json_round_trip() {
+    let json = serde_json::to_string(&kes(10010)).unwrap();
+    assert_eq!(json, r#"{"minor_units":10010,"currency":"KES"}"#);
+    assert_eq!(serde_json::from_str::<Money>(&json).unwrap(), kes(10010));
+}